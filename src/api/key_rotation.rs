@@ -0,0 +1,113 @@
+use anyhow::bail;
+use bc_components::{PrivateKeyBase, PublicKeyBase};
+use bc_envelope::prelude::*;
+use depo_api::util::Abbrev;
+
+use super::{GRACE_PERIOD_END_PARAM, PREVIOUS_KEY_PARAM, ROTATION_DATE_PARAM};
+
+/// A statement that the server's key has been replaced, published on the
+/// `/key-rotations` route.
+///
+/// The statement is signed by both the previous and the new key, so a client
+/// that trusts the previous key can trust the new one. The server still accepts
+/// requests encrypted to the previous key until the grace period ends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRotation {
+    previous_key: PublicKeyBase,
+    new_key: PublicKeyBase,
+    date: dcbor::Date,
+    grace_period_end: dcbor::Date,
+}
+
+impl KeyRotation {
+    pub fn new(
+        previous_key: PublicKeyBase,
+        new_key: PublicKeyBase,
+        date: dcbor::Date,
+        grace_period_end: dcbor::Date,
+    ) -> Self {
+        Self { previous_key, new_key, date, grace_period_end }
+    }
+
+    pub fn previous_key(&self) -> &PublicKeyBase {
+        &self.previous_key
+    }
+
+    pub fn new_key(&self) -> &PublicKeyBase {
+        &self.new_key
+    }
+
+    pub fn date(&self) -> &dcbor::Date {
+        &self.date
+    }
+
+    pub fn grace_period_end(&self) -> &dcbor::Date {
+        &self.grace_period_end
+    }
+
+    /// Returns the statement signed by the private keys of both the previous
+    /// and the new key.
+    pub fn sign(&self, previous_private_key: &PrivateKeyBase, new_private_key: &PrivateKeyBase) -> Envelope {
+        self.clone()
+            .envelope()
+            .wrap_envelope()
+            .sign_with_keys(&[previous_private_key, new_private_key])
+    }
+
+    /// Returns the rotation in a signed statement, failing unless it replaces
+    /// `previous_key` and is signed by both that key and the new one.
+    pub fn verify(statement: &Envelope, previous_key: &PublicKeyBase) -> anyhow::Result<Self> {
+        let rotation = Self::from_envelope(statement.unwrap_envelope()?)?;
+        if &rotation.previous_key != previous_key {
+            bail!("the key rotation replaces {}, not {}", rotation.previous_key.abbrev(), previous_key.abbrev());
+        }
+        statement.verify_signatures_from(&[&rotation.previous_key, &rotation.new_key])?;
+        Ok(rotation)
+    }
+}
+
+impl EnvelopeEncodable for KeyRotation {
+    fn envelope(self) -> Envelope {
+        Envelope::new(self.new_key)
+            .add_assertion(PREVIOUS_KEY_PARAM, self.previous_key)
+            .add_assertion(ROTATION_DATE_PARAM, self.date)
+            .add_assertion(GRACE_PERIOD_END_PARAM, self.grace_period_end)
+    }
+}
+
+impl From<KeyRotation> for Envelope {
+    fn from(value: KeyRotation) -> Self {
+        value.envelope()
+    }
+}
+
+impl EnvelopeDecodable for KeyRotation {
+    fn from_envelope(envelope: Envelope) -> anyhow::Result<Self> {
+        Ok(Self::new(
+            envelope.extract_object_for_predicate(PREVIOUS_KEY_PARAM)?,
+            envelope.extract_subject()?,
+            envelope.extract_object_for_predicate(ROTATION_DATE_PARAM)?,
+            envelope.extract_object_for_predicate(GRACE_PERIOD_END_PARAM)?,
+        ))
+    }
+}
+
+impl TryFrom<Envelope> for KeyRotation {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Envelope) -> anyhow::Result<Self> {
+        Self::from_envelope(value)
+    }
+}
+
+impl EnvelopeCodable for KeyRotation {}
+
+impl std::fmt::Display for KeyRotation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("key rotation from {} to {}, accepting the previous key until {}",
+            self.previous_key.abbrev(),
+            self.new_key.abbrev(),
+            self.grace_period_end,
+        ))
+    }
+}
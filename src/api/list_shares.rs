@@ -0,0 +1,219 @@
+use bc_components::{PublicKeyBase, ARID};
+use bc_envelope::prelude::*;
+use depo_api::{parse_request, parse_response, receipt::Receipt, response_envelope, util::{Abbrev, FlankedFunction}};
+
+use super::{request_body, request_envelope, CREATED_PARAM, EXPIRY_PARAM, LABEL_PARAM_NAME, LIST_SHARES_FUNCTION};
+
+//
+// Request
+//
+
+/// Asks for the receipts of all the account's shares, with their metadata but
+/// without their data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListSharesRequest {
+    id: ARID,
+    key: PublicKeyBase,
+}
+
+impl ListSharesRequest {
+    pub fn new(key: impl AsRef<PublicKeyBase>) -> Self {
+        Self::new_opt(ARID::new(), key.as_ref().clone())
+    }
+
+    pub fn new_opt(id: ARID, key: PublicKeyBase) -> Self {
+        Self { id, key }
+    }
+
+    pub fn id(&self) -> &ARID {
+        &self.id
+    }
+
+    pub fn key(&self) -> &PublicKeyBase {
+        &self.key
+    }
+}
+
+impl EnvelopeEncodable for ListSharesRequest {
+    fn envelope(self) -> Envelope {
+        request_envelope(self.id, request_body(LIST_SHARES_FUNCTION, self.key))
+    }
+}
+
+impl From<ListSharesRequest> for Envelope {
+    fn from(value: ListSharesRequest) -> Self {
+        value.envelope()
+    }
+}
+
+impl EnvelopeDecodable for ListSharesRequest {
+    fn from_envelope(envelope: Envelope) -> anyhow::Result<Self> {
+        let (id, key, _body) = parse_request(LIST_SHARES_FUNCTION, envelope)?;
+        Ok(Self::new_opt(id, key))
+    }
+}
+
+impl TryFrom<Envelope> for ListSharesRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Envelope) -> anyhow::Result<Self> {
+        Self::from_envelope(value)
+    }
+}
+
+impl EnvelopeCodable for ListSharesRequest {}
+
+impl std::fmt::Display for ListSharesRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}: {} key {}",
+            self.id().abbrev(),
+            "listShares".flanked_function(),
+            self.key().abbrev()
+        ))
+    }
+}
+
+//
+// Response
+//
+
+/// What is known about a stored share, short of its data.
+#[derive(Debug, Clone)]
+pub struct ShareInfo {
+    receipt: Receipt,
+    size: usize,
+    created: Option<dcbor::Date>,
+    expiry: Option<dcbor::Date>,
+    label: Option<Envelope>,
+}
+
+impl ShareInfo {
+    pub fn new(receipt: Receipt, size: usize, created: Option<dcbor::Date>, expiry: Option<dcbor::Date>, label: Option<Envelope>) -> Self {
+        Self { receipt, size, created, expiry, label }
+    }
+
+    pub fn receipt(&self) -> &Receipt {
+        &self.receipt
+    }
+
+    /// The size of the share's data in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// When the share was stored, which is unknown for shares stored before
+    /// the depository recorded it.
+    pub fn created(&self) -> Option<&dcbor::Date> {
+        self.created.as_ref()
+    }
+
+    /// When the share will be removed, if it was stored with a time to live.
+    pub fn expiry(&self) -> Option<&dcbor::Date> {
+        self.expiry.as_ref()
+    }
+
+    /// The label the client stored with the share, if any.
+    pub fn label(&self) -> Option<&Envelope> {
+        self.label.as_ref()
+    }
+}
+
+impl PartialEq for ShareInfo {
+    fn eq(&self, other: &Self) -> bool {
+        self.receipt == other.receipt
+            && self.size == other.size
+            && self.created == other.created
+            && self.expiry == other.expiry
+            && match (&self.label, &other.label) {
+                (Some(label), Some(other_label)) => label.is_identical_to(other_label.clone()),
+                (None, None) => true,
+                _ => false,
+            }
+    }
+}
+
+impl Eq for ShareInfo {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListSharesResponse {
+    id: ARID,
+    shares: Vec<ShareInfo>,
+}
+
+impl ListSharesResponse {
+    pub fn new(id: ARID, mut shares: Vec<ShareInfo>) -> Self {
+        // An envelope does not preserve the order of its assertions.
+        shares.sort_by(|a, b| (&a.created, a.receipt.data()).cmp(&(&b.created, b.receipt.data())));
+        Self { id, shares }
+    }
+
+    pub fn id(&self) -> &ARID {
+        &self.id
+    }
+
+    /// The account's shares, oldest first.
+    pub fn shares(&self) -> &[ShareInfo] {
+        &self.shares
+    }
+}
+
+impl EnvelopeEncodable for ListSharesResponse {
+    fn envelope(self) -> Envelope {
+        let mut result = known_values::OK_VALUE.envelope();
+        for share in self.shares {
+            let info = Envelope::new(share.size as u64)
+                .add_optional_assertion(CREATED_PARAM, share.created)
+                .add_optional_assertion(EXPIRY_PARAM, share.expiry)
+                .add_optional_assertion(LABEL_PARAM_NAME, share.label);
+            result = result.add_assertion(share.receipt, info);
+        }
+        response_envelope(self.id, Some(result))
+    }
+}
+
+impl From<ListSharesResponse> for Envelope {
+    fn from(value: ListSharesResponse) -> Self {
+        value.envelope()
+    }
+}
+
+impl EnvelopeDecodable for ListSharesResponse {
+    fn from_envelope(envelope: Envelope) -> anyhow::Result<Self> {
+        let (id, result) = parse_response(envelope)?;
+        let mut shares = Vec::new();
+        for assertion in result.assertions() {
+            let receipt: Receipt = assertion.expect_predicate()?.try_into()?;
+            let info = assertion.expect_object()?;
+            let size: u64 = info.extract_subject()?;
+            shares.push(ShareInfo::new(
+                receipt,
+                size.try_into()?,
+                info.extract_optional_object_for_predicate(CREATED_PARAM)?,
+                info.extract_optional_object_for_predicate(EXPIRY_PARAM)?,
+                info.object_for_predicate(LABEL_PARAM_NAME).ok(),
+            ));
+        }
+        Ok(Self::new(id, shares))
+    }
+}
+
+impl TryFrom<Envelope> for ListSharesResponse {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Envelope) -> anyhow::Result<Self> {
+        Self::from_envelope(value)
+    }
+}
+
+impl EnvelopeCodable for ListSharesResponse {}
+
+impl std::fmt::Display for ListSharesResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let receipts = self.shares.iter().map(|share| share.receipt.clone()).collect::<Vec<_>>();
+        f.write_fmt(format_args!("{}: {} OK {}",
+            self.id().abbrev(),
+            "listShares".flanked_function(),
+            receipts.abbrev()
+        ))
+    }
+}
@@ -0,0 +1,156 @@
+//! Requests understood by this depository in addition to those defined by the
+//! `depo-api` crate.
+
+use std::collections::HashMap;
+
+use bc_components::{ARID, PublicKeyBase};
+use bc_envelope::prelude::*;
+use depo_api::{receipt::Receipt, KEY_PARAM};
+
+pub mod key_rotation;
+pub use key_rotation::KeyRotation;
+pub mod list_shares;
+pub use list_shares::{ListSharesRequest, ListSharesResponse, ShareInfo};
+pub mod reset_db;
+pub use reset_db::ResetDbRequest;
+pub mod server_descriptor;
+pub use server_descriptor::ServerDescriptor;
+pub mod update_share_label;
+pub use update_share_label::{UpdateShareLabelRequest, UpdateShareLabelResponse};
+
+// Functions
+
+pub const RESET_DB_FUNCTION_NAME: &str = "resetDb";
+pub const RESET_DB_FUNCTION: Function = Function::new_static_named(RESET_DB_FUNCTION_NAME);
+
+pub const LIST_SHARES_FUNCTION_NAME: &str = "listShares";
+pub const LIST_SHARES_FUNCTION: Function = Function::new_static_named(LIST_SHARES_FUNCTION_NAME);
+
+pub const UPDATE_SHARE_LABEL_FUNCTION_NAME: &str = "updateShareLabel";
+pub const UPDATE_SHARE_LABEL_FUNCTION: Function = Function::new_static_named(UPDATE_SHARE_LABEL_FUNCTION_NAME);
+
+// Parameters
+
+/// The date a request was made. Every request must carry one, and the server
+/// rejects requests whose date is too far from its own clock.
+pub const DATE_PARAM_NAME: &str = "date";
+pub const DATE_PARAM: Parameter = Parameter::new_static_named(DATE_PARAM_NAME);
+
+/// The one-time code delivered to the recovery contact by `startRecovery`,
+/// which must accompany the continuation in `finishRecovery`.
+pub const VERIFICATION_CODE_PARAM_NAME: &str = "verificationCode";
+pub const VERIFICATION_CODE_PARAM: Parameter = Parameter::new_static_named(VERIFICATION_CODE_PARAM_NAME);
+
+/// How many seconds a `storeShare` request asks for the share to be kept. The
+/// server caps it at its own limit, and keeps shares stored without one until
+/// they are deleted.
+pub const TTL_PARAM_NAME: &str = "ttl";
+pub const TTL_PARAM: Parameter = Parameter::new_static_named(TTL_PARAM_NAME);
+
+/// A small envelope the client stores alongside a share to tell it apart from
+/// the account's others, given to `storeShare` or `updateShareLabel`. The server
+/// never reads it, so clients should encrypt it before sending it.
+pub const LABEL_PARAM_NAME: &str = "label";
+pub const LABEL_PARAM: Parameter = Parameter::new_static_named(LABEL_PARAM_NAME);
+
+// Responses
+
+/// The predicate of the assertion that carries an error response's stable,
+/// machine-readable code, alongside the `error` message.
+pub const ERROR_CODE_PARAM: &str = "errorCode";
+
+/// Returns the code of an error response, if it has one.
+pub fn error_code(response: &Envelope) -> Option<String> {
+    response.extract_object_for_predicate(ERROR_CODE_PARAM).ok()
+}
+
+// Share listings
+
+/// The predicates of the assertions on the size of each share in a
+/// `listShares` response, giving when it was stored and when it expires. A
+/// labeled share's size also carries a `label` assertion.
+pub const CREATED_PARAM: &str = "created";
+pub const EXPIRY_PARAM: &str = "expiry";
+
+/// The predicate of the assertions on a `getShares` response that carry the
+/// labels of its shares. Each object is a share's receipt with a `label`
+/// assertion, which older clients ignore.
+pub const SHARE_LABEL_PARAM: &str = "shareLabel";
+
+// Key rotations
+
+/// The predicates of the assertions on a `KeyRotation` statement, whose subject
+/// is the new key.
+pub const PREVIOUS_KEY_PARAM: &str = "previousKey";
+pub const ROTATION_DATE_PARAM: &str = "rotationDate";
+pub const GRACE_PERIOD_END_PARAM: &str = "gracePeriodEnd";
+
+// Server descriptors
+
+/// The predicates of the assertions on a `ServerDescriptor`, whose subject is
+/// the server's public key. `function` and `recoveryVerification` are repeated
+/// once for each supported function and verification method.
+pub const VERSION_PARAM: &str = "version";
+pub const MAX_DATA_SIZE_PARAM: &str = "maxDataSize";
+pub const MAX_LABEL_SIZE_PARAM: &str = "maxLabelSize";
+pub const CONTINUATION_EXPIRY_SECONDS_PARAM: &str = "continuationExpirySeconds";
+pub const MAX_SHARES_PER_ACCOUNT_PARAM: &str = "maxSharesPerAccount";
+pub const MAX_BYTES_PER_ACCOUNT_PARAM: &str = "maxBytesPerAccount";
+pub const MAX_SHARE_TTL_SECONDS_PARAM: &str = "maxShareTtlSeconds";
+pub const REQUEST_WINDOW_SECONDS_PARAM: &str = "requestWindowSeconds";
+pub const FUNCTION_PARAM: &str = "function";
+pub const RECOVERY_VERIFICATION_PARAM: &str = "recoveryVerification";
+
+/// Returns the request with a date parameter added to its body.
+pub fn add_request_date(request: Envelope, date: dcbor::Date) -> anyhow::Result<Envelope> {
+    add_body_parameter(request, DATE_PARAM, date)
+}
+
+/// Returns the `finishRecovery` request with the verification code added to
+/// its body.
+pub fn add_verification_code(request: Envelope, code: impl Into<String>) -> anyhow::Result<Envelope> {
+    add_body_parameter(request, VERIFICATION_CODE_PARAM, code.into())
+}
+
+/// Returns the `storeShare` request with a time to live, in seconds, added to
+/// its body.
+pub fn add_share_ttl(request: Envelope, seconds: u32) -> anyhow::Result<Envelope> {
+    add_body_parameter(request, TTL_PARAM, seconds)
+}
+
+/// Returns the `storeShare` request with a label for the share added to its
+/// body.
+pub fn add_share_label(request: Envelope, label: Envelope) -> anyhow::Result<Envelope> {
+    add_body_parameter(request, LABEL_PARAM, label)
+}
+
+/// Returns the `getShares` response with the labels of its shares added.
+pub fn add_share_labels(response: Envelope, labels: impl IntoIterator<Item = (Receipt, Envelope)>) -> Envelope {
+    labels.into_iter().fold(response, |response, (receipt, label)| {
+        response.add_assertion(SHARE_LABEL_PARAM, receipt.envelope().add_assertion(LABEL_PARAM_NAME, label))
+    })
+}
+
+/// Returns the labels of the shares in a `getShares` response, for those that
+/// have one.
+pub fn share_labels(response: &Envelope) -> anyhow::Result<HashMap<Receipt, Envelope>> {
+    response.objects_for_predicate(SHARE_LABEL_PARAM)
+        .into_iter()
+        .map(|object| Ok((Receipt::from_envelope(object.clone())?, object.object_for_predicate(LABEL_PARAM_NAME)?)))
+        .collect()
+}
+
+fn add_body_parameter(request: Envelope, parameter: Parameter, value: impl EnvelopeEncodable) -> anyhow::Result<Envelope> {
+    let id = request.request_id()?;
+    let body = request.request_body()?.add_parameter(parameter, value);
+    Ok(request_envelope(id, body))
+}
+
+fn request_body(function: Function, key: PublicKeyBase) -> Envelope {
+    Envelope::new(function)
+        .add_parameter(KEY_PARAM, key)
+}
+
+fn request_envelope(id: ARID, body: Envelope) -> Envelope {
+    Envelope::new_request(id, body)
+}
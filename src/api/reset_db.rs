@@ -0,0 +1,74 @@
+use bc_components::{PublicKeyBase, ARID};
+use bc_envelope::prelude::*;
+use depo_api::{parse_request, util::{Abbrev, FlankedFunction}};
+
+use super::{request_body, request_envelope, RESET_DB_FUNCTION};
+
+//
+// Request
+//
+
+/// Asks the server to drop and recreate its database. Only accepted on the
+/// `/reset-db` route, and only when signed by the configured admin key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResetDbRequest {
+    id: ARID,
+    key: PublicKeyBase,
+}
+
+impl ResetDbRequest {
+    pub fn new(key: impl AsRef<PublicKeyBase>) -> Self {
+        Self::new_opt(ARID::new(), key.as_ref().clone())
+    }
+
+    pub fn new_opt(id: ARID, key: PublicKeyBase) -> Self {
+        Self { id, key }
+    }
+
+    pub fn id(&self) -> &ARID {
+        &self.id
+    }
+
+    pub fn key(&self) -> &PublicKeyBase {
+        &self.key
+    }
+}
+
+impl EnvelopeEncodable for ResetDbRequest {
+    fn envelope(self) -> Envelope {
+        request_envelope(self.id, request_body(RESET_DB_FUNCTION, self.key))
+    }
+}
+
+impl From<ResetDbRequest> for Envelope {
+    fn from(value: ResetDbRequest) -> Self {
+        value.envelope()
+    }
+}
+
+impl EnvelopeDecodable for ResetDbRequest {
+    fn from_envelope(envelope: Envelope) -> anyhow::Result<Self> {
+        let (id, key, _body) = parse_request(RESET_DB_FUNCTION, envelope)?;
+        Ok(Self::new_opt(id, key))
+    }
+}
+
+impl TryFrom<Envelope> for ResetDbRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Envelope) -> anyhow::Result<Self> {
+        Self::from_envelope(value)
+    }
+}
+
+impl EnvelopeCodable for ResetDbRequest {}
+
+impl std::fmt::Display for ResetDbRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}: {} key {}",
+            self.id().abbrev(),
+            "resetDb".flanked_function(),
+            self.key().abbrev()
+        ))
+    }
+}
@@ -0,0 +1,208 @@
+use anyhow::bail;
+use bc_components::{PrivateKeyBase, PublicKeyBase};
+use bc_envelope::prelude::*;
+use depo_api::util::Abbrev;
+
+use super::{
+    CONTINUATION_EXPIRY_SECONDS_PARAM, FUNCTION_PARAM, MAX_BYTES_PER_ACCOUNT_PARAM, MAX_DATA_SIZE_PARAM,
+    MAX_LABEL_SIZE_PARAM, MAX_SHARES_PER_ACCOUNT_PARAM, MAX_SHARE_TTL_SECONDS_PARAM, RECOVERY_VERIFICATION_PARAM,
+    REQUEST_WINDOW_SECONDS_PARAM, VERSION_PARAM,
+};
+
+/// What a client needs to know to configure itself for a depository, published
+/// on the `/descriptor` route.
+///
+/// The descriptor is signed by the server key it describes, so a client that
+/// has pinned that key can trust the limits it advertises.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerDescriptor {
+    public_key: PublicKeyBase,
+    version: String,
+    max_data_size: u32,
+    max_label_size: u32,
+    continuation_expiry_seconds: u32,
+    max_shares_per_account: u32,
+    max_bytes_per_account: u32,
+    max_share_ttl_seconds: u32,
+    request_window_seconds: u32,
+    functions: Vec<String>,
+    recovery_verifications: Vec<String>,
+}
+
+impl ServerDescriptor {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        public_key: PublicKeyBase,
+        version: impl Into<String>,
+        max_data_size: u32,
+        max_label_size: u32,
+        continuation_expiry_seconds: u32,
+        max_shares_per_account: u32,
+        max_bytes_per_account: u32,
+        max_share_ttl_seconds: u32,
+        request_window_seconds: u32,
+        functions: Vec<String>,
+        recovery_verifications: Vec<String>,
+    ) -> Self {
+        Self {
+            public_key,
+            version: version.into(),
+            max_data_size,
+            max_label_size,
+            continuation_expiry_seconds,
+            max_shares_per_account,
+            max_bytes_per_account,
+            max_share_ttl_seconds,
+            request_window_seconds,
+            functions,
+            recovery_verifications,
+        }
+    }
+
+    pub fn public_key(&self) -> &PublicKeyBase {
+        &self.public_key
+    }
+
+    /// The version of the server software.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// The largest share, in bytes, that `storeShare` accepts.
+    pub fn max_data_size(&self) -> u32 {
+        self.max_data_size
+    }
+
+    /// The largest share label, in bytes, that `storeShare` and
+    /// `updateShareLabel` accept.
+    pub fn max_label_size(&self) -> u32 {
+        self.max_label_size
+    }
+
+    /// How long a recovery continuation remains valid.
+    pub fn continuation_expiry_seconds(&self) -> u32 {
+        self.continuation_expiry_seconds
+    }
+
+    pub fn max_shares_per_account(&self) -> u32 {
+        self.max_shares_per_account
+    }
+
+    pub fn max_bytes_per_account(&self) -> u32 {
+        self.max_bytes_per_account
+    }
+
+    /// The longest time to live a share can be stored with.
+    pub fn max_share_ttl_seconds(&self) -> u32 {
+        self.max_share_ttl_seconds
+    }
+
+    /// How far the date of a request may be from the server's clock.
+    pub fn request_window_seconds(&self) -> u32 {
+        self.request_window_seconds
+    }
+
+    /// The names of the functions the server accepts on its main route.
+    pub fn functions(&self) -> &[String] {
+        &self.functions
+    }
+
+    /// How the server delivers recovery verification codes, such as `"email"`.
+    pub fn recovery_verifications(&self) -> &[String] {
+        &self.recovery_verifications
+    }
+
+    pub fn supports_function(&self, name: &str) -> bool {
+        self.functions.iter().any(|function| function == name)
+    }
+
+    /// Returns the descriptor signed by the server's private key.
+    pub fn sign(&self, private_key: &PrivateKeyBase) -> Envelope {
+        self.clone()
+            .envelope()
+            .wrap_envelope()
+            .sign_with(private_key)
+    }
+
+    /// Returns the descriptor in a signed envelope, failing unless it describes
+    /// `server_key` and is signed by it.
+    pub fn verify(signed: &Envelope, server_key: &PublicKeyBase) -> anyhow::Result<Self> {
+        let descriptor = Self::from_envelope(signed.unwrap_envelope()?)?;
+        if &descriptor.public_key != server_key {
+            bail!("the descriptor is for server {}, not {}", descriptor.public_key.abbrev(), server_key.abbrev());
+        }
+        signed.verify_signature_from(server_key)?;
+        Ok(descriptor)
+    }
+}
+
+impl EnvelopeEncodable for ServerDescriptor {
+    fn envelope(self) -> Envelope {
+        let mut envelope = Envelope::new(self.public_key)
+            .add_assertion(VERSION_PARAM, self.version)
+            .add_assertion(MAX_DATA_SIZE_PARAM, self.max_data_size)
+            .add_assertion(MAX_LABEL_SIZE_PARAM, self.max_label_size)
+            .add_assertion(CONTINUATION_EXPIRY_SECONDS_PARAM, self.continuation_expiry_seconds)
+            .add_assertion(MAX_SHARES_PER_ACCOUNT_PARAM, self.max_shares_per_account)
+            .add_assertion(MAX_BYTES_PER_ACCOUNT_PARAM, self.max_bytes_per_account)
+            .add_assertion(MAX_SHARE_TTL_SECONDS_PARAM, self.max_share_ttl_seconds)
+            .add_assertion(REQUEST_WINDOW_SECONDS_PARAM, self.request_window_seconds);
+        for function in self.functions {
+            envelope = envelope.add_assertion(FUNCTION_PARAM, function);
+        }
+        for method in self.recovery_verifications {
+            envelope = envelope.add_assertion(RECOVERY_VERIFICATION_PARAM, method);
+        }
+        envelope
+    }
+}
+
+impl From<ServerDescriptor> for Envelope {
+    fn from(value: ServerDescriptor) -> Self {
+        value.envelope()
+    }
+}
+
+impl EnvelopeDecodable for ServerDescriptor {
+    fn from_envelope(envelope: Envelope) -> anyhow::Result<Self> {
+        let mut functions: Vec<String> = envelope.extract_objects_for_predicate(FUNCTION_PARAM)?;
+        functions.sort();
+        let mut recovery_verifications: Vec<String> = envelope.extract_objects_for_predicate(RECOVERY_VERIFICATION_PARAM)?;
+        recovery_verifications.sort();
+        Ok(Self::new(
+            envelope.extract_subject()?,
+            envelope.extract_object_for_predicate::<String>(VERSION_PARAM)?,
+            envelope.extract_object_for_predicate(MAX_DATA_SIZE_PARAM)?,
+            envelope.extract_object_for_predicate(MAX_LABEL_SIZE_PARAM)?,
+            envelope.extract_object_for_predicate(CONTINUATION_EXPIRY_SECONDS_PARAM)?,
+            envelope.extract_object_for_predicate(MAX_SHARES_PER_ACCOUNT_PARAM)?,
+            envelope.extract_object_for_predicate(MAX_BYTES_PER_ACCOUNT_PARAM)?,
+            envelope.extract_object_for_predicate(MAX_SHARE_TTL_SECONDS_PARAM)?,
+            envelope.extract_object_for_predicate(REQUEST_WINDOW_SECONDS_PARAM)?,
+            functions,
+            recovery_verifications,
+        ))
+    }
+}
+
+impl TryFrom<Envelope> for ServerDescriptor {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Envelope) -> anyhow::Result<Self> {
+        Self::from_envelope(value)
+    }
+}
+
+impl EnvelopeCodable for ServerDescriptor {}
+
+impl std::fmt::Display for ServerDescriptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("server {} version {}, max data size {}, functions [{}], recovery verification [{}]",
+            self.public_key.abbrev(),
+            self.version,
+            self.max_data_size,
+            self.functions.join(", "),
+            self.recovery_verifications.join(", "),
+        ))
+    }
+}
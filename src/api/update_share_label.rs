@@ -0,0 +1,163 @@
+use bc_components::{PublicKeyBase, ARID};
+use bc_envelope::prelude::*;
+use depo_api::{parse_request, parse_response, receipt::Receipt, response_envelope, util::{Abbrev, FlankedFunction}, RECEIPT_PARAM};
+
+use super::{request_body, request_envelope, LABEL_PARAM, UPDATE_SHARE_LABEL_FUNCTION};
+
+//
+// Request
+//
+
+/// Replaces the label of one of the account's shares without touching its
+/// data, or removes the label if none is given.
+#[derive(Debug, Clone)]
+pub struct UpdateShareLabelRequest {
+    id: ARID,
+    key: PublicKeyBase,
+    receipt: Receipt,
+    label: Option<Envelope>,
+}
+
+impl UpdateShareLabelRequest {
+    pub fn new(key: impl AsRef<PublicKeyBase>, receipt: Receipt, label: Option<Envelope>) -> Self {
+        Self::new_opt(ARID::new(), key.as_ref().clone(), receipt, label)
+    }
+
+    pub fn new_opt(id: ARID, key: PublicKeyBase, receipt: Receipt, label: Option<Envelope>) -> Self {
+        Self { id, key, receipt, label }
+    }
+
+    pub fn id(&self) -> &ARID {
+        &self.id
+    }
+
+    pub fn key(&self) -> &PublicKeyBase {
+        &self.key
+    }
+
+    pub fn receipt(&self) -> &Receipt {
+        &self.receipt
+    }
+
+    pub fn label(&self) -> Option<&Envelope> {
+        self.label.as_ref()
+    }
+}
+
+impl PartialEq for UpdateShareLabelRequest {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+            && self.key == other.key
+            && self.receipt == other.receipt
+            && match (&self.label, &other.label) {
+                (Some(label), Some(other_label)) => label.is_identical_to(other_label.clone()),
+                (None, None) => true,
+                _ => false,
+            }
+    }
+}
+
+impl Eq for UpdateShareLabelRequest {}
+
+impl EnvelopeEncodable for UpdateShareLabelRequest {
+    fn envelope(self) -> Envelope {
+        let body = request_body(UPDATE_SHARE_LABEL_FUNCTION, self.key)
+            .add_parameter(RECEIPT_PARAM, self.receipt)
+            .add_optional_parameter(LABEL_PARAM, self.label);
+        request_envelope(self.id, body)
+    }
+}
+
+impl From<UpdateShareLabelRequest> for Envelope {
+    fn from(value: UpdateShareLabelRequest) -> Self {
+        value.envelope()
+    }
+}
+
+impl EnvelopeDecodable for UpdateShareLabelRequest {
+    fn from_envelope(envelope: Envelope) -> anyhow::Result<Self> {
+        let (id, key, body) = parse_request(UPDATE_SHARE_LABEL_FUNCTION, envelope)?;
+        let receipt = Receipt::from_envelope(body.object_for_parameter(RECEIPT_PARAM)?)?;
+        let label = body.object_for_parameter(LABEL_PARAM).ok();
+        Ok(Self::new_opt(id, key, receipt, label))
+    }
+}
+
+impl TryFrom<Envelope> for UpdateShareLabelRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Envelope) -> anyhow::Result<Self> {
+        Self::from_envelope(value)
+    }
+}
+
+impl EnvelopeCodable for UpdateShareLabelRequest {}
+
+impl std::fmt::Display for UpdateShareLabelRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}: {} {} key {} to {}",
+            self.id().abbrev(),
+            "updateShareLabel".flanked_function(),
+            self.receipt().abbrev(),
+            self.key().abbrev(),
+            self.label().map_or("none".to_string(), |label| label.abbrev())
+        ))
+    }
+}
+
+//
+// Response
+//
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateShareLabelResponse {
+    id: ARID,
+}
+
+impl UpdateShareLabelResponse {
+    pub fn new(id: ARID) -> Self {
+        Self { id }
+    }
+
+    pub fn id(&self) -> &ARID {
+        &self.id
+    }
+}
+
+impl EnvelopeEncodable for UpdateShareLabelResponse {
+    fn envelope(self) -> Envelope {
+        response_envelope(self.id, None)
+    }
+}
+
+impl From<UpdateShareLabelResponse> for Envelope {
+    fn from(value: UpdateShareLabelResponse) -> Self {
+        value.envelope()
+    }
+}
+
+impl EnvelopeDecodable for UpdateShareLabelResponse {
+    fn from_envelope(envelope: Envelope) -> anyhow::Result<Self> {
+        let (id, _result) = parse_response(envelope)?;
+        Ok(Self::new(id))
+    }
+}
+
+impl TryFrom<Envelope> for UpdateShareLabelResponse {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Envelope) -> anyhow::Result<Self> {
+        Self::from_envelope(value)
+    }
+}
+
+impl EnvelopeCodable for UpdateShareLabelResponse {}
+
+impl std::fmt::Display for UpdateShareLabelResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}: {} OK",
+            self.id().abbrev(),
+            "updateShareLabel".flanked_function()
+        ))
+    }
+}
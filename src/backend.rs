@@ -0,0 +1,98 @@
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::bail;
+
+use crate::{api::KeyRotation, db_depo, pg_depo, sqlite_depo, DbConfig, Depo, KeyStorage, MigrationPlan};
+
+/// The storage used by a depository, selected at startup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Backend {
+    /// Volatile storage that is lost when the process exits. Useful for
+    /// testing.
+    Memory,
+    /// A schema in a MySQL or MariaDB server.
+    MySql { config: DbConfig, schema_name: String },
+    /// A schema in a PostgreSQL server.
+    Postgres { config: DbConfig, schema_name: String },
+    /// An embedded SQLite database file.
+    Sqlite(PathBuf),
+}
+
+impl Backend {
+    pub async fn new_depo(&self) -> anyhow::Result<Depo> {
+        self.new_depo_with_key_storage(&KeyStorage::default()).await
+    }
+
+    /// Opens the depository with its private key in the given storage. The
+    /// memory backend ignores the storage, as it generates a new key each time.
+    pub async fn new_depo_with_key_storage(&self, key_storage: &KeyStorage) -> anyhow::Result<Depo> {
+        match self {
+            Self::Memory => Ok(Depo::new_in_memory()),
+            Self::MySql { config, schema_name } => Depo::new_db_with_key_storage(config, schema_name, key_storage).await,
+            Self::Postgres { config, schema_name } => Depo::new_pg_with_key_storage(config, schema_name, key_storage).await,
+            Self::Sqlite(path) => Depo::new_sqlite_with_key_storage(path, key_storage).await,
+        }
+    }
+
+    pub async fn reset_db(&self) -> anyhow::Result<()> {
+        match self {
+            Self::Memory => Ok(()),
+            Self::MySql { config, schema_name } => db_depo::reset_db(config, schema_name).await,
+            Self::Postgres { config, schema_name } => pg_depo::reset_db(config, schema_name).await,
+            Self::Sqlite(path) => sqlite_depo::reset_db(path),
+        }
+    }
+
+    pub async fn create_db_if_needed(&self) -> anyhow::Result<()> {
+        match self {
+            Self::Memory => Ok(()),
+            Self::MySql { config, schema_name } => db_depo::create_db_if_needed(config, schema_name).await,
+            Self::Postgres { config, schema_name } => pg_depo::create_db_if_needed(config, schema_name).await,
+            Self::Sqlite(path) => sqlite_depo::create_db_if_needed(path),
+        }
+    }
+
+    /// Brings the schema up to date by applying the migrations it needs, or
+    /// with `dry_run` only returns them. Fails if the schema is newer than this
+    /// server supports.
+    pub async fn migrate(&self, dry_run: bool) -> anyhow::Result<MigrationPlan> {
+        match self {
+            Self::Memory => Ok(MigrationPlan::default()),
+            Self::MySql { config, schema_name } => db_depo::migrate(config, schema_name, dry_run).await,
+            Self::Postgres { config, schema_name } => pg_depo::migrate(config, schema_name, dry_run).await,
+            Self::Sqlite(path) => sqlite_depo::migrate(path, dry_run),
+        }
+    }
+
+    /// Replaces the server key with a new one, which the depository uses the
+    /// next time it is opened. Requests encrypted to the previous key are still
+    /// accepted until the grace period ends.
+    pub async fn rotate_key(&self, key_storage: &KeyStorage, grace_period: Duration) -> anyhow::Result<KeyRotation> {
+        match self {
+            Self::Memory => bail!("the memory backend generates a new key each time it starts"),
+            Self::MySql { config, schema_name } => db_depo::rotate_key(config, schema_name, key_storage, grace_period).await,
+            Self::Postgres { config, schema_name } => pg_depo::rotate_key(config, schema_name, key_storage, grace_period).await,
+            Self::Sqlite(path) => sqlite_depo::rotate_key(path, key_storage, grace_period),
+        }
+    }
+
+    pub async fn can_connect_to_db(&self) -> anyhow::Result<bool> {
+        match self {
+            Self::Memory => Ok(true),
+            Self::MySql { config, schema_name } => db_depo::can_connect_to_db(config, schema_name).await,
+            Self::Postgres { config, schema_name } => pg_depo::can_connect_to_db(config, schema_name).await,
+            Self::Sqlite(path) => sqlite_depo::can_connect_to_db(path),
+        }
+    }
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Memory => write!(f, "memory"),
+            Self::MySql { schema_name, .. } => write!(f, "mysql:{}", schema_name),
+            Self::Postgres { schema_name, .. } => write!(f, "postgres:{}", schema_name),
+            Self::Sqlite(path) => write!(f, "sqlite:{}", path.display()),
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use std::{io::Write, path::{Path, PathBuf}, process::ExitCode};
+
+use anyhow::Context;
+use bc_components::{PrivateKeyBase, PublicKeyBase};
+use bc_envelope::prelude::*;
+use clap::{Parser, Subcommand};
+use depo::{setup_log_with_level, DepoClient};
+use depo_api::receipt::Receipt;
+use log::{error, info, LevelFilter};
+use nu_ansi_term::Color::{Green, Red};
+use url::Url;
+
+/// Command-line client for a Blockchain Commons Depository.
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    /// The depository's URL.
+    #[arg(long, env = "DEPO_URL", default_value = "http://localhost:5332", global = true)]
+    url: Url,
+
+    /// The file holding the account's private key (`ur:crypto-prvkeys`), as
+    /// written by `keygen`.
+    #[arg(long, env = "DEPO_CLI_KEY_FILE", global = true)]
+    key_file: Option<PathBuf>,
+
+    /// The server's public key (`ur:crypto-pubkeys`). Without it, the key the
+    /// server presents is trusted.
+    #[arg(long, env = "DEPO_SERVER_KEY", value_parser = parse_public_key, global = true)]
+    server_key: Option<PublicKeyBase>,
+
+    /// The most verbose level of log messages to show.
+    #[arg(long, env = "DEPO_LOG_LEVEL", default_value_t = LevelFilter::Warn, global = true)]
+    log_level: LevelFilter,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Generate a new account key and write it to `--key-file`, or print it if
+    /// no file is given.
+    Keygen,
+    /// Print the account's public key.
+    PublicKey,
+    /// Store the contents of a file as a share, printing its receipt.
+    Store {
+        file: PathBuf,
+        /// How many seconds the share is kept.
+        #[arg(long)]
+        ttl: Option<u32>,
+        /// A label to tell the share apart from others, encrypted to the
+        /// account's key before it is sent.
+        #[arg(long)]
+        label: Option<String>,
+    },
+    /// Print the receipts of all the account's shares, oldest first.
+    List {
+        /// Also print each share's size in bytes, when it was stored, when it
+        /// expires, and its label.
+        #[arg(long, short)]
+        long: bool,
+    },
+    /// Write a share to a file, or to standard output if no file is given.
+    Get {
+        #[arg(value_parser = parse_receipt)]
+        receipt: Receipt,
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Set the label of a share, or remove it if none is given.
+    SetLabel {
+        #[arg(value_parser = parse_receipt)]
+        receipt: Receipt,
+        label: Option<String>,
+    },
+    /// Delete shares by receipt.
+    Delete {
+        #[arg(value_parser = parse_receipt, required = true)]
+        receipts: Vec<Receipt>,
+    },
+    /// Set the account's recovery contact method, or remove it if none is
+    /// given.
+    SetRecovery {
+        recovery: Option<String>,
+    },
+    /// Print the account's recovery contact method.
+    GetRecovery,
+    /// Start moving the account with the given recovery contact method to the
+    /// key in `--key-file`, printing the continuation to pass to
+    /// `finish-recovery`.
+    StartRecovery {
+        recovery: String,
+    },
+    /// Finish moving an account to the key in `--key-file`, with the
+    /// continuation printed by `start-recovery` and the code sent to the
+    /// recovery contact.
+    FinishRecovery {
+        #[arg(value_parser = parse_envelope)]
+        continuation: Envelope,
+        verification_code: String,
+    },
+}
+
+fn parse_public_key(s: &str) -> Result<PublicKeyBase, String> {
+    PublicKeyBase::from_ur_string(s).map_err(|e| e.to_string())
+}
+
+fn parse_envelope(s: &str) -> Result<Envelope, String> {
+    Envelope::from_ur_string(s).map_err(|e| e.to_string())
+}
+
+fn parse_receipt(s: &str) -> Result<Receipt, String> {
+    parse_envelope(s).and_then(|envelope| Receipt::from_envelope(envelope).map_err(|e| e.to_string()))
+}
+
+fn receipt_string(receipt: &Receipt) -> String {
+    receipt.clone().envelope().ur_string()
+}
+
+/// Returns the label encrypted so that only the account's key can read it.
+fn seal_label(label: &str, private_key: &PrivateKeyBase) -> anyhow::Result<Envelope> {
+    Ok(Envelope::new(label).encrypt_subject_to_recipient(&private_key.public_keys())?)
+}
+
+/// Returns the text of a label sealed by `seal_label`.
+fn open_label(label: &Envelope, private_key: &PrivateKeyBase) -> anyhow::Result<String> {
+    label.decrypt_to_recipient(private_key)?.extract_subject()
+}
+
+impl Cli {
+    fn private_key(&self) -> anyhow::Result<PrivateKeyBase> {
+        let path = self.key_file.as_deref().context("give the account's key with --key-file")?;
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read key file {}", path.display()))?;
+        PrivateKeyBase::from_ur_string(contents.trim())
+            .with_context(|| format!("{} does not hold a ur:crypto-prvkeys", path.display()))
+    }
+
+    async fn client(&self) -> anyhow::Result<DepoClient> {
+        match &self.server_key {
+            Some(server_key) => Ok(DepoClient::new(self.url.clone(), server_key.clone())),
+            None => DepoClient::connect(self.url.clone()).await,
+        }
+    }
+
+    async fn run(&self) -> anyhow::Result<()> {
+        match &self.command {
+            Command::Keygen => {
+                let private_key = PrivateKeyBase::new();
+                match &self.key_file {
+                    Some(path) => {
+                        write_key_file(path, &private_key)?;
+                        info!("{}", Green.paint(format!("Wrote a new account key to {}.", path.display())));
+                        println!("{}", private_key.public_keys().ur_string());
+                    }
+                    None => println!("{}", private_key.ur_string()),
+                }
+            }
+            Command::PublicKey => println!("{}", self.private_key()?.public_keys().ur_string()),
+            Command::Store { file, ttl, label } => {
+                let data = std::fs::read(file).with_context(|| format!("could not read {}", file.display()))?;
+                let private_key = self.private_key()?;
+                let label = label.as_deref().map(|label| seal_label(label, &private_key)).transpose()?;
+                let receipt = self.client().await?.store_share(&private_key, data, *ttl, label.as_ref()).await?;
+                println!("{}", receipt_string(&receipt));
+            }
+            Command::List { long } => {
+                let private_key = self.private_key()?;
+                for share in self.client().await?.list_shares(&private_key).await? {
+                    if *long {
+                        let label = match share.label() {
+                            Some(label) => open_label(label, &private_key)?,
+                            None => "-".to_string(),
+                        };
+                        println!("{} {} {} {} {}",
+                            receipt_string(share.receipt()),
+                            share.size(),
+                            share.created().map_or("-".to_string(), |date| date.to_string()),
+                            share.expiry().map_or("-".to_string(), |date| date.to_string()),
+                            label,
+                        );
+                    } else {
+                        println!("{}", receipt_string(share.receipt()));
+                    }
+                }
+            }
+            Command::Get { receipt, output } => {
+                let data = self.client().await?.get_share(&self.private_key()?, receipt).await?;
+                match output {
+                    Some(path) => std::fs::write(path, &data).with_context(|| format!("could not write {}", path.display()))?,
+                    None => std::io::stdout().write_all(&data)?,
+                }
+            }
+            Command::SetLabel { receipt, label } => {
+                let private_key = self.private_key()?;
+                let label = label.as_deref().map(|label| seal_label(label, &private_key)).transpose()?;
+                self.client().await?.update_share_label(&private_key, receipt, label.as_ref()).await?;
+            }
+            Command::Delete { receipts } => {
+                let receipts = receipts.iter().cloned().collect();
+                self.client().await?.delete_shares(&self.private_key()?, &receipts).await?;
+            }
+            Command::SetRecovery { recovery } => {
+                self.client().await?.update_recovery(&self.private_key()?, recovery.as_deref()).await?;
+            }
+            Command::GetRecovery => {
+                if let Some(recovery) = self.client().await?.get_recovery(&self.private_key()?).await? {
+                    println!("{}", recovery);
+                }
+            }
+            Command::StartRecovery { recovery } => {
+                let continuation = self.client().await?.start_recovery(&self.private_key()?, recovery).await?;
+                println!("{}", continuation.ur_string());
+            }
+            Command::FinishRecovery { continuation, verification_code } => {
+                self.client().await?
+                    .finish_recovery(&self.private_key()?, continuation.clone(), verification_code)
+                    .await?;
+                info!("{}", Green.paint("The account now uses the key in --key-file."));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes the key to a new file that only its owner can read.
+fn write_key_file(path: &Path, private_key: &PrivateKeyBase) -> anyhow::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)
+        .with_context(|| format!("could not create key file {}", path.display()))?;
+    writeln!(file, "{}", private_key.ur_string())?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    setup_log_with_level(cli.log_level);
+
+    if let Err(e) = cli.run().await {
+        error!("{}", Red.paint(format!("{:#}", e)).to_string());
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
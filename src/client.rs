@@ -0,0 +1,265 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Context};
+use bc_components::{PrivateKeyBase, PublicKeyBase};
+use bc_envelope::prelude::*;
+use bytes::Bytes;
+use depo_api::{
+    receipt::Receipt, util::Abbrev, DeleteAccountRequest, DeleteAccountResponse, DeleteSharesRequest,
+    DeleteSharesResponse, FinishRecoveryRequest, FinishRecoveryResponse, GetRecoveryRequest,
+    GetRecoveryResponse, GetSharesRequest, GetSharesResponse, StartRecoveryRequest,
+    StartRecoveryResponse, StoreShareRequest, StoreShareResponse, UpdateKeyRequest,
+    UpdateKeyResponse, UpdateRecoveryRequest, UpdateRecoveryResponse,
+};
+use url::Url;
+
+use crate::api::{
+    add_request_date, add_share_label, add_share_ttl, add_verification_code, error_code, KeyRotation,
+    ListSharesRequest, ListSharesResponse, ServerDescriptor, ShareInfo, UpdateShareLabelRequest,
+    UpdateShareLabelResponse,
+};
+
+/// A client of a depository server, which encrypts each request to the server's
+/// public key, signs it with the account's private key, and accepts only
+/// responses signed by the server.
+///
+/// The server's key is pinned when the client is created. If the server later
+/// rotates its key, `follow_key_rotations` moves the client to the new key,
+/// provided the rotation is signed by the pinned one.
+#[derive(Debug, Clone)]
+pub struct DepoClient {
+    http: reqwest::Client,
+    url: Url,
+    server_key: PublicKeyBase,
+}
+
+/// An error response from the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorResponse {
+    code: Option<String>,
+    message: String,
+}
+
+impl ErrorResponse {
+    fn from_envelope(response: &Envelope) -> Self {
+        Self {
+            code: error_code(response),
+            message: response.error().unwrap_or_else(|_| "unknown error".to_string()),
+        }
+    }
+
+    /// The stable, machine-readable code of the error, such as
+    /// `"unknown_receipt"`, if the server gave one.
+    pub fn code(&self) -> Option<&str> {
+        self.code.as_deref()
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl std::fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.code {
+            Some(code) => write!(f, "{} ({})", self.message, code),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for ErrorResponse {}
+
+impl DepoClient {
+    /// Returns a client of the server at `url` that trusts only `server_key`.
+    pub fn new(url: Url, server_key: PublicKeyBase) -> Self {
+        Self { http: reqwest::Client::new(), url, server_key }
+    }
+
+    /// Returns a client of the server at `url`, pinning the public key the
+    /// server presents now. Prefer `new` with a key obtained out of band where
+    /// possible, as this trusts the first response it receives.
+    pub async fn connect(url: Url) -> anyhow::Result<Self> {
+        let http = reqwest::Client::new();
+        let server_key = fetch_public_key(&http, &url).await?;
+        Ok(Self { http, url, server_key })
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// The pinned public key of the server.
+    pub fn server_key(&self) -> &PublicKeyBase {
+        &self.server_key
+    }
+
+    /// Moves the client to the server's current key, if it has rotated its key
+    /// since the client's was pinned, by following the rotation statements
+    /// from the pinned key. Fails unless every step is signed by both its
+    /// previous and new key. Returns whether the pinned key changed.
+    pub async fn follow_key_rotations(&mut self) -> anyhow::Result<bool> {
+        let current_key = fetch_public_key(&self.http, &self.url).await?;
+        if current_key == self.server_key {
+            return Ok(false);
+        }
+        let statements = self.get("key-rotations").await?;
+        let mut key = self.server_key.clone();
+        for line in statements.lines().filter(|line| !line.is_empty()) {
+            let statement = Envelope::from_ur_string(line)?;
+            if let Ok(rotation) = KeyRotation::verify(&statement, &key) {
+                key = rotation.new_key().clone();
+            }
+        }
+        if key != current_key {
+            bail!("the server's key {} is not reached by any rotation from the pinned key {}", current_key.abbrev(), self.server_key.abbrev());
+        }
+        self.server_key = key;
+        Ok(true)
+    }
+
+    /// Returns the server's descriptor, failing unless it is signed by the
+    /// pinned key.
+    pub async fn descriptor(&self) -> anyhow::Result<ServerDescriptor> {
+        let signed = Envelope::from_ur_string(self.get("descriptor").await?)?;
+        ServerDescriptor::verify(&signed, &self.server_key)
+    }
+
+    /// Stores a share, returning its receipt. If `ttl_seconds` is given, the
+    /// server removes the share after that many seconds, or its own maximum if
+    /// that is sooner. The label, if given, is stored with the share as it is,
+    /// so it should already be encrypted.
+    pub async fn store_share(&self, key: &PrivateKeyBase, data: impl AsRef<[u8]>, ttl_seconds: Option<u32>, label: Option<&Envelope>) -> anyhow::Result<Receipt> {
+        let mut request = StoreShareRequest::new(key.public_keys(), data).envelope();
+        if let Some(ttl_seconds) = ttl_seconds {
+            request = add_share_ttl(request, ttl_seconds)?;
+        }
+        if let Some(label) = label {
+            request = add_share_label(request, label.clone())?;
+        }
+        let response = StoreShareResponse::try_from(self.call(request, key).await?)?;
+        Ok(response.receipt())
+    }
+
+    /// Returns the shares with the given receipts, or all the account's shares
+    /// if no receipts are given.
+    pub async fn get_shares(&self, key: &PrivateKeyBase, receipts: &HashSet<Receipt>) -> anyhow::Result<HashMap<Receipt, Bytes>> {
+        let request = GetSharesRequest::new(key.public_keys(), receipts);
+        let response = GetSharesResponse::try_from(self.call(request.envelope(), key).await?)?;
+        Ok(response.receipt_to_data().clone())
+    }
+
+    pub async fn get_share(&self, key: &PrivateKeyBase, receipt: &Receipt) -> anyhow::Result<Bytes> {
+        let receipts = HashSet::from([receipt.clone()]);
+        self.get_shares(key, &receipts).await?
+            .remove(receipt)
+            .context("the server did not return the share")
+    }
+
+    /// Returns the receipts of all the account's shares with their size,
+    /// creation time and expiry, oldest first, without their data.
+    pub async fn list_shares(&self, key: &PrivateKeyBase) -> anyhow::Result<Vec<ShareInfo>> {
+        let request = ListSharesRequest::new(key.public_keys());
+        let response = ListSharesResponse::try_from(self.call(request.envelope(), key).await?)?;
+        Ok(response.shares().to_vec())
+    }
+
+    /// Replaces the label of a share, or removes it if `None`.
+    pub async fn update_share_label(&self, key: &PrivateKeyBase, receipt: &Receipt, label: Option<&Envelope>) -> anyhow::Result<()> {
+        let request = UpdateShareLabelRequest::new(key.public_keys(), receipt.clone(), label.cloned());
+        UpdateShareLabelResponse::try_from(self.call(request.envelope(), key).await?)?;
+        Ok(())
+    }
+
+    /// Deletes the shares with the given receipts, or all the account's shares
+    /// if no receipts are given.
+    pub async fn delete_shares(&self, key: &PrivateKeyBase, receipts: &HashSet<Receipt>) -> anyhow::Result<()> {
+        let request = DeleteSharesRequest::new(key.public_keys(), receipts);
+        DeleteSharesResponse::try_from(self.call(request.envelope(), key).await?)?;
+        Ok(())
+    }
+
+    pub async fn delete_share(&self, key: &PrivateKeyBase, receipt: &Receipt) -> anyhow::Result<()> {
+        self.delete_shares(key, &HashSet::from([receipt.clone()])).await
+    }
+
+    /// Changes the key that identifies the account.
+    pub async fn update_key(&self, key: &PrivateKeyBase, new_key: &PublicKeyBase) -> anyhow::Result<()> {
+        let request = UpdateKeyRequest::new(key.public_keys(), new_key);
+        UpdateKeyResponse::try_from(self.call(request.envelope(), key).await?)?;
+        Ok(())
+    }
+
+    pub async fn delete_account(&self, key: &PrivateKeyBase) -> anyhow::Result<()> {
+        let request = DeleteAccountRequest::new(key.public_keys());
+        DeleteAccountResponse::try_from(self.call(request.envelope(), key).await?)?;
+        Ok(())
+    }
+
+    /// Sets the account's recovery contact method, or removes it if `None`.
+    pub async fn update_recovery(&self, key: &PrivateKeyBase, recovery: Option<&str>) -> anyhow::Result<()> {
+        let request = UpdateRecoveryRequest::new(key.public_keys(), recovery);
+        UpdateRecoveryResponse::try_from(self.call(request.envelope(), key).await?)?;
+        Ok(())
+    }
+
+    pub async fn get_recovery(&self, key: &PrivateKeyBase) -> anyhow::Result<Option<String>> {
+        let request = GetRecoveryRequest::new(key.public_keys());
+        let response = GetRecoveryResponse::try_from(self.call(request.envelope(), key).await?)?;
+        Ok(response.recovery().map(|recovery| recovery.to_string()))
+    }
+
+    /// Starts moving the account with the given recovery contact method to
+    /// `new_key`, returning the continuation to pass to `finish_recovery`
+    /// along with the code the server sends to the recovery contact.
+    pub async fn start_recovery(&self, new_key: &PrivateKeyBase, recovery: impl AsRef<str>) -> anyhow::Result<Envelope> {
+        let request = StartRecoveryRequest::new(new_key.public_keys(), recovery);
+        let response = StartRecoveryResponse::try_from(self.call(request.envelope(), new_key).await?)?;
+        Ok(response.continuation())
+    }
+
+    pub async fn finish_recovery(&self, new_key: &PrivateKeyBase, continuation: Envelope, verification_code: impl Into<String>) -> anyhow::Result<()> {
+        let request = FinishRecoveryRequest::new(new_key.public_keys(), continuation).envelope();
+        let request = add_verification_code(request, verification_code)?;
+        FinishRecoveryResponse::try_from(self.call(request, new_key).await?)?;
+        Ok(())
+    }
+
+    /// Sends the request signed by `key` and encrypted to the server, returning
+    /// the decrypted response. An error response is returned as an
+    /// `ErrorResponse`, which callers can recover with `downcast_ref`.
+    async fn call(&self, request: Envelope, key: &PrivateKeyBase) -> anyhow::Result<Envelope> {
+        let request = add_request_date(request, dcbor::Date::now())?;
+        let id = request.request_id()?;
+        let encrypted_request = request.sign_and_encrypt(key, &self.server_key)?;
+        let body = self.http.post(self.url.clone())
+            .body(encrypted_request.ur_string())
+            .send().await?
+            .text().await?;
+        let response = Envelope::from_ur_string(body)?;
+        // Requests the server could not decrypt are answered in the clear.
+        if response.is_error() {
+            return Err(ErrorResponse::from_envelope(&response).into());
+        }
+        let response = response.verify_and_decrypt(&self.server_key, key)
+            .context("the response is not signed by the server's key")?;
+        if response.is_error() {
+            return Err(ErrorResponse::from_envelope(&response).into());
+        }
+        if response.response_id()? != id {
+            bail!("the response is for a different request");
+        }
+        Ok(response)
+    }
+
+    async fn get(&self, route: &str) -> anyhow::Result<String> {
+        let response = self.http.get(self.url.join(route)?).send().await?.error_for_status()?;
+        Ok(response.text().await?)
+    }
+}
+
+async fn fetch_public_key(http: &reqwest::Client, url: &Url) -> anyhow::Result<PublicKeyBase> {
+    let response = http.get(url.clone()).send().await?.error_for_status()?;
+    PublicKeyBase::from_ur_string(response.text().await?.trim())
+        .context("the server did not present a public key")
+}
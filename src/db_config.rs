@@ -0,0 +1,214 @@
+use std::{fmt, path::{Path, PathBuf}, str::FromStr};
+
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+use url::Url;
+
+/// Names the configuration file to read when none is given explicitly.
+pub const CONFIG_FILE_ENV: &str = "DEPO_CONFIG";
+/// A complete connection URL, which takes precedence over the individual settings.
+pub const DATABASE_URL_ENV: &str = "DATABASE_URL";
+
+const HOST_ENV: &str = "DEPO_DB_HOST";
+const PORT_ENV: &str = "DEPO_DB_PORT";
+const USER_ENV: &str = "DEPO_DB_USER";
+const PASSWORD_ENV: &str = "DEPO_DB_PASSWORD";
+const PASSWORD_FILE_ENV: &str = "DEPO_DB_PASSWORD_FILE";
+const TLS_ENV: &str = "DEPO_DB_TLS";
+const POOL_SIZE_ENV: &str = "DEPO_DB_POOL_SIZE";
+
+/// How the connection to the database server is secured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TlsMode {
+    /// Plain TCP connection.
+    #[default]
+    Disabled,
+    /// Encrypted connection, without verifying the server's certificate.
+    Required,
+    /// Encrypted connection, verifying the server's certificate authority.
+    VerifyCa,
+    /// Encrypted connection, verifying both the certificate authority and
+    /// that the certificate matches the host name.
+    VerifyIdentity,
+}
+
+impl FromStr for TlsMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disabled" => Ok(Self::Disabled),
+            "required" => Ok(Self::Required),
+            "verify-ca" => Ok(Self::VerifyCa),
+            "verify-identity" => Ok(Self::VerifyIdentity),
+            _ => bail!("unknown TLS mode: {}", s),
+        }
+    }
+}
+
+/// Connection settings for the database server backends.
+///
+/// Settings are layered: built-in defaults, then the `[database]` table of a
+/// TOML configuration file, then `DEPO_DB_*` environment variables. If a full
+/// connection URL is provided (`url` in the file or `DATABASE_URL` in the
+/// environment) it is used in place of the host, port, user, and password.
+///
+/// Unset fields fall back to the defaults of the particular backend, which for
+/// development are a passwordless administrative user on `localhost`.
+#[derive(Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DbConfig {
+    pub url: Option<Url>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub password_file: Option<PathBuf>,
+    pub tls: TlsMode,
+    pub pool_size: Option<usize>,
+}
+
+impl fmt::Debug for DbConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let url = self.url.as_ref().map(|url| {
+            let mut url = url.clone();
+            _ = url.set_password(None);
+            url
+        });
+        f.debug_struct("DbConfig")
+            .field("url", &url.as_ref().map(Url::as_str))
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("user", &self.user)
+            .field("password", &self.password.as_ref().map(|_| Redacted))
+            .field("password_file", &self.password_file)
+            .field("tls", &self.tls)
+            .field("pool_size", &self.pool_size)
+            .finish()
+    }
+}
+
+/// Stands in for a secret when debug-formatting.
+struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "..")
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    database: DbConfig,
+}
+
+impl DbConfig {
+    /// Loads the configuration from the file named by `DEPO_CONFIG`, if any,
+    /// and the environment.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let path = std::env::var_os(CONFIG_FILE_ENV).map(PathBuf::from);
+        Self::load(path.as_deref())
+    }
+
+    /// Loads the configuration from the given file, if any, and the
+    /// environment.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    /// Reads the `[database]` table of a TOML configuration file.
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read config file {}", path.display()))?;
+        let file: ConfigFile = toml::from_str(&contents)
+            .with_context(|| format!("invalid config file {}", path.display()))?;
+        Ok(file.database)
+    }
+
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+        if let Some(url) = env_var(DATABASE_URL_ENV) {
+            self.url = Some(Url::parse(&url).context(DATABASE_URL_ENV)?);
+        }
+        if let Some(host) = env_var(HOST_ENV) {
+            self.host = Some(host);
+        }
+        if let Some(port) = env_var(PORT_ENV) {
+            self.port = Some(port.parse().context(PORT_ENV)?);
+        }
+        if let Some(user) = env_var(USER_ENV) {
+            self.user = Some(user);
+        }
+        if let Some(password) = env_var(PASSWORD_ENV) {
+            self.password = Some(password);
+        }
+        if let Some(password_file) = env_var(PASSWORD_FILE_ENV) {
+            self.password_file = Some(password_file.into());
+        }
+        if let Some(tls) = env_var(TLS_ENV) {
+            self.tls = tls.parse().context(TLS_ENV)?;
+        }
+        if let Some(pool_size) = env_var(POOL_SIZE_ENV) {
+            self.pool_size = Some(pool_size.parse().context(POOL_SIZE_ENV)?);
+        }
+        Ok(())
+    }
+
+    /// Returns the password, reading it from `password_file` if one is
+    /// configured. Trailing whitespace in the file is ignored.
+    pub fn resolved_password(&self) -> anyhow::Result<Option<String>> {
+        if let Some(password_file) = &self.password_file {
+            let password = std::fs::read_to_string(password_file)
+                .with_context(|| format!("could not read password file {}", password_file.display()))?;
+            return Ok(Some(password.trim_end().to_string()));
+        }
+        Ok(self.password.clone())
+    }
+
+    /// Builds a connection URL for the server.
+    ///
+    /// The fields of the configuration are used in place of the given
+    /// defaults, unless a full URL is configured, in which case its scheme
+    /// must be one of `schemes`.
+    pub fn server_url(
+        &self,
+        schemes: &[&str],
+        default_host: &str,
+        default_port: u16,
+        default_user: &str,
+    ) -> anyhow::Result<Url> {
+        let mut server_url = match &self.url {
+            Some(url) => {
+                if !schemes.contains(&url.scheme()) {
+                    bail!("expected a {}:// database URL, got {}://", schemes[0], url.scheme());
+                }
+                url.clone()
+            }
+            None => {
+                let mut url = Url::parse(&format!("{}://", schemes[0]))?;
+                url.set_host(Some(self.host.as_deref().unwrap_or(default_host)))?;
+                url.set_port(Some(self.port.unwrap_or(default_port)))
+                    .map_err(|_| anyhow!("invalid database port"))?;
+                url.set_username(self.user.as_deref().unwrap_or(default_user))
+                    .map_err(|_| anyhow!("invalid database user"))?;
+                url
+            }
+        };
+        if server_url.password().is_none() {
+            server_url.set_password(self.resolved_password()?.as_deref())
+                .map_err(|_| anyhow!("invalid database password"))?;
+        }
+        Ok(server_url)
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
@@ -1,91 +1,241 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{collections::HashSet, ops::{Deref, DerefMut}, sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::Duration};
 
 use anyhow::anyhow;
 use async_trait::async_trait;
 use bc_components::{PrivateKeyBase, PublicKeyBase, ARID};
 use bc_envelope::prelude::*;
 use depo_api::receipt::Receipt;
-use mysql_async::{prelude::*, Pool, Row};
+use log::info;
+use mysql_async::{prelude::*, Conn, Opts, Pool, Row, Transaction, TxOpts};
 use url::Url;
 
 use crate::{
-    depo_impl::DepoImpl, function::Depo, record::Record, user::User,
-    CONTINUATION_EXPIRY_SECONDS, MAX_DATA_SIZE,
+    api::{KeyRotation, ShareInfo}, db_config::{DbConfig, TlsMode}, depo_error::DepoResult, depo_impl::{DepoImpl, DepoTransaction, PoolStatus}, function::Depo,
+    migration::{Migration, MigrationPlan, MigrationStatement::{AddColumn, Sql}}, record::Record,
+    server_key::{KeyStorage, RetiredKey}, user::User, CONTINUATION_EXPIRY_SECONDS, MAX_BYTES_PER_ACCOUNT, MAX_DATA_SIZE,
+    MAX_SHARES_PER_ACCOUNT, MAX_SHARE_TTL_SECONDS,
 };
 
 const USER: &str = "root";
-const PASSWORD: Option<&str> = None;
 const HOST: &str = "localhost";
 const PORT: u16 = 3306;
+const DEFAULT_POOL_MIN: usize = 10;
 
 const USERS_TABLE_NAME: &str = "users";
 const RECORDS_TABLE_NAME: &str = "records";
 const SETTINGS_TABLE_NAME: &str = "settings";
+const CONTINUATIONS_TABLE_NAME: &str = "continuations";
+const SCHEMA_VERSION_TABLE_NAME: &str = "schema_version";
+const KEY_ROTATIONS_TABLE_NAME: &str = "key_rotations";
 
 struct DbDepoImpl {
     schema_name: String,
     pool: Pool,
+    pool_max_size: usize,
+    // mysql_async does not report the state of its pool, so connections taken
+    // from it are counted here.
+    connections_in_use: Arc<AtomicUsize>,
     private_key: PrivateKeyBase,
     public_key: PublicKeyBase,
     public_key_string: String,
+    retired_keys: Vec<RetiredKey>,
+    key_rotations: Vec<Envelope>,
     continuation_expiry_seconds: u32,
     max_data_size: u32,
+    max_shares_per_account: u32,
+    max_bytes_per_account: u32,
+    max_share_ttl_seconds: u32,
 }
 
 impl DbDepoImpl {
-    async fn new(schema_name: impl AsRef<str>) -> anyhow::Result<Arc<Self>> {
+    async fn new(
+        config: &DbConfig,
+        schema_name: impl AsRef<str>,
+        key_storage: &KeyStorage,
+    ) -> anyhow::Result<Arc<Self>> {
         let schema_name = schema_name.as_ref().to_string();
-        let pool = db_pool(&schema_name);
-        let (private_key, continuation_expiry_seconds, max_data_size) =
-            get_settings(&pool, &schema_name).await?;
+        plan_migrations(&server_pool(config)?, &schema_name).await?.expect_current()?;
+        let pool = db_pool(config, &schema_name)?;
+        let pool_max_size = Opts::from_url(database_url(config, &schema_name)?.as_str())?.pool_opts().constraints().max();
+        let private_key = load_private_key(&pool, &schema_name, key_storage).await?;
+        let (retired_keys, key_rotations) = load_key_rotations(&pool, &schema_name, key_storage).await?;
+        let (
+            continuation_expiry_seconds,
+            max_data_size,
+            max_shares_per_account,
+            max_bytes_per_account,
+            max_share_ttl_seconds,
+        ) = get_settings(&pool, &schema_name).await?;
         let public_key = private_key.public_keys();
         let public_key_string = public_key.ur_string();
         Ok(Arc::new(Self {
             schema_name,
             pool,
+            pool_max_size,
+            connections_in_use: Arc::new(AtomicUsize::new(0)),
             private_key,
             public_key,
             public_key_string,
+            retired_keys,
+            key_rotations,
             continuation_expiry_seconds,
             max_data_size,
+            max_shares_per_account,
+            max_bytes_per_account,
+            max_share_ttl_seconds,
         }))
     }
 
-    fn schema_name(&self) -> &str {
-        &self.schema_name
+    async fn conn(&self) -> DepoResult<PooledConn> {
+        let conn = self.pool.get_conn().await?;
+        Ok(PooledConn { conn, _in_use: InUse::new(&self.connections_in_use) })
+    }
+}
+
+/// Counts a connection as in use until it is dropped.
+struct InUse(Arc<AtomicUsize>);
+
+impl InUse {
+    fn new(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(count.clone())
+    }
+}
+
+impl Drop for InUse {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A connection taken from the pool, returned to it when dropped.
+struct PooledConn {
+    conn: Conn,
+    _in_use: InUse,
+}
+
+impl Deref for PooledConn {
+    type Target = Conn;
+
+    fn deref(&self) -> &Conn {
+        &self.conn
+    }
+}
+
+impl DerefMut for PooledConn {
+    fn deref_mut(&mut self) -> &mut Conn {
+        &mut self.conn
     }
 }
 
 async fn get_settings(
     pool: &Pool,
     schema_name: &str,
-) -> anyhow::Result<(PrivateKeyBase, u32, u32)> {
+) -> anyhow::Result<(u32, u32, u32, u32, u32)> {
     let mut conn = pool.get_conn().await?;
     let query = format!(
-        "SELECT private_key, continuation_expiry_seconds, max_data_size FROM {}.{}",
+        "SELECT continuation_expiry_seconds, max_data_size, max_shares_per_account, max_bytes_per_account, max_share_ttl_seconds FROM {}.{}",
         schema_name, SETTINGS_TABLE_NAME
     );
 
     let result: Option<Row> = conn.query_first(query).await?;
     match result {
         Some(row) => {
-            let private_key_string: String = row
-                .get("private_key")
-                .ok_or_else(|| anyhow!("Private key not found"))?;
-            let private_key = PrivateKeyBase::from_ur_string(private_key_string)?;
             let continuation_expiry_seconds: u32 = row
                 .get("continuation_expiry_seconds")
                 .ok_or_else(|| anyhow!("Continuation expiry seconds not found"))?;
             let max_data_size: u32 = row
                 .get("max_data_size")
                 .ok_or_else(|| anyhow!("Max payload size not found"))?;
-
-            Ok((private_key, continuation_expiry_seconds, max_data_size))
+            let max_shares_per_account: u32 = row
+                .get("max_shares_per_account")
+                .ok_or_else(|| anyhow!("Max shares per account not found"))?;
+            let max_bytes_per_account: u32 = row
+                .get("max_bytes_per_account")
+                .ok_or_else(|| anyhow!("Max bytes per account not found"))?;
+            let max_share_ttl_seconds: u32 = row
+                .get("max_share_ttl_seconds")
+                .ok_or_else(|| anyhow!("Max share TTL seconds not found"))?;
+
+            Ok((
+                continuation_expiry_seconds,
+                max_data_size,
+                max_shares_per_account,
+                max_bytes_per_account,
+                max_share_ttl_seconds,
+            ))
         }
         None => Err(anyhow!("Settings not found")),
     }
 }
 
+/// Loads the server's private key from the given storage, first recording a
+/// newly assigned key or moving an existing one into that storage if needed.
+async fn load_private_key(
+    pool: &Pool,
+    schema_name: &str,
+    key_storage: &KeyStorage,
+) -> anyhow::Result<PrivateKeyBase> {
+    let mut conn = pool.get_conn().await?;
+    loop {
+        let query = format!("SELECT private_key FROM {}.{}", schema_name, SETTINGS_TABLE_NAME);
+        let stored: Option<String> = conn
+            .query_first::<Option<String>, _>(query).await?
+            .ok_or_else(|| anyhow!("Settings not found"))?;
+        let (private_key, replacement) = key_storage.load(stored.as_deref())?;
+        let Some(replacement) = replacement else {
+            return Ok(private_key);
+        };
+        // Only replace the value that was read, in case another server
+        // replaced it first.
+        let query = format!(
+            "UPDATE {}.{} SET private_key = :replacement WHERE private_key <=> :stored",
+            schema_name, SETTINGS_TABLE_NAME
+        );
+        conn.exec_drop(query, params! { "replacement" => replacement, "stored" => stored }).await?;
+        if conn.affected_rows() > 0 {
+            return Ok(private_key);
+        }
+    }
+}
+
+/// Returns the keys replaced by rotation that are still accepted, after
+/// destroying those whose grace periods have ended, along with the statements
+/// of every rotation.
+async fn load_key_rotations(
+    pool: &Pool,
+    schema_name: &str,
+    key_storage: &KeyStorage,
+) -> anyhow::Result<(Vec<RetiredKey>, Vec<Envelope>)> {
+    let mut conn = pool.get_conn().await?;
+    let now = dcbor::Date::now().timestamp() as i64;
+    let query = format!(
+        "SELECT private_key, expiry, statement FROM {}.{} ORDER BY rotated_at",
+        schema_name, KEY_ROTATIONS_TABLE_NAME
+    );
+    let rows: Vec<(Option<String>, i64, String)> = conn.query(query).await?;
+    let mut retired_keys = Vec::new();
+    let mut key_rotations = Vec::new();
+    for (private_key, expiry, statement) in rows {
+        if let Some(private_key) = private_key {
+            if expiry <= now {
+                key_storage.discard_retired_key(&private_key)?;
+            } else {
+                let (retired_key, resealed) = key_storage.retired_key(&private_key, dcbor::Date::from_timestamp(expiry as f64))?;
+                if let Some(resealed) = resealed {
+                    let query = format!("UPDATE {}.{} SET private_key = :resealed WHERE private_key = :private_key", schema_name, KEY_ROTATIONS_TABLE_NAME);
+                    conn.exec_drop(query, params! { "resealed" => resealed, "private_key" => &private_key }).await?;
+                }
+                retired_keys.push(retired_key);
+            }
+        }
+        key_rotations.push(Envelope::from_ur_string(statement)?);
+    }
+    let query = format!("UPDATE {}.{} SET private_key = NULL WHERE expiry <= :now", schema_name, KEY_ROTATIONS_TABLE_NAME);
+    conn.exec_drop(query, params! { "now" => now }).await?;
+    Ok((retired_keys, key_rotations))
+}
+
 #[async_trait]
 impl DepoImpl for DbDepoImpl {
     fn max_data_size(&self) -> u32 {
@@ -96,6 +246,18 @@ impl DepoImpl for DbDepoImpl {
         self.continuation_expiry_seconds
     }
 
+    fn max_shares_per_account(&self) -> u32 {
+        self.max_shares_per_account
+    }
+
+    fn max_bytes_per_account(&self) -> u32 {
+        self.max_bytes_per_account
+    }
+
+    fn max_share_ttl_seconds(&self) -> u32 {
+        self.max_share_ttl_seconds
+    }
+
     fn private_key(&self) -> &PrivateKeyBase {
         &self.private_key
     }
@@ -108,14 +270,41 @@ impl DepoImpl for DbDepoImpl {
         &self.public_key_string
     }
 
-    async fn existing_key_to_id(&self, public_key: &PublicKeyBase) -> anyhow::Result<Option<ARID>> {
-        let user = key_to_user(&self.pool, public_key).await?;
+    fn retired_keys(&self) -> &[RetiredKey] {
+        &self.retired_keys
+    }
+
+    fn key_rotations(&self) -> &[Envelope] {
+        &self.key_rotations
+    }
+
+    fn pool_status(&self) -> Option<PoolStatus> {
+        let in_use = self.connections_in_use.load(Ordering::Relaxed);
+        Some(PoolStatus { max_size: self.pool_max_size, in_use, idle: None })
+    }
+
+    async fn ping(&self) -> DepoResult<()> {
+        let mut conn = self.conn().await?;
+        conn.ping().await?;
+
+        Ok(())
+    }
+
+    async fn existing_key_to_id(&self, public_key: &PublicKeyBase) -> DepoResult<Option<ARID>> {
+        let mut conn = self.conn().await?;
+        let user = key_to_user(&mut *conn, public_key).await?;
         let id = user.map(|user| user.user_id().clone());
         Ok(id)
     }
 
-    async fn existing_id_to_user(&self, user_id: &ARID) -> anyhow::Result<Option<User>> {
-        let mut conn = self.pool.get_conn().await?;
+    async fn begin(&self) -> DepoResult<Box<dyn DepoTransaction>> {
+        let transaction = self.pool.start_transaction(TxOpts::default()).await?;
+        let _in_use = InUse::new(&self.connections_in_use);
+        Ok(Box::new(DbDepoTransaction { schema_name: self.schema_name.clone(), transaction, _in_use }))
+    }
+
+    async fn existing_id_to_user(&self, user_id: &ARID) -> DepoResult<Option<User>> {
+        let mut conn = self.conn().await?;
         let query = "SELECT user_id, public_key, recovery FROM users WHERE user_id = :user_id";
         let params = params! {
             "user_id" => user_id.as_ref().ur_string()
@@ -129,34 +318,12 @@ impl DepoImpl for DbDepoImpl {
         }
     }
 
-    async fn insert_user(&self, user: &User) -> anyhow::Result<()> {
-        let mut conn = self.pool.get_conn().await?;
-        let query = format!("INSERT INTO {}.{} (user_id, public_key, recovery) VALUES (:user_id, :public_key, :recovery)", self.schema_name(), USERS_TABLE_NAME);
-        let params = params! {
-            "user_id" => user.user_id().ur_string(),
-            "public_key" => user.public_key().ur_string(),
-            "recovery" => user.recovery(),
-        };
-
-        conn.exec_drop(query, params).await?;
-
-        Ok(())
-    }
-
-    async fn insert_record(&self, record: &Record) -> anyhow::Result<()> {
-        let mut conn = self.pool.get_conn().await?;
-        let query = format!(
-            r#"
-            INSERT IGNORE INTO {}.{} (receipt, user_id, data)
-            VALUES (:receipt, :user_id, :data)
-        "#,
-            self.schema_name(),
-            RECORDS_TABLE_NAME
-        );
+    async fn touch_user(&self, key: &PublicKeyBase, date: &dcbor::Date) -> DepoResult<()> {
+        let mut conn = self.conn().await?;
+        let query = "UPDATE users SET last_active = :last_active WHERE public_key = :key";
         let params = params! {
-            "receipt" => record.receipt().envelope().ur_string(),
-            "user_id" => record.user_id().ur_string(),
-            "data" => record.data().as_ref(),
+            "last_active" => date.timestamp() as i64,
+            "key" => key.ur_string(),
         };
 
         conn.exec_drop(query, params).await?;
@@ -164,8 +331,8 @@ impl DepoImpl for DbDepoImpl {
         Ok(())
     }
 
-    async fn id_to_receipts(&self, user_id: &ARID) -> anyhow::Result<HashSet<Receipt>> {
-        let mut conn = self.pool.get_conn().await?;
+    async fn id_to_receipts(&self, user_id: &ARID) -> DepoResult<HashSet<Receipt>> {
+        let mut conn = self.conn().await?;
         let query = "SELECT receipt FROM records WHERE user_id = :user_id";
         let params = params! {
             "user_id" => user_id.as_ref().ur_string()
@@ -183,31 +350,70 @@ impl DepoImpl for DbDepoImpl {
         Ok(receipts)
     }
 
-    async fn receipt_to_record(&self, receipt: &Receipt) -> anyhow::Result<Option<Record>> {
-        let mut conn = self.pool.get_conn().await?;
-        let query = "SELECT user_id, data FROM records WHERE receipt = :receipt";
+    async fn id_to_share_infos(&self, user_id: &ARID) -> DepoResult<Vec<ShareInfo>> {
+        let mut conn = self.conn().await?;
+        let query = "SELECT receipt, size, created_at, expiry, label FROM records WHERE user_id = :user_id";
+        let params = params! {
+            "user_id" => user_id.ur_string()
+        };
+
+        let mut infos = Vec::new();
+        let result: Vec<Row> = conn.exec(query, params).await?;
+        for row in result {
+            let receipt_string: String = row.get("receipt").unwrap();
+            let receipt = Receipt::from_envelope(Envelope::from_ur_string(receipt_string)?)?;
+            let size: u64 = row.get("size").unwrap();
+            let created: Option<i64> = row.get("created_at").unwrap();
+            let created = created.map(|created| dcbor::Date::from_timestamp(created as f64));
+            let expiry: Option<i64> = row.get("expiry").unwrap();
+            let expiry = expiry.map(|expiry| dcbor::Date::from_timestamp(expiry as f64));
+            let label: Option<Vec<u8>> = row.get("label").unwrap();
+            let label = label.map(|label| Envelope::from_tagged_cbor_data(&label)).transpose()?;
+            infos.push(ShareInfo::new(receipt, size.try_into()?, created, expiry, label));
+        }
+
+        Ok(infos)
+    }
+
+    async fn receipt_to_record(&self, receipt: &Receipt) -> DepoResult<Option<Record>> {
+        let mut conn = self.conn().await?;
+        receipt_to_record(&mut *conn, receipt).await
+    }
+
+    async fn delete_record(&self, receipt: &Receipt) -> DepoResult<()> {
+        let mut conn = self.conn().await?;
+        let query = "DELETE FROM records WHERE receipt = :receipt";
         let params = params! {
             "receipt" => receipt.envelope().ur_string()
         };
 
+        conn.exec_drop(query, params).await?;
+
+        Ok(())
+    }
+
+    async fn recovery_to_user(&self, recovery: &str) -> DepoResult<Option<User>> {
+        let mut conn = self.conn().await?;
+        let query = "SELECT user_id, public_key, recovery FROM users WHERE recovery = :recovery";
+        let params = params! {
+            "recovery" => recovery
+        };
+
         let result: Option<Row> = conn.exec_first(query, params).await?;
         if let Some(row) = result {
-            let user_id_string: String = row.get("user_id").unwrap();
-            let user_id = ARID::from_ur_string(user_id_string).unwrap();
-            let data: Vec<u8> = row.get("data").unwrap();
-            let record = Record::new_opt(receipt.clone(), user_id, data.into());
-
-            Ok(Some(record))
+            Ok(Some(row_to_user(row)))
         } else {
             Ok(None)
         }
     }
 
-    async fn delete_record(&self, receipt: &Receipt) -> anyhow::Result<()> {
-        let mut conn = self.pool.get_conn().await?;
-        let query = "DELETE FROM records WHERE receipt = :receipt";
+    async fn insert_continuation(&self, continuation_id: &ARID, user_id: &ARID, expiry: &dcbor::Date) -> DepoResult<()> {
+        let mut conn = self.conn().await?;
+        let query = "INSERT INTO continuations (continuation_id, user_id, expiry) VALUES (:continuation_id, :user_id, :expiry)";
         let params = params! {
-            "receipt" => receipt.envelope().ur_string()
+            "continuation_id" => continuation_id.ur_string(),
+            "user_id" => user_id.ur_string(),
+            "expiry" => expiry.timestamp() as i64,
         };
 
         conn.exec_drop(query, params).await?;
@@ -215,62 +421,219 @@ impl DepoImpl for DbDepoImpl {
         Ok(())
     }
 
-    async fn set_user_key(
-        &self,
-        old_public_key: &PublicKeyBase,
-        new_public_key: &PublicKeyBase,
-    ) -> anyhow::Result<()> {
-        let mut conn = self.pool.get_conn().await?;
-        let query =
-            "UPDATE users SET public_key = :new_public_key WHERE public_key = :old_public_key";
+    async fn remove_expired_records(&self, date: &dcbor::Date) -> DepoResult<usize> {
+        let mut conn = self.conn().await?;
+        let query = "DELETE FROM records WHERE expiry < :date";
         let params = params! {
-            "new_public_key" => new_public_key.ur_string(),
-            "old_public_key" => old_public_key.ur_string(),
+            "date" => date.timestamp() as i64,
         };
 
         conn.exec_drop(query, params).await?;
 
+        Ok(conn.affected_rows().try_into()?)
+    }
+
+    async fn remove_idle_users(&self, date: &dcbor::Date) -> DepoResult<usize> {
+        let mut conn = self.conn().await?;
+        let query = r"DELETE FROM users WHERE last_active < :date
+            AND NOT EXISTS (SELECT 1 FROM records WHERE records.user_id = users.user_id)";
+        let params = params! {
+            "date" => date.timestamp() as i64,
+        };
+
+        conn.exec_drop(query, params).await?;
+
+        Ok(conn.affected_rows().try_into()?)
+    }
+
+    async fn user_count(&self) -> DepoResult<usize> {
+        let mut conn = self.conn().await?;
+        let count: Option<u64> = conn.query_first("SELECT COUNT(*) FROM users").await?;
+        Ok(count.unwrap_or(0).try_into()?)
+    }
+
+    async fn record_count(&self) -> DepoResult<usize> {
+        let mut conn = self.conn().await?;
+        let count: Option<u64> = conn.query_first("SELECT COUNT(*) FROM records").await?;
+        Ok(count.unwrap_or(0).try_into()?)
+    }
+}
+
+/// A MySQL transaction. Reads lock the rows they find, or the gaps where rows
+/// they did not find would go.
+struct DbDepoTransaction {
+    schema_name: String,
+    transaction: Transaction<'static>,
+    _in_use: InUse,
+}
+
+#[async_trait]
+impl DepoTransaction for DbDepoTransaction {
+    async fn existing_key_to_user(&mut self, key: &PublicKeyBase) -> DepoResult<Option<User>> {
+        let query = "SELECT user_id, public_key, recovery FROM users WHERE public_key = :key FOR UPDATE";
+        let params = params! {
+            "key" => key.ur_string()
+        };
+
+        let result: Option<Row> = self.transaction.exec_first(query, params).await?;
+        Ok(result.map(row_to_user))
+    }
+
+    async fn insert_user(&mut self, user: &User) -> DepoResult<()> {
+        let query = format!(
+            r#"
+            INSERT INTO {}.{} (user_id, public_key, recovery, last_active)
+            VALUES (:user_id, :public_key, :recovery, :last_active)
+            ON DUPLICATE KEY UPDATE user_id = user_id
+        "#,
+            self.schema_name,
+            USERS_TABLE_NAME
+        );
+        let params = params! {
+            "user_id" => user.user_id().ur_string(),
+            "public_key" => user.public_key().ur_string(),
+            "recovery" => user.recovery(),
+            "last_active" => dcbor::Date::now().timestamp() as i64,
+        };
+
+        self.transaction.exec_drop(query, params).await?;
+
         Ok(())
     }
 
-    async fn set_user_recovery(&self, user: &User, recovery: Option<&str>) -> anyhow::Result<()> {
-        let mut conn = self.pool.get_conn().await?;
+    async fn recovery_to_user(&mut self, recovery: &str) -> DepoResult<Option<User>> {
+        let query = "SELECT user_id, public_key, recovery FROM users WHERE recovery = :recovery FOR UPDATE";
+        let params = params! {
+            "recovery" => recovery
+        };
+
+        let result: Option<Row> = self.transaction.exec_first(query, params).await?;
+        Ok(result.map(row_to_user))
+    }
+
+    async fn set_user_recovery(&mut self, user: &User, recovery: Option<&str>) -> DepoResult<()> {
         let query = "UPDATE users SET recovery = :recovery WHERE user_id = :user_id";
         let params = params! {
             "recovery" => recovery,
             "user_id" => user.user_id().as_ref().ur_string(),
         };
 
-        conn.exec_drop(query, params).await?;
+        self.transaction.exec_drop(query, params).await?;
+
+        Ok(())
+    }
+
+    async fn remove_records(&mut self, user_id: &ARID) -> DepoResult<()> {
+        let query = "DELETE FROM records WHERE user_id = :user_id";
+        let params = params! {
+            "user_id" => user_id.ur_string(),
+        };
+
+        self.transaction.exec_drop(query, params).await?;
 
         Ok(())
     }
 
-    async fn remove_user(&self, user: &User) -> anyhow::Result<()> {
-        let mut conn = self.pool.get_conn().await?;
+    async fn remove_user(&mut self, user: &User) -> DepoResult<()> {
         let query = "DELETE FROM users WHERE user_id = :user_id";
         let params = params! {
             "user_id" => user.user_id().as_ref().ur_string(),
         };
 
-        conn.exec_drop(query, params).await?;
+        self.transaction.exec_drop(query, params).await?;
 
         Ok(())
     }
 
-    async fn recovery_to_user(&self, recovery: &str) -> anyhow::Result<Option<User>> {
-        let mut conn = self.pool.get_conn().await?;
-        let query = "SELECT user_id, public_key, recovery FROM users WHERE recovery = :recovery";
+    async fn insert_record(&mut self, record: &Record) -> DepoResult<()> {
+        let query = format!(
+            r#"
+            INSERT INTO {}.{} (receipt, user_id, data, expiry, size, created_at, label)
+            VALUES (:receipt, :user_id, :data, :expiry, :size, :created_at, :label)
+            ON DUPLICATE KEY UPDATE expiry = VALUES(expiry), label = COALESCE(VALUES(label), label)
+        "#,
+            self.schema_name,
+            RECORDS_TABLE_NAME
+        );
         let params = params! {
-            "recovery" => recovery
+            "receipt" => record.receipt().envelope().ur_string(),
+            "user_id" => record.user_id().ur_string(),
+            "data" => record.data().as_ref(),
+            "expiry" => record.expiry().map(|expiry| expiry.timestamp() as i64),
+            "size" => record.data().len() as u64,
+            "created_at" => record.created().map(|created| created.timestamp() as i64),
+            "label" => record.label().map(|label| label.tagged_cbor_data()),
         };
 
-        let result: Option<Row> = conn.exec_first(query, params).await?;
-        if let Some(row) = result {
-            Ok(Some(row_to_user(row)))
-        } else {
-            Ok(None)
-        }
+        self.transaction.exec_drop(query, params).await?;
+
+        Ok(())
+    }
+
+    async fn set_record_label(&mut self, receipt: &Receipt, label: Option<&Envelope>) -> DepoResult<()> {
+        let query = "UPDATE records SET label = :label WHERE receipt = :receipt";
+        let params = params! {
+            "label" => label.map(|label| label.tagged_cbor_data()),
+            "receipt" => receipt.envelope().ur_string(),
+        };
+
+        self.transaction.exec_drop(query, params).await?;
+
+        Ok(())
+    }
+
+    async fn id_to_usage(&mut self, user_id: &ARID) -> DepoResult<(usize, usize)> {
+        let query = "SELECT COUNT(*), CAST(COALESCE(SUM(LENGTH(data) + COALESCE(LENGTH(label), 0)), 0) AS UNSIGNED) FROM records WHERE user_id = :user_id";
+        let params = params! {
+            "user_id" => user_id.ur_string()
+        };
+
+        let result: Option<(u64, u64)> = self.transaction.exec_first(query, params).await?;
+        let (count, bytes) = result.unwrap_or_default();
+        Ok((count.try_into()?, bytes.try_into()?))
+    }
+
+    async fn receipt_to_record(&mut self, receipt: &Receipt) -> DepoResult<Option<Record>> {
+        receipt_to_record(&mut self.transaction, receipt).await
+    }
+
+    async fn set_user_key(&mut self, user: &User, new_public_key: &PublicKeyBase) -> DepoResult<()> {
+        let query = "UPDATE users SET public_key = :new_public_key WHERE user_id = :user_id";
+        let params = params! {
+            "new_public_key" => new_public_key.ur_string(),
+            "user_id" => user.user_id().ur_string(),
+        };
+
+        self.transaction.exec_drop(query, params).await?;
+
+        Ok(())
+    }
+
+    async fn take_continuation(&mut self, continuation_id: &ARID) -> DepoResult<bool> {
+        let query = "DELETE FROM continuations WHERE continuation_id = :continuation_id";
+        let params = params! {
+            "continuation_id" => continuation_id.ur_string(),
+        };
+
+        self.transaction.exec_drop(query, params).await?;
+
+        Ok(self.transaction.affected_rows() > 0)
+    }
+
+    async fn remove_continuations(&mut self, user_id: &ARID) -> DepoResult<()> {
+        let query = "DELETE FROM continuations WHERE user_id = :user_id";
+        let params = params! {
+            "user_id" => user_id.ur_string(),
+        };
+
+        self.transaction.exec_drop(query, params).await?;
+
+        Ok(())
+    }
+
+    async fn commit(self: Box<Self>) -> DepoResult<()> {
+        self.transaction.commit().await?;
+        Ok(())
     }
 }
 
@@ -285,16 +648,48 @@ fn row_to_user(row: Row) -> User {
 }
 
 impl Depo {
-    pub async fn new_db(schema_name: impl AsRef<str>) -> anyhow::Result<Self> {
-        Ok(Self::new(DbDepoImpl::new(schema_name).await?))
+    pub async fn new_db(config: &DbConfig, schema_name: impl AsRef<str>) -> anyhow::Result<Self> {
+        Self::new_db_with_key_storage(config, schema_name, &KeyStorage::default()).await
+    }
+
+    pub async fn new_db_with_key_storage(
+        config: &DbConfig,
+        schema_name: impl AsRef<str>,
+        key_storage: &KeyStorage,
+    ) -> anyhow::Result<Self> {
+        Ok(Self::new(DbDepoImpl::new(config, schema_name, key_storage).await?))
     }
 }
 
-pub async fn key_to_user(
-    pool: &Pool,
+async fn receipt_to_record(conn: &mut impl Queryable, receipt: &Receipt) -> DepoResult<Option<Record>> {
+    let query = "SELECT user_id, data, expiry, created_at, label FROM records WHERE receipt = :receipt";
+    let params = params! {
+        "receipt" => receipt.envelope().ur_string()
+    };
+
+    let result: Option<Row> = conn.exec_first(query, params).await?;
+    if let Some(row) = result {
+        let user_id_string: String = row.get("user_id").unwrap();
+        let user_id = ARID::from_ur_string(user_id_string).unwrap();
+        let data: Vec<u8> = row.get("data").unwrap();
+        let expiry: Option<i64> = row.get("expiry").unwrap();
+        let expiry = expiry.map(|expiry| dcbor::Date::from_timestamp(expiry as f64));
+        let created: Option<i64> = row.get("created_at").unwrap();
+        let created = created.map(|created| dcbor::Date::from_timestamp(created as f64));
+        let label: Option<Vec<u8>> = row.get("label").unwrap();
+        let label = label.map(|label| Envelope::from_tagged_cbor_data(&label)).transpose()?;
+        let record = Record::new_opt(receipt.clone(), user_id, data.into(), expiry, created, label);
+
+        Ok(Some(record))
+    } else {
+        Ok(None)
+    }
+}
+
+async fn key_to_user(
+    conn: &mut impl Queryable,
     key: impl AsRef<PublicKeyBase>,
 ) -> anyhow::Result<Option<User>> {
-    let mut conn = pool.get_conn().await?;
     let query = "SELECT user_id, public_key, recovery FROM users WHERE public_key = :key";
     let params = params! {
         "key" => key.as_ref().ur_string()
@@ -308,29 +703,50 @@ pub async fn key_to_user(
     }
 }
 
-pub fn server_url() -> Url {
-    let mut server_url = Url::parse("mysql://").unwrap();
-    server_url.set_host(Some(HOST)).unwrap();
-    server_url.set_username(USER).unwrap();
-    server_url.set_password(PASSWORD).unwrap();
-    server_url.set_port(Some(PORT)).unwrap();
-    server_url
+pub fn server_url(config: &DbConfig) -> anyhow::Result<Url> {
+    let mut server_url = config.server_url(&["mysql"], HOST, PORT, USER)?;
+    server_url.set_path("");
+
+    let mut params = Vec::new();
+    match config.tls {
+        TlsMode::Disabled => {}
+        TlsMode::Required => {
+            params.push(("require_ssl", "true".to_string()));
+            params.push(("verify_ca", "false".to_string()));
+            params.push(("verify_identity", "false".to_string()));
+        }
+        TlsMode::VerifyCa => {
+            params.push(("require_ssl", "true".to_string()));
+            params.push(("verify_identity", "false".to_string()));
+        }
+        TlsMode::VerifyIdentity => {
+            params.push(("require_ssl", "true".to_string()));
+        }
+    }
+    if let Some(pool_size) = config.pool_size {
+        params.push(("pool_min", pool_size.min(DEFAULT_POOL_MIN).to_string()));
+        params.push(("pool_max", pool_size.to_string()));
+    }
+    if !params.is_empty() {
+        server_url.query_pairs_mut().extend_pairs(params);
+    }
+
+    Ok(server_url)
 }
 
-pub fn database_url(schema_name: &str) -> Url {
-    let mut database_url = server_url();
+pub fn database_url(config: &DbConfig, schema_name: &str) -> anyhow::Result<Url> {
+    let mut database_url = server_url(config)?;
     database_url.set_path(schema_name);
-    database_url
+    Ok(database_url)
 }
 
-pub fn server_pool() -> Pool {
-    Pool::new(server_url().as_str())
+pub fn server_pool(config: &DbConfig) -> anyhow::Result<Pool> {
+    Ok(Pool::new(server_url(config)?.as_str()))
 }
 
-pub fn db_pool(schema_name: &str) -> Pool {
-    Pool::new(database_url(schema_name).as_str())
+pub fn db_pool(config: &DbConfig, schema_name: &str) -> anyhow::Result<Pool> {
+    Ok(Pool::new(database_url(config, schema_name)?.as_str()))
 }
-
 pub async fn drop_db(server_pool: &Pool, schema_name: &str) -> anyhow::Result<()> {
     let query = format!("DROP DATABASE IF EXISTS {}", schema_name);
     server_pool.get_conn().await?.query_drop(query).await?;
@@ -338,46 +754,171 @@ pub async fn drop_db(server_pool: &Pool, schema_name: &str) -> anyhow::Result<()
     Ok(())
 }
 
-pub async fn create_db(server_pool: &Pool, schema_name: &str) -> anyhow::Result<()> {
-    let query = format!("CREATE DATABASE IF NOT EXISTS {}", schema_name);
-    server_pool.get_conn().await?.query_drop(query).await?;
+fn migrations(schema_name: &str) -> Vec<Migration> {
+    vec![
+        Migration::new(1, "Create the users, records, continuations, and settings tables", vec![
+            Sql(format!(
+                r"CREATE TABLE IF NOT EXISTS {}.{} (
+                    user_id VARCHAR(100) NOT NULL,
+                    public_key VARCHAR(200) UNIQUE NOT NULL,
+                    recovery VARCHAR(1000),
+                    PRIMARY KEY (user_id),
+                    INDEX (public_key),
+                    INDEX (recovery)
+                )",
+                schema_name, USERS_TABLE_NAME
+            )),
+            Sql(format!(
+                r"CREATE TABLE IF NOT EXISTS {}.{} (
+                    receipt VARCHAR(150) NOT NULL,
+                    user_id VARCHAR(100) NOT NULL,
+                    data BLOB NOT NULL,
+                    PRIMARY KEY (receipt),
+                    INDEX (user_id),
+                    FOREIGN KEY (user_id) REFERENCES {}.{}(user_id) ON DELETE CASCADE
+                )",
+                schema_name, RECORDS_TABLE_NAME, schema_name, USERS_TABLE_NAME
+            )),
+            Sql(format!(
+                r"CREATE TABLE IF NOT EXISTS {}.{} (
+                    continuation_id VARCHAR(100) NOT NULL,
+                    user_id VARCHAR(100) NOT NULL,
+                    expiry BIGINT NOT NULL,
+                    PRIMARY KEY (continuation_id),
+                    INDEX (user_id),
+                    FOREIGN KEY (user_id) REFERENCES {}.{}(user_id) ON DELETE CASCADE
+                )",
+                schema_name, CONTINUATIONS_TABLE_NAME, schema_name, USERS_TABLE_NAME
+            )),
+            Sql(format!(
+                r"CREATE TABLE IF NOT EXISTS {}.{} (
+                    private_key VARCHAR(120),
+                    continuation_expiry_seconds INT UNSIGNED,
+                    max_data_size INT UNSIGNED
+                )",
+                schema_name, SETTINGS_TABLE_NAME
+            )),
+        ]),
+        Migration::new(2, "Add per-account quotas and the maximum share TTL to settings", vec![
+            AddColumn {
+                table: SETTINGS_TABLE_NAME,
+                column: "max_shares_per_account",
+                definition: format!("INT UNSIGNED NOT NULL DEFAULT {}", MAX_SHARES_PER_ACCOUNT),
+            },
+            AddColumn {
+                table: SETTINGS_TABLE_NAME,
+                column: "max_bytes_per_account",
+                definition: format!("INT UNSIGNED NOT NULL DEFAULT {}", MAX_BYTES_PER_ACCOUNT),
+            },
+            AddColumn {
+                table: SETTINGS_TABLE_NAME,
+                column: "max_share_ttl_seconds",
+                definition: format!("INT UNSIGNED NOT NULL DEFAULT {}", MAX_SHARE_TTL_SECONDS),
+            },
+        ]),
+        Migration::new(3, "Add share expiry", vec![
+            AddColumn { table: RECORDS_TABLE_NAME, column: "expiry", definition: "BIGINT".to_string() },
+        ]),
+        Migration::new(4, "Track when accounts were last active", vec![
+            AddColumn { table: USERS_TABLE_NAME, column: "last_active", definition: "BIGINT".to_string() },
+            // Accounts that predate activity tracking count as active now.
+            Sql(format!(
+                "UPDATE {}.{} SET last_active = UNIX_TIMESTAMP() WHERE last_active IS NULL",
+                schema_name, USERS_TABLE_NAME
+            )),
+        ]),
+        Migration::new(5, "Widen the private key setting to hold an encrypted key", vec![
+            Sql(format!(
+                "ALTER TABLE {}.{} MODIFY private_key TEXT",
+                schema_name, SETTINGS_TABLE_NAME
+            )),
+        ]),
+        Migration::new(6, "Add server key rotations", vec![
+            Sql(format!(
+                r"CREATE TABLE IF NOT EXISTS {}.{} (
+                    private_key TEXT,
+                    expiry BIGINT NOT NULL,
+                    rotated_at BIGINT NOT NULL,
+                    statement TEXT NOT NULL
+                )",
+                schema_name, KEY_ROTATIONS_TABLE_NAME
+            )),
+        ]),
+        // Records that predate this migration have no known creation time.
+        Migration::new(7, "Record the size and creation time of shares", vec![
+            AddColumn { table: RECORDS_TABLE_NAME, column: "size", definition: "BIGINT UNSIGNED NOT NULL DEFAULT 0".to_string() },
+            AddColumn { table: RECORDS_TABLE_NAME, column: "created_at", definition: "BIGINT".to_string() },
+            Sql(format!("UPDATE {}.{} SET size = LENGTH(data)", schema_name, RECORDS_TABLE_NAME)),
+        ]),
+        Migration::new(8, "Add share labels", vec![
+            AddColumn { table: RECORDS_TABLE_NAME, column: "label", definition: "BLOB".to_string() },
+        ]),
+    ]
+}
 
-    let query = format!(
-        r"CREATE TABLE IF NOT EXISTS {}.{} (
-            user_id VARCHAR(100) NOT NULL,
-            public_key VARCHAR(200) UNIQUE NOT NULL,
-            recovery VARCHAR(1000),
-            PRIMARY KEY (user_id),
-            INDEX (public_key),
-            INDEX (recovery)
-        )",
-        schema_name, USERS_TABLE_NAME
-    );
-    server_pool.get_conn().await?.query_drop(query).await?;
+async fn schema_version(server_pool: &Pool, schema_name: &str) -> anyhow::Result<u32> {
+    let mut conn = server_pool.get_conn().await?;
+    let query = r"SELECT COUNT(*) FROM information_schema.tables
+        WHERE table_schema = :schema_name AND table_name = :table_name";
+    let params = params! {
+        "schema_name" => schema_name,
+        "table_name" => SCHEMA_VERSION_TABLE_NAME,
+    };
+    let exists: u64 = conn.exec_first(query, params).await?.unwrap_or(0);
+    if exists == 0 {
+        return Ok(0);
+    }
+    let query = format!("SELECT MAX(version) FROM {}.{}", schema_name, SCHEMA_VERSION_TABLE_NAME);
+    let version: Option<Option<u32>> = conn.query_first(query).await?;
+    Ok(version.flatten().unwrap_or(0))
+}
 
-    let query = format!(
-        r"CREATE TABLE IF NOT EXISTS {}.{} (
-            receipt VARCHAR(150) NOT NULL,
-            user_id VARCHAR(100) NOT NULL,
-            data BLOB NOT NULL,
-            PRIMARY KEY (receipt),
-            INDEX (user_id),
-            FOREIGN KEY (user_id) REFERENCES {}.{}(user_id) ON DELETE CASCADE
-        )",
-        schema_name, RECORDS_TABLE_NAME, schema_name, USERS_TABLE_NAME
-    );
+/// Returns the migrations the database needs, without applying them.
+pub async fn plan_migrations(server_pool: &Pool, schema_name: &str) -> anyhow::Result<MigrationPlan> {
+    MigrationPlan::new(&migrations(schema_name), schema_version(server_pool, schema_name).await?)
+}
 
+/// Applies the migrations the database needs and returns them. MySQL commits
+/// schema changes immediately, so the version is recorded after each
+/// migration, and a migration that fails part way is run again in full.
+pub async fn migrate_db(server_pool: &Pool, schema_name: &str) -> anyhow::Result<MigrationPlan> {
+    let plan = plan_migrations(server_pool, schema_name).await?;
+    if plan.is_empty() {
+        return Ok(plan);
+    }
+
+    let query = format!("CREATE DATABASE IF NOT EXISTS {}", schema_name);
     server_pool.get_conn().await?.query_drop(query).await?;
     let query = format!(
-        r"CREATE TABLE IF NOT EXISTS {}.{} (
-            private_key VARCHAR(120),
-            continuation_expiry_seconds INT UNSIGNED,
-            max_data_size INT UNSIGNED
-        )",
-        schema_name, SETTINGS_TABLE_NAME
+        "CREATE TABLE IF NOT EXISTS {}.{} (version INT UNSIGNED NOT NULL)",
+        schema_name, SCHEMA_VERSION_TABLE_NAME
     );
     server_pool.get_conn().await?.query_drop(query).await?;
 
+    for migration in migrations(schema_name).into_iter().filter(|migration| migration.version > plan.from_version()) {
+        info!("Migrating {} to schema version {}: {}", schema_name, migration.version, migration.description);
+        for statement in &migration.statements {
+            match statement {
+                Sql(query) => server_pool.get_conn().await?.query_drop(query).await?,
+                AddColumn { table, column, definition } => {
+                    add_column_if_missing(server_pool, schema_name, table, column, definition).await?
+                }
+            }
+        }
+        let mut conn = server_pool.get_conn().await?;
+        let mut transaction = conn.start_transaction(TxOpts::default()).await?;
+        transaction.query_drop(format!("DELETE FROM {}.{}", schema_name, SCHEMA_VERSION_TABLE_NAME)).await?;
+        let query = format!("INSERT INTO {}.{} (version) VALUES (:version)", schema_name, SCHEMA_VERSION_TABLE_NAME);
+        transaction.exec_drop(query, params! { "version" => migration.version }).await?;
+        transaction.commit().await?;
+    }
+
+    Ok(plan)
+}
+
+pub async fn create_db(server_pool: &Pool, schema_name: &str) -> anyhow::Result<MigrationPlan> {
+    let plan = migrate_db(server_pool, schema_name).await?;
+
     // Check if settings already exist
     let check_query = format!(
         "SELECT COUNT(*) FROM {}.{}",
@@ -388,42 +929,114 @@ pub async fn create_db(server_pool: &Pool, schema_name: &str) -> anyhow::Result<
         .query_first(check_query).await?
         .unwrap_or(0);
 
-    // Only insert if settings do not exist
+    // Only insert if settings do not exist. The private key is assigned when
+    // the depository is first opened, in the storage it is configured with.
     if count == 0 {
-        let private_key = PrivateKeyBase::new().ur_string();
-
         let query = format!(
             r"INSERT INTO {}.{}
-            (private_key, continuation_expiry_seconds, max_data_size) VALUES ('{}', {}, {})",
+            (continuation_expiry_seconds, max_data_size) VALUES ({}, {})",
             schema_name,
             SETTINGS_TABLE_NAME,
-            private_key,
             CONTINUATION_EXPIRY_SECONDS,
             MAX_DATA_SIZE
         );
         server_pool.get_conn().await?.query_drop(query).await?;
     }
 
+    Ok(plan)
+}
+
+async fn add_column_if_missing(
+    server_pool: &Pool,
+    schema_name: &str,
+    table_name: &str,
+    column_name: &str,
+    definition: &str,
+) -> anyhow::Result<()> {
+    let mut conn = server_pool.get_conn().await?;
+    let query = r"SELECT COUNT(*) FROM information_schema.columns
+        WHERE table_schema = :schema_name AND table_name = :table_name AND column_name = :column_name";
+    let params = params! {
+        "schema_name" => schema_name,
+        "table_name" => table_name,
+        "column_name" => column_name,
+    };
+    let count: u64 = conn.exec_first(query, params).await?.unwrap_or(0);
+    if count == 0 {
+        let query = format!(
+            "ALTER TABLE {}.{} ADD COLUMN {} {}",
+            schema_name, table_name, column_name, definition
+        );
+        conn.query_drop(query).await?;
+    }
+
     Ok(())
 }
 
-pub async fn reset_db(schema_name: &str) -> anyhow::Result<()> {
-    let server_pool = server_pool();
+/// Replaces the server key with a new one, which the depository uses the next
+/// time it is opened. The previous key is still accepted for the grace period.
+pub async fn rotate_key(
+    config: &DbConfig,
+    schema_name: &str,
+    key_storage: &KeyStorage,
+    grace_period: Duration,
+) -> anyhow::Result<KeyRotation> {
+    let server_pool = server_pool(config)?;
+    plan_migrations(&server_pool, schema_name).await?.expect_current()?;
+    let mut transaction = server_pool.start_transaction(TxOpts::default()).await?;
+    let query = format!("SELECT private_key FROM {}.{} FOR UPDATE", schema_name, SETTINGS_TABLE_NAME);
+    let stored: Option<String> = transaction
+        .query_first::<Option<String>, _>(query).await?
+        .ok_or_else(|| anyhow!("Settings not found"))?;
+    let rotation = key_storage.rotate(stored.as_deref(), grace_period)?;
+
+    let query = format!(
+        r"INSERT INTO {}.{} (private_key, expiry, rotated_at, statement)
+        VALUES (:private_key, :expiry, :rotated_at, :statement)",
+        schema_name, KEY_ROTATIONS_TABLE_NAME
+    );
+    transaction.exec_drop(query, params! {
+        "private_key" => &rotation.previous_key,
+        "expiry" => rotation.rotation.grace_period_end().timestamp() as i64,
+        "rotated_at" => rotation.rotation.date().timestamp() as i64,
+        "statement" => rotation.statement.ur_string(),
+    }).await?;
+    let query = format!("UPDATE {}.{} SET private_key = :private_key", schema_name, SETTINGS_TABLE_NAME);
+    transaction.exec_drop(query, params! { "private_key" => &rotation.new_key }).await?;
+    transaction.commit().await?;
+    rotation.install()?;
+
+    Ok(rotation.rotation)
+}
+
+pub async fn reset_db(config: &DbConfig, schema_name: &str) -> anyhow::Result<()> {
+    let server_pool = server_pool(config)?;
     drop_db(&server_pool, schema_name).await?;
     create_db(&server_pool, schema_name).await?;
 
     Ok(())
 }
 
-pub async fn create_db_if_needed(schema_name: &str) -> anyhow::Result<()> {
-    let server_pool = server_pool();
+pub async fn create_db_if_needed(config: &DbConfig, schema_name: &str) -> anyhow::Result<()> {
+    let server_pool = server_pool(config)?;
     create_db(&server_pool, schema_name).await?;
 
     Ok(())
 }
 
-pub async fn can_connect_to_db(schema_name: &str) -> anyhow::Result<bool> {
-    let pool = db_pool(schema_name);
+/// Brings the database's schema up to date, or with `dry_run` only reports
+/// what that would involve.
+pub async fn migrate(config: &DbConfig, schema_name: &str, dry_run: bool) -> anyhow::Result<MigrationPlan> {
+    let server_pool = server_pool(config)?;
+    if dry_run {
+        plan_migrations(&server_pool, schema_name).await
+    } else {
+        create_db(&server_pool, schema_name).await
+    }
+}
+
+pub async fn can_connect_to_db(config: &DbConfig, schema_name: &str) -> anyhow::Result<bool> {
+    let pool = db_pool(config, schema_name)?;
     let mut conn = pool.get_conn().await?;
     conn.ping().await?;
 
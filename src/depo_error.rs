@@ -0,0 +1,162 @@
+use std::fmt::Display;
+
+/// An error from a depository, returned by the functions of `Depo` and the
+/// storage backends behind it.
+///
+/// Each kind of error has a stable [code](DepoError::code) that is sent to
+/// clients in error responses along with the message, so that clients can
+/// tell errors apart without parsing messages meant for people.
+#[derive(Debug)]
+pub enum DepoError {
+    /// The request could not be parsed, or was missing a required parameter.
+    InvalidRequest(String),
+    /// The request was not encrypted to the depository's public key.
+    WrongRecipient,
+    /// The request was not signed by the key it contains.
+    InvalidSignature,
+    /// The request has no date.
+    MissingDate,
+    /// The request's date is outside the request window.
+    StaleRequest,
+    /// The request's ID was already used by the same key.
+    DuplicateRequest,
+    /// The key that signed the request has made too many requests.
+    KeyRateLimited,
+    /// Recovery has been started too many times for the recovery method.
+    RecoveryRateLimited,
+    /// The request named a function the depository does not have.
+    UnknownFunction(String),
+    /// The share is larger than the depository accepts.
+    DataTooLarge,
+    /// The share's label is larger than the depository accepts.
+    LabelTooLarge,
+    /// Storing the share would exceed one of the account's quotas.
+    QuotaExceeded(String),
+    /// The receipt does not name a share held by the account.
+    UnknownReceipt,
+    /// No account uses the public key.
+    UnknownPublicKey(String),
+    /// Another account already uses the public key.
+    PublicKeyInUse,
+    /// Another account already uses the recovery method.
+    RecoveryInUse,
+    /// No account uses the recovery method.
+    UnknownRecovery,
+    /// The verification code could not be sent to the recovery method.
+    VerificationDeliveryFailed(String),
+    /// The request has no verification code.
+    MissingVerificationCode,
+    /// The verification code does not match the one that was sent.
+    InvalidVerificationCode,
+    /// The recovery continuation has expired.
+    ContinuationExpired,
+    /// The recovery continuation was already used, or was invalidated by a
+    /// change to the account.
+    ContinuationUsed,
+    /// The request was not signed by the new key in the recovery continuation.
+    InvalidSigningKey,
+    /// Anything else, such as a failure of storage.
+    Internal(anyhow::Error),
+}
+
+impl DepoError {
+    pub fn invalid_request(error: impl Display) -> Self {
+        Self::InvalidRequest(error.to_string())
+    }
+
+    /// Returns the error's machine-readable code, which does not change
+    /// between releases.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidRequest(_) => "invalid_request",
+            Self::WrongRecipient => "wrong_recipient",
+            Self::InvalidSignature => "invalid_signature",
+            Self::MissingDate => "missing_date",
+            Self::StaleRequest => "stale_request",
+            Self::DuplicateRequest => "duplicate_request",
+            Self::KeyRateLimited => "key_rate_limited",
+            Self::RecoveryRateLimited => "recovery_rate_limited",
+            Self::UnknownFunction(_) => "unknown_function",
+            Self::DataTooLarge => "data_too_large",
+            Self::LabelTooLarge => "label_too_large",
+            Self::QuotaExceeded(_) => "quota_exceeded",
+            Self::UnknownReceipt => "unknown_receipt",
+            Self::UnknownPublicKey(_) => "unknown_public_key",
+            Self::PublicKeyInUse => "public_key_in_use",
+            Self::RecoveryInUse => "recovery_in_use",
+            Self::UnknownRecovery => "unknown_recovery",
+            Self::VerificationDeliveryFailed(_) => "verification_delivery_failed",
+            Self::MissingVerificationCode => "missing_verification_code",
+            Self::InvalidVerificationCode => "invalid_verification_code",
+            Self::ContinuationExpired => "continuation_expired",
+            Self::ContinuationUsed => "continuation_used",
+            Self::InvalidSigningKey => "invalid_signing_key",
+            Self::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl Display for DepoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidRequest(message) => write!(f, "invalid request: {}", message),
+            Self::WrongRecipient => write!(f, "request not encrypted to depository public key"),
+            Self::InvalidSignature => write!(f, "request signature does not match request key"),
+            Self::MissingDate => write!(f, "request has no date"),
+            Self::StaleRequest => write!(f, "stale request"),
+            Self::DuplicateRequest => write!(f, "duplicate request"),
+            Self::KeyRateLimited => write!(f, "rate limit exceeded for this key"),
+            Self::RecoveryRateLimited => write!(f, "rate limit exceeded for this recovery method"),
+            Self::UnknownFunction(name) => write!(f, "unknown function: {}", name),
+            Self::DataTooLarge => write!(f, "data too large"),
+            Self::LabelTooLarge => write!(f, "label too large"),
+            Self::QuotaExceeded(message) => write!(f, "quota exceeded: {}", message),
+            Self::UnknownReceipt => write!(f, "unknown receipt"),
+            Self::UnknownPublicKey(key) => write!(f, "unknown public key {}", key),
+            Self::PublicKeyInUse => write!(f, "public key already in use"),
+            Self::RecoveryInUse => write!(f, "recovery method already exists"),
+            Self::UnknownRecovery => write!(f, "unknown recovery"),
+            Self::VerificationDeliveryFailed(message) => write!(f, "could not deliver verification code: {}", message),
+            Self::MissingVerificationCode => write!(f, "missing verification code"),
+            Self::InvalidVerificationCode => write!(f, "invalid verification code"),
+            Self::ContinuationExpired => write!(f, "continuation expired"),
+            Self::ContinuationUsed => write!(f, "continuation already used or no longer valid"),
+            Self::InvalidSigningKey => write!(f, "invalid user signing key"),
+            Self::Internal(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for DepoError {}
+
+impl From<anyhow::Error> for DepoError {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<DepoError>() {
+            Ok(error) => error,
+            Err(error) => Self::Internal(error),
+        }
+    }
+}
+
+macro_rules! internal_error_from {
+    ($($error:ty),* $(,)?) => {
+        $(
+            impl From<$error> for DepoError {
+                fn from(error: $error) -> Self {
+                    Self::Internal(error.into())
+                }
+            }
+        )*
+    };
+}
+
+internal_error_from!(
+    mysql_async::Error,
+    tokio_postgres::Error,
+    deadpool_postgres::PoolError,
+    rusqlite::Error,
+    std::num::TryFromIntError,
+    dcbor::CBORError,
+);
+
+pub type DepoResult<T> = std::result::Result<T, DepoError>;
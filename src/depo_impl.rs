@@ -1,32 +1,70 @@
 use std::collections::HashSet;
 
-use anyhow::bail;
 use async_trait::async_trait;
 use bc_components::{PublicKeyBase, ARID, PrivateKeyBase};
+use bc_envelope::Envelope;
 use depo_api::{receipt::Receipt, util::Abbrev};
 
-use crate::{user::User, record::Record};
+use crate::{api::ShareInfo, depo_error::{DepoError, DepoResult}, server_key::RetiredKey, user::User, record::Record};
+
+/// How busy a backend's pool of database connections is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStatus {
+    /// The most connections the pool will open.
+    pub max_size: usize,
+    /// Connections taken from the pool and not yet returned.
+    pub in_use: usize,
+    /// Open connections waiting in the pool, if the pool reports them.
+    pub idle: Option<usize>,
+}
 
 #[async_trait]
 pub trait DepoImpl {
     fn max_data_size(&self) -> u32;
     fn continuation_expiry_seconds(&self) -> u32;
+    fn max_shares_per_account(&self) -> u32;
+    fn max_bytes_per_account(&self) -> u32;
+    fn max_share_ttl_seconds(&self) -> u32;
     fn private_key(&self) -> &PrivateKeyBase;
     fn public_key(&self) -> &PublicKeyBase;
     fn public_key_string(&self) -> &str;
-    async fn existing_key_to_id(&self, key: &PublicKeyBase) -> anyhow::Result<Option<ARID>>;
-    async fn existing_id_to_user(&self, user_id: &ARID) -> anyhow::Result<Option<User>>;
-    async fn insert_user(&self, user: &User) -> anyhow::Result<()>;
-    async fn insert_record(&self, record: &Record) -> anyhow::Result<()>;
-    async fn id_to_receipts(&self, user_id: &ARID) -> anyhow::Result<HashSet<Receipt>>;
-    async fn receipt_to_record(&self, receipt: &Receipt) -> anyhow::Result<Option<Record>>;
-    async fn delete_record(&self, receipt: &Receipt) -> anyhow::Result<()>;
-    async fn set_user_key(&self, old_key: &PublicKeyBase, new_key: &PublicKeyBase) -> anyhow::Result<()>;
-    async fn set_user_recovery(&self, user: &User, recovery: Option<&str>) -> anyhow::Result<()>;
-    async fn remove_user(&self, user: &User) -> anyhow::Result<()>;
-    async fn recovery_to_user(&self, recovery: &str) -> anyhow::Result<Option<User>>;
+    /// Keys replaced by rotation whose grace periods had not ended when the
+    /// depository was opened.
+    fn retired_keys(&self) -> &[RetiredKey];
+    /// The signed statements of each rotation of the server key, oldest first.
+    fn key_rotations(&self) -> &[Envelope];
+    /// The state of the backend's connection pool, or `None` if it has none.
+    fn pool_status(&self) -> Option<PoolStatus> {
+        None
+    }
+    /// Fails unless storage can be reached.
+    async fn ping(&self) -> DepoResult<()>;
+    async fn existing_key_to_id(&self, key: &PublicKeyBase) -> DepoResult<Option<ARID>>;
+    async fn existing_id_to_user(&self, user_id: &ARID) -> DepoResult<Option<User>>;
+    /// Starts a transaction, in which operations that must happen together
+    /// either all take effect when it is committed, or none do.
+    async fn begin(&self) -> DepoResult<Box<dyn DepoTransaction>>;
+    /// Records that the account with this key made a request, so that it is not
+    /// considered idle.
+    async fn touch_user(&self, key: &PublicKeyBase, date: &dcbor::Date) -> DepoResult<()>;
+    async fn id_to_receipts(&self, user_id: &ARID) -> DepoResult<HashSet<Receipt>>;
+    /// Returns the receipts of a user's records with their size, creation time,
+    /// expiry and label, without reading their data.
+    async fn id_to_share_infos(&self, user_id: &ARID) -> DepoResult<Vec<ShareInfo>>;
+    async fn receipt_to_record(&self, receipt: &Receipt) -> DepoResult<Option<Record>>;
+    async fn delete_record(&self, receipt: &Receipt) -> DepoResult<()>;
+    async fn recovery_to_user(&self, recovery: &str) -> DepoResult<Option<User>>;
+    async fn insert_continuation(&self, continuation_id: &ARID, user_id: &ARID, expiry: &dcbor::Date) -> DepoResult<()>;
+    /// Removes records that expired before `date`, returning how many were
+    /// removed.
+    async fn remove_expired_records(&self, date: &dcbor::Date) -> DepoResult<usize>;
+    /// Removes accounts that hold no records and have not made a request since
+    /// `date`, returning how many were removed.
+    async fn remove_idle_users(&self, date: &dcbor::Date) -> DepoResult<usize>;
+    async fn user_count(&self) -> DepoResult<usize>;
+    async fn record_count(&self) -> DepoResult<usize>;
 
-    async fn records_for_id_and_receipts(&self, user_id: &ARID, recipts: &HashSet<Receipt>) -> anyhow::Result<Vec<Record>> {
+    async fn records_for_id_and_receipts(&self, user_id: &ARID, recipts: &HashSet<Receipt>) -> DepoResult<Vec<Record>> {
         let mut result = Vec::new();
         let user_receipts = self.id_to_receipts(user_id).await?;
         for receipt in recipts {
@@ -40,7 +78,7 @@ pub trait DepoImpl {
         Ok(result)
     }
 
-    async fn existing_key_to_user(&self, key: &PublicKeyBase) -> anyhow::Result<Option<User>> {
+    async fn existing_key_to_user(&self, key: &PublicKeyBase) -> DepoResult<Option<User>> {
         let user_id = self.existing_key_to_id(key).await?;
         let user = match user_id {
             Some(user_id) => self.existing_id_to_user(&user_id).await?,
@@ -49,26 +87,76 @@ pub trait DepoImpl {
         Ok(user)
     }
 
-    async fn key_to_user(&self, key: &PublicKeyBase) -> anyhow::Result<User> {
-        let user = self.existing_key_to_user(key).await?;
-        let user = match user {
-            Some(user_id) => user_id,
-            None => {
-                let user_id = ARID::new();
-                let user = User::new(user_id.clone(), key.clone());
-                self.insert_user(&user).await?;
-                user
-            }
-        };
+    /// Returns the account with this key, creating it if there is none. Two
+    /// requests creating the same account at once both get the same one.
+    async fn key_to_user(&self, key: &PublicKeyBase) -> DepoResult<User> {
+        if let Some(user) = self.existing_key_to_user(key).await? {
+            return Ok(user);
+        }
+        let mut transaction = self.begin().await?;
+        let user = transaction.key_to_user(key).await?;
+        transaction.commit().await?;
         Ok(user)
     }
 
-    async fn expect_key_to_user(&self, key: &PublicKeyBase) -> anyhow::Result<User> {
+    async fn expect_key_to_user(&self, key: &PublicKeyBase) -> DepoResult<User> {
         let user_id = self.existing_key_to_user(key).await?;
         let user_id = match user_id {
             Some(user_id) => user_id,
-            None => bail!("unknown public key {}", key.abbrev()),
+            None => return Err(DepoError::UnknownPublicKey(key.abbrev())),
         };
         Ok(user_id)
     }
 }
+
+/// Operations on storage that run in one transaction, started by
+/// `DepoImpl::begin`.
+///
+/// Reads take locks where the backend needs them, so that what they return
+/// stays true until the transaction ends. Dropping a transaction without
+/// committing it discards its changes.
+#[async_trait]
+pub trait DepoTransaction: Send {
+    async fn existing_key_to_user(&mut self, key: &PublicKeyBase) -> DepoResult<Option<User>>;
+    /// Inserts a user, unless another user already has the same public key.
+    async fn insert_user(&mut self, user: &User) -> DepoResult<()>;
+    async fn recovery_to_user(&mut self, recovery: &str) -> DepoResult<Option<User>>;
+    async fn set_user_recovery(&mut self, user: &User, recovery: Option<&str>) -> DepoResult<()>;
+    async fn set_user_key(&mut self, user: &User, new_key: &PublicKeyBase) -> DepoResult<()>;
+    /// Inserts a record, or replaces the expiry of an identical existing record,
+    /// and its label if the new record has one.
+    async fn insert_record(&mut self, record: &Record) -> DepoResult<()>;
+    /// Replaces the label of a record, or removes it if `label` is `None`.
+    async fn set_record_label(&mut self, receipt: &Receipt, label: Option<&Envelope>) -> DepoResult<()>;
+    /// Returns the number of records stored by a user and their total size in
+    /// bytes, including their labels.
+    async fn id_to_usage(&mut self, user_id: &ARID) -> DepoResult<(usize, usize)>;
+    async fn receipt_to_record(&mut self, receipt: &Receipt) -> DepoResult<Option<Record>>;
+    /// Removes all the records stored by a user.
+    async fn remove_records(&mut self, user_id: &ARID) -> DepoResult<()>;
+    async fn remove_user(&mut self, user: &User) -> DepoResult<()>;
+    /// Removes an outstanding continuation, returning `false` if it had already
+    /// been removed.
+    async fn take_continuation(&mut self, continuation_id: &ARID) -> DepoResult<bool>;
+    async fn remove_continuations(&mut self, user_id: &ARID) -> DepoResult<()>;
+    async fn commit(self: Box<Self>) -> DepoResult<()>;
+
+    /// Returns the account with this key, creating it if there is none.
+    ///
+    /// The user is inserted before it is looked up, rather than after finding
+    /// none, because a locking read of a missing row takes a gap lock in MySQL,
+    /// and two transactions holding one then both inserting deadlock.
+    async fn key_to_user(&mut self, key: &PublicKeyBase) -> DepoResult<User> {
+        // Does nothing if the user exists, or another transaction inserted it
+        // first.
+        self.insert_user(&User::new(ARID::new(), key.clone())).await?;
+        self.expect_key_to_user(key).await
+    }
+
+    async fn expect_key_to_user(&mut self, key: &PublicKeyBase) -> DepoResult<User> {
+        match self.existing_key_to_user(key).await? {
+            Some(user) => Ok(user),
+            None => Err(DepoError::UnknownPublicKey(key.abbrev())),
+        }
+    }
+}
@@ -1,6 +1,5 @@
-use std::{collections::{HashMap, HashSet}, sync::Arc};
+use std::{collections::{HashMap, HashSet}, sync::Arc, time::Duration};
 
-use anyhow::bail;
 use bc_components::{PublicKeyBase, PrivateKeyBase, ARID};
 use bc_envelope::prelude::*;
 use bytes::Bytes;
@@ -12,36 +11,154 @@ use depo_api::{
     UpdateRecoveryRequest, UpdateRecoveryResponse, DELETE_ACCOUNT_FUNCTION, DELETE_SHARES_FUNCTION,
     FINISH_RECOVERY_FUNCTION, GET_RECOVERY_FUNCTION, GET_SHARES_FUNCTION, KEY_PARAM,
     START_RECOVERY_FUNCTION, STORE_SHARE_FUNCTION, UPDATE_KEY_FUNCTION, UPDATE_RECOVERY_FUNCTION, util::{Abbrev, FlankedFunction},
+    DELETE_ACCOUNT_FUNCTION_NAME, DELETE_SHARES_FUNCTION_NAME, FINISH_RECOVERY_FUNCTION_NAME,
+    GET_RECOVERY_FUNCTION_NAME, GET_SHARES_FUNCTION_NAME, START_RECOVERY_FUNCTION_NAME,
+    STORE_SHARE_FUNCTION_NAME, UPDATE_KEY_FUNCTION_NAME, UPDATE_RECOVERY_FUNCTION_NAME,
 };
 use log::{info, error};
 
-use crate::{depo_impl::DepoImpl, record::Record, recovery_continuation::RecoveryContinuation};
+use crate::{
+    api::{
+        add_share_labels, ListSharesRequest, ListSharesResponse, ServerDescriptor, ShareInfo, UpdateShareLabelRequest,
+        UpdateShareLabelResponse, DATE_PARAM, ERROR_CODE_PARAM, LABEL_PARAM, LIST_SHARES_FUNCTION,
+        LIST_SHARES_FUNCTION_NAME, TTL_PARAM, UPDATE_SHARE_LABEL_FUNCTION, UPDATE_SHARE_LABEL_FUNCTION_NAME,
+        VERIFICATION_CODE_PARAM,
+    },
+    depo_error::{DepoError, DepoResult}, depo_impl::{DepoImpl, DepoTransaction},
+    metrics::Metrics,
+    rate_limiter::{RateLimit, RateLimiter, DEFAULT_KEY_RATE_LIMIT, DEFAULT_RECOVERY_RATE_LIMIT},
+    record::{label_size, Record},
+    recovery_continuation::RecoveryContinuation,
+    recovery_verifier::{codes_match, new_verification_code, LocalRecoveryVerifier, RecoveryVerifier},
+    replay_guard::ReplayGuard, server_key::RetiredKey, MAX_LABEL_SIZE, REQUEST_WINDOW_SECONDS,
+};
+
+/// The functions handled by `dispatch_request`.
+pub(crate) const SUPPORTED_FUNCTIONS: &[&str] = &[
+    STORE_SHARE_FUNCTION_NAME,
+    GET_SHARES_FUNCTION_NAME,
+    DELETE_SHARES_FUNCTION_NAME,
+    UPDATE_KEY_FUNCTION_NAME,
+    DELETE_ACCOUNT_FUNCTION_NAME,
+    UPDATE_RECOVERY_FUNCTION_NAME,
+    GET_RECOVERY_FUNCTION_NAME,
+    START_RECOVERY_FUNCTION_NAME,
+    FINISH_RECOVERY_FUNCTION_NAME,
+    LIST_SHARES_FUNCTION_NAME,
+    UPDATE_SHARE_LABEL_FUNCTION_NAME,
+];
 
 #[derive(Clone)]
-pub struct Depo(Arc<dyn DepoImpl + Send + Sync>);
+pub struct Depo {
+    inner: Arc<dyn DepoImpl + Send + Sync>,
+    replay_guard: Arc<ReplayGuard>,
+    recovery_verifier: Arc<dyn RecoveryVerifier>,
+    metrics: Arc<Metrics>,
+    key_rate_limiter: Option<Arc<RateLimiter<PublicKeyBase>>>,
+    recovery_rate_limiter: Option<Arc<RateLimiter<String>>>,
+}
 
 impl Depo {
     pub fn new(inner: Arc<dyn DepoImpl + Send + Sync>) -> Self {
-        Self(inner)
+        let metrics = Arc::new(Metrics::new(inner.pool_status().as_ref()));
+        Self {
+            inner,
+            replay_guard: Arc::new(ReplayGuard::new(REQUEST_WINDOW_SECONDS)),
+            recovery_verifier: Arc::new(LocalRecoveryVerifier::new()),
+            metrics,
+            key_rate_limiter: Some(Arc::new(RateLimiter::new(DEFAULT_KEY_RATE_LIMIT))),
+            recovery_rate_limiter: Some(Arc::new(RateLimiter::new(DEFAULT_RECOVERY_RATE_LIMIT))),
+        }
+    }
+
+    /// Limits how many requests each account key may make, or removes the
+    /// limit if `None`.
+    pub fn with_key_rate_limit(mut self, limit: Option<RateLimit>) -> Self {
+        self.key_rate_limiter = limit.map(|limit| Arc::new(RateLimiter::new(limit)));
+        self
+    }
+
+    /// Limits how many times recovery may be started for each recovery method,
+    /// whether or not an account uses it, or removes the limit if `None`.
+    pub fn with_recovery_rate_limit(mut self, limit: Option<RateLimit>) -> Self {
+        self.recovery_rate_limiter = limit.map(|limit| Arc::new(RateLimiter::new(limit)));
+        self
+    }
+
+    /// Uses the given verifier to deliver recovery codes, in place of the
+    /// default one that only logs them.
+    pub fn with_recovery_verifier(mut self, recovery_verifier: Arc<dyn RecoveryVerifier>) -> Self {
+        self.recovery_verifier = recovery_verifier;
+        self
+    }
+
+    pub fn recovery_verifier(&self) -> &dyn RecoveryVerifier {
+        self.recovery_verifier.as_ref()
     }
 
     pub fn private_key(&self) -> &PrivateKeyBase {
-        self.0.private_key()
+        self.inner.private_key()
     }
 
     pub fn public_key(&self) -> &PublicKeyBase {
-        self.0.public_key()
+        self.inner.public_key()
     }
 
     pub fn public_key_string(&self) -> &str {
-        self.0.public_key_string()
+        self.inner.public_key_string()
+    }
+
+    /// The signed statements of each rotation of the server key, oldest first.
+    pub fn key_rotations(&self) -> &[Envelope] {
+        self.inner.key_rotations()
+    }
+
+    pub(crate) fn retired_keys(&self) -> &[RetiredKey] {
+        self.inner.retired_keys()
+    }
+
+    /// Returns a description of this depository's key, limits, and supported
+    /// functions, for clients to configure themselves with.
+    pub fn descriptor(&self) -> ServerDescriptor {
+        let mut functions = SUPPORTED_FUNCTIONS.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        functions.sort();
+        ServerDescriptor::new(
+            self.public_key().clone(),
+            env!("CARGO_PKG_VERSION"),
+            self.inner.max_data_size(),
+            MAX_LABEL_SIZE,
+            self.inner.continuation_expiry_seconds(),
+            self.inner.max_shares_per_account(),
+            self.inner.max_bytes_per_account(),
+            self.inner.max_share_ttl_seconds(),
+            REQUEST_WINDOW_SECONDS,
+            functions,
+            vec![self.recovery_verifier.method().to_string()],
+        )
+    }
+
+    /// Returns the descriptor signed by the server key.
+    pub fn signed_descriptor(&self) -> Envelope {
+        self.descriptor().sign(self.private_key())
+    }
+
+    /// Returns the current server key pair, followed by the retired ones whose
+    /// grace periods have not ended.
+    fn accepted_keys(&self) -> impl Iterator<Item = (&PrivateKeyBase, &PublicKeyBase)> {
+        let now = dcbor::Date::now();
+        std::iter::once((self.inner.private_key(), self.inner.public_key()))
+            .chain(self.inner.retired_keys().iter()
+                .filter(move |key| key.is_accepted_at(&now))
+                .map(|key| (key.private_key(), key.public_key())))
     }
 
     pub async fn handle_request_string(&self, request: String) -> String {
         let request_envelope = match Envelope::from_ur_string(&request) {
             Ok(request) => request,
-            Err(_) => {
-                return new_error_response(None, None, "invalid request").ur_string();
+            Err(e) => {
+                let error = DepoError::invalid_request(e);
+                self.metrics.record_error(error.code());
+                return new_error_response(None, None, &error).ur_string();
             }
         };
         self.handle_request(request_envelope).await.ur_string()
@@ -50,71 +167,152 @@ impl Depo {
     pub async fn handle_request(&self, encrypted_request: Envelope) -> Envelope {
         match self.handle_unverified_request(encrypted_request).await {
             Ok(success_response) => success_response,
-            Err(e) => new_error_response(None, None, e.to_string()),
+            Err(e) => {
+                self.metrics.record_error(e.code());
+                new_error_response(None, None, &e)
+            }
         }
     }
 
-    pub async fn handle_unverified_request(&self, encrypted_request: Envelope) -> anyhow::Result<Envelope> {
-        let decrypted_request = encrypted_request
-            .decrypt_to_recipient(self.0.private_key())
-            .map_err(|_| anyhow::anyhow!("request not encrypted to depository public key"))?;
-        let signed_request = decrypted_request.unwrap_envelope()?;
+    /// Fails unless the depository's storage can be reached.
+    pub async fn check_storage(&self) -> DepoResult<()> {
+        self.inner.ping().await
+    }
+
+    /// Returns the depository's metrics in the Prometheus text format. The
+    /// counts of accounts and shares in storage are those found when garbage
+    /// was last collected, so that scrapes do not count them each time.
+    pub fn metrics_text(&self) -> DepoResult<String> {
+        if let Some(pool_status) = self.inner.pool_status() {
+            self.metrics.set_pool_status(&pool_status);
+        }
+        Ok(self.metrics.render()?)
+    }
+
+    /// Decrypts a request sent to this depository and verifies that it was
+    /// signed by the key it contains. Returns the request and that key.
+    pub fn verify_request(&self, encrypted_request: Envelope) -> DepoResult<(Envelope, PublicKeyBase)> {
+        let (request, key, _) = self.decrypt_and_verify_request(&encrypted_request)?;
+        Ok((request, key))
+    }
+
+    /// Verifies a request encrypted to the current server key or a retired one
+    /// that is still accepted, also returning the server key that decrypted it.
+    fn decrypt_and_verify_request(&self, encrypted_request: &Envelope) -> DepoResult<(Envelope, PublicKeyBase, &PrivateKeyBase)> {
+        let (decrypted_request, server_key) = self.accepted_keys()
+            .find_map(|(private_key, _)| {
+                encrypted_request.decrypt_to_recipient(private_key).ok().map(|request| (request, private_key))
+            })
+            .ok_or(DepoError::WrongRecipient)?;
+        let signed_request = decrypted_request.unwrap_envelope().map_err(DepoError::invalid_request)?;
 
         // Verify that the key in the request is the same as the key used to sign the request
-        let request = signed_request.unwrap_envelope()?;
-        let body = request.request_body()?;
-        let key: PublicKeyBase = body.extract_object_for_parameter(KEY_PARAM)?;
-        let id = request.request_id()?;
-        let function = body.function()?;
+        let request = signed_request.unwrap_envelope().map_err(DepoError::invalid_request)?;
+        let body = request.request_body().map_err(DepoError::invalid_request)?;
+        let key: PublicKeyBase = body.extract_object_for_parameter(KEY_PARAM).map_err(DepoError::invalid_request)?;
         signed_request
             .verify_signature_from(&key)
-            .map_err(|_| anyhow::anyhow!("request signature does not match request key"))?;
+            .map_err(|_| DepoError::InvalidSignature)?;
 
-        let unsigned_response = match self.handle_verified_request(body, request, &key).await {
+        Ok((request, key, server_key))
+    }
+
+    /// Handles a request encrypted to the current server key, or to a retired
+    /// key during its grace period, in which case the response is signed with
+    /// the retired key so that clients which have not yet followed the rotation
+    /// can verify it.
+    pub async fn handle_unverified_request(&self, encrypted_request: Envelope) -> DepoResult<Envelope> {
+        let (request, key, server_key) = self.decrypt_and_verify_request(&encrypted_request)?;
+        let body = request.request_body().map_err(DepoError::invalid_request)?;
+        let id = request.request_id().map_err(DepoError::invalid_request)?;
+        let function = body.function().map_err(DepoError::invalid_request)?;
+
+        let unsigned_response = match self.handle_verified_request(&function, request, &key).await {
             Ok(success_response) => success_response,
             Err(e) => {
                 let function_name = function.named_name().unwrap_or("unknown".to_string());
-                new_error_response(Some(&id), Some(&function_name), e.to_string())
+                new_error_response(Some(&id), Some(&function_name), &e)
             }
         };
 
-        let signed_response = unsigned_response.sign_and_encrypt(self.0.private_key(), &key)?;
+        let signed_response = unsigned_response.sign_and_encrypt(server_key, &key)?;
         Ok(signed_response)
     }
 
-    async fn handle_verified_request(&self, body: Envelope, request: Envelope, user_signing_key: &PublicKeyBase) -> anyhow::Result<Envelope> {
-        let function = &body.function()?;
+    /// Rejects a verified request unless it is dated within the request window
+    /// and its ID has not already been used by the same key in that window.
+    pub fn check_replay(&self, request: &Envelope, key: &PublicKeyBase) -> DepoResult<()> {
+        let id = request.request_id().map_err(DepoError::invalid_request)?;
+        let date: dcbor::Date = request
+            .request_body()
+            .map_err(DepoError::invalid_request)?
+            .extract_object_for_parameter(DATE_PARAM)
+            .map_err(|_| DepoError::MissingDate)?;
+        self.replay_guard.check(key, &id, &date)
+    }
+
+    async fn handle_verified_request(&self, function: &Function, request: Envelope, user_signing_key: &PublicKeyBase) -> DepoResult<Envelope> {
+        let result = self.handle_accepted_request(function, request, user_signing_key).await;
+        if let Err(e) = &result {
+            self.metrics.record_error(e.code());
+        }
+        result
+    }
 
+    async fn handle_accepted_request(&self, function: &Function, request: Envelope, user_signing_key: &PublicKeyBase) -> DepoResult<Envelope> {
+        self.check_replay(&request, user_signing_key)?;
+        if self.key_rate_limiter.as_ref().is_some_and(|limiter| !limiter.check(user_signing_key)) {
+            return Err(DepoError::KeyRateLimited);
+        }
+        self.inner.touch_user(user_signing_key, &dcbor::Date::now()).await?;
+
+        let function_label = Metrics::function_label(function.named_name().as_deref());
+        let timer = self.metrics.start_request(function_label);
+        let result = self.dispatch_request(function, &request, user_signing_key).await;
+        timer.observe_duration();
+        result
+    }
+
+    async fn dispatch_request(&self, function: &Function, request: &Envelope, user_signing_key: &PublicKeyBase) -> DepoResult<Envelope> {
         let response = if function == &STORE_SHARE_FUNCTION {
-            self.handle_store_share(&request).await?
+            self.handle_store_share(request).await?
         } else if function == &GET_SHARES_FUNCTION {
-            self.handle_get_shares(&request).await?
+            self.handle_get_shares(request).await?
         } else if function == &DELETE_SHARES_FUNCTION {
-            self.handle_delete_shares(&request).await?
+            self.handle_delete_shares(request).await?
         } else if function == &UPDATE_KEY_FUNCTION {
-            self.handle_update_key(&request).await?
+            self.handle_update_key(request).await?
         } else if function == &DELETE_ACCOUNT_FUNCTION {
-            self.handle_delete_account(&request).await?
+            self.handle_delete_account(request).await?
         } else if function == &UPDATE_RECOVERY_FUNCTION {
-            self.handle_update_recovery(&request).await?
+            self.handle_update_recovery(request).await?
         } else if function == &GET_RECOVERY_FUNCTION {
-            self.handle_get_recovery(&request).await?
+            self.handle_get_recovery(request).await?
         } else if function == &START_RECOVERY_FUNCTION {
-            self.handle_start_recovery(&request).await?
+            self.handle_start_recovery(request).await?
         } else if function == &FINISH_RECOVERY_FUNCTION {
-            self.handle_finish_recovery(&request, user_signing_key).await?
+            self.handle_finish_recovery(request, user_signing_key).await?
+        } else if function == &LIST_SHARES_FUNCTION {
+            self.handle_list_shares(request).await?
+        } else if function == &UPDATE_SHARE_LABEL_FUNCTION {
+            self.handle_update_share_label(request).await?
         } else {
-            bail!("unknown function: {}", function.name());
+            return Err(DepoError::UnknownFunction(function.name()));
         };
 
         Ok(response)
     }
 
-    async fn handle_store_share(&self, request: &Envelope) -> anyhow::Result<Envelope> {
-        let request = StoreShareRequest::from_envelope(request.clone())?;
+    async fn handle_store_share(&self, request: &Envelope) -> DepoResult<Envelope> {
+        let body = request.request_body().map_err(DepoError::invalid_request)?;
+        let ttl_seconds = body
+            .extract_optional_object_for_parameter(TTL_PARAM)
+            .map_err(DepoError::invalid_request)?;
+        let label = body.object_for_parameter(LABEL_PARAM).ok();
+        let request = StoreShareRequest::from_envelope(request.clone()).map_err(DepoError::invalid_request)?;
         info!("{}", request);
 
-        let receipt = self.store_share(request.key(), request.data()).await?;
+        let receipt = self.store_share(request.key(), request.data(), ttl_seconds, label.as_ref()).await?;
 
         let response = StoreShareResponse::new(request.id().clone(), receipt);
         info!("{}", response);
@@ -123,21 +321,52 @@ impl Depo {
         Ok(response_envelope)
     }
 
-    async fn handle_get_shares(&self, request: &Envelope) -> anyhow::Result<Envelope> {
-        let request = GetSharesRequest::from_envelope(request.clone())?;
+    async fn handle_get_shares(&self, request: &Envelope) -> DepoResult<Envelope> {
+        let request = GetSharesRequest::from_envelope(request.clone()).map_err(DepoError::invalid_request)?;
         info!("{}", request);
 
-        let receipt_to_data = self.get_shares(request.key(), request.receipts()).await?;
+        let records = self.share_records(request.key(), request.receipts()).await?;
+        let receipt_to_data = records.iter()
+            .map(|record| (record.receipt().clone(), record.data().clone()))
+            .collect();
+        let labels = records.iter()
+            .filter_map(|record| Some((record.receipt().clone(), record.label()?.clone())));
 
         let response = GetSharesResponse::new(request.id().clone(), receipt_to_data);
         info!("{}", response);
 
+        let response_envelope = add_share_labels(response.into(), labels);
+        Ok(response_envelope)
+    }
+
+    async fn handle_list_shares(&self, request: &Envelope) -> DepoResult<Envelope> {
+        let request = ListSharesRequest::from_envelope(request.clone()).map_err(DepoError::invalid_request)?;
+        info!("{}", request);
+
+        let shares = self.list_shares(request.key()).await?;
+
+        let response = ListSharesResponse::new(request.id().clone(), shares);
+        info!("{}", response);
+
         let response_envelope = response.into();
         Ok(response_envelope)
     }
 
-    async fn handle_delete_shares(&self, request: &Envelope) -> anyhow::Result<Envelope> {
-        let request = DeleteSharesRequest::from_envelope(request.clone())?;
+    async fn handle_update_share_label(&self, request: &Envelope) -> DepoResult<Envelope> {
+        let request = UpdateShareLabelRequest::from_envelope(request.clone()).map_err(DepoError::invalid_request)?;
+        info!("{}", request);
+
+        self.update_share_label(request.key(), request.receipt(), request.label()).await?;
+
+        let response = UpdateShareLabelResponse::new(request.id().clone());
+        info!("{}", response);
+
+        let response_envelope = response.into();
+        Ok(response_envelope)
+    }
+
+    async fn handle_delete_shares(&self, request: &Envelope) -> DepoResult<Envelope> {
+        let request = DeleteSharesRequest::from_envelope(request.clone()).map_err(DepoError::invalid_request)?;
         info!("{}", request);
 
         self.delete_shares(request.key(), request.receipts())
@@ -150,8 +379,8 @@ impl Depo {
         Ok(response_envelope)
     }
 
-    async fn handle_update_key(&self, request: &Envelope) -> anyhow::Result<Envelope> {
-        let request = UpdateKeyRequest::from_envelope(request.clone())?;
+    async fn handle_update_key(&self, request: &Envelope) -> DepoResult<Envelope> {
+        let request = UpdateKeyRequest::from_envelope(request.clone()).map_err(DepoError::invalid_request)?;
         info!("{}", request);
 
         self.update_key(request.key(), request.new_key()).await?;
@@ -163,8 +392,8 @@ impl Depo {
         Ok(response_envelope)
     }
 
-    async fn handle_delete_account(&self, request: &Envelope) -> anyhow::Result<Envelope> {
-        let request = DeleteAccountRequest::from_envelope(request.clone())?;
+    async fn handle_delete_account(&self, request: &Envelope) -> DepoResult<Envelope> {
+        let request = DeleteAccountRequest::from_envelope(request.clone()).map_err(DepoError::invalid_request)?;
         info!("{}", request);
 
         self.delete_account(request.key()).await?;
@@ -176,8 +405,8 @@ impl Depo {
         Ok(response_envelope)
     }
 
-    async fn handle_update_recovery(&self, request: &Envelope) -> anyhow::Result<Envelope> {
-        let request = UpdateRecoveryRequest::from_envelope(request.clone())?;
+    async fn handle_update_recovery(&self, request: &Envelope) -> DepoResult<Envelope> {
+        let request = UpdateRecoveryRequest::from_envelope(request.clone()).map_err(DepoError::invalid_request)?;
         info!("{}", request);
 
         self.update_recovery(request.key(), request.recovery().map(|x| x.as_str()))
@@ -190,8 +419,8 @@ impl Depo {
         Ok(response_envelope)
     }
 
-    async fn handle_get_recovery(&self, request: &Envelope) -> anyhow::Result<Envelope> {
-        let request = GetRecoveryRequest::from_envelope(request.clone())?;
+    async fn handle_get_recovery(&self, request: &Envelope) -> DepoResult<Envelope> {
+        let request = GetRecoveryRequest::from_envelope(request.clone()).map_err(DepoError::invalid_request)?;
         info!("{}", request);
 
         let recovery_method = self.get_recovery(request.key()).await?;
@@ -203,8 +432,8 @@ impl Depo {
         Ok(response_envelope)
     }
 
-    async fn handle_start_recovery(&self, request: &Envelope) -> anyhow::Result<Envelope> {
-        let request = StartRecoveryRequest::from_envelope(request.clone())?;
+    async fn handle_start_recovery(&self, request: &Envelope) -> DepoResult<Envelope> {
+        let request = StartRecoveryRequest::from_envelope(request.clone()).map_err(DepoError::invalid_request)?;
         info!("{}", request);
 
         let continuation = self
@@ -218,11 +447,16 @@ impl Depo {
         Ok(response_envelope)
     }
 
-    async fn handle_finish_recovery(&self, request: &Envelope, user_signing_key: &PublicKeyBase) -> anyhow::Result<Envelope> {
-        let request = FinishRecoveryRequest::from_envelope(request.clone())?;
+    async fn handle_finish_recovery(&self, request: &Envelope, user_signing_key: &PublicKeyBase) -> DepoResult<Envelope> {
+        let verification_code: String = request
+            .request_body()
+            .map_err(DepoError::invalid_request)?
+            .extract_object_for_parameter(VERIFICATION_CODE_PARAM)
+            .map_err(|_| DepoError::MissingVerificationCode)?;
+        let request = FinishRecoveryRequest::from_envelope(request.clone()).map_err(DepoError::invalid_request)?;
         info!("{}", request);
 
-        self.finish_recovery(request.continuation(), user_signing_key).await?;
+        self.finish_recovery(request.continuation(), &verification_code, user_signing_key).await?;
 
         let response = FinishRecoveryResponse::new(request.id().clone());
         info!("{}", response);
@@ -236,17 +470,74 @@ impl Depo {
     /// This is a Trust-On-First-Use (TOFU) function. If the provided public key is not
     /// recognized, then a new account is created and the provided data is stored in
     /// it. It is also used to add additional shares to an existing account. Adding an
-    /// already existing share to an account is idempotent.
-    pub async fn store_share(&self, key: &PublicKeyBase, data: &Bytes) -> anyhow::Result<Receipt> {
-        let user = self.0.key_to_user(key).await?;
-        if data.len() > self.0.max_data_size() as usize {
-            bail!("data too large");
+    /// already existing share to an account is idempotent, except that it replaces
+    /// the share's expiry, and its label if one is given.
+    ///
+    /// If `ttl_seconds` is given, the share expires after that many seconds, or
+    /// the server's maximum if that is sooner, and is later removed by
+    /// `collect_garbage`.
+    pub async fn store_share(&self, key: &PublicKeyBase, data: &Bytes, ttl_seconds: Option<u32>, label: Option<&Envelope>) -> DepoResult<Receipt> {
+        if data.len() > self.inner.max_data_size() as usize {
+            return Err(DepoError::DataTooLarge);
         }
-        let record = Record::new(user.user_id(), data);
-        self.0.insert_record(&record).await?;
+        check_label_size(label)?;
+        // The account is locked by the transaction, so that concurrent stores
+        // cannot together exceed its quota.
+        let mut transaction = self.inner.begin().await?;
+        let user = transaction.key_to_user(key).await?;
+        let expiry = ttl_seconds.map(|ttl_seconds| {
+            let ttl_seconds = ttl_seconds.min(self.inner.max_share_ttl_seconds());
+            dcbor::Date::now() + ttl_seconds as f64
+        });
+        let record = Record::new(user.user_id(), data, expiry, label.cloned());
+        // Storing the same data again is idempotent, so only new records count
+        // against the account's quota, along with any growth of the label.
+        match transaction.receipt_to_record(record.receipt()).await? {
+            None => self.check_quota(transaction.as_mut(), user.user_id(), 1, data.len() + record.label_size()).await?,
+            Some(existing) if label.is_some() => {
+                self.check_quota(transaction.as_mut(), user.user_id(), 0, record.label_size().saturating_sub(existing.label_size())).await?
+            }
+            Some(_) => {}
+        }
+        transaction.insert_record(&record).await?;
+        transaction.commit().await?;
         Ok(record.receipt().clone())
     }
 
+    /// Fails if adding `added_shares` shares holding `added_bytes` bytes would
+    /// exceed one of the account's quotas.
+    async fn check_quota(&self, transaction: &mut dyn DepoTransaction, user_id: &ARID, added_shares: usize, added_bytes: usize) -> DepoResult<()> {
+        if added_shares == 0 && added_bytes == 0 {
+            return Ok(());
+        }
+        let (share_count, total_bytes) = transaction.id_to_usage(user_id).await?;
+        if share_count + added_shares > self.inner.max_shares_per_account() as usize {
+            return Err(DepoError::QuotaExceeded(format!("account already has {} shares", share_count)));
+        }
+        if total_bytes + added_bytes > self.inner.max_bytes_per_account() as usize {
+            return Err(DepoError::QuotaExceeded(format!("account would exceed {} bytes", self.inner.max_bytes_per_account())));
+        }
+        Ok(())
+    }
+
+    /// Replaces the label of a share the user controls without changing its
+    /// data, or removes the label if `label` is `None`. Attempting to label a
+    /// nonexistent receipt or a receipt from the wrong account is an error.
+    pub async fn update_share_label(&self, key: &PublicKeyBase, receipt: &Receipt, label: Option<&Envelope>) -> DepoResult<()> {
+        check_label_size(label)?;
+        let mut transaction = self.inner.begin().await?;
+        let user = transaction.expect_key_to_user(key).await?;
+        let record = transaction
+            .receipt_to_record(receipt)
+            .await?
+            .filter(|record| record.user_id() == user.user_id())
+            .ok_or(DepoError::UnknownReceipt)?;
+        let new_label_size = label.map_or(0, label_size);
+        self.check_quota(transaction.as_mut(), user.user_id(), 0, new_label_size.saturating_sub(record.label_size())).await?;
+        transaction.set_record_label(receipt, label).await?;
+        transaction.commit().await
+    }
+
     /// Returns a dictionary of `[Receipt: Payload]` corresponding to the set of
     /// input receipts, or corresponding to all the controlled shares if no input
     /// receipts are provided. Attempting to retrieve nonexistent receipts or receipts
@@ -255,17 +546,8 @@ impl Depo {
         &self,
         key: &PublicKeyBase,
         receipts: &HashSet<Receipt>,
-    ) -> anyhow::Result<HashMap<Receipt, Bytes>> {
-        let user = self.0.expect_key_to_user(key).await?;
-        let receipts = if receipts.is_empty() {
-            self.0.id_to_receipts(user.user_id()).await?
-        } else {
-            receipts.clone()
-        };
-        let records = self
-            .0
-            .records_for_id_and_receipts(user.user_id(), &receipts)
-            .await?;
+    ) -> DepoResult<HashMap<Receipt, Bytes>> {
+        let records = self.share_records(key, receipts).await?;
         let mut result = HashMap::new();
         for record in records {
             result.insert(record.receipt().clone(), record.data().clone());
@@ -273,15 +555,33 @@ impl Depo {
         Ok(result)
     }
 
+    /// Returns the records behind `get_shares`, with their labels.
+    async fn share_records(&self, key: &PublicKeyBase, receipts: &HashSet<Receipt>) -> DepoResult<Vec<Record>> {
+        let user = self.inner.expect_key_to_user(key).await?;
+        let receipts = if receipts.is_empty() {
+            self.inner.id_to_receipts(user.user_id()).await?
+        } else {
+            receipts.clone()
+        };
+        self.inner.records_for_id_and_receipts(user.user_id(), &receipts).await
+    }
+
+    /// Returns the receipts of all the shares a user controls, with their size,
+    /// creation time and expiry, without reading their data.
+    pub async fn list_shares(&self, key: &PublicKeyBase) -> DepoResult<Vec<ShareInfo>> {
+        let user = self.inner.expect_key_to_user(key).await?;
+        self.inner.id_to_share_infos(user.user_id()).await
+    }
+
     /// Returns a single share corresponding to the provided receipt. Attempting to
     /// retrieve a nonexistent receipt or a receipt from the wrong account is an error.
-    pub async fn get_share(&self, key: &PublicKeyBase, receipt: &Receipt) -> anyhow::Result<Bytes> {
+    pub async fn get_share(&self, key: &PublicKeyBase, receipt: &Receipt) -> DepoResult<Bytes> {
         let mut receipts = HashSet::new();
         receipts.insert(receipt.clone());
         let result = self.get_shares(key, &receipts).await?;
         let result = match result.get(receipt) {
             Some(result) => result.clone(),
-            None => bail!("unknown receipt"),
+            None => return Err(DepoError::UnknownReceipt),
         };
         Ok(result)
     }
@@ -293,16 +593,16 @@ impl Depo {
         &self,
         key: &PublicKeyBase,
         receipts: &HashSet<Receipt>,
-    ) -> anyhow::Result<()> {
-        let user = self.0.expect_key_to_user(key).await?;
+    ) -> DepoResult<()> {
+        let user = self.inner.expect_key_to_user(key).await?;
         let recpts = if receipts.is_empty() {
-            self.0.id_to_receipts(user.user_id()).await?
+            self.inner.id_to_receipts(user.user_id()).await?
         } else {
             receipts.clone()
         };
         for receipt in recpts {
-            if self.0.receipt_to_record(&receipt).await?.is_some() {
-                self.0.delete_record(&receipt).await?;
+            if self.inner.receipt_to_record(&receipt).await?.is_some() {
+                self.inner.delete_record(&receipt).await?;
             }
         }
         Ok(())
@@ -310,7 +610,7 @@ impl Depo {
 
     /// Deletes a single share a user controls. Deletes are idempotent; in other words,
     /// deleting a nonexistent share is not an error.
-    pub async fn delete_share(&self, key: &PublicKeyBase, receipt: &Receipt) -> anyhow::Result<()> {
+    pub async fn delete_share(&self, key: &PublicKeyBase, receipt: &Receipt) -> DepoResult<()> {
         let mut receipts = HashSet::new();
         receipts.insert(receipt.clone());
         self.delete_shares(key, &receipts).await?;
@@ -325,23 +625,47 @@ impl Depo {
         &self,
         old_key: &PublicKeyBase,
         new_key: &PublicKeyBase,
-    ) -> anyhow::Result<()> {
-        if self.0.existing_key_to_id(new_key).await?.is_some() {
-            bail!("public key already in use");
+    ) -> DepoResult<()> {
+        let mut transaction = self.inner.begin().await?;
+        let user = transaction.expect_key_to_user(old_key).await?;
+        if transaction.existing_key_to_user(new_key).await?.is_some() {
+            return Err(DepoError::PublicKeyInUse);
         }
-        self.0.set_user_key(old_key, new_key).await?;
-        Ok(())
+        transaction.set_user_key(&user, new_key).await?;
+        // Recoveries started for the old key no longer apply.
+        transaction.remove_continuations(user.user_id()).await?;
+        transaction.commit().await
     }
 
     /// Deletes all the shares of an account and any other data associated with it, such
     /// as the recovery contact method. Deleting an account is idempotent; in other words,
-    /// deleting a nonexistent account is not an error.
-    pub async fn delete_account(&self, key: &PublicKeyBase) -> anyhow::Result<()> {
-        if let Some(user) = self.0.existing_key_to_user(key).await? {
-            self.delete_shares(key, &HashSet::new()).await?;
-            self.0.remove_user(&user).await?;
+    /// deleting a nonexistent account is not an error. Either everything is deleted or,
+    /// if deletion fails, nothing is.
+    pub async fn delete_account(&self, key: &PublicKeyBase) -> DepoResult<()> {
+        let mut transaction = self.inner.begin().await?;
+        if let Some(user) = transaction.existing_key_to_user(key).await? {
+            transaction.remove_records(user.user_id()).await?;
+            transaction.remove_continuations(user.user_id()).await?;
+            transaction.remove_user(&user).await?;
         }
-        Ok(())
+        transaction.commit().await
+    }
+
+    /// Removes expired shares and, if `idle_account_expiry` is given, accounts
+    /// that hold no shares and have made no requests for that long, then
+    /// refreshes the counts of what remains for the metrics. Returns the number
+    /// of shares and accounts removed.
+    pub async fn collect_garbage(&self, idle_account_expiry: Option<Duration>) -> DepoResult<(usize, usize)> {
+        let now = dcbor::Date::now();
+        let records = self.inner.remove_expired_records(&now).await?;
+        let users = match idle_account_expiry {
+            Some(expiry) => self.inner.remove_idle_users(&(now + -expiry.as_secs_f64())).await?,
+            None => 0,
+        };
+        let user_count = self.inner.user_count().await?;
+        let record_count = self.inner.record_count().await?;
+        self.metrics.set_storage_counts(user_count, record_count);
+        Ok((records, users))
     }
 
     /// Updates an account's recovery contact method, which could be a phone
@@ -360,14 +684,15 @@ impl Depo {
         &self,
         key: &PublicKeyBase,
         recovery: Option<&str>,
-    ) -> anyhow::Result<()> {
-        let user = self.0.expect_key_to_user(key).await?;
+    ) -> DepoResult<()> {
+        let mut transaction = self.inner.begin().await?;
+        let user = transaction.expect_key_to_user(key).await?;
         // Recovery methods must be unique
         if let Some(non_opt_recovery) = recovery {
-            let existing_recovery_user = self.0.recovery_to_user(non_opt_recovery).await?;
+            let existing_recovery_user = transaction.recovery_to_user(non_opt_recovery).await?;
             if let Some(existing_recovery_user) = existing_recovery_user {
                 if existing_recovery_user.user_id() != user.user_id() {
-                    bail!("recovery method already exists");
+                    return Err(DepoError::RecoveryInUse);
                 } else {
                     // The user is already using this recovery, so we can just return
                     // (idempotency)
@@ -375,13 +700,15 @@ impl Depo {
                 }
             }
         }
-        self.0.set_user_recovery(&user, recovery).await?;
-        Ok(())
+        transaction.set_user_recovery(&user, recovery).await?;
+        // Recoveries started with the old recovery method no longer apply.
+        transaction.remove_continuations(user.user_id()).await?;
+        transaction.commit().await
     }
 
     /// Retrieves an account's recovery contact method, if any.
-    pub async fn get_recovery(&self, key: &PublicKeyBase) -> anyhow::Result<Option<String>> {
-        let user = self.0.expect_key_to_user(key).await?;
+    pub async fn get_recovery(&self, key: &PublicKeyBase) -> DepoResult<Option<String>> {
+        let user = self.inner.expect_key_to_user(key).await?;
         let recovery = user.recovery().map(|s| s.to_string());
         Ok(recovery)
     }
@@ -405,64 +732,109 @@ impl Depo {
         &self,
         recovery: impl AsRef<str>,
         new_key: &PublicKeyBase,
-    ) -> anyhow::Result<Envelope> {
+    ) -> DepoResult<Envelope> {
+        // Limit attempts before looking the recovery up, so that the limit also
+        // slows down probing for recovery methods that are in use.
+        if self.recovery_rate_limiter.as_ref().is_some_and(|limiter| !limiter.check(&recovery.as_ref().to_string())) {
+            return Err(DepoError::RecoveryRateLimited);
+        }
         // First find the user for the recovery.
-        let user = self.0.recovery_to_user(recovery.as_ref()).await?;
+        let user = self.inner.recovery_to_user(recovery.as_ref()).await?;
         // If no recovery was found return an error.
         let user = match user {
             Some(user) => user,
-            None => bail!("unknown recovery"),
+            None => return Err(DepoError::UnknownRecovery),
         };
         // Ensure there is no account with the new public key
-        let existing_user = self.0.existing_key_to_id(new_key).await?;
+        let existing_user = self.inner.existing_key_to_id(new_key).await?;
         if existing_user.is_some() {
-            bail!("public key already in use");
+            return Err(DepoError::PublicKeyInUse);
         }
+        // Send a one-time code to the recovery contact. It is also sealed in
+        // the continuation, so only someone who received it can finish.
+        let verification_code = new_verification_code();
+        self.recovery_verifier
+            .deliver_code(recovery.as_ref(), &verification_code)
+            .await
+            .map_err(|e| DepoError::VerificationDeliveryFailed(e.to_string()))?;
         let recovery_continuation = RecoveryContinuation::new(
             user.public_key().clone(),
             new_key.clone(),
-            dcbor::Date::now() + self.0.continuation_expiry_seconds() as f64,
+            dcbor::Date::now() + self.inner.continuation_expiry_seconds() as f64,
+            verification_code,
         );
+        self.inner
+            .insert_continuation(recovery_continuation.id(), user.user_id(), recovery_continuation.expiry())
+            .await?;
         let continuation_envelope = recovery_continuation
             .envelope()
-            .sign_and_encrypt(self.0.private_key(), self.0.public_key())?;
+            .sign_and_encrypt(self.inner.private_key(), self.inner.public_key())?;
         Ok(continuation_envelope)
     }
 
     /// Completes a reset of the account's public key. This is called after the
-    /// user has confirmed the change via their recovery contact method.
-    pub async fn finish_recovery(&self, continuation_envelope: &Envelope, user_signing_key: &PublicKeyBase) -> anyhow::Result<()> {
-        let continuation: RecoveryContinuation = continuation_envelope
-            .verify_and_decrypt(self.0.public_key(), self.0.private_key())?
-            .try_into()?;
+    /// user has confirmed the change via their recovery contact method, by
+    /// providing the verification code that was delivered to it.
+    pub async fn finish_recovery(&self, continuation_envelope: &Envelope, verification_code: &str, user_signing_key: &PublicKeyBase) -> DepoResult<()> {
+        // Continuations issued before a key rotation are still honored.
+        let continuation: RecoveryContinuation = self.accepted_keys()
+            .find_map(|(private_key, public_key)| continuation_envelope.verify_and_decrypt(public_key, private_key).ok())
+            .ok_or_else(|| DepoError::invalid_request("continuation is not from this depository"))
+            .and_then(|envelope| envelope.try_into().map_err(DepoError::invalid_request))?;
         // Ensure the continuation is valid
         let seconds_until_expiry = continuation.expiry().clone() - dcbor::Date::now();
         if seconds_until_expiry < 0.0 {
-            bail!("continuation expired");
+            return Err(DepoError::ContinuationExpired);
         }
 
         // Ensure the user's public key used to sign the request matches the new public key in the continuation
         if continuation.new_key() != user_signing_key {
-            bail!("invalid user signing key");
+            return Err(DepoError::InvalidSigningKey);
+        }
+
+        // Ensure the continuation has not already been used, or invalidated by
+        // a change to the account. It is used up before the verification code
+        // is compared, so that each code can only be guessed once.
+        let mut transaction = self.inner.begin().await?;
+        if !transaction.take_continuation(continuation.id()).await? {
+            return Err(DepoError::ContinuationUsed);
         }
 
         // Ensure the recovery has been verified.
+        if !codes_match(continuation.verification_code(), verification_code) {
+            transaction.commit().await?;
+            return Err(DepoError::InvalidVerificationCode);
+        }
 
-        // Set the user's public key to the new public key
-        self.0
-            .set_user_key(continuation.old_key(), continuation.new_key())
-            .await?;
-        Ok(())
+        // Set the user's public key to the new public key, unless another
+        // account has taken it since the recovery was started.
+        let user = transaction.expect_key_to_user(continuation.old_key()).await?;
+        if transaction.existing_key_to_user(continuation.new_key()).await?.is_some() {
+            return Err(DepoError::PublicKeyInUse);
+        }
+        transaction.set_user_key(&user, continuation.new_key()).await?;
+        transaction.remove_continuations(user.user_id()).await?;
+        transaction.commit().await
     }
 }
 
-fn new_error_response(response_id: Option<&ARID>, function: Option<&str>, error: impl AsRef<str>) -> Envelope {
+/// Returns an error response carrying both a message for people and the
+/// error's stable code.
+fn new_error_response(response_id: Option<&ARID>, function: Option<&str>, error: &DepoError) -> Envelope {
     let function_string = match function {
         Some(function) => function.to_string(),
         None => "unknown".to_string(),
     }.flanked_function();
-    let message = format!("{} {}", function_string, error.as_ref());
+    let message = format!("{} {}", function_string, error);
     let id_string = response_id.map(|id| id.abbrev()).unwrap_or_else(|| "unknown   ".to_string());
-    error!("{}: {}", id_string, message);
+    error!("{}: {} ({})", id_string, message, error.code());
     Envelope::new_error_response(response_id, Some(message))
+        .add_assertion(ERROR_CODE_PARAM, error.code())
+}
+
+fn check_label_size(label: Option<&Envelope>) -> DepoResult<()> {
+    if label.is_some_and(|label| label_size(label) > MAX_LABEL_SIZE as usize) {
+        return Err(DepoError::LabelTooLarge);
+    }
+    Ok(())
 }
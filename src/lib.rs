@@ -1,17 +1,51 @@
+pub mod api;
+mod backend;
+#[cfg(feature = "client")]
+mod client;
+mod db_config;
 mod db_depo;
+mod depo_error;
 mod depo_impl;
 mod function;
 mod mem_depo;
+mod metrics;
+mod migration;
+mod pg_depo;
+mod rate_limiter;
 mod record;
 mod recovery_continuation;
+mod recovery_verifier;
+mod replay_guard;
 mod user;
 mod server;
+mod server_key;
+mod sqlite_depo;
+#[cfg(feature = "client")]
+mod sskr_distribution;
+mod tls;
 mod log;
 
+pub use backend::Backend;
+#[cfg(feature = "client")]
+pub use client::{DepoClient, ErrorResponse};
+pub use db_config::{DbConfig, TlsMode};
+pub use depo_error::{DepoError, DepoResult};
 pub use function::Depo;
-pub use server::start_server;
-pub use log::setup_log;
+pub use migration::{MigrationPlan, MigrationStep};
+pub use rate_limiter::{RateLimit, DEFAULT_IP_RATE_LIMIT, DEFAULT_KEY_RATE_LIMIT, DEFAULT_RECOVERY_RATE_LIMIT};
+pub use recovery_verifier::{LocalRecoveryVerifier, RecoveryVerifier};
+pub use server::{start_server, ServerConfig, DEFAULT_GC_INTERVAL, DEFAULT_PORT};
+pub use server_key::KeyStorage;
+#[cfg(feature = "client")]
+pub use sskr_distribution::{DistributedShare, ShareDistribution};
+pub use tls::TlsConfig;
+pub use log::{setup_log, setup_log_with_level};
 pub use db_depo::{reset_db, can_connect_to_db, create_db_if_needed};
 
 const MAX_DATA_SIZE: u32 = 1000;
+const MAX_LABEL_SIZE: u32 = 500;
 const CONTINUATION_EXPIRY_SECONDS: u32 = 60 * 60 * 24;
+const REQUEST_WINDOW_SECONDS: u32 = 60 * 5;
+const MAX_SHARES_PER_ACCOUNT: u32 = 100;
+const MAX_BYTES_PER_ACCOUNT: u32 = 50 * MAX_DATA_SIZE;
+const MAX_SHARE_TTL_SECONDS: u32 = 60 * 60 * 24 * 365 * 5;
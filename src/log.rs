@@ -5,9 +5,15 @@ use log::LevelFilter;
 static INIT: Once = Once::new();
 
 pub fn setup_log() {
+    setup_log_with_level(LevelFilter::Info);
+}
+
+/// Initializes logging at the given level. Only the first call has any
+/// effect.
+pub fn setup_log_with_level(level: LevelFilter) {
     INIT.call_once(|| {
         Builder::new()
-            .filter(None, LevelFilter::Info)
+            .filter(None, level)
             .init();
         // Builder::from_env(
         //     Env::default()
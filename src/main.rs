@@ -1,15 +1,328 @@
-use depo::{start_server, setup_log};
-use log::error;
-use nu_ansi_term::Color::Red;
+use std::{net::IpAddr, path::PathBuf, process::ExitCode, sync::Arc, time::Duration};
 
-#[tokio::main]
-async fn main() {
-    setup_log();
+use bc_components::PublicKeyBase;
+use bc_ur::{URDecodable, UREncodable};
+use clap::{Parser, Subcommand, ValueEnum};
+use depo::{
+    start_server, setup_log_with_level, Backend, DbConfig, KeyStorage, LocalRecoveryVerifier, RateLimit,
+    ServerConfig, TlsConfig, DEFAULT_GC_INTERVAL, DEFAULT_IP_RATE_LIMIT, DEFAULT_KEY_RATE_LIMIT,
+    DEFAULT_PORT, DEFAULT_RECOVERY_RATE_LIMIT,
+};
+use log::{error, info, LevelFilter};
+use nu_ansi_term::Color::{Green, Red};
+
+/// Blockchain Commons Depository server.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// The address the server listens on.
+    #[arg(long, env = "DEPO_BIND", default_value = "127.0.0.1", global = true)]
+    bind: IpAddr,
+
+    /// The port the server listens on.
+    #[arg(long, env = "DEPO_PORT", default_value_t = DEFAULT_PORT, global = true)]
+    port: u16,
+
+    /// The storage backend.
+    #[arg(long, value_enum, env = "DEPO_BACKEND", default_value_t = BackendKind::Mysql, global = true)]
+    backend: BackendKind,
+
+    /// The database schema holding the depository's tables.
+    #[arg(long, env = "DEPO_SCHEMA", default_value = "depo", global = true)]
+    schema: String,
+
+    /// The SQLite database file. Defaults to `<schema>.sqlite`.
+    #[arg(long, env = "DEPO_SQLITE_PATH", global = true)]
+    sqlite_path: Option<PathBuf>,
+
+    /// A TOML file with the database connection settings.
+    #[arg(long, env = "DEPO_CONFIG", global = true)]
+    config: Option<PathBuf>,
+
+    /// A PEM certificate chain. Serves HTTPS when given along with
+    /// `--tls-key`. Both files are reread on SIGHUP.
+    #[arg(long, env = "DEPO_TLS_CERT", requires = "tls_key", global = true)]
+    tls_cert: Option<PathBuf>,
+
+    /// The PEM private key for `--tls-cert`.
+    #[arg(long, env = "DEPO_TLS_KEY", requires = "tls_cert", global = true)]
+    tls_key: Option<PathBuf>,
+
+    /// Encrypts the server's private key in the database with this
+    /// passphrase. Prefer giving it in the environment, where other users of
+    /// the machine cannot see it.
+    #[arg(long, env = "DEPO_KEY_PASSPHRASE", hide_env_values = true, conflicts_with_all = ["keyfile", "private_key_file"], global = true)]
+    key_passphrase: Option<String>,
+
+    /// Encrypts the server's private key in the database with a key derived
+    /// from this file, which must hold at least 32 random bytes.
+    #[arg(long, env = "DEPO_KEYFILE", conflicts_with = "private_key_file", global = true)]
+    keyfile: Option<PathBuf>,
+
+    /// Keeps the server's private key in this file instead of the database,
+    /// creating it if it does not exist.
+    #[arg(long, env = "DEPO_PRIVATE_KEY_FILE", global = true)]
+    private_key_file: Option<PathBuf>,
+
+    /// Appends account recovery verification codes to this file, in addition
+    /// to logging them, for relaying to users by hand or in tests.
+    #[arg(long, env = "DEPO_RECOVERY_CODES_FILE", global = true)]
+    recovery_codes_file: Option<PathBuf>,
+
+    /// Enables the `/reset-db` route for requests signed by this admin key
+    /// (`ur:crypto-pubkeys`). Only intended for development.
+    #[arg(long, env = "DEPO_ADMIN_KEY", value_parser = parse_public_key, global = true)]
+    admin_key: Option<PublicKeyBase>,
+
+    /// How often, in seconds, expired shares and idle accounts are removed.
+    #[arg(long, env = "DEPO_GC_INTERVAL", default_value_t = DEFAULT_GC_INTERVAL.as_secs(), value_parser = clap::value_parser!(u64).range(1..), global = true)]
+    gc_interval: u64,
+
+    /// Removes accounts that hold no shares and have made no requests for
+    /// this many days. Such accounts are kept forever if not given.
+    #[arg(long, env = "DEPO_IDLE_ACCOUNT_DAYS", global = true)]
+    idle_account_days: Option<u64>,
+
+    /// The requests accepted from each remote address, such as `300/minute`,
+    /// or `off`.
+    #[arg(long, env = "DEPO_IP_RATE_LIMIT", value_parser = parse_rate_limit, default_value_t = RateLimitArg(Some(DEFAULT_IP_RATE_LIMIT)), global = true)]
+    ip_rate_limit: RateLimitArg,
+
+    /// The requests accepted from each account key, or `off`.
+    #[arg(long, env = "DEPO_KEY_RATE_LIMIT", value_parser = parse_rate_limit, default_value_t = RateLimitArg(Some(DEFAULT_KEY_RATE_LIMIT)), global = true)]
+    key_rate_limit: RateLimitArg,
+
+    /// The recovery attempts accepted for each recovery method, or `off`.
+    #[arg(long, env = "DEPO_RECOVERY_RATE_LIMIT", value_parser = parse_rate_limit, default_value_t = RateLimitArg(Some(DEFAULT_RECOVERY_RATE_LIMIT)), global = true)]
+    recovery_rate_limit: RateLimitArg,
 
-    let schema_name = "depo";
+    /// The most verbose level of log messages to show.
+    #[arg(long, env = "DEPO_LOG_LEVEL", default_value_t = LevelFilter::Info, global = true)]
+    log_level: LevelFilter,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run the server (the default).
+    Serve,
+    /// Create the database schema if it does not already exist.
+    InitDb,
+    /// Drop and recreate the database schema, deleting all accounts and
+    /// assigning a new server key.
+    ResetDb {
+        /// Confirm that all data should be deleted.
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Print the server's public key.
+    ShowPublicKey,
+    /// Check that the database is reachable and its schema exists.
+    CheckDb,
+    /// Replace the server key with a new one, still accepting the previous
+    /// key for a grace period. Takes effect when the server restarts.
+    RotateKey {
+        /// How many days requests encrypted to the previous key are accepted.
+        #[arg(long, default_value_t = DEFAULT_KEY_ROTATION_GRACE_DAYS)]
+        grace_days: u64,
+    },
+    /// Apply the schema migrations the database needs.
+    Migrate {
+        /// Only list the migrations that would be applied.
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+/// How many days a rotated key is accepted unless another grace period is
+/// given.
+const DEFAULT_KEY_ROTATION_GRACE_DAYS: u64 = 30;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum BackendKind {
+    Mysql,
+    Postgres,
+    Sqlite,
+    Memory,
+}
+
+/// A rate limit given on the command line, where `off` disables it.
+#[derive(Debug, Clone, Copy)]
+struct RateLimitArg(Option<RateLimit>);
+
+impl std::fmt::Display for RateLimitArg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(limit) => write!(f, "{}", limit),
+            None => write!(f, "off"),
+        }
+    }
+}
+
+fn parse_rate_limit(s: &str) -> Result<RateLimitArg, String> {
+    if s == "off" {
+        return Ok(RateLimitArg(None));
+    }
+    s.parse().map(|limit| RateLimitArg(Some(limit))).map_err(|e: anyhow::Error| e.to_string())
+}
+
+fn parse_public_key(s: &str) -> Result<PublicKeyBase, String> {
+    PublicKeyBase::from_ur_string(s).map_err(|e| e.to_string())
+}
+
+impl Cli {
+    fn backend(&self) -> anyhow::Result<Backend> {
+        let backend = match self.backend {
+            BackendKind::Mysql => Backend::MySql {
+                config: DbConfig::load(self.config.as_deref())?,
+                schema_name: self.schema.clone(),
+            },
+            BackendKind::Postgres => Backend::Postgres {
+                config: DbConfig::load(self.config.as_deref())?,
+                schema_name: self.schema.clone(),
+            },
+            BackendKind::Sqlite => Backend::Sqlite(
+                self.sqlite_path.clone().unwrap_or_else(|| format!("{}.sqlite", self.schema).into())
+            ),
+            BackendKind::Memory => Backend::Memory,
+        };
+        Ok(backend)
+    }
+
+    fn key_storage(&self) -> KeyStorage {
+        if let Some(passphrase) = &self.key_passphrase {
+            KeyStorage::passphrase(passphrase.clone())
+        } else if let Some(path) = &self.keyfile {
+            KeyStorage::Keyfile(path.clone())
+        } else if let Some(path) = &self.private_key_file {
+            KeyStorage::File(path.clone())
+        } else {
+            KeyStorage::Plaintext
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    setup_log_with_level(cli.log_level);
 
-    if let Err(e) = start_server(schema_name, 5332).await {
-        error!("{}", Red.paint("Could not start server. Is the database running?").to_string());
-        error!("{}", Red.paint(format!("{}", e)).to_string());
+    let backend = match cli.backend() {
+        Ok(backend) => backend,
+        Err(e) => {
+            error!("{}", Red.paint(format!("Invalid database configuration: {:#}", e)).to_string());
+            return ExitCode::FAILURE;
+        }
     };
+
+    let key_storage = cli.key_storage();
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            let config = ServerConfig {
+                key_storage,
+                bind_address: cli.bind,
+                port: cli.port,
+                admin_key: cli.admin_key,
+                tls: cli.tls_cert.zip(cli.tls_key).map(|(cert, key)| TlsConfig::new(cert, key)),
+                recovery_verifier: Arc::new(match cli.recovery_codes_file {
+                    Some(path) => LocalRecoveryVerifier::with_file(path),
+                    None => LocalRecoveryVerifier::new(),
+                }),
+                gc_interval: Duration::from_secs(cli.gc_interval),
+                idle_account_expiry: cli.idle_account_days.map(|days| Duration::from_secs(days * 60 * 60 * 24)),
+                ip_rate_limit: cli.ip_rate_limit.0,
+                key_rate_limit: cli.key_rate_limit.0,
+                recovery_rate_limit: cli.recovery_rate_limit.0,
+                ..ServerConfig::new(backend)
+            };
+            if let Err(e) = start_server(&config).await {
+                error!("{}", Red.paint("Could not start server. Is the database running?").to_string());
+                error!("{}", Red.paint(format!("{}", e)).to_string());
+                return ExitCode::FAILURE;
+            }
+        }
+        Command::InitDb => {
+            if let Err(e) = backend.create_db_if_needed().await {
+                error!("{}", Red.paint(format!("Could not create database {}: {:#}", backend, e)).to_string());
+                return ExitCode::FAILURE;
+            }
+            info!("{}", Green.paint(format!("Database {} is ready.", backend)));
+        }
+        Command::ResetDb { yes } => {
+            if !yes {
+                error!("{}", Red.paint(format!("Resetting {} deletes all of its data. Pass --yes to confirm.", backend)).to_string());
+                return ExitCode::FAILURE;
+            }
+            if let Err(e) = backend.reset_db().await {
+                error!("{}", Red.paint(format!("Could not reset database {}: {:#}", backend, e)).to_string());
+                return ExitCode::FAILURE;
+            }
+            info!("{}", Green.paint(format!("Database {} reset. A new private key has been assigned.", backend)));
+        }
+        Command::ShowPublicKey => {
+            if matches!(backend, Backend::Memory) {
+                error!("{}", Red.paint("The memory backend generates a new key each time it starts.").to_string());
+                return ExitCode::FAILURE;
+            }
+            match backend.new_depo_with_key_storage(&key_storage).await {
+                Ok(depo) => println!("{}", depo.public_key_string()),
+                Err(e) => {
+                    error!("{}", Red.paint(format!("Could not open database {}: {:#}", backend, e)).to_string());
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        Command::RotateKey { grace_days } => {
+            let grace_period = Duration::from_secs(grace_days * 60 * 60 * 24);
+            match backend.rotate_key(&key_storage, grace_period).await {
+                Ok(rotation) => {
+                    info!("{}", Green.paint(format!("Rotated the server key of {}. The previous key is accepted until {}. Restart the server to use the new key:", backend, rotation.grace_period_end())));
+                    println!("{}", rotation.new_key().ur_string());
+                }
+                Err(e) => {
+                    error!("{}", Red.paint(format!("Could not rotate the server key of {}: {:#}", backend, e)).to_string());
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        Command::Migrate { dry_run } => {
+            if matches!(backend, Backend::Memory) {
+                error!("{}", Red.paint("The memory backend has no schema to migrate.").to_string());
+                return ExitCode::FAILURE;
+            }
+            let plan = match backend.migrate(dry_run).await {
+                Ok(plan) => plan,
+                Err(e) => {
+                    error!("{}", Red.paint(format!("Could not migrate database {}: {:#}", backend, e)).to_string());
+                    return ExitCode::FAILURE;
+                }
+            };
+            if plan.is_empty() {
+                info!("{}", Green.paint(format!("Database {} is up to date at schema version {}.", backend, plan.to_version())));
+            } else {
+                let verb = if dry_run { "Would migrate" } else { "Migrated" };
+                info!("{}", Green.paint(format!("{} database {} from schema version {} to {}:", verb, backend, plan.from_version(), plan.to_version())));
+                for step in plan.steps() {
+                    info!("  {}", step);
+                }
+            }
+        }
+        Command::CheckDb => {
+            match backend.can_connect_to_db().await {
+                Ok(true) => info!("{}", Green.paint(format!("Database {} is reachable.", backend))),
+                Ok(false) => {
+                    error!("{}", Red.paint(format!("Database {} has not been created.", backend)).to_string());
+                    return ExitCode::FAILURE;
+                }
+                Err(e) => {
+                    error!("{}", Red.paint(format!("Could not connect to database {}: {:#}", backend, e)).to_string());
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+    }
+    ExitCode::SUCCESS
 }
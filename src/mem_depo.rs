@@ -2,11 +2,11 @@ use std::{collections::{HashSet, HashMap}, sync::Arc};
 
 use async_trait::async_trait;
 use bc_components::{PublicKeyBase, PrivateKeyBase, ARID};
-use tokio::sync::RwLock;
+use tokio::sync::{OwnedRwLockWriteGuard, RwLock};
 use depo_api::receipt::Receipt;
 use bc_envelope::prelude::*;
 
-use crate::{depo_impl::DepoImpl, user::User, record::Record, function::Depo, MAX_DATA_SIZE, CONTINUATION_EXPIRY_SECONDS};
+use crate::{api::ShareInfo, depo_error::DepoResult, depo_impl::{DepoImpl, DepoTransaction}, server_key::RetiredKey, user::User, record::Record, function::Depo, MAX_DATA_SIZE, CONTINUATION_EXPIRY_SECONDS, MAX_SHARES_PER_ACCOUNT, MAX_BYTES_PER_ACCOUNT, MAX_SHARE_TTL_SECONDS};
 
 struct Inner {
     id_to_user: HashMap<ARID, User>,
@@ -14,13 +14,173 @@ struct Inner {
     public_key_to_id: HashMap<PublicKeyBase, ARID>,
     receipt_to_record: HashMap<Receipt, Record>,
     id_to_receipts: HashMap<ARID, HashSet<Receipt>>,
+    continuation_to_id: HashMap<ARID, ARID>,
+    id_to_last_active: HashMap<ARID, f64>,
+}
+
+impl Inner {
+    fn key_to_user(&self, key: &PublicKeyBase) -> Option<User> {
+        self.public_key_to_id.get(key).and_then(|user_id| self.id_to_user.get(user_id)).cloned()
+    }
+
+    fn insert_user(&mut self, user: &User) {
+        if self.public_key_to_id.contains_key(user.public_key()) {
+            return;
+        }
+        self.id_to_user.insert(user.user_id().clone(), user.clone());
+        self.public_key_to_id.insert(user.public_key().clone(), user.user_id().clone());
+        self.id_to_receipts.insert(user.user_id().clone(), HashSet::new());
+        self.id_to_last_active.insert(user.user_id().clone(), dcbor::Date::now().timestamp());
+    }
+
+    fn set_user_recovery(&mut self, user: &User, recovery: Option<&str>) {
+        // get the user's existing recovery
+        let old_recovery = user.recovery();
+        // if the new and old recoverys are the same, return (idempotency)
+        if old_recovery == recovery {
+            return;
+        }
+        // Remove the old recovery, if any
+        if let Some(old_recovery) = old_recovery {
+            self.recovery_to_id.remove(old_recovery);
+        }
+        // Add the new recovery, if any
+        if let Some(recovery) = recovery {
+            self.recovery_to_id.insert(recovery.to_string(), user.user_id().clone());
+        }
+        // Set the user record to the new recovery
+        let user = self.id_to_user.get_mut(user.user_id()).unwrap();
+        user.set_recovery(recovery);
+    }
+
+    fn set_user_key(&mut self, user: &User, new_public_key: &PublicKeyBase) {
+        self.public_key_to_id.remove(user.public_key());
+        self.public_key_to_id.insert(new_public_key.clone(), user.user_id().clone());
+        let user = self.id_to_user.get_mut(user.user_id()).unwrap();
+        user.set_public_key(new_public_key.clone());
+    }
+
+    fn insert_record(&mut self, record: &Record) {
+        let receipt = record.receipt();
+        // Storing the same data again keeps the original creation time, and the
+        // label unless a new one is given.
+        let record = match self.receipt_to_record.get(receipt) {
+            Some(existing) => Record::new_opt(
+                receipt.clone(),
+                record.user_id().clone(),
+                record.data().clone(),
+                record.expiry().cloned(),
+                existing.created().cloned(),
+                record.label().or(existing.label()).cloned(),
+            ),
+            None => record.clone(),
+        };
+        self.receipt_to_record.insert(receipt.clone(), record.clone());
+        self.id_to_receipts.get_mut(record.user_id()).unwrap().insert(receipt.clone());
+    }
+
+    fn set_record_label(&mut self, receipt: &Receipt, label: Option<&Envelope>) {
+        if let Some(record) = self.receipt_to_record.get(receipt).cloned() {
+            let record = Record::new_opt(
+                receipt.clone(),
+                record.user_id().clone(),
+                record.data().clone(),
+                record.expiry().cloned(),
+                record.created().cloned(),
+                label.cloned(),
+            );
+            self.receipt_to_record.insert(receipt.clone(), record);
+        }
+    }
+
+    fn usage(&self, user_id: &ARID) -> (usize, usize) {
+        let receipts = self.id_to_receipts.get(user_id).unwrap();
+        let bytes = receipts.iter()
+            .filter_map(|receipt| self.receipt_to_record.get(receipt))
+            .map(|record| record.data().len() + record.label_size())
+            .sum();
+        (receipts.len(), bytes)
+    }
+
+    fn remove_records(&mut self, user_id: &ARID) {
+        if let Some(receipts) = self.id_to_receipts.get_mut(user_id) {
+            for receipt in receipts.drain() {
+                self.receipt_to_record.remove(&receipt);
+            }
+        }
+    }
+
+    fn remove_user(&mut self, user: &User) {
+        self.public_key_to_id.remove(user.public_key());
+        self.recovery_to_id.remove(user.recovery().unwrap_or_default());
+        self.id_to_user.remove(user.user_id());
+        self.id_to_receipts.remove(user.user_id());
+        self.continuation_to_id.retain(|_, user_id| user_id != user.user_id());
+        self.id_to_last_active.remove(user.user_id());
+    }
+
+    fn user_state(&self, user_id: &ARID) -> UserState {
+        let records = self.id_to_receipts.get(user_id)
+            .map(|receipts| receipts.iter().filter_map(|receipt| self.receipt_to_record.get(receipt)).cloned().collect())
+            .unwrap_or_default();
+        let continuations = self.continuation_to_id.iter()
+            .filter(|(_, id)| *id == user_id)
+            .map(|(continuation_id, _)| continuation_id.clone())
+            .collect();
+        UserState {
+            user_id: user_id.clone(),
+            user: self.id_to_user.get(user_id).cloned(),
+            records,
+            continuations,
+            last_active: self.id_to_last_active.get(user_id).copied(),
+        }
+    }
+
+    fn remove_user_state(&mut self, user_id: &ARID) {
+        if let Some(user) = self.id_to_user.get(user_id).cloned() {
+            self.remove_records(user_id);
+            self.remove_user(&user);
+        }
+    }
+
+    fn insert_user_state(&mut self, state: UserState) {
+        let Some(user) = state.user else {
+            return;
+        };
+        self.public_key_to_id.insert(user.public_key().clone(), state.user_id.clone());
+        if let Some(recovery) = user.recovery() {
+            self.recovery_to_id.insert(recovery.to_string(), state.user_id.clone());
+        }
+        self.id_to_user.insert(state.user_id.clone(), user);
+        let receipts = state.records.iter().map(|record| record.receipt().clone()).collect();
+        self.id_to_receipts.insert(state.user_id.clone(), receipts);
+        for record in state.records {
+            self.receipt_to_record.insert(record.receipt().clone(), record);
+        }
+        for continuation_id in state.continuations {
+            self.continuation_to_id.insert(continuation_id, state.user_id.clone());
+        }
+        if let Some(last_active) = state.last_active {
+            self.id_to_last_active.insert(state.user_id, last_active);
+        }
+    }
+}
+
+/// Everything stored for one user, kept so that a transaction's changes to the
+/// user can be undone.
+struct UserState {
+    user_id: ARID,
+    user: Option<User>,
+    records: Vec<Record>,
+    continuations: Vec<ARID>,
+    last_active: Option<f64>,
 }
 
 struct MemDepoImpl {
     private_key: PrivateKeyBase,
     public_key: PublicKeyBase,
     public_key_string: String,
-    inner: RwLock<Inner>,
+    inner: Arc<RwLock<Inner>>,
 }
 
 impl MemDepoImpl {
@@ -32,13 +192,15 @@ impl MemDepoImpl {
             private_key,
             public_key,
             public_key_string,
-            inner: RwLock::new(Inner {
+            inner: Arc::new(RwLock::new(Inner {
                 id_to_user: HashMap::new(),
                 recovery_to_id: HashMap::new(),
                 public_key_to_id: HashMap::new(),
                 receipt_to_record: HashMap::new(),
                 id_to_receipts: HashMap::new(),
-            })
+                continuation_to_id: HashMap::new(),
+                id_to_last_active: HashMap::new(),
+            })),
         })
     }
 }
@@ -69,6 +231,18 @@ impl DepoImpl for MemDepoImpl {
         CONTINUATION_EXPIRY_SECONDS
     }
 
+    fn max_shares_per_account(&self) -> u32 {
+        MAX_SHARES_PER_ACCOUNT
+    }
+
+    fn max_bytes_per_account(&self) -> u32 {
+        MAX_BYTES_PER_ACCOUNT
+    }
+
+    fn max_share_ttl_seconds(&self) -> u32 {
+        MAX_SHARE_TTL_SECONDS
+    }
+
     fn private_key(&self) -> &PrivateKeyBase {
         &self.private_key
     }
@@ -81,41 +255,65 @@ impl DepoImpl for MemDepoImpl {
         &self.public_key_string
     }
 
-    async fn existing_key_to_id(&self, public_key: &PublicKeyBase) -> anyhow::Result<Option<ARID>> {
+    fn retired_keys(&self) -> &[RetiredKey] {
+        &[]
+    }
+
+    fn key_rotations(&self) -> &[Envelope] {
+        &[]
+    }
+
+    async fn ping(&self) -> DepoResult<()> {
+        Ok(())
+    }
+
+    async fn existing_key_to_id(&self, public_key: &PublicKeyBase) -> DepoResult<Option<ARID>> {
         Ok(self.inner.read().await.public_key_to_id.get(public_key).cloned())
     }
 
-    async fn existing_id_to_user(&self, user_id: &ARID) -> anyhow::Result<Option<User>> {
+    async fn existing_id_to_user(&self, user_id: &ARID) -> DepoResult<Option<User>> {
         Ok(self.inner.read().await.id_to_user.get(user_id).cloned())
     }
 
-    async fn insert_user(&self, user: &User) -> anyhow::Result<()> {
-        let mut write = self.inner.write().await;
-        write.id_to_user.insert(user.user_id().clone(), user.clone());
-        write.public_key_to_id.insert(user.public_key().clone(), user.user_id().clone());
-        write.id_to_receipts.insert(user.user_id().clone(), HashSet::new());
-        Ok(())
+    async fn begin(&self) -> DepoResult<Box<dyn DepoTransaction>> {
+        let inner = self.inner.clone().write_owned().await;
+        Ok(Box::new(MemDepoTransaction { inner, undo: HashMap::new() }))
     }
 
-    async fn insert_record(&self, record: &Record) -> anyhow::Result<()> {
+    async fn touch_user(&self, key: &PublicKeyBase, date: &dcbor::Date) -> DepoResult<()> {
         let mut write = self.inner.write().await;
-        let receipt = record.receipt();
-        write.receipt_to_record.insert(receipt.clone(), record.clone());
-        write.id_to_receipts.get_mut(record.user_id()).unwrap().insert(receipt.clone());
+        if let Some(user_id) = write.public_key_to_id.get(key).cloned() {
+            write.id_to_last_active.insert(user_id, date.timestamp());
+        }
         Ok(())
     }
 
-    async fn id_to_receipts(&self, user_id: &ARID) -> anyhow::Result<HashSet<Receipt>> {
+    async fn id_to_receipts(&self, user_id: &ARID) -> DepoResult<HashSet<Receipt>> {
         Ok(self.inner.read().await.id_to_receipts.get(user_id).unwrap().clone())
     }
 
-    async fn receipt_to_record(&self, receipt: &Receipt) -> anyhow::Result<Option<Record>> {
+    async fn id_to_share_infos(&self, user_id: &ARID) -> DepoResult<Vec<ShareInfo>> {
+        let read = self.inner.read().await;
+        let infos = read.id_to_receipts.get(user_id).unwrap().iter()
+            .filter_map(|receipt| read.receipt_to_record.get(receipt))
+            .map(|record| ShareInfo::new(
+                record.receipt().clone(),
+                record.data().len(),
+                record.created().cloned(),
+                record.expiry().cloned(),
+                record.label().cloned(),
+            ))
+            .collect();
+        Ok(infos)
+    }
+
+    async fn receipt_to_record(&self, receipt: &Receipt) -> DepoResult<Option<Record>> {
         let read = self.inner.read().await;
         let record = read.receipt_to_record.get(receipt);
         Ok(record.cloned())
     }
 
-    async fn delete_record(&self, receipt: &Receipt) -> anyhow::Result<()> {
+    async fn delete_record(&self, receipt: &Receipt) -> DepoResult<()> {
         let record = self.receipt_to_record(receipt).await?;
         if let Some(record) = record {
             let mut write = self.inner.write().await;
@@ -125,59 +323,174 @@ impl DepoImpl for MemDepoImpl {
         Ok(())
     }
 
-    async fn set_user_key(&self, old_public_key: &PublicKeyBase, new_public_key: &PublicKeyBase) -> anyhow::Result<()> {
-        let user = self.expect_key_to_user(old_public_key).await?;
+    async fn recovery_to_user(&self, recovery: &str) -> DepoResult<Option<User>> {
+        let read = self.inner.read().await;
+        let user = read.recovery_to_id
+            .get(recovery)
+            .and_then(|user_id| read.id_to_user.get(user_id))
+            .cloned();
+        Ok(user)
+    }
+
+    async fn insert_continuation(&self, continuation_id: &ARID, user_id: &ARID, _expiry: &dcbor::Date) -> DepoResult<()> {
         let mut write = self.inner.write().await;
-        write.public_key_to_id.remove(old_public_key);
-        write.public_key_to_id.insert(new_public_key.clone(), user.user_id().clone());
-        let user = write.id_to_user.get_mut(user.user_id()).unwrap();
-        user.set_public_key(new_public_key.clone());
+        write.continuation_to_id.insert(continuation_id.clone(), user_id.clone());
         Ok(())
     }
 
-    async fn set_user_recovery(&self, user: &User, recovery: Option<&str>) -> anyhow::Result<()> {
+    async fn remove_expired_records(&self, date: &dcbor::Date) -> DepoResult<usize> {
         let mut write = self.inner.write().await;
+        let expired: Vec<Record> = write.receipt_to_record.values()
+            .filter(|record| record.expiry().is_some_and(|expiry| expiry.timestamp() < date.timestamp()))
+            .cloned()
+            .collect();
+        for record in &expired {
+            write.receipt_to_record.remove(record.receipt());
+            if let Some(receipts) = write.id_to_receipts.get_mut(record.user_id()) {
+                receipts.remove(record.receipt());
+            }
+        }
+        Ok(expired.len())
+    }
 
-        // get the user's existing recovery
-        let old_recovery = user.recovery();
-        // if the new and old recoverys are the same, return (idempotency)
-        if old_recovery == recovery {
-            return Ok(());
+    async fn remove_idle_users(&self, date: &dcbor::Date) -> DepoResult<usize> {
+        let mut write = self.inner.write().await;
+        let idle: Vec<User> = write.id_to_user.values()
+            .filter(|user| write.id_to_receipts.get(user.user_id()).is_none_or(HashSet::is_empty))
+            .filter(|user| write.id_to_last_active.get(user.user_id()).is_none_or(|last_active| *last_active < date.timestamp()))
+            .cloned()
+            .collect();
+        for user in &idle {
+            write.remove_user(user);
         }
-        // Remove the old recovery, if any
-        if let Some(old_recovery) = old_recovery {
-            write.recovery_to_id.remove(old_recovery);
+        Ok(idle.len())
+    }
+
+    async fn user_count(&self) -> DepoResult<usize> {
+        Ok(self.inner.read().await.id_to_user.len())
+    }
+
+    async fn record_count(&self) -> DepoResult<usize> {
+        Ok(self.inner.read().await.receipt_to_record.len())
+    }
+}
+
+/// Holds the store locked and changes it in place, remembering the state of
+/// each user it changes so that the changes can be undone if it is dropped
+/// without being committed.
+struct MemDepoTransaction {
+    inner: OwnedRwLockWriteGuard<Inner>,
+    undo: HashMap<ARID, UserState>,
+}
+
+impl MemDepoTransaction {
+    /// Remembers the state of a user before the transaction first changes it.
+    fn will_change(&mut self, user_id: &ARID) {
+        if !self.undo.contains_key(user_id) {
+            let state = self.inner.user_state(user_id);
+            self.undo.insert(user_id.clone(), state);
         }
-        // Add the new recovery, if any
-        if let Some(recovery) = recovery {
-            write.recovery_to_id.insert(recovery.to_string(), user.user_id().clone());
+    }
+}
+
+impl Drop for MemDepoTransaction {
+    fn drop(&mut self) {
+        // Every changed user is removed before any is restored, as a change may
+        // have moved a public key or recovery method from one user to another.
+        for user_id in self.undo.keys() {
+            self.inner.remove_user_state(user_id);
         }
-        // Set the user record to the new recovery
-        let user = write.id_to_user.get_mut(user.user_id()).unwrap();
-        user.set_recovery(recovery);
+        for (_, state) in self.undo.drain() {
+            self.inner.insert_user_state(state);
+        }
+    }
+}
+
+#[async_trait]
+impl DepoTransaction for MemDepoTransaction {
+    async fn existing_key_to_user(&mut self, key: &PublicKeyBase) -> DepoResult<Option<User>> {
+        Ok(self.inner.key_to_user(key))
+    }
+
+    async fn insert_user(&mut self, user: &User) -> DepoResult<()> {
+        self.will_change(user.user_id());
+        self.inner.insert_user(user);
         Ok(())
     }
 
-    async fn remove_user(&self, user: &User) -> anyhow::Result<()> {
-        let mut write = self.inner.write().await;
+    async fn recovery_to_user(&mut self, recovery: &str) -> DepoResult<Option<User>> {
+        let user = self.inner.recovery_to_id
+            .get(recovery)
+            .and_then(|user_id| self.inner.id_to_user.get(user_id))
+            .cloned();
+        Ok(user)
+    }
 
-        write.public_key_to_id.remove(user.public_key());
-        write.recovery_to_id.remove(user.recovery().unwrap_or_default());
-        write.id_to_user.remove(user.user_id());
-        write.id_to_receipts.remove(user.user_id());
+    async fn set_user_recovery(&mut self, user: &User, recovery: Option<&str>) -> DepoResult<()> {
+        self.will_change(user.user_id());
+        self.inner.set_user_recovery(user, recovery);
         Ok(())
     }
 
-    async fn recovery_to_user(&self, recovery: &str) -> anyhow::Result<Option<User>> {
-        let read = self.inner.read().await;
-        let user_id = read.recovery_to_id
-            .get(recovery);
-        let user = if let Some(user_id) = user_id {
-            self.existing_id_to_user(user_id).await?
-        } else {
-            None
+    async fn set_user_key(&mut self, user: &User, new_key: &PublicKeyBase) -> DepoResult<()> {
+        self.will_change(user.user_id());
+        self.inner.set_user_key(user, new_key);
+        Ok(())
+    }
+
+    async fn insert_record(&mut self, record: &Record) -> DepoResult<()> {
+        self.will_change(record.user_id());
+        self.inner.insert_record(record);
+        Ok(())
+    }
+
+    async fn set_record_label(&mut self, receipt: &Receipt, label: Option<&Envelope>) -> DepoResult<()> {
+        let Some(user_id) = self.inner.receipt_to_record.get(receipt).map(|record| record.user_id().clone()) else {
+            return Ok(());
         };
-        Ok(user)
+        self.will_change(&user_id);
+        self.inner.set_record_label(receipt, label);
+        Ok(())
+    }
+
+    async fn id_to_usage(&mut self, user_id: &ARID) -> DepoResult<(usize, usize)> {
+        Ok(self.inner.usage(user_id))
+    }
+
+    async fn receipt_to_record(&mut self, receipt: &Receipt) -> DepoResult<Option<Record>> {
+        Ok(self.inner.receipt_to_record.get(receipt).cloned())
+    }
+
+    async fn remove_records(&mut self, user_id: &ARID) -> DepoResult<()> {
+        self.will_change(user_id);
+        self.inner.remove_records(user_id);
+        Ok(())
+    }
+
+    async fn remove_user(&mut self, user: &User) -> DepoResult<()> {
+        self.will_change(user.user_id());
+        self.inner.remove_user(user);
+        Ok(())
+    }
+
+    async fn take_continuation(&mut self, continuation_id: &ARID) -> DepoResult<bool> {
+        let Some(user_id) = self.inner.continuation_to_id.get(continuation_id).cloned() else {
+            return Ok(false);
+        };
+        self.will_change(&user_id);
+        self.inner.continuation_to_id.remove(continuation_id);
+        Ok(true)
+    }
+
+    async fn remove_continuations(&mut self, user_id: &ARID) -> DepoResult<()> {
+        self.will_change(user_id);
+        self.inner.continuation_to_id.retain(|_, id| id != user_id);
+        Ok(())
+    }
+
+    async fn commit(mut self: Box<Self>) -> DepoResult<()> {
+        self.undo.clear();
+        Ok(())
     }
 }
 
@@ -0,0 +1,105 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramTimer, HistogramVec, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+use crate::{depo_impl::PoolStatus, function::SUPPORTED_FUNCTIONS};
+
+/// The function label used for requests naming a function the depository does
+/// not have, so that clients cannot create arbitrary label values.
+const UNKNOWN_FUNCTION: &str = "unknown";
+
+/// Request counters and storage gauges for one depository, exposed in the
+/// Prometheus text format.
+///
+/// Labels are limited to function names and error codes, so nothing here
+/// identifies an account.
+pub(crate) struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    errors: IntCounterVec,
+    latency: HistogramVec,
+    users: IntGauge,
+    records: IntGauge,
+    pool_max_size: IntGauge,
+    pool_in_use: IntGauge,
+    pool_idle: IntGauge,
+}
+
+impl Metrics {
+    /// Creates the metrics, with gauges for whatever `pool_status` reports
+    /// of the backend's connection pool, if it has one.
+    pub fn new(pool_status: Option<&PoolStatus>) -> Self {
+        let registry = Registry::new_custom(Some("depo".to_string()), None).unwrap();
+        let requests = IntCounterVec::new(
+            Opts::new("requests_total", "Verified requests handled, by function."),
+            &["function"],
+        ).unwrap();
+        let errors = IntCounterVec::new(
+            Opts::new("errors_total", "Requests that failed, by error code."),
+            &["code"],
+        ).unwrap();
+        let latency = HistogramVec::new(
+            HistogramOpts::new("request_duration_seconds", "Time taken to handle verified requests, by function."),
+            &["function"],
+        ).unwrap();
+        let users = IntGauge::new("users", "Accounts in storage.").unwrap();
+        let records = IntGauge::new("records", "Shares in storage.").unwrap();
+        let pool_max_size = IntGauge::new("pool_max_size", "Most database connections the pool will open.").unwrap();
+        let pool_in_use = IntGauge::new("pool_connections_in_use", "Database connections taken from the pool.").unwrap();
+        let pool_idle = IntGauge::new("pool_connections_idle", "Open database connections waiting in the pool.").unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(errors.clone())).unwrap();
+        registry.register(Box::new(latency.clone())).unwrap();
+        registry.register(Box::new(users.clone())).unwrap();
+        registry.register(Box::new(records.clone())).unwrap();
+        if let Some(pool_status) = pool_status {
+            registry.register(Box::new(pool_max_size.clone())).unwrap();
+            registry.register(Box::new(pool_in_use.clone())).unwrap();
+            if pool_status.idle.is_some() {
+                registry.register(Box::new(pool_idle.clone())).unwrap();
+            }
+        }
+
+        Self { registry, requests, errors, latency, users, records, pool_max_size, pool_in_use, pool_idle }
+    }
+
+    /// Returns the name to label a function with: its own name if the
+    /// depository has it, or `unknown`.
+    pub fn function_label(name: Option<&str>) -> &'static str {
+        name.and_then(|name| SUPPORTED_FUNCTIONS.iter().find(|known| **known == name))
+            .copied()
+            .unwrap_or(UNKNOWN_FUNCTION)
+    }
+
+    /// Counts a request and starts timing it. The time is recorded when the
+    /// returned timer is dropped.
+    pub fn start_request(&self, function: &str) -> HistogramTimer {
+        self.requests.with_label_values(&[function]).inc();
+        self.latency.with_label_values(&[function]).start_timer()
+    }
+
+    pub fn record_error(&self, code: &str) {
+        self.errors.with_label_values(&[code]).inc();
+    }
+
+    pub fn set_storage_counts(&self, users: usize, records: usize) {
+        self.users.set(users as i64);
+        self.records.set(records as i64);
+    }
+
+    pub fn set_pool_status(&self, status: &PoolStatus) {
+        self.pool_max_size.set(status.max_size as i64);
+        self.pool_in_use.set(status.in_use as i64);
+        if let Some(idle) = status.idle {
+            self.pool_idle.set(idle as i64);
+        }
+    }
+
+    pub fn render(&self) -> anyhow::Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
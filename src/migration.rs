@@ -0,0 +1,105 @@
+use std::fmt::Display;
+
+use anyhow::bail;
+
+/// One step in the history of a database schema, which brings it from the
+/// previous version to `version`.
+///
+/// Databases created before schema versions were recorded are at version 0,
+/// and run every step even though they may already have some of their effects,
+/// so each statement must be harmless to run again.
+pub(crate) struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub statements: Vec<MigrationStatement>,
+}
+
+impl Migration {
+    pub fn new(version: u32, description: &'static str, statements: Vec<MigrationStatement>) -> Self {
+        Self { version, description, statements }
+    }
+}
+
+pub(crate) enum MigrationStatement {
+    /// A statement run as is.
+    Sql(String),
+    /// Adds a column unless the table already has it.
+    AddColumn { table: &'static str, column: &'static str, definition: String },
+}
+
+/// The migrations that bring a database's schema up to date, and the versions
+/// it goes between.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrationPlan {
+    from_version: u32,
+    to_version: u32,
+    steps: Vec<MigrationStep>,
+}
+
+impl MigrationPlan {
+    /// Plans the migrations after `current_version`, failing if the database
+    /// is newer than the latest migration this server knows about.
+    pub(crate) fn new(migrations: &[Migration], current_version: u32) -> anyhow::Result<Self> {
+        let latest_version = migrations.last().map(|migration| migration.version).unwrap_or(0);
+        if current_version > latest_version {
+            bail!(
+                "database schema version {} is newer than the latest version {} this server supports",
+                current_version, latest_version
+            );
+        }
+        let steps = migrations.iter()
+            .filter(|migration| migration.version > current_version)
+            .map(|migration| MigrationStep { version: migration.version, description: migration.description })
+            .collect();
+        Ok(Self { from_version: current_version, to_version: latest_version, steps })
+    }
+
+    /// Fails unless the schema is already up to date.
+    pub(crate) fn expect_current(&self) -> anyhow::Result<()> {
+        if !self.is_empty() {
+            bail!(
+                "database schema version {} is older than version {} required by this server; run `depo migrate`",
+                self.from_version, self.to_version
+            );
+        }
+        Ok(())
+    }
+
+    pub fn from_version(&self) -> u32 {
+        self.from_version
+    }
+
+    pub fn to_version(&self) -> u32 {
+        self.to_version
+    }
+
+    pub fn steps(&self) -> &[MigrationStep] {
+        &self.steps
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationStep {
+    version: u32,
+    description: &'static str,
+}
+
+impl MigrationStep {
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn description(&self) -> &str {
+        self.description
+    }
+}
+
+impl Display for MigrationStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.version, self.description)
+    }
+}
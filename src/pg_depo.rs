@@ -0,0 +1,985 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bc_components::{PrivateKeyBase, PublicKeyBase, ARID};
+use bc_envelope::prelude::*;
+use deadpool_postgres::{Manager, Object, Pool};
+use depo_api::receipt::Receipt;
+use log::{info, warn};
+use native_tls::TlsConnector;
+use postgres_native_tls::MakeTlsConnector;
+use tokio_postgres::{config::SslMode, Client, NoTls, Row};
+use url::Url;
+
+use crate::{
+    api::{KeyRotation, ShareInfo}, db_config::{DbConfig, TlsMode}, depo_error::DepoResult, depo_impl::{DepoImpl, DepoTransaction, PoolStatus}, function::Depo,
+    migration::{Migration, MigrationPlan, MigrationStatement::{AddColumn, Sql}}, record::Record,
+    server_key::{KeyStorage, RetiredKey}, user::User, CONTINUATION_EXPIRY_SECONDS, MAX_BYTES_PER_ACCOUNT, MAX_DATA_SIZE,
+    MAX_SHARES_PER_ACCOUNT, MAX_SHARE_TTL_SECONDS,
+};
+
+const USER: &str = "postgres";
+const HOST: &str = "localhost";
+const PORT: u16 = 5432;
+const DATABASE: &str = "postgres";
+
+const USERS_TABLE_NAME: &str = "users";
+const RECORDS_TABLE_NAME: &str = "records";
+const SETTINGS_TABLE_NAME: &str = "settings";
+const CONTINUATIONS_TABLE_NAME: &str = "continuations";
+const SCHEMA_VERSION_TABLE_NAME: &str = "schema_version";
+const KEY_ROTATIONS_TABLE_NAME: &str = "key_rotations";
+
+struct PgDepoImpl {
+    schema_name: String,
+    pool: Pool,
+    private_key: PrivateKeyBase,
+    public_key: PublicKeyBase,
+    public_key_string: String,
+    retired_keys: Vec<RetiredKey>,
+    key_rotations: Vec<Envelope>,
+    continuation_expiry_seconds: u32,
+    max_data_size: u32,
+    max_shares_per_account: u32,
+    max_bytes_per_account: u32,
+    max_share_ttl_seconds: u32,
+}
+
+impl PgDepoImpl {
+    async fn new(
+        config: &DbConfig,
+        schema_name: impl AsRef<str>,
+        key_storage: &KeyStorage,
+    ) -> anyhow::Result<Arc<Self>> {
+        let schema_name = schema_name.as_ref().to_string();
+        let pool = server_pool(config)?;
+        plan_migrations(&pool, &schema_name).await?.expect_current()?;
+        let private_key = load_private_key(&pool, &schema_name, key_storage).await?;
+        let (retired_keys, key_rotations) = load_key_rotations(&pool, &schema_name, key_storage).await?;
+        let (
+            continuation_expiry_seconds,
+            max_data_size,
+            max_shares_per_account,
+            max_bytes_per_account,
+            max_share_ttl_seconds,
+        ) = get_settings(&pool, &schema_name).await?;
+        let public_key = private_key.public_keys();
+        let public_key_string = public_key.ur_string();
+        Ok(Arc::new(Self {
+            schema_name,
+            pool,
+            private_key,
+            public_key,
+            public_key_string,
+            retired_keys,
+            key_rotations,
+            continuation_expiry_seconds,
+            max_data_size,
+            max_shares_per_account,
+            max_bytes_per_account,
+            max_share_ttl_seconds,
+        }))
+    }
+
+    fn schema_name(&self) -> &str {
+        &self.schema_name
+    }
+}
+
+async fn get_settings(
+    pool: &Pool,
+    schema_name: &str,
+) -> anyhow::Result<(u32, u32, u32, u32, u32)> {
+    let client = pool.get().await?;
+    let query = format!(
+        "SELECT continuation_expiry_seconds, max_data_size, max_shares_per_account, max_bytes_per_account, max_share_ttl_seconds FROM {}.{}",
+        schema_name, SETTINGS_TABLE_NAME
+    );
+
+    let result = client.query_opt(&query, &[]).await?;
+    match result {
+        Some(row) => {
+            let continuation_expiry_seconds: i64 = row
+                .try_get("continuation_expiry_seconds")
+                .map_err(|_| anyhow!("Continuation expiry seconds not found"))?;
+            let max_data_size: i64 = row
+                .try_get("max_data_size")
+                .map_err(|_| anyhow!("Max payload size not found"))?;
+            let max_shares_per_account: i64 = row
+                .try_get("max_shares_per_account")
+                .map_err(|_| anyhow!("Max shares per account not found"))?;
+            let max_bytes_per_account: i64 = row
+                .try_get("max_bytes_per_account")
+                .map_err(|_| anyhow!("Max bytes per account not found"))?;
+            let max_share_ttl_seconds: i64 = row
+                .try_get("max_share_ttl_seconds")
+                .map_err(|_| anyhow!("Max share TTL seconds not found"))?;
+
+            Ok((
+                continuation_expiry_seconds.try_into()?,
+                max_data_size.try_into()?,
+                max_shares_per_account.try_into()?,
+                max_bytes_per_account.try_into()?,
+                max_share_ttl_seconds.try_into()?,
+            ))
+        }
+        None => Err(anyhow!("Settings not found")),
+    }
+}
+
+/// Loads the server's private key from the given storage, first recording a
+/// newly assigned key or moving an existing one into that storage if needed.
+async fn load_private_key(
+    pool: &Pool,
+    schema_name: &str,
+    key_storage: &KeyStorage,
+) -> anyhow::Result<PrivateKeyBase> {
+    let client = pool.get().await?;
+    loop {
+        let query = format!("SELECT private_key FROM {}.{}", schema_name, SETTINGS_TABLE_NAME);
+        let stored: Option<String> = client
+            .query_opt(&query, &[]).await?
+            .ok_or_else(|| anyhow!("Settings not found"))?
+            .try_get("private_key")?;
+        let (private_key, replacement) = key_storage.load(stored.as_deref())?;
+        let Some(replacement) = replacement else {
+            return Ok(private_key);
+        };
+        // Only replace the value that was read, in case another server
+        // replaced it first.
+        let query = format!(
+            "UPDATE {}.{} SET private_key = $1 WHERE private_key IS NOT DISTINCT FROM $2",
+            schema_name, SETTINGS_TABLE_NAME
+        );
+        if client.execute(&query, &[&replacement, &stored]).await? > 0 {
+            return Ok(private_key);
+        }
+    }
+}
+
+/// Returns the keys replaced by rotation that are still accepted, after
+/// destroying those whose grace periods have ended, along with the statements
+/// of every rotation.
+async fn load_key_rotations(
+    pool: &Pool,
+    schema_name: &str,
+    key_storage: &KeyStorage,
+) -> anyhow::Result<(Vec<RetiredKey>, Vec<Envelope>)> {
+    let client = pool.get().await?;
+    let now = dcbor::Date::now().timestamp() as i64;
+    let query = format!(
+        "SELECT private_key, expiry, statement FROM {}.{} ORDER BY rotated_at",
+        schema_name, KEY_ROTATIONS_TABLE_NAME
+    );
+    let mut retired_keys = Vec::new();
+    let mut key_rotations = Vec::new();
+    for row in client.query(&query, &[]).await? {
+        let private_key: Option<String> = row.try_get("private_key")?;
+        let expiry: i64 = row.try_get("expiry")?;
+        let statement: String = row.try_get("statement")?;
+        if let Some(private_key) = private_key {
+            if expiry <= now {
+                key_storage.discard_retired_key(&private_key)?;
+            } else {
+                let (retired_key, resealed) = key_storage.retired_key(&private_key, dcbor::Date::from_timestamp(expiry as f64))?;
+                if let Some(resealed) = resealed {
+                    let query = format!("UPDATE {}.{} SET private_key = $1 WHERE private_key = $2", schema_name, KEY_ROTATIONS_TABLE_NAME);
+                    client.execute(&query, &[&resealed, &private_key]).await?;
+                }
+                retired_keys.push(retired_key);
+            }
+        }
+        key_rotations.push(Envelope::from_ur_string(statement)?);
+    }
+    let query = format!("UPDATE {}.{} SET private_key = NULL WHERE expiry <= $1", schema_name, KEY_ROTATIONS_TABLE_NAME);
+    client.execute(&query, &[&now]).await?;
+    Ok((retired_keys, key_rotations))
+}
+
+#[async_trait]
+impl DepoImpl for PgDepoImpl {
+    fn max_data_size(&self) -> u32 {
+        self.max_data_size
+    }
+
+    fn continuation_expiry_seconds(&self) -> u32 {
+        self.continuation_expiry_seconds
+    }
+
+    fn max_shares_per_account(&self) -> u32 {
+        self.max_shares_per_account
+    }
+
+    fn max_bytes_per_account(&self) -> u32 {
+        self.max_bytes_per_account
+    }
+
+    fn max_share_ttl_seconds(&self) -> u32 {
+        self.max_share_ttl_seconds
+    }
+
+    fn private_key(&self) -> &PrivateKeyBase {
+        &self.private_key
+    }
+
+    fn public_key(&self) -> &PublicKeyBase {
+        &self.public_key
+    }
+
+    fn public_key_string(&self) -> &str {
+        &self.public_key_string
+    }
+
+    fn retired_keys(&self) -> &[RetiredKey] {
+        &self.retired_keys
+    }
+
+    fn key_rotations(&self) -> &[Envelope] {
+        &self.key_rotations
+    }
+
+    fn pool_status(&self) -> Option<PoolStatus> {
+        let status = self.pool.status();
+        let idle = status.available.max(0) as usize;
+        Some(PoolStatus { max_size: status.max_size, in_use: status.size.saturating_sub(idle), idle: Some(idle) })
+    }
+
+    async fn ping(&self) -> DepoResult<()> {
+        let client = self.pool.get().await?;
+        client.simple_query("SELECT 1").await?;
+
+        Ok(())
+    }
+
+    async fn existing_key_to_id(&self, public_key: &PublicKeyBase) -> DepoResult<Option<ARID>> {
+        let client = self.pool.get().await?;
+        let query = format!(
+            "SELECT user_id, public_key, recovery FROM {}.{} WHERE public_key = $1",
+            self.schema_name(), USERS_TABLE_NAME
+        );
+
+        let result = client.query_opt(&query, &[&public_key.ur_string()]).await?;
+        let id = result.map(row_to_user).map(|user| user.user_id().clone());
+        Ok(id)
+    }
+
+    async fn begin(&self) -> DepoResult<Box<dyn DepoTransaction>> {
+        let client = self.pool.get().await?;
+        client.batch_execute("BEGIN").await?;
+        Ok(Box::new(PgDepoTransaction { schema_name: self.schema_name.clone(), client: Some(client) }))
+    }
+
+    async fn existing_id_to_user(&self, user_id: &ARID) -> DepoResult<Option<User>> {
+        let client = self.pool.get().await?;
+        let query = format!(
+            "SELECT user_id, public_key, recovery FROM {}.{} WHERE user_id = $1",
+            self.schema_name(), USERS_TABLE_NAME
+        );
+
+        let result = client.query_opt(&query, &[&user_id.ur_string()]).await?;
+        Ok(result.map(row_to_user))
+    }
+
+    async fn touch_user(&self, key: &PublicKeyBase, date: &dcbor::Date) -> DepoResult<()> {
+        let client = self.pool.get().await?;
+        let query = format!(
+            "UPDATE {}.{} SET last_active = $1 WHERE public_key = $2",
+            self.schema_name(), USERS_TABLE_NAME
+        );
+
+        client.execute(&query, &[&(date.timestamp() as i64), &key.ur_string()]).await?;
+
+        Ok(())
+    }
+
+    async fn id_to_receipts(&self, user_id: &ARID) -> DepoResult<HashSet<Receipt>> {
+        let client = self.pool.get().await?;
+        let query = format!(
+            "SELECT receipt FROM {}.{} WHERE user_id = $1",
+            self.schema_name(), RECORDS_TABLE_NAME
+        );
+
+        let mut receipts = HashSet::new();
+        let result = client.query(&query, &[&user_id.ur_string()]).await?;
+        for row in result {
+            let receipt_string: String = row.get("receipt");
+            let receipt_envelope = Envelope::from_ur_string(receipt_string)?;
+            let receipt = Receipt::from_envelope(receipt_envelope)?;
+            receipts.insert(receipt);
+        }
+
+        Ok(receipts)
+    }
+
+    async fn id_to_share_infos(&self, user_id: &ARID) -> DepoResult<Vec<ShareInfo>> {
+        let client = self.pool.get().await?;
+        let query = format!(
+            "SELECT receipt, size, created_at, expiry, label FROM {}.{} WHERE user_id = $1",
+            self.schema_name(), RECORDS_TABLE_NAME
+        );
+
+        let mut infos = Vec::new();
+        let result = client.query(&query, &[&user_id.ur_string()]).await?;
+        for row in result {
+            let receipt_string: String = row.get("receipt");
+            let receipt = Receipt::from_envelope(Envelope::from_ur_string(receipt_string)?)?;
+            let size: i64 = row.get("size");
+            let created: Option<i64> = row.get("created_at");
+            let created = created.map(|created| dcbor::Date::from_timestamp(created as f64));
+            let expiry: Option<i64> = row.get("expiry");
+            let expiry = expiry.map(|expiry| dcbor::Date::from_timestamp(expiry as f64));
+            let label: Option<Vec<u8>> = row.get("label");
+            let label = label.map(|label| Envelope::from_tagged_cbor_data(&label)).transpose()?;
+            infos.push(ShareInfo::new(receipt, size.try_into()?, created, expiry, label));
+        }
+
+        Ok(infos)
+    }
+
+    async fn receipt_to_record(&self, receipt: &Receipt) -> DepoResult<Option<Record>> {
+        let client = self.pool.get().await?;
+        receipt_to_record(&client, self.schema_name(), receipt).await
+    }
+
+    async fn delete_record(&self, receipt: &Receipt) -> DepoResult<()> {
+        let client = self.pool.get().await?;
+        let query = format!(
+            "DELETE FROM {}.{} WHERE receipt = $1",
+            self.schema_name(), RECORDS_TABLE_NAME
+        );
+
+        client.execute(&query, &[&receipt.envelope().ur_string()]).await?;
+
+        Ok(())
+    }
+
+    async fn recovery_to_user(&self, recovery: &str) -> DepoResult<Option<User>> {
+        let client = self.pool.get().await?;
+        let query = format!(
+            "SELECT user_id, public_key, recovery FROM {}.{} WHERE recovery = $1",
+            self.schema_name(), USERS_TABLE_NAME
+        );
+
+        let result = client.query_opt(&query, &[&recovery]).await?;
+        Ok(result.map(row_to_user))
+    }
+
+    async fn insert_continuation(&self, continuation_id: &ARID, user_id: &ARID, expiry: &dcbor::Date) -> DepoResult<()> {
+        let client = self.pool.get().await?;
+        let query = format!(
+            "INSERT INTO {}.{} (continuation_id, user_id, expiry) VALUES ($1, $2, $3)",
+            self.schema_name(), CONTINUATIONS_TABLE_NAME
+        );
+
+        client.execute(&query, &[
+            &continuation_id.ur_string(),
+            &user_id.ur_string(),
+            &(expiry.timestamp() as i64),
+        ]).await?;
+
+        Ok(())
+    }
+
+    async fn remove_expired_records(&self, date: &dcbor::Date) -> DepoResult<usize> {
+        let client = self.pool.get().await?;
+        let query = format!(
+            "DELETE FROM {}.{} WHERE expiry < $1",
+            self.schema_name(), RECORDS_TABLE_NAME
+        );
+
+        let removed = client.execute(&query, &[&(date.timestamp() as i64)]).await?;
+
+        Ok(removed.try_into()?)
+    }
+
+    async fn remove_idle_users(&self, date: &dcbor::Date) -> DepoResult<usize> {
+        let client = self.pool.get().await?;
+        let query = format!(
+            r"DELETE FROM {0}.{1} WHERE last_active < $1
+            AND NOT EXISTS (SELECT 1 FROM {0}.{2} WHERE {2}.user_id = {1}.user_id)",
+            self.schema_name(), USERS_TABLE_NAME, RECORDS_TABLE_NAME
+        );
+
+        let removed = client.execute(&query, &[&(date.timestamp() as i64)]).await?;
+
+        Ok(removed.try_into()?)
+    }
+
+    async fn user_count(&self) -> DepoResult<usize> {
+        let client = self.pool.get().await?;
+        let query = format!("SELECT COUNT(*) FROM {}.{}", self.schema_name(), USERS_TABLE_NAME);
+        let count: i64 = client.query_one(&query, &[]).await?.get(0);
+        Ok(count.try_into()?)
+    }
+
+    async fn record_count(&self) -> DepoResult<usize> {
+        let client = self.pool.get().await?;
+        let query = format!("SELECT COUNT(*) FROM {}.{}", self.schema_name(), RECORDS_TABLE_NAME);
+        let count: i64 = client.query_one(&query, &[]).await?.get(0);
+        Ok(count.try_into()?)
+    }
+}
+
+/// A PostgreSQL transaction on a connection taken from the pool. Reads lock
+/// the rows they find, and inserts that conflict with a concurrent one wait
+/// for it to finish.
+struct PgDepoTransaction {
+    schema_name: String,
+    client: Option<Object>,
+}
+
+impl PgDepoTransaction {
+    fn client(&self) -> &Object {
+        self.client.as_ref().unwrap()
+    }
+}
+
+#[async_trait]
+impl DepoTransaction for PgDepoTransaction {
+    async fn existing_key_to_user(&mut self, key: &PublicKeyBase) -> DepoResult<Option<User>> {
+        let query = format!(
+            "SELECT user_id, public_key, recovery FROM {}.{} WHERE public_key = $1 FOR UPDATE",
+            self.schema_name, USERS_TABLE_NAME
+        );
+
+        let result = self.client().query_opt(&query, &[&key.ur_string()]).await?;
+        Ok(result.map(row_to_user))
+    }
+
+    async fn insert_user(&mut self, user: &User) -> DepoResult<()> {
+        let query = format!(
+            "INSERT INTO {}.{} (user_id, public_key, recovery, last_active) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (public_key) DO NOTHING",
+            self.schema_name, USERS_TABLE_NAME
+        );
+
+        self.client().execute(&query, &[
+            &user.user_id().ur_string(),
+            &user.public_key().ur_string(),
+            &user.recovery(),
+            &(dcbor::Date::now().timestamp() as i64),
+        ]).await?;
+
+        Ok(())
+    }
+
+    async fn recovery_to_user(&mut self, recovery: &str) -> DepoResult<Option<User>> {
+        let query = format!(
+            "SELECT user_id, public_key, recovery FROM {}.{} WHERE recovery = $1 FOR UPDATE",
+            self.schema_name, USERS_TABLE_NAME
+        );
+
+        let result = self.client().query_opt(&query, &[&recovery]).await?;
+        Ok(result.map(row_to_user))
+    }
+
+    async fn set_user_recovery(&mut self, user: &User, recovery: Option<&str>) -> DepoResult<()> {
+        let query = format!(
+            "UPDATE {}.{} SET recovery = $1 WHERE user_id = $2",
+            self.schema_name, USERS_TABLE_NAME
+        );
+
+        self.client().execute(&query, &[&recovery, &user.user_id().ur_string()]).await?;
+
+        Ok(())
+    }
+
+    async fn set_user_key(&mut self, user: &User, new_public_key: &PublicKeyBase) -> DepoResult<()> {
+        let query = format!(
+            "UPDATE {}.{} SET public_key = $1 WHERE user_id = $2",
+            self.schema_name, USERS_TABLE_NAME
+        );
+
+        self.client().execute(&query, &[&new_public_key.ur_string(), &user.user_id().ur_string()]).await?;
+
+        Ok(())
+    }
+
+    async fn insert_record(&mut self, record: &Record) -> DepoResult<()> {
+        let query = format!(
+            r#"
+            INSERT INTO {}.{} (receipt, user_id, data, expiry, size, created_at, label)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (receipt) DO UPDATE SET expiry = EXCLUDED.expiry, label = COALESCE(EXCLUDED.label, records.label)
+        "#,
+            self.schema_name,
+            RECORDS_TABLE_NAME
+        );
+
+        self.client().execute(&query, &[
+            &record.receipt().envelope().ur_string(),
+            &record.user_id().ur_string(),
+            &record.data().as_ref(),
+            &record.expiry().map(|expiry| expiry.timestamp() as i64),
+            &(record.data().len() as i64),
+            &record.created().map(|created| created.timestamp() as i64),
+            &record.label().map(|label| label.tagged_cbor_data()),
+        ]).await?;
+
+        Ok(())
+    }
+
+    async fn set_record_label(&mut self, receipt: &Receipt, label: Option<&Envelope>) -> DepoResult<()> {
+        let query = format!(
+            "UPDATE {}.{} SET label = $1 WHERE receipt = $2",
+            self.schema_name, RECORDS_TABLE_NAME
+        );
+        self.client().execute(&query, &[
+            &label.map(|label| label.tagged_cbor_data()),
+            &receipt.envelope().ur_string(),
+        ]).await?;
+        Ok(())
+    }
+
+    async fn id_to_usage(&mut self, user_id: &ARID) -> DepoResult<(usize, usize)> {
+        let query = format!(
+            "SELECT COUNT(*), COALESCE(SUM(LENGTH(data) + COALESCE(LENGTH(label), 0)), 0)::BIGINT FROM {}.{} WHERE user_id = $1",
+            self.schema_name, RECORDS_TABLE_NAME
+        );
+
+        let row = self.client().query_one(&query, &[&user_id.ur_string()]).await?;
+        let count: i64 = row.get(0);
+        let bytes: i64 = row.get(1);
+        Ok((count.try_into()?, bytes.try_into()?))
+    }
+
+    async fn receipt_to_record(&mut self, receipt: &Receipt) -> DepoResult<Option<Record>> {
+        receipt_to_record(self.client(), &self.schema_name, receipt).await
+    }
+
+    async fn remove_records(&mut self, user_id: &ARID) -> DepoResult<()> {
+        let query = format!(
+            "DELETE FROM {}.{} WHERE user_id = $1",
+            self.schema_name, RECORDS_TABLE_NAME
+        );
+
+        self.client().execute(&query, &[&user_id.ur_string()]).await?;
+
+        Ok(())
+    }
+
+    async fn remove_user(&mut self, user: &User) -> DepoResult<()> {
+        let query = format!(
+            "DELETE FROM {}.{} WHERE user_id = $1",
+            self.schema_name, USERS_TABLE_NAME
+        );
+
+        self.client().execute(&query, &[&user.user_id().ur_string()]).await?;
+
+        Ok(())
+    }
+
+    async fn take_continuation(&mut self, continuation_id: &ARID) -> DepoResult<bool> {
+        let query = format!(
+            "DELETE FROM {}.{} WHERE continuation_id = $1",
+            self.schema_name, CONTINUATIONS_TABLE_NAME
+        );
+
+        let deleted = self.client().execute(&query, &[&continuation_id.ur_string()]).await?;
+
+        Ok(deleted > 0)
+    }
+
+    async fn remove_continuations(&mut self, user_id: &ARID) -> DepoResult<()> {
+        let query = format!(
+            "DELETE FROM {}.{} WHERE user_id = $1",
+            self.schema_name, CONTINUATIONS_TABLE_NAME
+        );
+
+        self.client().execute(&query, &[&user_id.ur_string()]).await?;
+
+        Ok(())
+    }
+
+    async fn commit(mut self: Box<Self>) -> DepoResult<()> {
+        let client = self.client.take().unwrap();
+        client.batch_execute("COMMIT").await?;
+        Ok(())
+    }
+}
+
+impl Drop for PgDepoTransaction {
+    /// Rolls back a transaction that was not committed before returning its
+    /// connection to the pool. The connection is closed instead if it cannot be
+    /// rolled back, so that the server rolls it back.
+    fn drop(&mut self) {
+        let Some(client) = self.client.take() else {
+            return;
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(async move {
+                    if let Err(e) = client.batch_execute("ROLLBACK").await {
+                        warn!("Could not roll back a transaction, closing its connection: {}", e);
+                        drop(Object::take(client));
+                    }
+                });
+            }
+            Err(_) => drop(Object::take(client)),
+        }
+    }
+}
+
+async fn receipt_to_record(client: &Client, schema_name: &str, receipt: &Receipt) -> DepoResult<Option<Record>> {
+    let query = format!(
+        "SELECT user_id, data, expiry, created_at, label FROM {}.{} WHERE receipt = $1",
+        schema_name, RECORDS_TABLE_NAME
+    );
+
+    let result = client.query_opt(&query, &[&receipt.envelope().ur_string()]).await?;
+    if let Some(row) = result {
+        let user_id_string: String = row.get("user_id");
+        let user_id = ARID::from_ur_string(user_id_string)?;
+        let data: Vec<u8> = row.get("data");
+        let expiry: Option<i64> = row.get("expiry");
+        let expiry = expiry.map(|expiry| dcbor::Date::from_timestamp(expiry as f64));
+        let created: Option<i64> = row.get("created_at");
+        let created = created.map(|created| dcbor::Date::from_timestamp(created as f64));
+        let label: Option<Vec<u8>> = row.get("label");
+        let label = label.map(|label| Envelope::from_tagged_cbor_data(&label)).transpose()?;
+        let record = Record::new_opt(receipt.clone(), user_id, data.into(), expiry, created, label);
+
+        Ok(Some(record))
+    } else {
+        Ok(None)
+    }
+}
+
+fn row_to_user(row: Row) -> User {
+    let user_id_string: String = row.get("user_id");
+    let user_id = ARID::from_ur_string(user_id_string).unwrap();
+    let public_key_string: String = row.get("public_key");
+    let public_key = PublicKeyBase::from_ur_string(public_key_string).unwrap();
+    let recovery: Option<String> = row.get("recovery");
+
+    User::new_opt(user_id, public_key, recovery)
+}
+
+impl Depo {
+    pub async fn new_pg(config: &DbConfig, schema_name: impl AsRef<str>) -> anyhow::Result<Self> {
+        Self::new_pg_with_key_storage(config, schema_name, &KeyStorage::default()).await
+    }
+
+    pub async fn new_pg_with_key_storage(
+        config: &DbConfig,
+        schema_name: impl AsRef<str>,
+        key_storage: &KeyStorage,
+    ) -> anyhow::Result<Self> {
+        Ok(Self::new(PgDepoImpl::new(config, schema_name, key_storage).await?))
+    }
+}
+
+pub fn server_url(config: &DbConfig) -> anyhow::Result<Url> {
+    let mut server_url = config.server_url(&["postgresql", "postgres"], HOST, PORT, USER)?;
+    if server_url.path().trim_start_matches('/').is_empty() {
+        server_url.set_path(DATABASE);
+    }
+    Ok(server_url)
+}
+
+/// PostgreSQL schemas live inside a single database, so unlike MySQL the same
+/// pool is used both to manage schemas and to access their tables.
+pub fn server_pool(config: &DbConfig) -> anyhow::Result<Pool> {
+    let mut pg_config: tokio_postgres::Config = server_url(config)?.as_str().parse()?;
+    let manager = match tls_connector(config.tls)? {
+        Some(connector) => {
+            pg_config.ssl_mode(SslMode::Require);
+            Manager::new(pg_config, MakeTlsConnector::new(connector))
+        }
+        None => Manager::new(pg_config, NoTls),
+    };
+    let mut builder = Pool::builder(manager);
+    if let Some(pool_size) = config.pool_size {
+        builder = builder.max_size(pool_size);
+    }
+    Ok(builder.build()?)
+}
+
+/// Returns the connector for a TLS mode, which checks the server's certificate
+/// as the MySQL backend does for the same mode, or `None` if TLS is disabled.
+fn tls_connector(mode: TlsMode) -> anyhow::Result<Option<TlsConnector>> {
+    let mut builder = TlsConnector::builder();
+    match mode {
+        TlsMode::Disabled => return Ok(None),
+        TlsMode::Required => {
+            builder.danger_accept_invalid_certs(true);
+        }
+        TlsMode::VerifyCa => {
+            builder.danger_accept_invalid_hostnames(true);
+        }
+        TlsMode::VerifyIdentity => {}
+    }
+    Ok(Some(builder.build()?))
+}
+
+pub async fn drop_db(server_pool: &Pool, schema_name: &str) -> anyhow::Result<()> {
+    let query = format!("DROP SCHEMA IF EXISTS {} CASCADE", schema_name);
+    server_pool.get().await?.batch_execute(&query).await?;
+
+    Ok(())
+}
+
+fn migrations(schema_name: &str) -> Vec<Migration> {
+    vec![
+        Migration::new(1, "Create the users, records, continuations, and settings tables", vec![
+            Sql(format!(
+                r"CREATE TABLE IF NOT EXISTS {}.{} (
+                    user_id VARCHAR(100) NOT NULL,
+                    public_key VARCHAR(200) UNIQUE NOT NULL,
+                    recovery VARCHAR(1000) UNIQUE,
+                    PRIMARY KEY (user_id)
+                )",
+                schema_name, USERS_TABLE_NAME
+            )),
+            Sql(format!(
+                r"CREATE TABLE IF NOT EXISTS {}.{} (
+                    receipt VARCHAR(150) NOT NULL,
+                    user_id VARCHAR(100) NOT NULL,
+                    data BYTEA NOT NULL,
+                    PRIMARY KEY (receipt),
+                    FOREIGN KEY (user_id) REFERENCES {}.{}(user_id) ON DELETE CASCADE
+                )",
+                schema_name, RECORDS_TABLE_NAME, schema_name, USERS_TABLE_NAME
+            )),
+            Sql(format!(
+                "CREATE INDEX IF NOT EXISTS {}_user_id ON {}.{} (user_id)",
+                RECORDS_TABLE_NAME, schema_name, RECORDS_TABLE_NAME
+            )),
+            Sql(format!(
+                r"CREATE TABLE IF NOT EXISTS {}.{} (
+                    continuation_id VARCHAR(100) NOT NULL,
+                    user_id VARCHAR(100) NOT NULL,
+                    expiry BIGINT NOT NULL,
+                    PRIMARY KEY (continuation_id),
+                    FOREIGN KEY (user_id) REFERENCES {}.{}(user_id) ON DELETE CASCADE
+                )",
+                schema_name, CONTINUATIONS_TABLE_NAME, schema_name, USERS_TABLE_NAME
+            )),
+            Sql(format!(
+                "CREATE INDEX IF NOT EXISTS {}_user_id ON {}.{} (user_id)",
+                CONTINUATIONS_TABLE_NAME, schema_name, CONTINUATIONS_TABLE_NAME
+            )),
+            Sql(format!(
+                r"CREATE TABLE IF NOT EXISTS {}.{} (
+                    private_key VARCHAR(120),
+                    continuation_expiry_seconds BIGINT,
+                    max_data_size BIGINT
+                )",
+                schema_name, SETTINGS_TABLE_NAME
+            )),
+        ]),
+        Migration::new(2, "Add per-account quotas and the maximum share TTL to settings", vec![
+            AddColumn {
+                table: SETTINGS_TABLE_NAME,
+                column: "max_shares_per_account",
+                definition: format!("BIGINT NOT NULL DEFAULT {}", MAX_SHARES_PER_ACCOUNT),
+            },
+            AddColumn {
+                table: SETTINGS_TABLE_NAME,
+                column: "max_bytes_per_account",
+                definition: format!("BIGINT NOT NULL DEFAULT {}", MAX_BYTES_PER_ACCOUNT),
+            },
+            AddColumn {
+                table: SETTINGS_TABLE_NAME,
+                column: "max_share_ttl_seconds",
+                definition: format!("BIGINT NOT NULL DEFAULT {}", MAX_SHARE_TTL_SECONDS),
+            },
+        ]),
+        Migration::new(3, "Add share expiry", vec![
+            AddColumn { table: RECORDS_TABLE_NAME, column: "expiry", definition: "BIGINT".to_string() },
+        ]),
+        Migration::new(4, "Track when accounts were last active", vec![
+            AddColumn { table: USERS_TABLE_NAME, column: "last_active", definition: "BIGINT".to_string() },
+            // Accounts that predate activity tracking count as active now.
+            Sql(format!(
+                "UPDATE {}.{} SET last_active = EXTRACT(EPOCH FROM now())::BIGINT WHERE last_active IS NULL",
+                schema_name, USERS_TABLE_NAME
+            )),
+        ]),
+        Migration::new(5, "Widen the private key setting to hold an encrypted key", vec![
+            Sql(format!(
+                "ALTER TABLE {}.{} ALTER COLUMN private_key TYPE TEXT",
+                schema_name, SETTINGS_TABLE_NAME
+            )),
+        ]),
+        Migration::new(6, "Add server key rotations", vec![
+            Sql(format!(
+                r"CREATE TABLE IF NOT EXISTS {}.{} (
+                    private_key TEXT,
+                    expiry BIGINT NOT NULL,
+                    rotated_at BIGINT NOT NULL,
+                    statement TEXT NOT NULL
+                )",
+                schema_name, KEY_ROTATIONS_TABLE_NAME
+            )),
+        ]),
+        // Records that predate this migration have no known creation time.
+        Migration::new(7, "Record the size and creation time of shares", vec![
+            AddColumn { table: RECORDS_TABLE_NAME, column: "size", definition: "BIGINT NOT NULL DEFAULT 0".to_string() },
+            AddColumn { table: RECORDS_TABLE_NAME, column: "created_at", definition: "BIGINT".to_string() },
+            Sql(format!("UPDATE {}.{} SET size = LENGTH(data)", schema_name, RECORDS_TABLE_NAME)),
+        ]),
+        Migration::new(8, "Add share labels", vec![
+            AddColumn { table: RECORDS_TABLE_NAME, column: "label", definition: "BYTEA".to_string() },
+        ]),
+    ]
+}
+
+async fn schema_version(server_pool: &Pool, schema_name: &str) -> anyhow::Result<u32> {
+    let client = server_pool.get().await?;
+    let query = "SELECT 1 FROM information_schema.tables WHERE table_schema = $1 AND table_name = $2";
+    if client.query_opt(query, &[&schema_name, &SCHEMA_VERSION_TABLE_NAME]).await?.is_none() {
+        return Ok(0);
+    }
+    let query = format!("SELECT MAX(version) FROM {}.{}", schema_name, SCHEMA_VERSION_TABLE_NAME);
+    let version: Option<i64> = client.query_one(&query, &[]).await?.get(0);
+    Ok(version.unwrap_or(0).try_into()?)
+}
+
+/// Returns the migrations the database needs, without applying them.
+pub async fn plan_migrations(server_pool: &Pool, schema_name: &str) -> anyhow::Result<MigrationPlan> {
+    MigrationPlan::new(&migrations(schema_name), schema_version(server_pool, schema_name).await?)
+}
+
+/// Applies the migrations the database needs, each in its own transaction,
+/// and returns them.
+pub async fn migrate_db(server_pool: &Pool, schema_name: &str) -> anyhow::Result<MigrationPlan> {
+    let plan = plan_migrations(server_pool, schema_name).await?;
+    if plan.is_empty() {
+        return Ok(plan);
+    }
+
+    let mut client = server_pool.get().await?;
+    let query = format!(
+        r"CREATE SCHEMA IF NOT EXISTS {};
+        CREATE TABLE IF NOT EXISTS {}.{} (version BIGINT NOT NULL)",
+        schema_name, schema_name, SCHEMA_VERSION_TABLE_NAME
+    );
+    client.batch_execute(&query).await?;
+
+    for migration in migrations(schema_name).into_iter().filter(|migration| migration.version > plan.from_version()) {
+        info!("Migrating {} to schema version {}: {}", schema_name, migration.version, migration.description);
+        let transaction = client.transaction().await?;
+        for statement in &migration.statements {
+            let query = match statement {
+                Sql(query) => query.clone(),
+                AddColumn { table, column, definition } => format!(
+                    "ALTER TABLE {}.{} ADD COLUMN IF NOT EXISTS {} {}",
+                    schema_name, table, column, definition
+                ),
+            };
+            transaction.batch_execute(&query).await?;
+        }
+        transaction.batch_execute(&format!("DELETE FROM {}.{}", schema_name, SCHEMA_VERSION_TABLE_NAME)).await?;
+        let query = format!("INSERT INTO {}.{} (version) VALUES ($1)", schema_name, SCHEMA_VERSION_TABLE_NAME);
+        transaction.execute(&query, &[&(migration.version as i64)]).await?;
+        transaction.commit().await?;
+    }
+
+    Ok(plan)
+}
+
+pub async fn create_db(server_pool: &Pool, schema_name: &str) -> anyhow::Result<MigrationPlan> {
+    let plan = migrate_db(server_pool, schema_name).await?;
+
+    // Check if settings already exist
+    let check_query = format!(
+        "SELECT COUNT(*) FROM {}.{}",
+        schema_name, SETTINGS_TABLE_NAME
+    );
+    let count: i64 = server_pool
+        .get().await?
+        .query_one(&check_query, &[]).await?
+        .get(0);
+
+    // Only insert if settings do not exist. The private key is assigned when
+    // the depository is first opened, in the storage it is configured with.
+    if count == 0 {
+        let query = format!(
+            r"INSERT INTO {}.{}
+            (continuation_expiry_seconds, max_data_size) VALUES ($1, $2)",
+            schema_name,
+            SETTINGS_TABLE_NAME
+        );
+        server_pool.get().await?.execute(&query, &[
+            &(CONTINUATION_EXPIRY_SECONDS as i64),
+            &(MAX_DATA_SIZE as i64),
+        ]).await?;
+    }
+
+    Ok(plan)
+}
+
+
+/// Replaces the server key with a new one, which the depository uses the next
+/// time it is opened. The previous key is still accepted for the grace period.
+pub async fn rotate_key(
+    config: &DbConfig,
+    schema_name: &str,
+    key_storage: &KeyStorage,
+    grace_period: Duration,
+) -> anyhow::Result<KeyRotation> {
+    let server_pool = server_pool(config)?;
+    plan_migrations(&server_pool, schema_name).await?.expect_current()?;
+    let mut client = server_pool.get().await?;
+    let transaction = client.transaction().await?;
+    let query = format!("SELECT private_key FROM {}.{} FOR UPDATE", schema_name, SETTINGS_TABLE_NAME);
+    let stored: Option<String> = transaction
+        .query_opt(&query, &[]).await?
+        .ok_or_else(|| anyhow!("Settings not found"))?
+        .try_get("private_key")?;
+    let rotation = key_storage.rotate(stored.as_deref(), grace_period)?;
+
+    let query = format!(
+        "INSERT INTO {}.{} (private_key, expiry, rotated_at, statement) VALUES ($1, $2, $3, $4)",
+        schema_name, KEY_ROTATIONS_TABLE_NAME
+    );
+    transaction.execute(&query, &[
+        &rotation.previous_key,
+        &(rotation.rotation.grace_period_end().timestamp() as i64),
+        &(rotation.rotation.date().timestamp() as i64),
+        &rotation.statement.ur_string(),
+    ]).await?;
+    let query = format!("UPDATE {}.{} SET private_key = $1", schema_name, SETTINGS_TABLE_NAME);
+    transaction.execute(&query, &[&rotation.new_key]).await?;
+    transaction.commit().await?;
+    rotation.install()?;
+
+    Ok(rotation.rotation)
+}
+
+pub async fn reset_db(config: &DbConfig, schema_name: &str) -> anyhow::Result<()> {
+    let server_pool = server_pool(config)?;
+    drop_db(&server_pool, schema_name).await?;
+    create_db(&server_pool, schema_name).await?;
+
+    Ok(())
+}
+
+pub async fn create_db_if_needed(config: &DbConfig, schema_name: &str) -> anyhow::Result<()> {
+    let server_pool = server_pool(config)?;
+    create_db(&server_pool, schema_name).await?;
+
+    Ok(())
+}
+
+/// Brings the database's schema up to date, or with `dry_run` only reports
+/// what that would involve.
+pub async fn migrate(config: &DbConfig, schema_name: &str, dry_run: bool) -> anyhow::Result<MigrationPlan> {
+    let server_pool = server_pool(config)?;
+    if dry_run {
+        plan_migrations(&server_pool, schema_name).await
+    } else {
+        create_db(&server_pool, schema_name).await
+    }
+}
+
+pub async fn can_connect_to_db(config: &DbConfig, schema_name: &str) -> anyhow::Result<bool> {
+    let pool = server_pool(config)?;
+    let client = pool.get().await?;
+    let query = "SELECT schema_name FROM information_schema.schemata WHERE schema_name = $1";
+    let result = client.query_opt(query, &[&schema_name]).await?;
+
+    Ok(result.is_some())
+}
@@ -0,0 +1,151 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    hash::Hash,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+
+/// How many requests from each remote address the server accepts by default.
+pub const DEFAULT_IP_RATE_LIMIT: RateLimit = RateLimit::per_minute(300);
+/// How many requests from each account key the depository accepts by default.
+pub const DEFAULT_KEY_RATE_LIMIT: RateLimit = RateLimit::per_minute(60);
+/// How many recovery attempts for each recovery method the depository accepts
+/// by default.
+pub const DEFAULT_RECOVERY_RATE_LIMIT: RateLimit = RateLimit::per_hour(5);
+
+/// A number of requests allowed in each period, which may all arrive at once.
+///
+/// Written as `<requests>/<period>`, where the period is `second`, `minute`, or
+/// `hour`, such as `60/minute`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    requests: u32,
+    period: Duration,
+}
+
+impl RateLimit {
+    pub const fn new(requests: u32, period: Duration) -> Self {
+        Self { requests, period }
+    }
+
+    pub const fn per_second(requests: u32) -> Self {
+        Self::new(requests, Duration::from_secs(1))
+    }
+
+    pub const fn per_minute(requests: u32) -> Self {
+        Self::new(requests, Duration::from_secs(60))
+    }
+
+    pub const fn per_hour(requests: u32) -> Self {
+        Self::new(requests, Duration::from_secs(60 * 60))
+    }
+
+    pub fn requests(&self) -> u32 {
+        self.requests
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    fn tokens_per_second(&self) -> f64 {
+        self.requests as f64 / self.period.as_secs_f64()
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (requests, period) = s.split_once('/')
+            .ok_or_else(|| anyhow!("expected <requests>/<period>, such as 60/minute"))?;
+        let requests: u32 = requests.trim().parse()?;
+        if requests == 0 {
+            bail!("the number of requests must be positive");
+        }
+        match period.trim() {
+            "second" => Ok(Self::per_second(requests)),
+            "minute" => Ok(Self::per_minute(requests)),
+            "hour" => Ok(Self::per_hour(requests)),
+            period => bail!("unknown period {:?}, expected second, minute, or hour", period),
+        }
+    }
+}
+
+impl Display for RateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.period.as_secs() {
+            1 => write!(f, "{}/second", self.requests),
+            60 => write!(f, "{}/minute", self.requests),
+            3600 => write!(f, "{}/hour", self.requests),
+            seconds => write!(f, "{}/{}s", self.requests, seconds),
+        }
+    }
+}
+
+/// A token bucket for each key, refilled continuously at the rate limit.
+///
+/// Buckets that have refilled completely are indistinguishable from new ones,
+/// so they are dropped from time to time to bound memory use.
+pub(crate) struct RateLimiter<K> {
+    limit: RateLimit,
+    state: Mutex<RateLimiterState<K>>,
+}
+
+struct RateLimiterState<K> {
+    buckets: HashMap<K, Bucket>,
+    next_sweep: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.tokens_per_second()).min(limit.requests as f64);
+        self.updated = now;
+    }
+}
+
+impl<K: Hash + Eq + Clone> RateLimiter<K> {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            state: Mutex::new(RateLimiterState {
+                buckets: HashMap::new(),
+                next_sweep: Instant::now() + limit.period,
+            }),
+        }
+    }
+
+    /// Takes a token from the key's bucket, returning `false` if it is empty.
+    pub fn check(&self, key: &K) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if now >= state.next_sweep {
+            let limit = self.limit;
+            state.buckets.retain(|_, bucket| {
+                bucket.refill(&limit, now);
+                bucket.tokens < limit.requests as f64
+            });
+            state.next_sweep = now + limit.period;
+        }
+        let bucket = state.buckets.entry(key.clone()).or_insert_with(|| Bucket {
+            tokens: self.limit.requests as f64,
+            updated: now,
+        });
+        bucket.refill(&self.limit, now);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
@@ -1,6 +1,7 @@
 use std::fmt::Formatter;
 
 use bc_components::ARID;
+use bc_envelope::prelude::*;
 use bytes::Bytes;
 
 use depo_api::receipt::Receipt;
@@ -13,18 +14,29 @@ pub struct Record {
     receipt: Receipt,
     user_id: ARID,
     data: Bytes,
+    // Records without an expiry are kept until they are deleted.
+    expiry: Option<dcbor::Date>,
+    // Records stored before creation times were recorded have none.
+    created: Option<dcbor::Date>,
+    // An opaque envelope the client stores with the data, usually encrypted.
+    label: Option<Envelope>,
 }
 
 impl Record {
-    pub fn new(user_id: &ARID, data: &Bytes) -> Self {
-        Self::new_opt(Receipt::new(user_id, data), user_id.clone(), data.clone())
+    pub fn new(user_id: &ARID, data: &Bytes, expiry: Option<dcbor::Date>, label: Option<Envelope>) -> Self {
+        // Creation times are stored in whole seconds.
+        let created = dcbor::Date::from_timestamp(dcbor::Date::now().timestamp().floor());
+        Self::new_opt(Receipt::new(user_id, data), user_id.clone(), data.clone(), expiry, Some(created), label)
     }
 
-    pub fn new_opt(receipt: Receipt, user_id: ARID, data: Bytes) -> Self {
+    pub fn new_opt(receipt: Receipt, user_id: ARID, data: Bytes, expiry: Option<dcbor::Date>, created: Option<dcbor::Date>, label: Option<Envelope>) -> Self {
         Self {
             receipt,
             user_id,
             data,
+            expiry,
+            created,
+            label,
         }
     }
 
@@ -39,6 +51,29 @@ impl Record {
     pub fn data(&self) -> &Bytes {
         &self.data
     }
+
+    pub fn expiry(&self) -> Option<&dcbor::Date> {
+        self.expiry.as_ref()
+    }
+
+    pub fn created(&self) -> Option<&dcbor::Date> {
+        self.created.as_ref()
+    }
+
+    pub fn label(&self) -> Option<&Envelope> {
+        self.label.as_ref()
+    }
+
+    /// The size of the label as stored, in bytes, which counts against the
+    /// account's quota along with the data.
+    pub fn label_size(&self) -> usize {
+        self.label.as_ref().map_or(0, label_size)
+    }
+}
+
+/// The size of a label as stored, in bytes.
+pub fn label_size(label: &Envelope) -> usize {
+    label.tagged_cbor_data().len()
 }
 
 struct HexBytes(Bytes);
@@ -61,6 +96,9 @@ impl std::fmt::Debug for Record {
             .field("user_id", &self.user_id)
             .field("data", &HexBytes::new(self.data.clone()))
             .field("receipt", &self.receipt)
+            .field("expiry", &self.expiry)
+            .field("created", &self.created)
+            .field("label", &self.label)
             .finish()
     }
 }
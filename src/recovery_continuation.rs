@@ -1,25 +1,39 @@
-use bc_components::PublicKeyBase;
+use bc_components::{PublicKeyBase, ARID};
 use bc_envelope::prelude::*;
 
 #[derive(Clone, Debug)]
 pub struct RecoveryContinuation {
+    pub id: ARID,
     pub old_key: PublicKeyBase,
     pub new_key: PublicKeyBase,
     pub expiry: dcbor::Date,
+    pub verification_code: String,
 }
 
 impl RecoveryContinuation {
+    const ID_PARAM: &'static Parameter = &Parameter::new_static_named("id");
     const NEW_KEY_PARAM: &'static Parameter = &Parameter::new_static_named("newKey");
     const EXPIRY_PARAM: &'static Parameter = &Parameter::new_static_named("expiry");
+    const VERIFICATION_CODE_PARAM: &'static Parameter = &Parameter::new_static_named("verificationCode");
 
-    pub fn new(old_key: PublicKeyBase, new_key: PublicKeyBase, expiry: dcbor::Date) -> Self {
+    pub fn new(old_key: PublicKeyBase, new_key: PublicKeyBase, expiry: dcbor::Date, verification_code: impl Into<String>) -> Self {
+        Self::new_opt(ARID::new(), old_key, new_key, expiry, verification_code)
+    }
+
+    pub fn new_opt(id: ARID, old_key: PublicKeyBase, new_key: PublicKeyBase, expiry: dcbor::Date, verification_code: impl Into<String>) -> Self {
         Self {
+            id,
             old_key,
             new_key,
             expiry,
+            verification_code: verification_code.into(),
         }
     }
 
+    pub fn id(&self) -> &ARID {
+        &self.id
+    }
+
     pub fn old_key(&self) -> &PublicKeyBase {
         &self.old_key
     }
@@ -31,13 +45,19 @@ impl RecoveryContinuation {
     pub fn expiry(&self) -> &dcbor::Date {
         &self.expiry
     }
+
+    pub fn verification_code(&self) -> &str {
+        &self.verification_code
+    }
 }
 
 impl EnvelopeEncodable for RecoveryContinuation {
     fn envelope(self) -> Envelope {
         Envelope::new(self.old_key)
+            .add_parameter(Self::ID_PARAM, self.id)
             .add_parameter(Self::NEW_KEY_PARAM, self.new_key)
             .add_parameter(Self::EXPIRY_PARAM, self.expiry)
+            .add_parameter(Self::VERIFICATION_CODE_PARAM, self.verification_code)
     }
 }
 
@@ -50,9 +70,11 @@ impl From<RecoveryContinuation> for Envelope {
 impl EnvelopeDecodable for RecoveryContinuation {
     fn from_envelope(envelope: Envelope) -> anyhow::Result<Self> {
         let old_key: PublicKeyBase = envelope.extract_subject()?;
+        let id: ARID = envelope.extract_object_for_parameter(Self::ID_PARAM)?;
         let new_key: PublicKeyBase = envelope.extract_object_for_parameter(Self::NEW_KEY_PARAM)?;
         let expiry: dcbor::Date = envelope.extract_object_for_parameter(Self::EXPIRY_PARAM)?;
-        Ok(Self::new(old_key, new_key, expiry))
+        let verification_code: String = envelope.extract_object_for_parameter(Self::VERIFICATION_CODE_PARAM)?;
+        Ok(Self::new_opt(id, old_key, new_key, expiry, verification_code))
     }
 }
 
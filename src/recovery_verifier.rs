@@ -0,0 +1,69 @@
+use std::{io::Write, path::PathBuf};
+
+use async_trait::async_trait;
+use bc_rand::{RandomNumberGenerator, SecureRandomNumberGenerator};
+use log::warn;
+
+/// Delivers the one-time codes that prove control of a recovery method.
+///
+/// When an account recovery is started, the depository generates a code and
+/// hands it to the verifier, which sends it to the recovery contact (for
+/// example by email or SMS). The code is sealed inside the recovery
+/// continuation, and the recovery can only be finished by presenting both.
+#[async_trait]
+pub trait RecoveryVerifier: Send + Sync {
+    /// A short name for how codes are delivered, such as `"email"`.
+    fn method(&self) -> &str;
+
+    /// Sends `code` to the contact identified by `recovery`.
+    async fn deliver_code(&self, recovery: &str, code: &str) -> anyhow::Result<()>;
+}
+
+/// A verifier that writes codes to the log, and optionally appends them to a
+/// file as `recovery<TAB>code` lines, instead of contacting anyone.
+///
+/// This lets an operator relay codes by hand, and lets tests read them back.
+#[derive(Debug, Clone, Default)]
+pub struct LocalRecoveryVerifier {
+    path: Option<PathBuf>,
+}
+
+impl LocalRecoveryVerifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_file(path: impl Into<PathBuf>) -> Self {
+        Self { path: Some(path.into()) }
+    }
+}
+
+#[async_trait]
+impl RecoveryVerifier for LocalRecoveryVerifier {
+    fn method(&self) -> &str {
+        "local"
+    }
+
+    async fn deliver_code(&self, recovery: &str, code: &str) -> anyhow::Result<()> {
+        warn!(target: "depo::recovery", "Verification code for {}: {}", recovery, code);
+        if let Some(path) = &self.path {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            writeln!(file, "{}\t{}", recovery, code)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns a random eight-digit code.
+pub(crate) fn new_verification_code() -> String {
+    let mut rng = SecureRandomNumberGenerator;
+    format!("{:08}", rng.next_with_upper_bound(100_000_000u64))
+}
+
+/// Compares codes without revealing how much of them matched.
+pub(crate) fn codes_match(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,64 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use bc_components::{PublicKeyBase, ARID};
+
+use crate::depo_error::{DepoError, DepoResult};
+
+/// Remembers the IDs of recently accepted requests so that a captured request
+/// cannot be submitted again.
+///
+/// A request is only accepted if its date is within the window of the
+/// server's clock, so an ID only needs to be remembered until its request
+/// would be rejected as stale anyway.
+pub struct ReplayGuard {
+    window_seconds: f64,
+    state: Mutex<ReplayState>,
+}
+
+#[derive(Default)]
+struct ReplayState {
+    /// For each key, the IDs of its accepted requests and when they can be
+    /// forgotten.
+    seen: HashMap<PublicKeyBase, HashMap<ARID, f64>>,
+    next_sweep: f64,
+}
+
+impl ReplayGuard {
+    pub fn new(window_seconds: u32) -> Self {
+        Self {
+            window_seconds: window_seconds as f64,
+            state: Mutex::new(ReplayState::default()),
+        }
+    }
+
+    /// Records a request, failing if it is too old, too far in the future, or
+    /// has already been seen for the same key.
+    pub fn check(&self, key: &PublicKeyBase, id: &ARID, date: &dcbor::Date) -> DepoResult<()> {
+        let now = dcbor::Date::now().timestamp();
+        let date = date.timestamp();
+        if (now - date).abs() > self.window_seconds {
+            return Err(DepoError::StaleRequest);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if now >= state.next_sweep {
+            state.sweep(now);
+            state.next_sweep = now + self.window_seconds;
+        }
+        let ids = state.seen.entry(key.clone()).or_default();
+        if ids.get(id).is_some_and(|forget_at| *forget_at > now) {
+            return Err(DepoError::DuplicateRequest);
+        }
+        ids.insert(id.clone(), date + self.window_seconds);
+        Ok(())
+    }
+}
+
+impl ReplayState {
+    fn sweep(&mut self, now: f64) {
+        self.seen.retain(|_, ids| {
+            ids.retain(|_, forget_at| *forget_at > now);
+            !ids.is_empty()
+        });
+    }
+}
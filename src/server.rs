@@ -1,51 +1,245 @@
-use log::info;
+use std::{net::{IpAddr, Ipv4Addr, SocketAddr}, sync::Arc, time::Duration};
+
+use bc_components::PublicKeyBase;
+use bc_envelope::prelude::*;
+use depo_api::util::Abbrev;
+use log::{error, info, warn};
 use warp::{Filter, http::StatusCode, reply::{self, Reply}, reject::Rejection};
-use nu_ansi_term::Color::Green;
+use nu_ansi_term::Color::{Green, Yellow};
+use serde::Serialize;
+
+use crate::{
+    api::ResetDbRequest, rate_limiter::RateLimiter, tls::{serve_tls, RemoteAddr, TlsConfig}, Backend, Depo,
+    KeyStorage, LocalRecoveryVerifier, RateLimit, RecoveryVerifier, DEFAULT_IP_RATE_LIMIT, DEFAULT_KEY_RATE_LIMIT,
+    DEFAULT_RECOVERY_RATE_LIMIT,
+};
+
+/// The port the server listens on unless another is configured.
+pub const DEFAULT_PORT: u16 = 5332;
 
-use crate::{reset_db, Depo, db_depo::{create_db, server_pool}};
+/// How often expired shares are removed unless another interval is configured.
+pub const DEFAULT_GC_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
-pub async fn start_server(schema_name: &str, port: u16) -> anyhow::Result<()> {
-    create_db(&server_pool(), schema_name).await?;
+/// How long `/readyz` waits for storage to respond.
+const READINESS_TIMEOUT: Duration = Duration::from_secs(5);
 
-    let depo = Depo::new_db(schema_name).await?;
+/// The log target for administrative actions.
+const AUDIT_TARGET: &str = "depo::audit";
+
+/// Settings for running the depository's HTTP server.
+#[derive(Clone)]
+pub struct ServerConfig {
+    pub backend: Backend,
+    /// Where the server's private key is kept.
+    pub key_storage: KeyStorage,
+    pub bind_address: IpAddr,
+    pub port: u16,
+    /// Enables `POST /reset-db` for `resetDb` requests signed by this key.
+    /// The route does not exist when this is `None`.
+    pub admin_key: Option<PublicKeyBase>,
+    /// Serves HTTPS instead of plain HTTP when set.
+    pub tls: Option<TlsConfig>,
+    /// Delivers the one-time codes that authorize account recovery.
+    pub recovery_verifier: Arc<dyn RecoveryVerifier>,
+    /// How often expired shares and idle accounts are removed.
+    pub gc_interval: Duration,
+    /// Removes accounts that hold no shares and have made no requests for this
+    /// long. Such accounts are kept forever when this is `None`.
+    pub idle_account_expiry: Option<Duration>,
+    /// Limits the POST requests from each remote address, answering those
+    /// over the limit with 429 Too Many Requests.
+    pub ip_rate_limit: Option<RateLimit>,
+    /// Limits the requests signed by each account key.
+    pub key_rate_limit: Option<RateLimit>,
+    /// Limits the recovery attempts for each recovery method.
+    pub recovery_rate_limit: Option<RateLimit>,
+}
+
+impl ServerConfig {
+    /// Returns a configuration that serves the given backend on
+    /// `127.0.0.1:5332`.
+    pub fn new(backend: Backend) -> Self {
+        Self {
+            backend,
+            key_storage: KeyStorage::default(),
+            bind_address: Ipv4Addr::LOCALHOST.into(),
+            port: DEFAULT_PORT,
+            admin_key: None,
+            tls: None,
+            recovery_verifier: Arc::new(LocalRecoveryVerifier::new()),
+            gc_interval: DEFAULT_GC_INTERVAL,
+            idle_account_expiry: None,
+            ip_rate_limit: Some(DEFAULT_IP_RATE_LIMIT),
+            key_rate_limit: Some(DEFAULT_KEY_RATE_LIMIT),
+            recovery_rate_limit: Some(DEFAULT_RECOVERY_RATE_LIMIT),
+        }
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.port)
+    }
+}
+
+pub async fn start_server(config: &ServerConfig) -> anyhow::Result<()> {
+    let backend = &config.backend;
+    backend.create_db_if_needed().await?;
+
+    let depo = backend.new_depo_with_key_storage(&config.key_storage).await?
+        .with_recovery_verifier(config.recovery_verifier.clone())
+        .with_key_rate_limit(config.key_rate_limit)
+        .with_recovery_rate_limit(config.recovery_rate_limit);
+    let ip_rate_limiter = config.ip_rate_limit.map(|limit| Arc::new(RateLimiter::new(limit)));
 
     let key_route = warp::path::end()
         .and(warp::get())
         .and(with_depo(depo.clone()))
         .and_then(key_handler);
 
+    let descriptor_route = warp::path("descriptor")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_depo(depo.clone()))
+        .and_then(descriptor_handler);
+
+    let key_rotations_route = warp::path("key-rotations")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_depo(depo.clone()))
+        .and_then(key_rotations_handler);
+
     let operation_route = warp::path::end()
         .and(warp::post())
+        .and(limit_rate(ip_rate_limiter.clone()))
         .and(with_depo(depo.clone()))
         .and(warp::body::bytes())
         .and_then(operation_handler);
 
-    let cloned_schema_name = schema_name.to_owned();
+    let metrics_route = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_depo(depo.clone()))
+        .and_then(metrics_handler);
+
+    let health_route = warp::path("healthz")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(health_handler);
+
+    let ready_route = warp::path("readyz")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_depo(depo.clone()))
+        .and_then(ready_handler);
+
+    let cloned_backend = backend.clone();
+    let admin_key = config.admin_key.clone();
 
     let reset_db_route = warp::path("reset-db")
         .and(warp::post())
-        .and(warp::any().map(move || cloned_schema_name.clone()))
+        .and(warp::any().map(move || admin_key.clone()))
+        .and_then(require_admin_key)
+        .and(limit_rate(ip_rate_limiter))
+        .and(remote_addr())
+        .and(with_depo(depo.clone()))
+        .and(warp::any().map(move || cloned_backend.clone()))
+        .and(warp::body::bytes())
         .and_then(reset_db_handler);
 
     let routes =
         key_route
+        .or(descriptor_route)
+        .or(key_rotations_route)
         .or(operation_route)
-        .or(reset_db_route);
+        .or(metrics_route)
+        .or(health_route)
+        .or(ready_route)
+        .or(reset_db_route)
+        .recover(handle_rejection);
 
-    let host = "127.0.0.1";
-    let addr = format!("{}:{}", host, port);
-    let socket_addr = addr.parse::<std::net::SocketAddr>()?;
+    let socket_addr = config.socket_addr();
+    let scheme = if config.tls.is_some() { "https" } else { "http" };
 
-    info!("{}", Green.paint(format!("Starting Blockchain Commons Depository on {}:{}", host, port)));
+    info!("{}", Green.paint(format!("Starting Blockchain Commons Depository on {}://{}", scheme, socket_addr)));
+    info!("{}", Green.paint(format!("Storage: {}", backend)));
     info!("{}", Green.paint(format!("Public key: {}", depo.public_key_string())));
+    for retired_key in depo.retired_keys() {
+        info!("{}", Green.paint(format!("Previous key {} accepted until {}", retired_key.public_key().abbrev(), retired_key.expiry())));
+    }
+    info!("{}", Green.paint(format!("Recovery verification: {}", depo.recovery_verifier().method())));
+    if let Some(tls) = &config.tls {
+        info!("{}", Green.paint(format!("TLS certificate: {}", tls.cert_path.display())));
+    }
+    info!("{}", Green.paint(format!("Rate limits: {} per address, {} per key, {} per recovery method",
+        rate_limit_string(config.ip_rate_limit),
+        rate_limit_string(config.key_rate_limit),
+        rate_limit_string(config.recovery_rate_limit),
+    )));
+    if let Some(expiry) = config.idle_account_expiry {
+        info!("{}", Green.paint(format!("Idle accounts removed after: {} days", expiry.as_secs() / (60 * 60 * 24))));
+    }
+    if let Some(admin_key) = &config.admin_key {
+        warn!("{}", Yellow.paint(format!("Database reset enabled for admin key {}", admin_key.abbrev())));
+    }
 
-    warp::serve(routes)
-        .run(socket_addr)
-        .await;
+    tokio::spawn(collect_garbage_periodically(depo.clone(), config.gc_interval, config.idle_account_expiry));
+
+    match &config.tls {
+        Some(tls) => serve_tls(warp::service(routes), socket_addr, tls).await?,
+        None => warp::serve(routes).run(socket_addr).await,
+    }
 
     Ok(())
 }
 
+async fn collect_garbage_periodically(depo: Depo, interval: Duration, idle_account_expiry: Option<Duration>) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        match depo.collect_garbage(idle_account_expiry).await {
+            Ok((0, 0)) => {}
+            Ok((records, users)) => info!("Removed {} expired shares and {} idle accounts", records, users),
+            Err(e) => error!("Could not remove expired shares and idle accounts: {:#}", e),
+        }
+    }
+}
+
+fn rate_limit_string(limit: Option<RateLimit>) -> String {
+    limit.map_or_else(|| "unlimited".to_string(), |limit| limit.to_string())
+}
+
+/// Rejects the request if its remote address has exceeded the rate limit.
+fn limit_rate(limiter: Option<Arc<RateLimiter<IpAddr>>>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    remote_addr()
+        .and_then(move |remote: Option<SocketAddr>| {
+            let limiter = limiter.clone();
+            async move {
+                match (limiter, remote) {
+                    (Some(limiter), Some(remote)) if !limiter.check(&remote.ip()) => {
+                        Err(warp::reject::custom(RateLimited))
+                    }
+                    _ => Ok(()),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+async fn handle_rejection(rejection: Rejection) -> Result<Box<dyn Reply>, Rejection> {
+    if rejection.find::<RateLimited>().is_some() {
+        let reply = reply::with_status("Too many requests".to_string(), StatusCode::TOO_MANY_REQUESTS);
+        return Ok(Box::new(reply));
+    }
+    Err(rejection)
+}
+
+/// Extracts the address of the client, whether the connection was accepted by
+/// warp or by the TLS listener.
+fn remote_addr() -> impl Filter<Extract = (Option<SocketAddr>,), Error = std::convert::Infallible> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<RemoteAddr>())
+        .map(|addr: Option<SocketAddr>, tls_addr: Option<RemoteAddr>| tls_addr.map(|a| a.0).or(addr))
+}
+
 fn with_depo(depo: Depo) -> impl Filter<Extract = (Depo,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || depo.clone())
 }
@@ -54,6 +248,22 @@ async fn key_handler(depo: Depo) -> Result<Box<dyn Reply>, Rejection> {
     Ok(Box::new(reply::with_status(depo.public_key_string().to_string(), StatusCode::OK)))
 }
 
+/// Returns the depository's descriptor as a `ur:envelope` signed by the server
+/// key.
+async fn descriptor_handler(depo: Depo) -> Result<Box<dyn Reply>, Rejection> {
+    Ok(Box::new(reply::with_status(depo.signed_descriptor().ur_string(), StatusCode::OK)))
+}
+
+/// Returns the signed statement of each rotation of the server key as a
+/// `ur:envelope`, one per line and oldest first, so that a client that trusts an
+/// earlier key can follow the rotations to the current one.
+async fn key_rotations_handler(depo: Depo) -> Result<Box<dyn Reply>, Rejection> {
+    let statements = depo.key_rotations().iter()
+        .map(|statement| statement.ur_string() + "\n")
+        .collect::<String>();
+    Ok(Box::new(reply::with_status(statements, StatusCode::OK)))
+}
+
 async fn operation_handler(depo: Depo, body: bytes::Bytes) -> Result<Box<dyn Reply>, Rejection> {
     let body_string = std::str::from_utf8(&body).map_err(|_| warp::reject::custom(InvalidBody))?.to_string();
     let a = depo.handle_request_string(body_string).await;
@@ -61,10 +271,83 @@ async fn operation_handler(depo: Depo, body: bytes::Bytes) -> Result<Box<dyn Rep
     Ok(result)
 }
 
-async fn reset_db_handler(schema_name: String) -> Result<Box<dyn Reply>, Rejection> {
-    match reset_db(&schema_name).await {
-        Ok(_) => Ok(Box::new(reply::with_status("Database reset successfully. A new private key has been assigned. Server must be restarted.", StatusCode::OK))),
+async fn metrics_handler(depo: Depo) -> Result<Box<dyn Reply>, Rejection> {
+    let result: Box<dyn Reply> = match depo.metrics_text() {
+        Ok(text) => Box::new(reply::with_header(text, "Content-Type", prometheus::TEXT_FORMAT)),
+        Err(e) => {
+            error!("Could not gather metrics: {:#}", e);
+            Box::new(reply::with_status("Could not gather metrics".to_string(), StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    };
+    Ok(result)
+}
+
+#[derive(Serialize)]
+struct HealthStatus {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    storage: Option<&'static str>,
+}
+
+/// Reports that the process is up, without touching storage.
+async fn health_handler() -> Result<Box<dyn Reply>, Rejection> {
+    let status = HealthStatus { status: "ok", storage: None };
+    Ok(Box::new(reply::json(&status)))
+}
+
+/// Reports whether storage can be reached, so that traffic is only routed to
+/// servers that can handle it.
+async fn ready_handler(depo: Depo) -> Result<Box<dyn Reply>, Rejection> {
+    let result = match tokio::time::timeout(READINESS_TIMEOUT, depo.check_storage()).await {
+        Ok(result) => result.map_err(anyhow::Error::from),
+        Err(_) => Err(anyhow::anyhow!("timed out")),
+    };
+    let reply: Box<dyn Reply> = match result {
+        Ok(()) => {
+            let status = HealthStatus { status: "ready", storage: Some("ok") };
+            Box::new(reply::json(&status))
+        }
         Err(e) => {
+            warn!("Storage is not reachable: {:#}", e);
+            let status = HealthStatus { status: "unavailable", storage: Some("unreachable") };
+            Box::new(reply::with_status(reply::json(&status), StatusCode::SERVICE_UNAVAILABLE))
+        }
+    };
+    Ok(reply)
+}
+
+async fn require_admin_key(admin_key: Option<PublicKeyBase>) -> Result<PublicKeyBase, Rejection> {
+    admin_key.ok_or_else(warp::reject::not_found)
+}
+
+async fn reset_db_handler(
+    admin_key: PublicKeyBase,
+    remote: Option<SocketAddr>,
+    depo: Depo,
+    backend: Backend,
+    body: bytes::Bytes,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let remote = remote.map_or("unknown address".to_string(), |addr| addr.to_string());
+    let request = match parse_reset_db_request(&depo, &body) {
+        Ok(request) => request,
+        Err(e) => {
+            warn!(target: AUDIT_TARGET, "Rejected database reset from {}: {}", remote, e);
+            return Ok(Box::new(reply::with_status(format!("Invalid reset request: {}", e), StatusCode::BAD_REQUEST)));
+        }
+    };
+    if request.key() != &admin_key {
+        warn!(target: AUDIT_TARGET, "Rejected database reset from {} by non-admin key {}", remote, request.key().abbrev());
+        return Ok(Box::new(reply::with_status("Not authorized to reset the database.", StatusCode::FORBIDDEN)));
+    }
+
+    warn!(target: AUDIT_TARGET, "Database reset {} requested from {} by admin key {}", request.id().abbrev(), remote, admin_key.abbrev());
+    match backend.reset_db().await {
+        Ok(_) => {
+            warn!(target: AUDIT_TARGET, "Database reset {} succeeded", request.id().abbrev());
+            Ok(Box::new(reply::with_status("Database reset successfully. A new private key has been assigned. Server must be restarted.", StatusCode::OK)))
+        }
+        Err(e) => {
+            warn!(target: AUDIT_TARGET, "Database reset {} failed: {}", request.id().abbrev(), e);
             let error_message = format!("Failed to reset database: {}", e);
             let reply = reply::html(error_message);
             Ok(Box::new(reply::with_status(reply, StatusCode::INTERNAL_SERVER_ERROR)))
@@ -72,10 +355,18 @@ async fn reset_db_handler(schema_name: String) -> Result<Box<dyn Reply>, Rejecti
     }
 }
 
+fn parse_reset_db_request(depo: &Depo, body: &[u8]) -> anyhow::Result<ResetDbRequest> {
+    let body_string = std::str::from_utf8(body)?;
+    let encrypted_request = Envelope::from_ur_string(body_string)?;
+    let (request, key) = depo.verify_request(encrypted_request)?;
+    depo.check_replay(&request, &key)?;
+    ResetDbRequest::from_envelope(request)
+}
+
 #[derive(Debug)]
 struct InvalidBody;
 impl warp::reject::Reject for InvalidBody {}
 
 #[derive(Debug)]
-struct AnyhowError(anyhow::Error);
-impl warp::reject::Reject for AnyhowError {}
+struct RateLimited;
+impl warp::reject::Reject for RateLimited {}
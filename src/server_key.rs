@@ -0,0 +1,345 @@
+use std::{fmt, fs, io::Write, path::{Path, PathBuf}, time::Duration};
+
+use anyhow::{anyhow, bail, Context};
+use bc_components::{PrivateKeyBase, PublicKeyBase, Salt, SymmetricKey};
+use bc_crypto::hash::{hkdf_hmac_sha256, pbkdf2_hmac_sha256, sha256};
+use bc_envelope::prelude::*;
+use log::info;
+
+use crate::api::KeyRotation;
+
+/// The value of `settings.private_key` when the key is kept in a file of its
+/// own.
+const EXTERNAL_KEY: &str = "external";
+
+/// Prefixes the path of the file holding a key replaced by rotation when the
+/// server key is kept in a file of its own.
+const EXTERNAL_RETIRED_KEY_PREFIX: &str = "external:";
+
+const KDF_PARAM: &str = "kdf";
+const SALT_PARAM: &str = "salt";
+const ITERATIONS_PARAM: &str = "iterations";
+
+const PBKDF2: &str = "pbkdf2-hmac-sha256";
+const HKDF: &str = "hkdf-hmac-sha256";
+/// The PBKDF2 iterations used to encrypt the server key with a passphrase,
+/// unless others are given.
+pub const PBKDF2_ITERATIONS: u32 = 600_000;
+const SALT_SIZE: usize = 16;
+const MIN_KEYFILE_SIZE: usize = 32;
+
+/// Where the server keeps its private key.
+///
+/// A database holding the key unencrypted is converted to the configured
+/// storage the next time the depository opens it, so a server can be moved to
+/// any of the other kinds by restarting it with the new setting.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub enum KeyStorage {
+    /// In the database, unencrypted.
+    #[default]
+    Plaintext,
+    /// In the database, encrypted with a key derived from this passphrase with
+    /// this many PBKDF2 iterations. The iterations are stored with the key, so
+    /// a key encrypted with other iterations can still be decrypted.
+    Passphrase { passphrase: String, iterations: u32 },
+    /// In the database, encrypted with a key derived from the contents of this
+    /// file, which must be at least 32 bytes of random data.
+    Keyfile(PathBuf),
+    /// In this file as a `ur:crypto-prvkeys`, and not in the database. The file
+    /// is created with a new key if it does not exist.
+    File(PathBuf),
+}
+
+impl fmt::Debug for KeyStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Plaintext => write!(f, "Plaintext"),
+            Self::Passphrase { iterations, .. } => {
+                f.debug_struct("Passphrase").field("iterations", iterations).finish_non_exhaustive()
+            }
+            Self::Keyfile(path) => f.debug_tuple("Keyfile").field(path).finish(),
+            Self::File(path) => f.debug_tuple("File").field(path).finish(),
+        }
+    }
+}
+
+impl KeyStorage {
+    /// Encrypts the key in the database with this passphrase, using
+    /// `PBKDF2_ITERATIONS` iterations.
+    pub fn passphrase(passphrase: impl Into<String>) -> Self {
+        Self::Passphrase { passphrase: passphrase.into(), iterations: PBKDF2_ITERATIONS }
+    }
+
+    /// Returns the server's private key given the value of
+    /// `settings.private_key`, which is `None` until a key has been assigned,
+    /// along with the value to replace it with if the key was generated or
+    /// has to be moved into this storage.
+    pub(crate) fn load(&self, stored: Option<&str>) -> anyhow::Result<(PrivateKeyBase, Option<String>)> {
+        match (stored, self) {
+            (None, Self::File(path)) => {
+                let private_key = if path.exists() {
+                    read_key_file(path)?
+                } else {
+                    let private_key = PrivateKeyBase::new();
+                    write_key_file(path, &private_key)?;
+                    private_key
+                };
+                Ok((private_key, Some(EXTERNAL_KEY.to_string())))
+            }
+            (None, _) => {
+                let private_key = PrivateKeyBase::new();
+                let sealed = self.seal(&private_key)?;
+                Ok((private_key, Some(sealed)))
+            }
+            (Some(EXTERNAL_KEY), Self::File(path)) => Ok((read_key_file(path)?, None)),
+            (Some(EXTERNAL_KEY), _) => {
+                bail!("the server key is kept in a separate file; give its path with --private-key-file")
+            }
+            (Some(stored), _) if is_plaintext(stored) => {
+                let private_key = PrivateKeyBase::from_ur_string(stored)?;
+                match self {
+                    Self::Plaintext => Ok((private_key, None)),
+                    Self::File(path) => {
+                        if !path.exists() {
+                            write_key_file(path, &private_key)?;
+                        } else if read_key_file(path)?.ur_string() != private_key.ur_string() {
+                            bail!("{} holds a different server key than the database", path.display());
+                        }
+                        info!("Moving the server key from the database to {}", path.display());
+                        Ok((private_key, Some(EXTERNAL_KEY.to_string())))
+                    }
+                    _ => {
+                        info!("Encrypting the server key stored in the database");
+                        let sealed = self.seal(&private_key)?;
+                        Ok((private_key, Some(sealed)))
+                    }
+                }
+            }
+            (Some(_), Self::File(path)) => {
+                bail!("the server key in the database is encrypted, so it cannot be moved to {}", path.display())
+            }
+            (Some(stored), _) => Ok((self.unseal(stored)?, None)),
+        }
+    }
+
+    /// Replaces the key held by the given value of `settings.private_key` with
+    /// a new one, returning the rotation along with the values that hold the
+    /// new and the previous key in this storage.
+    ///
+    /// A key kept in a file of its own is moved to a file beside it, and the
+    /// new key is written to the file by `StoredRotation::install` once the
+    /// rotation has been recorded.
+    pub(crate) fn rotate(&self, stored: Option<&str>, grace_period: Duration) -> anyhow::Result<StoredRotation> {
+        // Any conversion of the current key to this storage is superseded by
+        // storing the new key.
+        let (current_key, _) = self.load(stored)?;
+        let current_key = &current_key;
+        let new_key = PrivateKeyBase::new();
+        // Whole seconds, as the database keeps them.
+        let date = dcbor::Date::from_timestamp(dcbor::Date::now().timestamp().floor());
+        let grace_period_end = date.clone() + grace_period.as_secs() as f64;
+        let rotation = KeyRotation::new(current_key.public_keys(), new_key.public_keys(), date, grace_period_end);
+        let statement = rotation.sign(current_key, &new_key);
+        if let Self::File(path) = self {
+            // Named after the key, as two rotations can happen within a second.
+            let digest = sha256(current_key.public_keys().tagged_cbor_data());
+            let retired_path = sibling_path(path, &format!("retired-{}", hex::encode(&digest[..8])));
+            write_key_file(&retired_path, current_key)?;
+            let pending_path = sibling_path(path, "new");
+            _ = fs::remove_file(&pending_path);
+            write_key_file(&pending_path, &new_key)?;
+            return Ok(StoredRotation {
+                rotation,
+                statement,
+                new_key: EXTERNAL_KEY.to_string(),
+                previous_key: format!("{}{}", EXTERNAL_RETIRED_KEY_PREFIX, retired_path.display()),
+                key_file: Some((pending_path, path.clone())),
+            });
+        }
+        Ok(StoredRotation {
+            statement,
+            rotation,
+            new_key: self.seal(&new_key)?,
+            previous_key: self.seal(current_key)?,
+            key_file: None,
+        })
+    }
+
+    /// Returns a key replaced by rotation, given the value that holds it, along
+    /// with the value to replace it with if the key was stored unencrypted and
+    /// this storage encrypts it.
+    pub(crate) fn retired_key(&self, stored: &str, expiry: dcbor::Date) -> anyhow::Result<(RetiredKey, Option<String>)> {
+        if let Some(path) = stored.strip_prefix(EXTERNAL_RETIRED_KEY_PREFIX) {
+            return Ok((RetiredKey::new(read_key_file(Path::new(path))?, expiry), None));
+        }
+        if !is_plaintext(stored) {
+            return Ok((RetiredKey::new(self.unseal(stored)?, expiry), None));
+        }
+        let private_key = PrivateKeyBase::from_ur_string(stored)?;
+        let resealed = match self {
+            Self::Plaintext | Self::File(_) => None,
+            Self::Passphrase { .. } | Self::Keyfile(_) => {
+                info!("Encrypting a previous server key stored in the database");
+                Some(self.seal(&private_key)?)
+            }
+        };
+        Ok((RetiredKey::new(private_key, expiry), resealed))
+    }
+
+    /// Destroys whatever holds a key replaced by rotation outside the database,
+    /// once its grace period has ended.
+    pub(crate) fn discard_retired_key(&self, stored: &str) -> anyhow::Result<()> {
+        if let Some(path) = stored.strip_prefix(EXTERNAL_RETIRED_KEY_PREFIX) {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(e).with_context(|| format!("could not remove previous server key file {}", path));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the value of `settings.private_key` that holds the key in this
+    /// storage.
+    fn seal(&self, private_key: &PrivateKeyBase) -> anyhow::Result<String> {
+        let salt = Salt::new_with_len(SALT_SIZE)?;
+        let envelope = Envelope::new(private_key.clone());
+        let envelope = match self {
+            Self::Passphrase { passphrase, iterations } => envelope
+                .encrypt_subject(&passphrase_key(passphrase, &salt, *iterations))?
+                .add_assertion(KDF_PARAM, PBKDF2)
+                .add_assertion(ITERATIONS_PARAM, *iterations),
+            Self::Keyfile(path) => envelope
+                .encrypt_subject(&keyfile_key(path, &salt)?)?
+                .add_assertion(KDF_PARAM, HKDF),
+            Self::Plaintext | Self::File(_) => return Ok(private_key.ur_string()),
+        };
+        Ok(envelope.add_assertion(SALT_PARAM, salt).ur_string())
+    }
+
+    fn unseal(&self, stored: &str) -> anyhow::Result<PrivateKeyBase> {
+        let envelope = Envelope::from_ur_string(stored)?;
+        let kdf: String = envelope.extract_object_for_predicate(KDF_PARAM)?;
+        let salt: Salt = envelope.extract_object_for_predicate(SALT_PARAM)?;
+        let key = match (self, kdf.as_str()) {
+            (Self::Passphrase { passphrase, .. }, PBKDF2) => {
+                let iterations: u32 = envelope.extract_object_for_predicate(ITERATIONS_PARAM)?;
+                passphrase_key(passphrase, &salt, iterations)
+            }
+            (Self::Keyfile(path), HKDF) => keyfile_key(path, &salt)?,
+            (_, PBKDF2) => bail!("the server key is encrypted with a passphrase; give it with --key-passphrase"),
+            (_, HKDF) => bail!("the server key is encrypted with a keyfile; give its path with --keyfile"),
+            (_, kdf) => bail!("the server key is encrypted with an unknown method: {}", kdf),
+        };
+        envelope.decrypt_subject(&key)
+            .map_err(|_| anyhow!("could not decrypt the server key; check its passphrase or keyfile"))?
+            .extract_subject()
+    }
+}
+
+/// A rotation of the server key, and the values that hold its keys.
+pub(crate) struct StoredRotation {
+    pub rotation: KeyRotation,
+    /// The statement linking the previous and new keys, signed by both.
+    pub statement: Envelope,
+    pub new_key: String,
+    pub previous_key: String,
+    /// The file holding the new key until it replaces the key file, and that
+    /// file, when the key is kept in a file of its own.
+    key_file: Option<(PathBuf, PathBuf)>,
+}
+
+impl StoredRotation {
+    /// Puts the new key in place once the rotation has been recorded in the
+    /// database.
+    pub fn install(&self) -> anyhow::Result<()> {
+        if let Some((pending_path, path)) = &self.key_file {
+            fs::rename(pending_path, path)
+                .with_context(|| format!("could not move the new server key from {} to {}", pending_path.display(), path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// A server key replaced by rotation, which is still accepted until its grace
+/// period ends.
+#[derive(Clone)]
+pub struct RetiredKey {
+    private_key: PrivateKeyBase,
+    public_key: PublicKeyBase,
+    expiry: dcbor::Date,
+}
+
+impl RetiredKey {
+    pub fn new(private_key: PrivateKeyBase, expiry: dcbor::Date) -> Self {
+        let public_key = private_key.public_keys();
+        Self { private_key, public_key, expiry }
+    }
+
+    pub fn private_key(&self) -> &PrivateKeyBase {
+        &self.private_key
+    }
+
+    pub fn public_key(&self) -> &PublicKeyBase {
+        &self.public_key
+    }
+
+    pub fn expiry(&self) -> &dcbor::Date {
+        &self.expiry
+    }
+
+    pub fn is_accepted_at(&self, date: &dcbor::Date) -> bool {
+        date < &self.expiry
+    }
+}
+
+fn is_plaintext(stored: &str) -> bool {
+    stored.starts_with("ur:crypto-prvkeys/")
+}
+
+/// Returns the path of a file beside the given one, named after it with the
+/// given extension appended.
+fn sibling_path(path: &Path, extension: &str) -> PathBuf {
+    let mut sibling = path.as_os_str().to_owned();
+    sibling.push(".");
+    sibling.push(extension);
+    PathBuf::from(sibling)
+}
+
+fn passphrase_key(passphrase: &str, salt: &Salt, iterations: u32) -> SymmetricKey {
+    let data = pbkdf2_hmac_sha256(passphrase, salt.data(), iterations, SymmetricKey::SYMMETRIC_KEY_SIZE);
+    SymmetricKey::from_data_ref(data).unwrap()
+}
+
+fn keyfile_key(path: &Path, salt: &Salt) -> anyhow::Result<SymmetricKey> {
+    let material = fs::read(path).with_context(|| format!("could not read keyfile {}", path.display()))?;
+    if material.len() < MIN_KEYFILE_SIZE {
+        bail!("keyfile {} must hold at least {} bytes", path.display(), MIN_KEYFILE_SIZE);
+    }
+    let data = hkdf_hmac_sha256(material, salt.data(), SymmetricKey::SYMMETRIC_KEY_SIZE);
+    SymmetricKey::from_data_ref(data)
+}
+
+fn read_key_file(path: &Path) -> anyhow::Result<PrivateKeyBase> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("could not read server key file {}", path.display()))?;
+    PrivateKeyBase::from_ur_string(contents.trim())
+        .with_context(|| format!("{} does not hold a ur:crypto-prvkeys", path.display()))
+}
+
+/// Writes the key to a new file that only its owner can read.
+fn write_key_file(path: &Path, private_key: &PrivateKeyBase) -> anyhow::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)
+        .with_context(|| format!("could not create server key file {}", path.display()))?;
+    writeln!(file, "{}", private_key.ur_string())?;
+    file.sync_all()?;
+    Ok(())
+}
@@ -0,0 +1,955 @@
+use std::{collections::HashSet, path::Path, sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bc_components::{PrivateKeyBase, PublicKeyBase, ARID};
+use bc_envelope::prelude::*;
+use depo_api::receipt::Receipt;
+use log::info;
+use rusqlite::{named_params, Connection, OpenFlags, OptionalExtension, Row, TransactionBehavior};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use crate::{
+    api::{KeyRotation, ShareInfo}, depo_error::DepoResult, depo_impl::{DepoImpl, DepoTransaction}, function::Depo,
+    migration::{Migration, MigrationPlan, MigrationStatement::{AddColumn, Sql}}, record::Record,
+    server_key::{KeyStorage, RetiredKey}, user::User, CONTINUATION_EXPIRY_SECONDS, MAX_BYTES_PER_ACCOUNT, MAX_DATA_SIZE,
+    MAX_SHARES_PER_ACCOUNT, MAX_SHARE_TTL_SECONDS,
+};
+
+const USERS_TABLE_NAME: &str = "users";
+const RECORDS_TABLE_NAME: &str = "records";
+const SETTINGS_TABLE_NAME: &str = "settings";
+const CONTINUATIONS_TABLE_NAME: &str = "continuations";
+const SCHEMA_VERSION_TABLE_NAME: &str = "schema_version";
+const KEY_ROTATIONS_TABLE_NAME: &str = "key_rotations";
+
+struct SqliteDepoImpl {
+    conn: Arc<Mutex<Connection>>,
+    private_key: PrivateKeyBase,
+    public_key: PublicKeyBase,
+    public_key_string: String,
+    retired_keys: Vec<RetiredKey>,
+    key_rotations: Vec<Envelope>,
+    continuation_expiry_seconds: u32,
+    max_data_size: u32,
+    max_shares_per_account: u32,
+    max_bytes_per_account: u32,
+    max_share_ttl_seconds: u32,
+}
+
+impl SqliteDepoImpl {
+    fn new(path: impl AsRef<Path>, key_storage: &KeyStorage) -> anyhow::Result<Arc<Self>> {
+        let conn = open_db(path)?;
+        create_db(&conn)?;
+        let private_key = load_private_key(&conn, key_storage)?;
+        let (retired_keys, key_rotations) = load_key_rotations(&conn, key_storage)?;
+        let (
+            continuation_expiry_seconds,
+            max_data_size,
+            max_shares_per_account,
+            max_bytes_per_account,
+            max_share_ttl_seconds,
+        ) = get_settings(&conn)?;
+        let public_key = private_key.public_keys();
+        let public_key_string = public_key.ur_string();
+        Ok(Arc::new(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            private_key,
+            public_key,
+            public_key_string,
+            retired_keys,
+            key_rotations,
+            continuation_expiry_seconds,
+            max_data_size,
+            max_shares_per_account,
+            max_bytes_per_account,
+            max_share_ttl_seconds,
+        }))
+    }
+
+    /// Runs `f` with the connection once no other request or transaction is
+    /// using it.
+    async fn with_conn<T, F>(&self, f: F) -> DepoResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> DepoResult<T> + Send + 'static,
+    {
+        let conn = self.conn.clone().lock_owned().await;
+        blocking(move || f(&conn)).await
+    }
+}
+
+fn get_settings(conn: &Connection) -> anyhow::Result<(u32, u32, u32, u32, u32)> {
+    let query = format!(
+        "SELECT continuation_expiry_seconds, max_data_size, max_shares_per_account, max_bytes_per_account, max_share_ttl_seconds FROM {}",
+        SETTINGS_TABLE_NAME
+    );
+    let result = conn
+        .query_row(&query, [], |row| {
+            Ok((
+                row.get::<_, u32>("continuation_expiry_seconds")?,
+                row.get::<_, u32>("max_data_size")?,
+                row.get::<_, u32>("max_shares_per_account")?,
+                row.get::<_, u32>("max_bytes_per_account")?,
+                row.get::<_, u32>("max_share_ttl_seconds")?,
+            ))
+        })
+        .optional()?;
+    result.ok_or_else(|| anyhow!("Settings not found"))
+}
+
+/// Loads the server's private key from the given storage, first recording a
+/// newly assigned key or moving an existing one into that storage if needed.
+fn load_private_key(conn: &Connection, key_storage: &KeyStorage) -> anyhow::Result<PrivateKeyBase> {
+    loop {
+        let query = format!("SELECT private_key FROM {}", SETTINGS_TABLE_NAME);
+        let stored: Option<String> = conn
+            .query_row(&query, [], |row| row.get("private_key"))
+            .optional()?
+            .ok_or_else(|| anyhow!("Settings not found"))?;
+        let (private_key, replacement) = key_storage.load(stored.as_deref())?;
+        let Some(replacement) = replacement else {
+            return Ok(private_key);
+        };
+        // Only replace the value that was read, in case another process
+        // replaced it first.
+        let query = format!(
+            "UPDATE {} SET private_key = :replacement WHERE private_key IS :stored",
+            SETTINGS_TABLE_NAME
+        );
+        if conn.execute(&query, named_params! { ":replacement": replacement, ":stored": stored })? > 0 {
+            return Ok(private_key);
+        }
+    }
+}
+
+/// Returns the keys replaced by rotation that are still accepted, after
+/// destroying those whose grace periods have ended, along with the statements
+/// of every rotation.
+fn load_key_rotations(conn: &Connection, key_storage: &KeyStorage) -> anyhow::Result<(Vec<RetiredKey>, Vec<Envelope>)> {
+    let now = dcbor::Date::now().timestamp() as i64;
+    let query = format!("SELECT private_key, expiry, statement FROM {} ORDER BY rotated_at", KEY_ROTATIONS_TABLE_NAME);
+    let rows = conn.prepare(&query)?.query_map([], |row| {
+        Ok((
+            row.get::<_, Option<String>>("private_key")?,
+            row.get::<_, i64>("expiry")?,
+            row.get::<_, String>("statement")?,
+        ))
+    })?.collect::<Result<Vec<_>, _>>()?;
+    let mut retired_keys = Vec::new();
+    let mut key_rotations = Vec::new();
+    for (private_key, expiry, statement) in rows {
+        if let Some(private_key) = private_key {
+            if expiry <= now {
+                key_storage.discard_retired_key(&private_key)?;
+            } else {
+                let (retired_key, resealed) = key_storage.retired_key(&private_key, dcbor::Date::from_timestamp(expiry as f64))?;
+                if let Some(resealed) = resealed {
+                    let query = format!("UPDATE {} SET private_key = :resealed WHERE private_key = :private_key", KEY_ROTATIONS_TABLE_NAME);
+                    conn.execute(&query, named_params! { ":resealed": resealed, ":private_key": private_key })?;
+                }
+                retired_keys.push(retired_key);
+            }
+        }
+        key_rotations.push(Envelope::from_ur_string(statement)?);
+    }
+    let query = format!("UPDATE {} SET private_key = NULL WHERE expiry <= :now", KEY_ROTATIONS_TABLE_NAME);
+    conn.execute(&query, named_params! { ":now": now })?;
+    Ok((retired_keys, key_rotations))
+}
+
+#[async_trait]
+impl DepoImpl for SqliteDepoImpl {
+    fn max_data_size(&self) -> u32 {
+        self.max_data_size
+    }
+
+    fn continuation_expiry_seconds(&self) -> u32 {
+        self.continuation_expiry_seconds
+    }
+
+    fn max_shares_per_account(&self) -> u32 {
+        self.max_shares_per_account
+    }
+
+    fn max_bytes_per_account(&self) -> u32 {
+        self.max_bytes_per_account
+    }
+
+    fn max_share_ttl_seconds(&self) -> u32 {
+        self.max_share_ttl_seconds
+    }
+
+    fn private_key(&self) -> &PrivateKeyBase {
+        &self.private_key
+    }
+
+    fn public_key(&self) -> &PublicKeyBase {
+        &self.public_key
+    }
+
+    fn public_key_string(&self) -> &str {
+        &self.public_key_string
+    }
+
+    fn retired_keys(&self) -> &[RetiredKey] {
+        &self.retired_keys
+    }
+
+    fn key_rotations(&self) -> &[Envelope] {
+        &self.key_rotations
+    }
+
+    async fn ping(&self) -> DepoResult<()> {
+        self.with_conn(|conn| {
+            conn.query_row("SELECT 1", [], |_| Ok(()))?;
+            Ok(())
+        }).await
+    }
+
+    async fn existing_key_to_id(&self, public_key: &PublicKeyBase) -> DepoResult<Option<ARID>> {
+        let key = public_key.ur_string();
+        self.with_conn(move |conn| {
+            let query = format!(
+                "SELECT user_id, public_key, recovery FROM {} WHERE public_key = :key",
+                USERS_TABLE_NAME
+            );
+            let user = conn
+                .query_row(&query, named_params! { ":key": key }, row_to_user)
+                .optional()?;
+            Ok(user.map(|user| user.user_id().clone()))
+        }).await
+    }
+
+    async fn begin(&self) -> DepoResult<Box<dyn DepoTransaction>> {
+        let conn = TransactionConn(self.conn.clone().lock_owned().await);
+        let conn = blocking(move || -> DepoResult<_> {
+            conn.0.execute_batch("BEGIN IMMEDIATE")?;
+            Ok(conn)
+        }).await?;
+        Ok(Box::new(SqliteDepoTransaction { conn: Some(conn) }))
+    }
+
+    async fn existing_id_to_user(&self, user_id: &ARID) -> DepoResult<Option<User>> {
+        let user_id = user_id.ur_string();
+        self.with_conn(move |conn| {
+            let query = format!(
+                "SELECT user_id, public_key, recovery FROM {} WHERE user_id = :user_id",
+                USERS_TABLE_NAME
+            );
+            let user = conn
+                .query_row(&query, named_params! { ":user_id": user_id }, row_to_user)
+                .optional()?;
+            Ok(user)
+        }).await
+    }
+
+    async fn touch_user(&self, key: &PublicKeyBase, date: &dcbor::Date) -> DepoResult<()> {
+        let key = key.ur_string();
+        let last_active = date.timestamp() as i64;
+        self.with_conn(move |conn| {
+            let query = format!("UPDATE {} SET last_active = :last_active WHERE public_key = :key", USERS_TABLE_NAME);
+            conn.execute(&query, named_params! {
+                ":last_active": last_active,
+                ":key": key,
+            })?;
+            Ok(())
+        }).await
+    }
+
+    async fn id_to_receipts(&self, user_id: &ARID) -> DepoResult<HashSet<Receipt>> {
+        let user_id = user_id.ur_string();
+        self.with_conn(move |conn| {
+            let query = format!("SELECT receipt FROM {} WHERE user_id = :user_id", RECORDS_TABLE_NAME);
+            let mut statement = conn.prepare(&query)?;
+            let rows = statement.query_map(named_params! { ":user_id": user_id }, |row| {
+                row.get::<_, String>("receipt")
+            })?;
+
+            let mut receipts = HashSet::new();
+            for receipt_string in rows {
+                let receipt_envelope = Envelope::from_ur_string(receipt_string?)?;
+                let receipt = Receipt::from_envelope(receipt_envelope)?;
+                receipts.insert(receipt);
+            }
+
+            Ok(receipts)
+        }).await
+    }
+
+    async fn id_to_share_infos(&self, user_id: &ARID) -> DepoResult<Vec<ShareInfo>> {
+        let user_id = user_id.ur_string();
+        self.with_conn(move |conn| {
+            let query = format!(
+                "SELECT receipt, size, created_at, expiry, label FROM {} WHERE user_id = :user_id",
+                RECORDS_TABLE_NAME
+            );
+            let mut statement = conn.prepare(&query)?;
+            let rows = statement.query_map(named_params! { ":user_id": user_id }, |row| {
+                Ok((
+                    row.get::<_, String>("receipt")?,
+                    row.get::<_, i64>("size")?,
+                    row.get::<_, Option<i64>>("created_at")?,
+                    row.get::<_, Option<i64>>("expiry")?,
+                    row.get::<_, Option<Vec<u8>>>("label")?,
+                ))
+            })?;
+
+            let mut infos = Vec::new();
+            for row in rows {
+                let (receipt_string, size, created, expiry, label) = row?;
+                let receipt = Receipt::from_envelope(Envelope::from_ur_string(receipt_string)?)?;
+                let created = created.map(|created| dcbor::Date::from_timestamp(created as f64));
+                let expiry = expiry.map(|expiry| dcbor::Date::from_timestamp(expiry as f64));
+                let label = label.map(|label| Envelope::from_tagged_cbor_data(&label)).transpose()?;
+                infos.push(ShareInfo::new(receipt, size.try_into()?, created, expiry, label));
+            }
+
+            Ok(infos)
+        }).await
+    }
+
+    async fn receipt_to_record(&self, receipt: &Receipt) -> DepoResult<Option<Record>> {
+        let receipt = receipt.clone();
+        self.with_conn(move |conn| receipt_to_record(conn, &receipt)).await
+    }
+
+    async fn delete_record(&self, receipt: &Receipt) -> DepoResult<()> {
+        let receipt = receipt.envelope().ur_string();
+        self.with_conn(move |conn| {
+            let query = format!("DELETE FROM {} WHERE receipt = :receipt", RECORDS_TABLE_NAME);
+            conn.execute(&query, named_params! { ":receipt": receipt })?;
+            Ok(())
+        }).await
+    }
+
+    async fn recovery_to_user(&self, recovery: &str) -> DepoResult<Option<User>> {
+        let recovery = recovery.to_string();
+        self.with_conn(move |conn| {
+            let query = format!(
+                "SELECT user_id, public_key, recovery FROM {} WHERE recovery = :recovery",
+                USERS_TABLE_NAME
+            );
+            let user = conn
+                .query_row(&query, named_params! { ":recovery": recovery }, row_to_user)
+                .optional()?;
+            Ok(user)
+        }).await
+    }
+
+    async fn insert_continuation(&self, continuation_id: &ARID, user_id: &ARID, expiry: &dcbor::Date) -> DepoResult<()> {
+        let continuation_id = continuation_id.ur_string();
+        let user_id = user_id.ur_string();
+        let expiry = expiry.timestamp() as i64;
+        self.with_conn(move |conn| {
+            let query = format!(
+                "INSERT INTO {} (continuation_id, user_id, expiry) VALUES (:continuation_id, :user_id, :expiry)",
+                CONTINUATIONS_TABLE_NAME
+            );
+            conn.execute(&query, named_params! {
+                ":continuation_id": continuation_id,
+                ":user_id": user_id,
+                ":expiry": expiry,
+            })?;
+            Ok(())
+        }).await
+    }
+
+    async fn remove_expired_records(&self, date: &dcbor::Date) -> DepoResult<usize> {
+        let date = date.timestamp() as i64;
+        self.with_conn(move |conn| {
+            let query = format!("DELETE FROM {} WHERE expiry < :date", RECORDS_TABLE_NAME);
+            let removed = conn.execute(&query, named_params! { ":date": date })?;
+            Ok(removed)
+        }).await
+    }
+
+    async fn remove_idle_users(&self, date: &dcbor::Date) -> DepoResult<usize> {
+        let date = date.timestamp() as i64;
+        self.with_conn(move |conn| {
+            let query = format!(
+                r"DELETE FROM {0} WHERE last_active < :date
+                AND NOT EXISTS (SELECT 1 FROM {1} WHERE {1}.user_id = {0}.user_id)",
+                USERS_TABLE_NAME, RECORDS_TABLE_NAME
+            );
+            let removed = conn.execute(&query, named_params! { ":date": date })?;
+            Ok(removed)
+        }).await
+    }
+
+    async fn user_count(&self) -> DepoResult<usize> {
+        self.with_conn(|conn| {
+            let query = format!("SELECT COUNT(*) FROM {}", USERS_TABLE_NAME);
+            Ok(conn.query_row(&query, [], |row| row.get(0))?)
+        }).await
+    }
+
+    async fn record_count(&self) -> DepoResult<usize> {
+        self.with_conn(|conn| {
+            let query = format!("SELECT COUNT(*) FROM {}", RECORDS_TABLE_NAME);
+            Ok(conn.query_row(&query, [], |row| row.get(0))?)
+        }).await
+    }
+}
+
+/// A SQLite transaction, which holds the connection for as long as it lasts.
+struct SqliteDepoTransaction {
+    /// Only taken while a statement runs on a blocking thread.
+    conn: Option<TransactionConn>,
+}
+
+/// The connection held by a transaction, which rolls back whatever has not
+/// been committed when it is released.
+struct TransactionConn(OwnedMutexGuard<Connection>);
+
+impl Drop for TransactionConn {
+    fn drop(&mut self) {
+        if !self.0.is_autocommit() {
+            let _ = self.0.execute_batch("ROLLBACK");
+        }
+    }
+}
+
+impl SqliteDepoTransaction {
+    async fn with_conn<T, F>(&mut self, f: F) -> DepoResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> DepoResult<T> + Send + 'static,
+    {
+        let conn = self.conn.take().expect("the transaction's connection is in use");
+        let (conn, result) = blocking(move || {
+            let result = f(&conn.0);
+            (conn, result)
+        }).await;
+        self.conn = Some(conn);
+        result
+    }
+}
+
+#[async_trait]
+impl DepoTransaction for SqliteDepoTransaction {
+    async fn existing_key_to_user(&mut self, key: &PublicKeyBase) -> DepoResult<Option<User>> {
+        let key = key.ur_string();
+        self.with_conn(move |conn| {
+            let query = format!(
+                "SELECT user_id, public_key, recovery FROM {} WHERE public_key = :key",
+                USERS_TABLE_NAME
+            );
+            let user = conn
+                .query_row(&query, named_params! { ":key": key }, row_to_user)
+                .optional()?;
+            Ok(user)
+        }).await
+    }
+
+    async fn insert_user(&mut self, user: &User) -> DepoResult<()> {
+        let user = user.clone();
+        self.with_conn(move |conn| {
+            let query = format!(
+                "INSERT INTO {} (user_id, public_key, recovery, last_active) VALUES (:user_id, :public_key, :recovery, :last_active)
+                ON CONFLICT (public_key) DO NOTHING",
+                USERS_TABLE_NAME
+            );
+            conn.execute(&query, named_params! {
+                ":user_id": user.user_id().ur_string(),
+                ":public_key": user.public_key().ur_string(),
+                ":recovery": user.recovery(),
+                ":last_active": dcbor::Date::now().timestamp() as i64,
+            })?;
+            Ok(())
+        }).await
+    }
+
+    async fn recovery_to_user(&mut self, recovery: &str) -> DepoResult<Option<User>> {
+        let recovery = recovery.to_string();
+        self.with_conn(move |conn| {
+            let query = format!(
+                "SELECT user_id, public_key, recovery FROM {} WHERE recovery = :recovery",
+                USERS_TABLE_NAME
+            );
+            let user = conn
+                .query_row(&query, named_params! { ":recovery": recovery }, row_to_user)
+                .optional()?;
+            Ok(user)
+        }).await
+    }
+
+    async fn set_user_recovery(&mut self, user: &User, recovery: Option<&str>) -> DepoResult<()> {
+        let user_id = user.user_id().ur_string();
+        let recovery = recovery.map(str::to_string);
+        self.with_conn(move |conn| {
+            let query = format!("UPDATE {} SET recovery = :recovery WHERE user_id = :user_id", USERS_TABLE_NAME);
+            conn.execute(&query, named_params! {
+                ":recovery": recovery,
+                ":user_id": user_id,
+            })?;
+            Ok(())
+        }).await
+    }
+
+    async fn set_user_key(&mut self, user: &User, new_public_key: &PublicKeyBase) -> DepoResult<()> {
+        let user_id = user.user_id().ur_string();
+        let new_public_key = new_public_key.ur_string();
+        self.with_conn(move |conn| {
+            let query = format!("UPDATE {} SET public_key = :new_public_key WHERE user_id = :user_id", USERS_TABLE_NAME);
+            conn.execute(&query, named_params! {
+                ":new_public_key": new_public_key,
+                ":user_id": user_id,
+            })?;
+            Ok(())
+        }).await
+    }
+
+    async fn insert_record(&mut self, record: &Record) -> DepoResult<()> {
+        let record = record.clone();
+        self.with_conn(move |conn| {
+            let query = format!(
+                r#"
+                INSERT INTO {} (receipt, user_id, data, expiry, size, created_at, label)
+                VALUES (:receipt, :user_id, :data, :expiry, :size, :created_at, :label)
+                ON CONFLICT (receipt) DO UPDATE SET expiry = excluded.expiry, label = COALESCE(excluded.label, label)
+            "#,
+                RECORDS_TABLE_NAME
+            );
+            conn.execute(&query, named_params! {
+                ":receipt": record.receipt().envelope().ur_string(),
+                ":user_id": record.user_id().ur_string(),
+                ":data": record.data().as_ref(),
+                ":expiry": record.expiry().map(|expiry| expiry.timestamp() as i64),
+                ":size": record.data().len() as i64,
+                ":created_at": record.created().map(|created| created.timestamp() as i64),
+                ":label": record.label().map(|label| label.tagged_cbor_data()),
+            })?;
+            Ok(())
+        }).await
+    }
+
+    async fn set_record_label(&mut self, receipt: &Receipt, label: Option<&Envelope>) -> DepoResult<()> {
+        let receipt = receipt.envelope().ur_string();
+        let label = label.map(|label| label.tagged_cbor_data());
+        self.with_conn(move |conn| {
+            let query = format!("UPDATE {} SET label = :label WHERE receipt = :receipt", RECORDS_TABLE_NAME);
+            conn.execute(&query, named_params! {
+                ":label": label,
+                ":receipt": receipt,
+            })?;
+            Ok(())
+        }).await
+    }
+
+    async fn id_to_usage(&mut self, user_id: &ARID) -> DepoResult<(usize, usize)> {
+        let user_id = user_id.ur_string();
+        self.with_conn(move |conn| {
+            let query = format!(
+                "SELECT COUNT(*), COALESCE(SUM(LENGTH(data) + COALESCE(LENGTH(label), 0)), 0) FROM {} WHERE user_id = :user_id",
+                RECORDS_TABLE_NAME
+            );
+            let (count, bytes) = conn.query_row(&query, named_params! { ":user_id": user_id }, |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
+            })?;
+            Ok((count.try_into()?, bytes.try_into()?))
+        }).await
+    }
+
+    async fn receipt_to_record(&mut self, receipt: &Receipt) -> DepoResult<Option<Record>> {
+        let receipt = receipt.clone();
+        self.with_conn(move |conn| receipt_to_record(conn, &receipt)).await
+    }
+
+    async fn remove_records(&mut self, user_id: &ARID) -> DepoResult<()> {
+        let user_id = user_id.ur_string();
+        self.with_conn(move |conn| {
+            let query = format!("DELETE FROM {} WHERE user_id = :user_id", RECORDS_TABLE_NAME);
+            conn.execute(&query, named_params! { ":user_id": user_id })?;
+            Ok(())
+        }).await
+    }
+
+    async fn remove_user(&mut self, user: &User) -> DepoResult<()> {
+        let user_id = user.user_id().ur_string();
+        self.with_conn(move |conn| {
+            let query = format!("DELETE FROM {} WHERE user_id = :user_id", USERS_TABLE_NAME);
+            conn.execute(&query, named_params! { ":user_id": user_id })?;
+            Ok(())
+        }).await
+    }
+
+    async fn take_continuation(&mut self, continuation_id: &ARID) -> DepoResult<bool> {
+        let continuation_id = continuation_id.ur_string();
+        self.with_conn(move |conn| {
+            let query = format!("DELETE FROM {} WHERE continuation_id = :continuation_id", CONTINUATIONS_TABLE_NAME);
+            let deleted = conn.execute(&query, named_params! { ":continuation_id": continuation_id })?;
+            Ok(deleted > 0)
+        }).await
+    }
+
+    async fn remove_continuations(&mut self, user_id: &ARID) -> DepoResult<()> {
+        let user_id = user_id.ur_string();
+        self.with_conn(move |conn| {
+            let query = format!("DELETE FROM {} WHERE user_id = :user_id", CONTINUATIONS_TABLE_NAME);
+            conn.execute(&query, named_params! { ":user_id": user_id })?;
+            Ok(())
+        }).await
+    }
+
+    async fn commit(mut self: Box<Self>) -> DepoResult<()> {
+        self.with_conn(|conn| {
+            conn.execute_batch("COMMIT")?;
+            Ok(())
+        }).await
+    }
+}
+
+impl Drop for SqliteDepoTransaction {
+    fn drop(&mut self) {
+        // Rolling back writes to the database file, so it is left to a thread
+        // where blocking is allowed.
+        if let Some(conn) = self.conn.take() {
+            if !conn.0.is_autocommit() {
+                if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                    runtime.spawn_blocking(move || drop(conn));
+                }
+            }
+        }
+    }
+}
+
+/// Runs `f` on a thread where blocking is allowed, as SQLite reads and writes
+/// the database file synchronously.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+fn receipt_to_record(conn: &Connection, receipt: &Receipt) -> DepoResult<Option<Record>> {
+    let query = format!("SELECT user_id, data, expiry, created_at, label FROM {} WHERE receipt = :receipt", RECORDS_TABLE_NAME);
+    let result = conn
+        .query_row(&query, named_params! { ":receipt": receipt.envelope().ur_string() }, |row| {
+            Ok((
+                row.get::<_, String>("user_id")?,
+                row.get::<_, Vec<u8>>("data")?,
+                row.get::<_, Option<i64>>("expiry")?,
+                row.get::<_, Option<i64>>("created_at")?,
+                row.get::<_, Option<Vec<u8>>>("label")?,
+            ))
+        })
+        .optional()?;
+    match result {
+        Some((user_id_string, data, expiry, created, label)) => {
+            let user_id = ARID::from_ur_string(user_id_string)?;
+            let expiry = expiry.map(|expiry| dcbor::Date::from_timestamp(expiry as f64));
+            let created = created.map(|created| dcbor::Date::from_timestamp(created as f64));
+            let label = label.map(|label| Envelope::from_tagged_cbor_data(&label)).transpose()?;
+            Ok(Some(Record::new_opt(receipt.clone(), user_id, data.into(), expiry, created, label)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn row_to_user(row: &Row<'_>) -> rusqlite::Result<User> {
+    let user_id_string: String = row.get("user_id")?;
+    let user_id = ARID::from_ur_string(user_id_string).unwrap();
+    let public_key_string: String = row.get("public_key")?;
+    let public_key = PublicKeyBase::from_ur_string(public_key_string).unwrap();
+    let recovery: Option<String> = row.get("recovery")?;
+
+    Ok(User::new_opt(user_id, public_key, recovery))
+}
+
+impl Depo {
+    pub async fn new_sqlite(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::new_sqlite_with_key_storage(path, &KeyStorage::default()).await
+    }
+
+    pub async fn new_sqlite_with_key_storage(path: impl AsRef<Path>, key_storage: &KeyStorage) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let key_storage = key_storage.clone();
+        Ok(Self::new(blocking(move || SqliteDepoImpl::new(path, &key_storage)).await?))
+    }
+}
+
+pub fn open_db(path: impl AsRef<Path>) -> anyhow::Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.pragma_update(None, "foreign_keys", true)?;
+    Ok(conn)
+}
+
+fn migrations() -> Vec<Migration> {
+    vec![
+        Migration::new(1, "Create the users, records, continuations, and settings tables", vec![
+            Sql(format!(
+                r"CREATE TABLE IF NOT EXISTS {} (
+                    user_id TEXT NOT NULL PRIMARY KEY,
+                    public_key TEXT UNIQUE NOT NULL,
+                    recovery TEXT UNIQUE
+                )",
+                USERS_TABLE_NAME
+            )),
+            Sql(format!(
+                r"CREATE TABLE IF NOT EXISTS {} (
+                    receipt TEXT NOT NULL PRIMARY KEY,
+                    user_id TEXT NOT NULL,
+                    data BLOB NOT NULL,
+                    FOREIGN KEY (user_id) REFERENCES {}(user_id) ON DELETE CASCADE
+                )",
+                RECORDS_TABLE_NAME, USERS_TABLE_NAME
+            )),
+            Sql(format!(
+                "CREATE INDEX IF NOT EXISTS {}_user_id ON {} (user_id)",
+                RECORDS_TABLE_NAME, RECORDS_TABLE_NAME
+            )),
+            Sql(format!(
+                r"CREATE TABLE IF NOT EXISTS {} (
+                    continuation_id TEXT NOT NULL PRIMARY KEY,
+                    user_id TEXT NOT NULL,
+                    expiry INTEGER NOT NULL,
+                    FOREIGN KEY (user_id) REFERENCES {}(user_id) ON DELETE CASCADE
+                )",
+                CONTINUATIONS_TABLE_NAME, USERS_TABLE_NAME
+            )),
+            Sql(format!(
+                "CREATE INDEX IF NOT EXISTS {}_user_id ON {} (user_id)",
+                CONTINUATIONS_TABLE_NAME, CONTINUATIONS_TABLE_NAME
+            )),
+            Sql(format!(
+                r"CREATE TABLE IF NOT EXISTS {} (
+                    private_key TEXT,
+                    continuation_expiry_seconds INTEGER,
+                    max_data_size INTEGER
+                )",
+                SETTINGS_TABLE_NAME
+            )),
+        ]),
+        Migration::new(2, "Add per-account quotas and the maximum share TTL to settings", vec![
+            AddColumn {
+                table: SETTINGS_TABLE_NAME,
+                column: "max_shares_per_account",
+                definition: format!("INTEGER NOT NULL DEFAULT {}", MAX_SHARES_PER_ACCOUNT),
+            },
+            AddColumn {
+                table: SETTINGS_TABLE_NAME,
+                column: "max_bytes_per_account",
+                definition: format!("INTEGER NOT NULL DEFAULT {}", MAX_BYTES_PER_ACCOUNT),
+            },
+            AddColumn {
+                table: SETTINGS_TABLE_NAME,
+                column: "max_share_ttl_seconds",
+                definition: format!("INTEGER NOT NULL DEFAULT {}", MAX_SHARE_TTL_SECONDS),
+            },
+        ]),
+        Migration::new(3, "Add share expiry", vec![
+            AddColumn { table: RECORDS_TABLE_NAME, column: "expiry", definition: "INTEGER".to_string() },
+        ]),
+        Migration::new(4, "Track when accounts were last active", vec![
+            AddColumn { table: USERS_TABLE_NAME, column: "last_active", definition: "INTEGER".to_string() },
+            // Accounts that predate activity tracking count as active now.
+            Sql(format!(
+                "UPDATE {} SET last_active = CAST(strftime('%s', 'now') AS INTEGER) WHERE last_active IS NULL",
+                USERS_TABLE_NAME
+            )),
+        ]),
+        // SQLite's TEXT columns already hold keys of any length.
+        Migration::new(5, "Widen the private key setting to hold an encrypted key", vec![]),
+        Migration::new(6, "Add server key rotations", vec![
+            Sql(format!(
+                r"CREATE TABLE IF NOT EXISTS {} (
+                    private_key TEXT,
+                    expiry INTEGER NOT NULL,
+                    rotated_at INTEGER NOT NULL,
+                    statement TEXT NOT NULL
+                )",
+                KEY_ROTATIONS_TABLE_NAME
+            )),
+        ]),
+        // Records that predate this migration have no known creation time.
+        Migration::new(7, "Record the size and creation time of shares", vec![
+            AddColumn { table: RECORDS_TABLE_NAME, column: "size", definition: "INTEGER NOT NULL DEFAULT 0".to_string() },
+            AddColumn { table: RECORDS_TABLE_NAME, column: "created_at", definition: "INTEGER".to_string() },
+            Sql(format!("UPDATE {} SET size = LENGTH(data)", RECORDS_TABLE_NAME)),
+        ]),
+        Migration::new(8, "Add share labels", vec![
+            AddColumn { table: RECORDS_TABLE_NAME, column: "label", definition: "BLOB".to_string() },
+        ]),
+    ]
+}
+
+fn schema_version(conn: &Connection) -> anyhow::Result<u32> {
+    let exists: u64 = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = :table_name",
+        named_params! { ":table_name": SCHEMA_VERSION_TABLE_NAME },
+        |row| row.get(0),
+    )?;
+    if exists == 0 {
+        return Ok(0);
+    }
+    let query = format!("SELECT MAX(version) FROM {}", SCHEMA_VERSION_TABLE_NAME);
+    let version: Option<u32> = conn.query_row(&query, [], |row| row.get(0))?;
+    Ok(version.unwrap_or(0))
+}
+
+/// Returns the migrations the database needs, without applying them.
+pub fn plan_migrations(conn: &Connection) -> anyhow::Result<MigrationPlan> {
+    MigrationPlan::new(&migrations(), schema_version(conn)?)
+}
+
+/// Applies the migrations the database needs, each in its own transaction,
+/// and returns them.
+pub fn migrate_db(conn: &Connection) -> anyhow::Result<MigrationPlan> {
+    let plan = plan_migrations(conn)?;
+    for migration in migrations().into_iter().filter(|migration| migration.version > plan.from_version()) {
+        info!("Migrating to schema version {}: {}", migration.version, migration.description);
+        let transaction = conn.unchecked_transaction()?;
+        for statement in &migration.statements {
+            match statement {
+                Sql(query) => {
+                    transaction.execute(query, [])?;
+                }
+                AddColumn { table, column, definition } => {
+                    add_column_if_missing(&transaction, table, column, definition)?;
+                }
+            }
+        }
+        let query = format!(
+            "CREATE TABLE IF NOT EXISTS {} (version INTEGER NOT NULL)",
+            SCHEMA_VERSION_TABLE_NAME
+        );
+        transaction.execute(&query, [])?;
+        transaction.execute(&format!("DELETE FROM {}", SCHEMA_VERSION_TABLE_NAME), [])?;
+        let query = format!("INSERT INTO {} (version) VALUES (:version)", SCHEMA_VERSION_TABLE_NAME);
+        transaction.execute(&query, named_params! { ":version": migration.version })?;
+        transaction.commit()?;
+    }
+
+    Ok(plan)
+}
+
+pub fn create_db(conn: &Connection) -> anyhow::Result<MigrationPlan> {
+    let plan = migrate_db(conn)?;
+
+    // Check if settings already exist
+    let check_query = format!("SELECT COUNT(*) FROM {}", SETTINGS_TABLE_NAME);
+    let count: u64 = conn.query_row(&check_query, [], |row| row.get(0))?;
+
+    // Only insert if settings do not exist. The private key is assigned when
+    // the depository is first opened, in the storage it is configured with.
+    if count == 0 {
+        let query = format!(
+            r"INSERT INTO {}
+            (continuation_expiry_seconds, max_data_size)
+            VALUES (:continuation_expiry_seconds, :max_data_size)",
+            SETTINGS_TABLE_NAME
+        );
+        conn.execute(&query, named_params! {
+            ":continuation_expiry_seconds": CONTINUATION_EXPIRY_SECONDS,
+            ":max_data_size": MAX_DATA_SIZE,
+        })?;
+    }
+
+    Ok(plan)
+}
+
+fn add_column_if_missing(
+    conn: &Connection,
+    table_name: &str,
+    column_name: &str,
+    definition: &str,
+) -> anyhow::Result<()> {
+    let count: u64 = conn.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info(:table_name) WHERE name = :column_name",
+        named_params! { ":table_name": table_name, ":column_name": column_name },
+        |row| row.get(0),
+    )?;
+    if count == 0 {
+        let query = format!("ALTER TABLE {} ADD COLUMN {} {}", table_name, column_name, definition);
+        conn.execute(&query, [])?;
+    }
+
+    Ok(())
+}
+
+pub fn drop_db(conn: &Connection) -> anyhow::Result<()> {
+    for table_name in [
+        CONTINUATIONS_TABLE_NAME, RECORDS_TABLE_NAME, USERS_TABLE_NAME, SETTINGS_TABLE_NAME,
+        KEY_ROTATIONS_TABLE_NAME, SCHEMA_VERSION_TABLE_NAME,
+    ] {
+        let query = format!("DROP TABLE IF EXISTS {}", table_name);
+        conn.execute(&query, [])?;
+    }
+
+    Ok(())
+}
+
+pub fn reset_db(path: impl AsRef<Path>) -> anyhow::Result<()> {
+    let conn = open_db(path)?;
+    drop_db(&conn)?;
+    create_db(&conn)?;
+
+    Ok(())
+}
+
+pub fn create_db_if_needed(path: impl AsRef<Path>) -> anyhow::Result<()> {
+    let conn = open_db(path)?;
+    create_db(&conn)?;
+
+    Ok(())
+}
+
+/// Brings the database's schema up to date, or with `dry_run` only reports
+/// what that would involve.
+pub fn migrate(path: impl AsRef<Path>, dry_run: bool) -> anyhow::Result<MigrationPlan> {
+    let path = path.as_ref();
+    if dry_run {
+        if !path.exists() {
+            return MigrationPlan::new(&migrations(), 0);
+        }
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        return plan_migrations(&conn);
+    }
+    create_db(&open_db(path)?)
+}
+
+/// Replaces the server key with a new one, which the depository uses the next
+/// time it is opened. The previous key is still accepted for the grace period.
+pub fn rotate_key(
+    path: impl AsRef<Path>,
+    key_storage: &KeyStorage,
+    grace_period: Duration,
+) -> anyhow::Result<KeyRotation> {
+    let mut conn = open_db(path)?;
+    create_db(&conn)?;
+    let transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let query = format!("SELECT private_key FROM {}", SETTINGS_TABLE_NAME);
+    let stored: Option<String> = transaction.query_row(&query, [], |row| row.get("private_key"))?;
+    let rotation = key_storage.rotate(stored.as_deref(), grace_period)?;
+
+    let query = format!(
+        r"INSERT INTO {} (private_key, expiry, rotated_at, statement)
+        VALUES (:private_key, :expiry, :rotated_at, :statement)",
+        KEY_ROTATIONS_TABLE_NAME
+    );
+    transaction.execute(&query, named_params! {
+        ":private_key": rotation.previous_key,
+        ":expiry": rotation.rotation.grace_period_end().timestamp() as i64,
+        ":rotated_at": rotation.rotation.date().timestamp() as i64,
+        ":statement": rotation.statement.ur_string(),
+    })?;
+    let query = format!("UPDATE {} SET private_key = :private_key", SETTINGS_TABLE_NAME);
+    transaction.execute(&query, named_params! { ":private_key": rotation.new_key })?;
+    transaction.commit()?;
+    rotation.install()?;
+
+    Ok(rotation.rotation)
+}
+
+pub fn can_connect_to_db(path: impl AsRef<Path>) -> anyhow::Result<bool> {
+    let path = path.as_ref();
+    if !path.exists() {
+        return Ok(false);
+    }
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    conn.query_row("SELECT 1", [], |_| Ok(()))?;
+
+    Ok(true)
+}
@@ -0,0 +1,229 @@
+use std::collections::HashSet;
+
+use anyhow::bail;
+use bc_components::{PrivateKeyBase, PublicKeyBase, SSKRSpec, SymmetricKey, ARID};
+use bc_envelope::prelude::*;
+use depo_api::{receipt::Receipt, util::Abbrev};
+use log::warn;
+use url::Url;
+
+use crate::DepoClient;
+
+const DISTRIBUTION_TYPE: &str = "SSKRDistribution";
+const SHARE_PARAM: &str = "share";
+const URL_PARAM: &str = "url";
+const SERVER_KEY_PARAM: &str = "serverKey";
+
+/// Where a secret's SSKR shares were stored, one share per depository, as
+/// returned by `distribute` and consumed by `recover`.
+///
+/// It holds no share data, only the receipts and the depositories holding
+/// them, so it can be kept anywhere the account key is not. It is portable as a
+/// `ur:envelope`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareDistribution {
+    shares: Vec<DistributedShare>,
+}
+
+/// A share stored by `ShareDistribution::distribute`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DistributedShare {
+    url: Url,
+    server_key: PublicKeyBase,
+    receipt: Receipt,
+}
+
+impl DistributedShare {
+    pub fn new(url: Url, server_key: PublicKeyBase, receipt: Receipt) -> Self {
+        Self { url, server_key, receipt }
+    }
+
+    /// The URL of the depository holding the share.
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// The public key of the depository holding the share.
+    pub fn server_key(&self) -> &PublicKeyBase {
+        &self.server_key
+    }
+
+    pub fn receipt(&self) -> &Receipt {
+        &self.receipt
+    }
+
+    fn client(&self) -> DepoClient {
+        DepoClient::new(self.url.clone(), self.server_key.clone())
+    }
+}
+
+impl ShareDistribution {
+    pub fn new(mut shares: Vec<DistributedShare>) -> Self {
+        // An envelope does not preserve the order of its assertions.
+        shares.sort_by(|a, b| (a.url.as_str(), a.receipt.data()).cmp(&(b.url.as_str(), b.receipt.data())));
+        Self { shares }
+    }
+
+    /// The shares, ordered by the URL of the depository holding them.
+    pub fn shares(&self) -> &[DistributedShare] {
+        &self.shares
+    }
+
+    /// Splits the secret into SSKR shares according to `spec` and stores each
+    /// share in the next of `depos`, under the account identified by `key`.
+    /// There must be exactly one depository for each share.
+    ///
+    /// If a share cannot be stored, the shares already stored are deleted
+    /// again, as far as possible, before the error is returned.
+    pub async fn distribute(secret: impl AsRef<[u8]>, spec: &SSKRSpec, depos: &[DepoClient], key: &PrivateKeyBase) -> anyhow::Result<Self> {
+        if depos.len() != spec.share_count() {
+            bail!("{} depositories were given for {} shares", depos.len(), spec.share_count());
+        }
+        let content_key = SymmetricKey::new();
+        let envelopes = Envelope::new(CBOR::byte_string(secret))
+            .wrap_envelope()
+            .encrypt_subject(&content_key)?
+            .sskr_split(spec, &content_key)?
+            .into_iter()
+            .flatten();
+        let mut shares = Vec::new();
+        for (depo, envelope) in depos.iter().zip(envelopes) {
+            match depo.store_share(key, envelope.tagged_cbor_data(), None, None).await {
+                Ok(receipt) => shares.push(DistributedShare::new(depo.url().clone(), depo.server_key().clone(), receipt)),
+                Err(e) => {
+                    for share in &shares {
+                        if let Err(e) = share.client().delete_share(key, share.receipt()).await {
+                            warn!("Could not delete the share stored at {}: {:#}", share.url(), e);
+                        }
+                    }
+                    return Err(e.context(format!("could not store a share at {}", depo.url())));
+                }
+            }
+        }
+        Ok(Self::new(shares))
+    }
+
+    /// Reconstructs the secret from the shares held by the depositories,
+    /// retrieving them until enough have been gathered. Depositories that
+    /// cannot return their share are skipped.
+    pub async fn recover(&self, key: &PrivateKeyBase) -> anyhow::Result<Vec<u8>> {
+        let mut envelopes = Vec::new();
+        for share in &self.shares {
+            let data = match share.client().get_share(key, share.receipt()).await {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Could not retrieve the share stored at {}: {:#}", share.url(), e);
+                    continue;
+                }
+            };
+            match Envelope::from_tagged_cbor_data(&data) {
+                Ok(envelope) => envelopes.push(envelope),
+                Err(e) => {
+                    warn!("The share stored at {} is not an envelope: {:#}", share.url(), e);
+                    continue;
+                }
+            }
+            if let Ok(envelope) = Envelope::sskr_join(&envelopes) {
+                let secret: CBOR = envelope.unwrap_envelope()?.extract_subject()?;
+                return Ok(secret.expect_byte_string()?.to_vec());
+            }
+        }
+        bail!("only {} of {} shares could be retrieved, which is not enough to recover the secret", envelopes.len(), self.shares.len())
+    }
+
+    /// Deletes the shares from the depositories holding them, returning an
+    /// error for the first that could not be deleted.
+    pub async fn delete(&self, key: &PrivateKeyBase) -> anyhow::Result<()> {
+        let mut result = Ok(());
+        for share in &self.shares {
+            let receipts = HashSet::from([share.receipt().clone()]);
+            if let Err(e) = share.client().delete_shares(key, &receipts).await {
+                if result.is_ok() {
+                    result = Err(e.context(format!("could not delete the share stored at {}", share.url())));
+                }
+            }
+        }
+        result
+    }
+}
+
+impl EnvelopeEncodable for DistributedShare {
+    fn envelope(self) -> Envelope {
+        self.receipt.envelope()
+            .add_assertion(URL_PARAM, self.url.to_string())
+            .add_assertion(SERVER_KEY_PARAM, self.server_key)
+    }
+}
+
+impl From<DistributedShare> for Envelope {
+    fn from(value: DistributedShare) -> Self {
+        value.envelope()
+    }
+}
+
+impl EnvelopeDecodable for DistributedShare {
+    fn from_envelope(envelope: Envelope) -> anyhow::Result<Self> {
+        let url: String = envelope.extract_object_for_predicate(URL_PARAM)?;
+        Ok(Self::new(
+            Url::parse(&url)?,
+            envelope.extract_object_for_predicate(SERVER_KEY_PARAM)?,
+            Receipt::from_envelope(envelope)?,
+        ))
+    }
+}
+
+impl TryFrom<Envelope> for DistributedShare {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Envelope) -> anyhow::Result<Self> {
+        Self::from_envelope(value)
+    }
+}
+
+impl EnvelopeCodable for DistributedShare {}
+
+impl EnvelopeEncodable for ShareDistribution {
+    fn envelope(self) -> Envelope {
+        let mut envelope = Envelope::new(ARID::new()).add_type(DISTRIBUTION_TYPE);
+        for share in self.shares {
+            envelope = envelope.add_assertion(SHARE_PARAM, share.envelope());
+        }
+        envelope
+    }
+}
+
+impl From<ShareDistribution> for Envelope {
+    fn from(value: ShareDistribution) -> Self {
+        value.envelope()
+    }
+}
+
+impl EnvelopeDecodable for ShareDistribution {
+    fn from_envelope(envelope: Envelope) -> anyhow::Result<Self> {
+        envelope.clone().check_type_envelope(DISTRIBUTION_TYPE)?;
+        let shares = envelope.objects_for_predicate(SHARE_PARAM)
+            .into_iter()
+            .map(DistributedShare::from_envelope)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self::new(shares))
+    }
+}
+
+impl TryFrom<Envelope> for ShareDistribution {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Envelope) -> anyhow::Result<Self> {
+        Self::from_envelope(value)
+    }
+}
+
+impl EnvelopeCodable for ShareDistribution {}
+
+impl std::fmt::Display for ShareDistribution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let depos = self.shares.iter()
+            .map(|share| format!("{} at {}", share.server_key.abbrev(), share.url))
+            .collect::<Vec<_>>();
+        f.write_fmt(format_args!("{} shares stored in [{}]", self.shares.len(), depos.join(", ")))
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    convert::Infallible,
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use log::{debug, error, info, warn};
+use tokio::net::TcpListener;
+use tokio_rustls::{rustls, TlsAcceptor};
+use warp::hyper::{server::conn::Http, service::{service_fn, Service}, Body, Request, Response};
+
+/// How long a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before accepting connections again after an error, such
+/// as running out of file descriptors.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// The PEM files used to serve HTTPS.
+///
+/// On Unix the files are read again when the server receives `SIGHUP`, so that
+/// renewed certificates can be picked up without a restart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        Self {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+        }
+    }
+
+    fn load(&self) -> anyhow::Result<Arc<rustls::ServerConfig>> {
+        let certs = read_certs(&self.cert_path)?;
+        let key = read_private_key(&self.key_path)?;
+        let mut config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .context("invalid TLS certificate or key")?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<rustls::Certificate>> {
+    let file = File::open(path)
+        .with_context(|| format!("could not read certificate file {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("invalid certificate file {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("no certificates found in {}", path.display());
+    }
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+fn read_private_key(path: &Path) -> anyhow::Result<rustls::PrivateKey> {
+    let file = File::open(path)
+        .with_context(|| format!("could not read key file {}", path.display()))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("invalid key file {}", path.display()))?;
+    for item in items {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(rustls::PrivateKey(key)),
+            _ => {}
+        }
+    }
+    bail!("no private key found in {}", path.display())
+}
+
+/// The address of the client on the other end of a TLS connection.
+///
+/// warp only knows the remote address of connections it accepts itself, so
+/// connections accepted here carry it as a request extension instead.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RemoteAddr(pub SocketAddr);
+
+/// Serves HTTPS on `addr`. Errors accepting connections are logged and the
+/// server keeps accepting them.
+pub(crate) async fn serve_tls<S>(service: S, addr: SocketAddr, config: &TlsConfig) -> anyhow::Result<()>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    let acceptor = Arc::new(RwLock::new(TlsAcceptor::from(config.load()?)));
+    let listener = TcpListener::bind(addr).await?;
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(config.clone(), acceptor.clone()));
+
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Could not accept a connection: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.read().unwrap().clone();
+        let service = service.clone();
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    debug!("TLS handshake with {} failed: {}", remote_addr, e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("TLS handshake with {} timed out", remote_addr);
+                    return;
+                }
+            };
+            let service = service_fn(move |mut request: Request<Body>| {
+                request.extensions_mut().insert(RemoteAddr(remote_addr));
+                service.clone().call(request)
+            });
+            if let Err(e) = Http::new().serve_connection(stream, service).await {
+                debug!("Connection from {} failed: {}", remote_addr, e);
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+async fn reload_on_hangup(config: TlsConfig, acceptor: Arc<RwLock<TlsAcceptor>>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("Could not listen for SIGHUP, TLS certificate will not be reloaded: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        match config.load() {
+            Ok(server_config) => {
+                *acceptor.write().unwrap() = TlsAcceptor::from(server_config);
+                info!("Reloaded TLS certificate {}", config.cert_path.display());
+            }
+            Err(e) => error!("Could not reload TLS certificate, keeping the previous one: {:#}", e),
+        }
+    }
+}
@@ -1,28 +1,38 @@
 use async_trait::async_trait;
 use bytes::Bytes;
 use bc_envelope::prelude::*;
-use depo::{Depo, start_server, setup_log, create_db_if_needed};
+use depo::{api::{add_request_date, add_share_label, add_share_ttl, add_verification_code, error_code, share_labels, KeyRotation, ListSharesRequest, ListSharesResponse, ResetDbRequest, ServerDescriptor, ShareInfo, UpdateShareLabelRequest, UpdateShareLabelResponse}, Backend, DbConfig, Depo, DepoError, KeyStorage, LocalRecoveryVerifier, RateLimit, ServerConfig, TlsConfig, TlsMode, start_server, setup_log, create_db_if_needed};
 use log::{warn, info};
 use reqwest::{self, Client, StatusCode};
 use hex_literal::hex;
 use tokio::time::sleep;
-use std::time::Duration;
+use std::{path::{Path, PathBuf}, sync::Arc, time::Duration};
 use url::Url;
+#[cfg(feature = "client")]
+use std::collections::HashSet;
+#[cfg(feature = "client")]
+use bc_components::{SSKRGroupSpec, SSKRSpec};
+#[cfg(feature = "client")]
+use depo::{DepoClient, ErrorResponse, ShareDistribution};
 use bc_components::{PublicKeyBase, PrivateKeyBase};
 use nu_ansi_term::Color::{Cyan, Red, Yellow};
 use depo_api::{
-    request::store_share::StoreShareRequest, DeleteAccountRequest, DeleteSharesRequest,
+    receipt::Receipt, request::store_share::StoreShareRequest, DeleteAccountRequest, DeleteSharesRequest,
     FinishRecoveryRequest, GetRecoveryRequest, GetRecoveryResponse, GetSharesRequest,
     GetSharesResponse, StartRecoveryRequest, StartRecoveryResponse, StoreShareResponse,
     UpdateKeyRequest, UpdateRecoveryRequest,
 };
 
+/// Few enough PBKDF2 iterations to encrypt a server key quickly in tests.
+const TEST_PBKDF2_ITERATIONS: u32 = 1_000;
+
 /// Test against the Depo API that stores data in memory.
 #[tokio::test]
 async fn test_in_memory_depo() {
     setup_log();
-    let depo = Depo::new_in_memory();
-    test_depo_scenario(depo.public_key(), &depo).await;
+    let codes = recovery_codes_path("test_in_memory_depo");
+    let depo = Depo::new_in_memory().with_recovery_verifier(Arc::new(LocalRecoveryVerifier::with_file(&codes)));
+    test_depo_scenario(depo.public_key(), &depo, &codes).await;
 }
 
 /// Test against the Depo API that stores data in a database.
@@ -31,14 +41,66 @@ async fn test_in_memory_depo() {
 async fn test_db_depo() {
     setup_log();
     let schema_name = "test_db_depo";
-    if let Err(e) = create_db_if_needed(schema_name).await {
+    let config = DbConfig::from_env().unwrap();
+    if let Err(e) = create_db_if_needed(&config, schema_name).await {
         warn!("{}", Yellow.paint(format!("Skipping `{}` because can't connect to the database.", schema_name)).to_string());
         warn!("{}", Yellow.paint(format!("{}", e)).to_string());
         return;
     }
 
-    let depo = Depo::new_db(schema_name).await.unwrap();
-    test_depo_scenario(depo.public_key(), &depo).await;
+    let codes = recovery_codes_path(schema_name);
+    let depo = Depo::new_db(&config, schema_name).await.unwrap()
+        .with_recovery_verifier(Arc::new(LocalRecoveryVerifier::with_file(&codes)));
+    test_depo_scenario(depo.public_key(), &depo, &codes).await;
+}
+
+/// Test against the Depo API that stores data in an embedded SQLite database.
+#[tokio::test]
+async fn test_sqlite_depo() {
+    setup_log();
+    let path = std::env::temp_dir().join("test_sqlite_depo.sqlite");
+    _ = std::fs::remove_file(&path);
+
+    let codes = recovery_codes_path("test_sqlite_depo");
+    let depo = Depo::new_sqlite(&path).await.unwrap()
+        .with_recovery_verifier(Arc::new(LocalRecoveryVerifier::with_file(&codes)));
+    test_depo_scenario(depo.public_key(), &depo, &codes).await;
+}
+
+/// Test against the Depo API that stores data in a PostgreSQL database.
+/// Requires a PostgreSQL server running on localhost.
+#[tokio::test]
+async fn test_pg_depo() {
+    setup_log();
+    let config = DbConfig::from_env().unwrap();
+    let backend = Backend::Postgres { config, schema_name: "test_pg_depo".to_string() };
+    if let Err(e) = backend.create_db_if_needed().await {
+        warn!("{}", Yellow.paint(format!("Skipping `{}` because can't connect to the database.", "test_pg_depo")).to_string());
+        warn!("{}", Yellow.paint(format!("{}", e)).to_string());
+        return;
+    }
+
+    let codes = recovery_codes_path("test_pg_depo");
+    let depo = backend.new_depo().await.unwrap()
+        .with_recovery_verifier(Arc::new(LocalRecoveryVerifier::with_file(&codes)));
+    test_depo_scenario(depo.public_key(), &depo, &codes).await;
+}
+
+/// Test that the PostgreSQL backend connects over TLS when configured to.
+/// Requires a PostgreSQL server running on localhost with TLS enabled.
+#[tokio::test]
+async fn test_pg_tls() {
+    setup_log();
+    let mut config = DbConfig::from_env().unwrap();
+    config.tls = TlsMode::Required;
+    let backend = Backend::Postgres { config, schema_name: "test_pg_tls".to_string() };
+    if let Err(e) = backend.create_db_if_needed().await {
+        warn!("{}", Yellow.paint(format!("Skipping `{}` because can't connect to the database over TLS.", "test_pg_tls")).to_string());
+        warn!("{}", Yellow.paint(format!("{:#}", e)).to_string());
+        return;
+    }
+    let depo = backend.new_depo().await.unwrap();
+    depo.check_storage().await.unwrap();
 }
 
 /// Test against the full Depo HTTP server running in a separate thread.
@@ -48,15 +110,20 @@ async fn test_server_depo() {
     setup_log();
     let schema_name = "test_server_depo";
     let port: u16 = 5333;
-    if let Err(e) = create_db_if_needed(schema_name).await {
+    let config = DbConfig::from_env().unwrap();
+    if let Err(e) = create_db_if_needed(&config, schema_name).await {
         warn!("{}", Yellow.paint(format!("Skipping `{}` because can't connect to the database.", schema_name)).to_string());
         warn!("{}", Yellow.paint(format!("{}", e)).to_string());
         return;
     }
 
     // Start the server and wait for it to be ready
+    let codes = recovery_codes_path(schema_name);
+    let recovery_verifier = Arc::new(LocalRecoveryVerifier::with_file(&codes));
     tokio::spawn(async move {
-        start_server(schema_name, port).await.unwrap();
+        let backend = Backend::MySql { config, schema_name: schema_name.to_string() };
+        let config = ServerConfig { port, recovery_verifier, ..ServerConfig::new(backend) };
+        start_server(&config).await.unwrap();
     });
     sleep(Duration::from_secs(1)).await;
 
@@ -65,13 +132,1100 @@ async fn test_server_depo() {
 
     let depo_public_key = &get_public_key(&depo).await.unwrap();
 
-    test_depo_scenario(depo_public_key, &depo).await;
+    test_depo_scenario(depo_public_key, &depo, &codes).await;
+}
+
+/// Test that a request is rejected if it is replayed, stale, or undated.
+#[tokio::test]
+async fn test_replay_protection() {
+    setup_log();
+    let depo = Depo::new_in_memory();
+    let alice_private_key = PrivateKeyBase::new();
+    let alice_public_key = alice_private_key.public_keys();
+    let data = Bytes::from_static(&hex!("cafebabe"));
+
+    let call = |request: Envelope| signed_call(&depo, request, &alice_private_key);
+
+    let request = StoreShareRequest::new(&alice_public_key, &data).envelope();
+    let dated_request = add_request_date(request.clone(), dcbor::Date::now()).unwrap();
+    let response = call(dated_request.clone()).await;
+    StoreShareResponse::try_from(response).unwrap();
+
+    let response = call(dated_request).await;
+    assert!(response.error::<String>().unwrap().contains("duplicate request"));
+    assert_eq!(error_code(&response).as_deref(), Some("duplicate_request"));
+
+    let stale_request = add_request_date(
+        StoreShareRequest::new(&alice_public_key, &data).envelope(),
+        dcbor::Date::now() + -600.0,
+    ).unwrap();
+    let response = call(stale_request).await;
+    assert!(response.error::<String>().unwrap().contains("stale request"));
+    assert_eq!(error_code(&response).as_deref(), Some("stale_request"));
+
+    let response = call(request).await;
+    assert!(response.error::<String>().unwrap().contains("request has no date"));
+    assert_eq!(error_code(&response).as_deref(), Some("missing_date"));
+}
+
+/// Test that errors are typed when calling the depository directly, and carry
+/// their codes in error responses.
+#[tokio::test]
+async fn test_error_codes() {
+    setup_log();
+    let depo = Depo::new_in_memory();
+    let alice_private_key = PrivateKeyBase::new();
+    let alice_public_key = alice_private_key.public_keys();
+
+    let error = depo.get_recovery(&alice_public_key).await.unwrap_err();
+    assert!(matches!(error, DepoError::UnknownPublicKey(_)));
+    assert_eq!(error.code(), "unknown_public_key");
+
+    let request = add_request_date(
+        GetRecoveryRequest::new(&alice_public_key).envelope(),
+        dcbor::Date::now(),
+    ).unwrap();
+    let response = signed_call(&depo, request, &alice_private_key).await;
+    assert!(response.error::<String>().unwrap().contains("unknown public key"));
+    assert_eq!(error_code(&response).as_deref(), Some("unknown_public_key"));
+
+    let data = Bytes::from(vec![0u8; 2000]);
+    let error = depo.store_share(&alice_public_key, &data, None, None).await.unwrap_err();
+    assert!(matches!(error, DepoError::DataTooLarge));
+
+    let response = Envelope::from_ur_string(depo.handle_request_string("nonsense".to_string()).await).unwrap();
+    assert_eq!(error_code(&response).as_deref(), Some("invalid_request"));
+}
+
+/// Test that per-account quotas from the `settings` table are enforced, and that
+/// storing the same data again does not count against them.
+#[tokio::test]
+async fn test_storage_quotas() {
+    setup_log();
+    let path = std::env::temp_dir().join("test_storage_quotas.sqlite");
+    _ = std::fs::remove_file(&path);
+
+    drop(Depo::new_sqlite(&path).await.unwrap());
+    rusqlite::Connection::open(&path).unwrap()
+        .execute("UPDATE settings SET max_shares_per_account = 3, max_bytes_per_account = 10", [])
+        .unwrap();
+    let depo = Depo::new_sqlite(&path).await.unwrap();
+
+    let alice_private_key = PrivateKeyBase::new();
+    let alice_public_key = alice_private_key.public_keys();
+    let store = |data: &'static [u8]| {
+        let request = StoreShareRequest::new(&alice_public_key, Bytes::from_static(data)).envelope();
+        signed_call(&depo, add_request_date(request, dcbor::Date::now()).unwrap(), &alice_private_key)
+    };
+
+    StoreShareResponse::try_from(store(b"abcd").await).unwrap();
+    StoreShareResponse::try_from(store(b"efgh").await).unwrap();
+    StoreShareResponse::try_from(store(b"abcd").await).unwrap();
+
+    let response = store(b"ijkl").await;
+    assert!(response.error::<String>().unwrap().contains("quota exceeded"));
+
+    StoreShareResponse::try_from(store(b"ij").await).unwrap();
+
+    let response = store(b"k").await;
+    assert!(response.error::<String>().unwrap().contains("quota exceeded"));
+}
+
+/// Test that a database created before schema versions were recorded is
+/// migrated in order, that a dry run changes nothing, and that a database newer
+/// than the server is refused.
+#[tokio::test]
+async fn test_schema_migrations() {
+    setup_log();
+    let path = std::env::temp_dir().join("test_schema_migrations.sqlite");
+    _ = std::fs::remove_file(&path);
+    rusqlite::Connection::open(&path).unwrap().execute_batch(indoc::indoc! {r"
+        CREATE TABLE users (user_id TEXT NOT NULL PRIMARY KEY, public_key TEXT UNIQUE NOT NULL, recovery TEXT UNIQUE);
+        CREATE TABLE records (receipt TEXT NOT NULL PRIMARY KEY, user_id TEXT NOT NULL, data BLOB NOT NULL);
+        CREATE TABLE continuations (continuation_id TEXT NOT NULL PRIMARY KEY, user_id TEXT NOT NULL, expiry INTEGER NOT NULL);
+        CREATE TABLE settings (private_key TEXT, continuation_expiry_seconds INTEGER, max_data_size INTEGER);
+        INSERT INTO users (user_id, public_key) VALUES ('legacy-user', 'legacy-key');
+        INSERT INTO records (receipt, user_id, data) VALUES ('legacy-receipt', 'legacy-user', X'cafebabe');
+    "}).unwrap();
+    let backend = Backend::Sqlite(path.clone());
+
+    let plan = backend.migrate(true).await.unwrap();
+    assert_eq!((plan.from_version(), plan.to_version()), (0, 8));
+    assert_eq!(plan.steps().iter().map(|step| step.version()).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5, 6, 7, 8]);
+    assert_eq!(backend.migrate(true).await.unwrap(), plan);
+
+    assert_eq!(backend.migrate(false).await.unwrap(), plan);
+    let last_active: Option<i64> = rusqlite::Connection::open(&path).unwrap()
+        .query_row("SELECT last_active FROM users", [], |row| row.get(0))
+        .unwrap();
+    assert!(last_active.is_some());
+    let (size, created_at): (i64, Option<i64>) = rusqlite::Connection::open(&path).unwrap()
+        .query_row("SELECT size, created_at FROM records", [], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap();
+    assert_eq!((size, created_at), (4, None));
+    assert!(backend.migrate(true).await.unwrap().is_empty());
+    drop(backend.new_depo().await.unwrap());
+
+    rusqlite::Connection::open(&path).unwrap()
+        .execute("UPDATE schema_version SET version = 99", [])
+        .unwrap();
+    assert!(backend.migrate(true).await.err().unwrap().to_string().contains("newer"));
+    assert!(backend.new_depo().await.is_err());
+
+    let config = DbConfig::from_env().unwrap();
+    let backend = Backend::Postgres { config, schema_name: "test_schema_migrations".to_string() };
+    if let Err(e) = backend.reset_db().await {
+        warn!("{}", Yellow.paint(format!("Skipping PostgreSQL in `{}` because can't connect to the database.", "test_schema_migrations")).to_string());
+        warn!("{}", Yellow.paint(format!("{}", e)).to_string());
+        return;
+    }
+    assert!(backend.migrate(true).await.unwrap().is_empty());
+    drop(backend.new_depo().await.unwrap());
+}
+
+/// Test that a plaintext server key is encrypted or moved to its own file when
+/// the depository is opened with that storage, and can only be loaded from it
+/// afterwards.
+#[tokio::test]
+async fn test_key_storage() {
+    setup_log();
+    let dir = std::env::temp_dir();
+    let stored_key = |path: &Path| -> String {
+        rusqlite::Connection::open(path).unwrap()
+            .query_row("SELECT private_key FROM settings", [], |row| row.get(0))
+            .unwrap()
+    };
+    let public_key = |depo: Depo| depo.public_key_string().to_string();
+
+    let path = dir.join("test_key_storage_passphrase.sqlite");
+    _ = std::fs::remove_file(&path);
+    let plaintext_key = public_key(Depo::new_sqlite(&path).await.unwrap());
+    assert!(stored_key(&path).starts_with("ur:crypto-prvkeys/"));
+    let passphrase = KeyStorage::Passphrase { passphrase: "correct horse battery staple".to_string(), iterations: TEST_PBKDF2_ITERATIONS };
+    assert_eq!(public_key(Depo::new_sqlite_with_key_storage(&path, &passphrase).await.unwrap()), plaintext_key);
+    assert!(stored_key(&path).starts_with("ur:envelope/"));
+    assert!(Depo::new_sqlite(&path).await.err().unwrap().to_string().contains("passphrase"));
+    let wrong_passphrase = KeyStorage::Passphrase { passphrase: "incorrect horse".to_string(), iterations: TEST_PBKDF2_ITERATIONS };
+    assert!(Depo::new_sqlite_with_key_storage(&path, &wrong_passphrase).await.err().unwrap().to_string().contains("could not decrypt"));
+
+    let path = dir.join("test_key_storage_keyfile.sqlite");
+    let keyfile = dir.join("test_key_storage.keyfile");
+    _ = std::fs::remove_file(&path);
+    std::fs::write(&keyfile, bc_rand::random_data(32)).unwrap();
+    let keyfile = KeyStorage::Keyfile(keyfile);
+    let encrypted_key = public_key(Depo::new_sqlite_with_key_storage(&path, &keyfile).await.unwrap());
+    assert!(stored_key(&path).starts_with("ur:envelope/"));
+    assert_eq!(public_key(Depo::new_sqlite_with_key_storage(&path, &keyfile).await.unwrap()), encrypted_key);
+    assert!(Depo::new_sqlite_with_key_storage(&path, &passphrase).await.err().unwrap().to_string().contains("keyfile"));
+
+    let path = dir.join("test_key_storage_file.sqlite");
+    let key_file = dir.join("test_key_storage.prvkeys");
+    _ = std::fs::remove_file(&path);
+    _ = std::fs::remove_file(&key_file);
+    let plaintext_key = public_key(Depo::new_sqlite(&path).await.unwrap());
+    let file = KeyStorage::File(key_file.clone());
+    assert_eq!(public_key(Depo::new_sqlite_with_key_storage(&path, &file).await.unwrap()), plaintext_key);
+    assert_eq!(stored_key(&path), "external");
+    assert!(std::fs::read_to_string(&key_file).unwrap().starts_with("ur:crypto-prvkeys/"));
+    assert!(Depo::new_sqlite(&path).await.err().unwrap().to_string().contains("separate file"));
+    assert_eq!(public_key(Depo::new_sqlite_with_key_storage(&path, &file).await.unwrap()), plaintext_key);
+
+    let config = DbConfig::from_env().unwrap();
+    let backend = Backend::Postgres { config, schema_name: "test_key_storage".to_string() };
+    if let Err(e) = backend.reset_db().await {
+        warn!("{}", Yellow.paint(format!("Skipping PostgreSQL in `{}` because can't connect to the database.", "test_key_storage")).to_string());
+        warn!("{}", Yellow.paint(format!("{}", e)).to_string());
+        return;
+    }
+    let plaintext_key = public_key(backend.new_depo().await.unwrap());
+    assert_eq!(public_key(backend.new_depo_with_key_storage(&passphrase).await.unwrap()), plaintext_key);
+    assert!(backend.new_depo().await.is_err());
+    assert_eq!(public_key(backend.new_depo_with_key_storage(&passphrase).await.unwrap()), plaintext_key);
+}
+
+/// Test that a rotated key is published with a statement linking it to the
+/// previous key, and that requests encrypted to the previous key are answered
+/// with that key until its grace period ends.
+#[tokio::test]
+async fn test_key_rotation() {
+    setup_log();
+    let path = std::env::temp_dir().join("test_key_rotation.sqlite");
+    _ = std::fs::remove_file(&path);
+    test_key_rotation_scenario(&Backend::Sqlite(path)).await;
+
+    // A key kept in a file of its own is replaced in that file, and the
+    // previous key is kept in a file beside it until its grace period ends.
+    let key_file = std::env::temp_dir().join("test_key_rotation.prvkeys");
+    _ = std::fs::remove_file(&key_file);
+    let path = std::env::temp_dir().join("test_key_rotation_file.sqlite");
+    _ = std::fs::remove_file(&path);
+    let backend = Backend::Sqlite(path);
+    let file = KeyStorage::File(key_file.clone());
+    let retired_files = || -> Vec<PathBuf> {
+        std::fs::read_dir(std::env::temp_dir()).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.file_name().unwrap().to_string_lossy().starts_with("test_key_rotation.prvkeys.retired-"))
+            .collect()
+    };
+    retired_files().iter().for_each(|path| _ = std::fs::remove_file(path));
+    let first_key = backend.new_depo_with_key_storage(&file).await.unwrap().public_key().clone();
+    let rotation = backend.rotate_key(&file, Duration::from_secs(60)).await.unwrap();
+    assert_eq!(rotation.previous_key(), &first_key);
+    assert_eq!(retired_files().len(), 1);
+    let depo = backend.new_depo_with_key_storage(&file).await.unwrap();
+    assert_eq!(depo.public_key(), rotation.new_key());
+    assert_eq!(PrivateKeyBase::from_ur_string(std::fs::read_to_string(&key_file).unwrap().trim()).unwrap().public_keys(), *rotation.new_key());
+    assert!(accepts_key(&depo, &first_key).await);
+    let second_key = rotation.new_key().clone();
+    let rotation = backend.rotate_key(&file, Duration::ZERO).await.unwrap();
+    let depo = backend.new_depo_with_key_storage(&file).await.unwrap();
+    assert_eq!(depo.public_key(), rotation.new_key());
+    assert!(accepts_key(&depo, &first_key).await);
+    assert!(!accepts_key(&depo, &second_key).await);
+    assert_eq!(retired_files().len(), 1);
+
+    // A previous key stored unencrypted is encrypted along with the current
+    // key once the depository is opened with a passphrase.
+    let path = std::env::temp_dir().join("test_key_rotation_passphrase.sqlite");
+    _ = std::fs::remove_file(&path);
+    let backend = Backend::Sqlite(path.clone());
+    let first_key = backend.new_depo().await.unwrap().public_key().clone();
+    backend.rotate_key(&KeyStorage::default(), Duration::from_secs(60)).await.unwrap();
+    let passphrase = KeyStorage::Passphrase { passphrase: "rotation passphrase".to_string(), iterations: TEST_PBKDF2_ITERATIONS };
+    let depo = backend.new_depo_with_key_storage(&passphrase).await.unwrap();
+    assert!(accepts_key(&depo, &first_key).await);
+    let retired_key: String = rusqlite::Connection::open(&path).unwrap()
+        .query_row("SELECT private_key FROM key_rotations", [], |row| row.get(0))
+        .unwrap();
+    assert!(retired_key.starts_with("ur:envelope/"));
+    let depo = backend.new_depo_with_key_storage(&passphrase).await.unwrap();
+    assert!(accepts_key(&depo, &first_key).await);
+
+    let config = DbConfig::from_env().unwrap();
+    let backend = Backend::Postgres { config, schema_name: "test_key_rotation".to_string() };
+    if let Err(e) = backend.reset_db().await {
+        warn!("{}", Yellow.paint(format!("Skipping PostgreSQL in `{}` because can't connect to the database.", "test_key_rotation")).to_string());
+        warn!("{}", Yellow.paint(format!("{}", e)).to_string());
+        return;
+    }
+    test_key_rotation_scenario(&backend).await;
+}
+
+/// Returns whether the depository answers a request encrypted to the given key.
+async fn accepts_key(depo: &Depo, server_key: &PublicKeyBase) -> bool {
+    let private_key = PrivateKeyBase::new();
+    let request = StoreShareRequest::new(private_key.public_keys(), Bytes::from_static(b"rotated")).envelope();
+    let request = add_request_date(request, dcbor::Date::now()).unwrap()
+        .sign_and_encrypt(&private_key, server_key).unwrap();
+    error_code(&depo.handle_request(request).await).as_deref() != Some(DepoError::WrongRecipient.code())
+}
+
+async fn test_key_rotation_scenario(backend: &Backend) {
+    let first_key = backend.new_depo().await.unwrap().public_key().clone();
+    let rotation = backend.rotate_key(&KeyStorage::default(), Duration::from_secs(60 * 60)).await.unwrap();
+    assert_eq!(rotation.previous_key(), &first_key);
+
+    let depo = backend.new_depo().await.unwrap();
+    let second_key = depo.public_key().clone();
+    assert_eq!(rotation.new_key(), &second_key);
+    assert_ne!(second_key, first_key);
+    assert_eq!(depo.key_rotations().len(), 1);
+    assert_eq!(KeyRotation::verify(&depo.key_rotations()[0], &first_key).unwrap(), rotation);
+    assert!(KeyRotation::verify(&depo.key_rotations()[0], &second_key).is_err());
+
+    let alice_private_key = PrivateKeyBase::new();
+    let alice_public_key = alice_private_key.public_keys();
+    let call_with_key = |server_key: PublicKeyBase| {
+        let request = StoreShareRequest::new(&alice_public_key, Bytes::from_static(b"rotated")).envelope();
+        let request = add_request_date(request, dcbor::Date::now()).unwrap()
+            .sign_and_encrypt(&alice_private_key, &server_key).unwrap();
+        let depo = depo.clone();
+        async move { depo.handle_request(request).await }
+    };
+    let response = call_with_key(first_key.clone()).await
+        .verify_and_decrypt(&first_key, &alice_private_key).unwrap();
+    StoreShareResponse::try_from(response).unwrap();
+    let response = call_with_key(second_key.clone()).await
+        .verify_and_decrypt(&second_key, &alice_private_key).unwrap();
+    StoreShareResponse::try_from(response).unwrap();
+
+    // A rotation with no grace period stops accepting the previous key at once,
+    // while the earlier rotation's grace period continues.
+    backend.rotate_key(&KeyStorage::default(), Duration::ZERO).await.unwrap();
+    let depo = backend.new_depo().await.unwrap();
+    assert_eq!(depo.key_rotations().len(), 2);
+    let third_key = KeyRotation::verify(&depo.key_rotations()[1], &second_key).unwrap().new_key().clone();
+    assert_eq!(depo.public_key(), &third_key);
+    let request = StoreShareRequest::new(&alice_public_key, Bytes::from_static(b"rotated")).envelope();
+    let request = add_request_date(request, dcbor::Date::now()).unwrap();
+    let response = depo.handle_request(request.sign_and_encrypt(&alice_private_key, &second_key).unwrap()).await;
+    assert_eq!(error_code(&response).as_deref(), Some(DepoError::WrongRecipient.code()));
+    let response = depo.handle_request(request.sign_and_encrypt(&alice_private_key, &first_key).unwrap()).await
+        .verify_and_decrypt(&first_key, &alice_private_key).unwrap();
+    StoreShareResponse::try_from(response).unwrap();
+}
+
+/// Test that expired shares and idle accounts without shares are removed by
+/// garbage collection.
+#[tokio::test]
+async fn test_garbage_collection() {
+    setup_log();
+    test_garbage_collection_scenario(&Depo::new_in_memory()).await;
+
+    let path = std::env::temp_dir().join("test_garbage_collection.sqlite");
+    _ = std::fs::remove_file(&path);
+    test_garbage_collection_scenario(&Depo::new_sqlite(&path).await.unwrap()).await;
+}
+
+async fn test_garbage_collection_scenario(depo: &Depo) {
+    let alice_private_key = PrivateKeyBase::new();
+    let alice_public_key = alice_private_key.public_keys();
+    let request = StoreShareRequest::new(&alice_public_key, Bytes::from_static(b"expiring")).envelope();
+    let response = dated_call(depo, add_share_ttl(request, 1).unwrap(), &alice_private_key).await;
+    StoreShareResponse::try_from(response).unwrap();
+    let request = StoreShareRequest::new(&alice_public_key, Bytes::from_static(b"permanent")).envelope();
+    let response = dated_call(depo, request, &alice_private_key).await;
+    let permanent_receipt = StoreShareResponse::try_from(response).unwrap().receipt().clone();
+
+    let bob_private_key = PrivateKeyBase::new();
+    let bob_public_key = bob_private_key.public_keys();
+    let request = StoreShareRequest::new(&bob_public_key, Bytes::from_static(b"deleted")).envelope();
+    let response = dated_call(depo, request, &bob_private_key).await;
+    let bob_receipt = StoreShareResponse::try_from(response).unwrap().receipt().clone();
+    let request = DeleteSharesRequest::new(&bob_public_key, vec![&bob_receipt]).envelope();
+    dated_call(depo, request, &bob_private_key).await;
+
+    assert_eq!(depo.collect_garbage(Some(Duration::from_secs(60))).await.unwrap(), (0, 0));
+
+    sleep(Duration::from_secs(3)).await;
+    assert_eq!(depo.collect_garbage(None).await.unwrap(), (1, 0));
+    assert_eq!(depo.collect_garbage(Some(Duration::from_secs(1))).await.unwrap(), (0, 1));
+
+    let request = GetSharesRequest::new(&alice_public_key, vec![]).envelope();
+    let response = GetSharesResponse::try_from(dated_call(depo, request, &alice_private_key).await).unwrap();
+    assert_eq!(response.receipt_to_data().len(), 1);
+    assert!(response.data_for_receipt(&permanent_receipt).is_some());
+}
+
+/// Test that `listShares` returns the receipts of an account's shares with
+/// their size, creation time and expiry.
+#[tokio::test]
+async fn test_list_shares() {
+    setup_log();
+    test_list_shares_scenario(&Depo::new_in_memory()).await;
+
+    let path = std::env::temp_dir().join("test_list_shares.sqlite");
+    _ = std::fs::remove_file(&path);
+    test_list_shares_scenario(&Depo::new_sqlite(&path).await.unwrap()).await;
+
+    let config = DbConfig::from_env().unwrap();
+    let backend = Backend::Postgres { config, schema_name: "test_list_shares".to_string() };
+    if let Err(e) = backend.reset_db().await {
+        warn!("{}", Yellow.paint(format!("Skipping PostgreSQL in `{}` because can't connect to the database.", "test_list_shares")).to_string());
+        warn!("{}", Yellow.paint(format!("{}", e)).to_string());
+        return;
+    }
+    test_list_shares_scenario(&backend.new_depo().await.unwrap()).await;
+}
+
+async fn test_list_shares_scenario(depo: &Depo) {
+    let alice_private_key = PrivateKeyBase::new();
+    let alice_public_key = alice_private_key.public_keys();
+
+    // Listing is no way to create an account.
+    assert!(list_shares(depo, &alice_private_key).await.is_error());
+
+    let before = dcbor::Date::now().timestamp().floor();
+    let request = StoreShareRequest::new(&alice_public_key, Bytes::from_static(b"cafebabe")).envelope();
+    let permanent_receipt = StoreShareResponse::try_from(dated_call(depo, request, &alice_private_key).await).unwrap().receipt();
+    let request = StoreShareRequest::new(&alice_public_key, Bytes::from_static(b"deadbeef00")).envelope();
+    let response = dated_call(depo, add_share_ttl(request, 60).unwrap(), &alice_private_key).await;
+    let expiring_receipt = StoreShareResponse::try_from(response).unwrap().receipt();
+    // Storing the same data again does not add a share.
+    let request = StoreShareRequest::new(&alice_public_key, Bytes::from_static(b"cafebabe")).envelope();
+    dated_call(depo, request, &alice_private_key).await;
+    let after = dcbor::Date::now().timestamp();
+
+    let bob_private_key = PrivateKeyBase::new();
+    let request = StoreShareRequest::new(bob_private_key.public_keys(), Bytes::from_static(b"bob")).envelope();
+    dated_call(depo, request, &bob_private_key).await;
+
+    let response = ListSharesResponse::try_from(list_shares(depo, &alice_private_key).await).unwrap();
+    let shares = response.shares();
+    assert_eq!(shares.len(), 2);
+    let permanent = shares.iter().find(|share| share.receipt() == &permanent_receipt).unwrap();
+    assert_eq!(permanent.size(), 8);
+    assert!(permanent.expiry().is_none());
+    let created = permanent.created().unwrap().timestamp();
+    assert!(before <= created && created <= after);
+    let expiring = shares.iter().find(|share| share.receipt() == &expiring_receipt).unwrap();
+    assert_eq!(expiring.size(), 10);
+    assert!(expiring.expiry().unwrap().timestamp() > after);
+
+    let response = ListSharesResponse::try_from(list_shares(depo, &bob_private_key).await).unwrap();
+    assert_eq!(response.shares().len(), 1);
+    assert_eq!(response.shares()[0].size(), 3);
+}
+
+/// Test that a share's label is stored with it, returned by `getShares` and
+/// `listShares`, can be changed without the data, and counts against the
+/// account's quota.
+#[tokio::test]
+async fn test_share_labels() {
+    setup_log();
+    test_share_labels_scenario(&Depo::new_in_memory()).await;
+
+    let path = std::env::temp_dir().join("test_share_labels.sqlite");
+    _ = std::fs::remove_file(&path);
+    test_share_labels_scenario(&Depo::new_sqlite(&path).await.unwrap()).await;
+
+    rusqlite::Connection::open(&path).unwrap()
+        .execute("UPDATE settings SET max_bytes_per_account = 100", [])
+        .unwrap();
+    let depo = Depo::new_sqlite(&path).await.unwrap();
+    let carol_public_key = PrivateKeyBase::new().public_keys();
+    let data = Bytes::from(vec![0u8; 50]);
+    let receipt = depo.store_share(&carol_public_key, &data, None, Some(&label_of_size(40))).await.unwrap();
+    let error = depo.update_share_label(&carol_public_key, &receipt, Some(&label_of_size(60))).await.unwrap_err();
+    assert!(matches!(error, DepoError::QuotaExceeded(_)));
+    depo.update_share_label(&carol_public_key, &receipt, None).await.unwrap();
+    let error = depo.store_share(&carol_public_key, &Bytes::from_static(b"more"), None, Some(&label_of_size(50))).await.unwrap_err();
+    assert!(matches!(error, DepoError::QuotaExceeded(_)));
+    depo.store_share(&carol_public_key, &Bytes::from_static(b"more"), None, Some(&label_of_size(40))).await.unwrap();
+
+    let config = DbConfig::from_env().unwrap();
+    let backend = Backend::Postgres { config, schema_name: "test_share_labels".to_string() };
+    if let Err(e) = backend.reset_db().await {
+        warn!("{}", Yellow.paint(format!("Skipping PostgreSQL in `{}` because can't connect to the database.", "test_share_labels")).to_string());
+        warn!("{}", Yellow.paint(format!("{}", e)).to_string());
+        return;
+    }
+    test_share_labels_scenario(&backend.new_depo().await.unwrap()).await;
+}
+
+/// Returns a label of about `size` bytes.
+fn label_of_size(size: usize) -> Envelope {
+    Envelope::new(CBOR::byte_string(vec![0u8; size.saturating_sub(4)]))
+}
+
+async fn test_share_labels_scenario(depo: &Depo) {
+    let alice_private_key = PrivateKeyBase::new();
+    let alice_public_key = alice_private_key.public_keys();
+    let wallet_label = Envelope::new("wallet").encrypt_subject_to_recipient(&alice_public_key).unwrap();
+    let label_of = |shares: &[ShareInfo], receipt: &Receipt| {
+        shares.iter().find(|share| share.receipt() == receipt).unwrap().label().cloned()
+    };
+
+    let request = add_share_label(
+        StoreShareRequest::new(&alice_public_key, Bytes::from_static(b"cafebabe")).envelope(),
+        wallet_label.clone(),
+    ).unwrap();
+    let labeled_receipt = StoreShareResponse::try_from(dated_call(depo, request, &alice_private_key).await).unwrap().receipt();
+    let unlabeled_receipt = depo.store_share(&alice_public_key, &Bytes::from_static(b"deadbeef"), None, None).await.unwrap();
+
+    let shares = depo.list_shares(&alice_public_key).await.unwrap();
+    let label = label_of(&shares, &labeled_receipt).unwrap();
+    assert!(label.is_identical_to(wallet_label.clone()));
+    let text: String = label.decrypt_to_recipient(&alice_private_key).unwrap().extract_subject().unwrap();
+    assert_eq!(text, "wallet");
+    assert!(label_of(&shares, &unlabeled_receipt).is_none());
+
+    // The labels ride alongside a `getShares` response that older clients can
+    // still read.
+    let request = GetSharesRequest::new(&alice_public_key, vec![]).envelope();
+    let response = dated_call(depo, request, &alice_private_key).await;
+    let labels = share_labels(&response).unwrap();
+    assert_eq!(labels.len(), 1);
+    assert!(labels[&labeled_receipt].is_identical_to(wallet_label.clone()));
+    assert_eq!(GetSharesResponse::try_from(response).unwrap().receipt_to_data().len(), 2);
+
+    // Storing the same data again keeps the label unless it gives a new one.
+    depo.store_share(&alice_public_key, &Bytes::from_static(b"cafebabe"), None, None).await.unwrap();
+    let shares = depo.list_shares(&alice_public_key).await.unwrap();
+    assert!(label_of(&shares, &labeled_receipt).unwrap().is_identical_to(wallet_label.clone()));
+    let vault_label = Envelope::new("vault");
+    depo.store_share(&alice_public_key, &Bytes::from_static(b"cafebabe"), None, Some(&vault_label)).await.unwrap();
+    let shares = depo.list_shares(&alice_public_key).await.unwrap();
+    assert!(label_of(&shares, &labeled_receipt).unwrap().is_identical_to(vault_label.clone()));
+
+    let request = UpdateShareLabelRequest::new(&alice_public_key, unlabeled_receipt.clone(), Some(wallet_label.clone()));
+    let response = dated_call(depo, request.envelope(), &alice_private_key).await;
+    UpdateShareLabelResponse::try_from(response).unwrap();
+    depo.update_share_label(&alice_public_key, &labeled_receipt, None).await.unwrap();
+    let shares = depo.list_shares(&alice_public_key).await.unwrap();
+    assert!(label_of(&shares, &labeled_receipt).is_none());
+    assert!(label_of(&shares, &unlabeled_receipt).unwrap().is_identical_to(wallet_label.clone()));
+    assert_eq!(depo.get_share(&alice_public_key, &unlabeled_receipt).await.unwrap(), Bytes::from_static(b"deadbeef"));
+
+    let error = depo.update_share_label(&alice_public_key, &labeled_receipt, Some(&label_of_size(501))).await.unwrap_err();
+    assert_eq!(error.code(), "label_too_large");
+    let error = depo.store_share(&alice_public_key, &Bytes::from_static(b"other"), None, Some(&label_of_size(501))).await.unwrap_err();
+    assert!(matches!(error, DepoError::LabelTooLarge));
+
+    let bob_public_key = PrivateKeyBase::new().public_keys();
+    depo.store_share(&bob_public_key, &Bytes::from_static(b"bob"), None, None).await.unwrap();
+    let error = depo.update_share_label(&bob_public_key, &labeled_receipt, Some(&wallet_label)).await.unwrap_err();
+    assert!(matches!(error, DepoError::UnknownReceipt));
+}
+
+async fn list_shares(depo: &Depo, private_key: &PrivateKeyBase) -> Envelope {
+    dated_call(depo, ListSharesRequest::new(private_key.public_keys()).envelope(), private_key).await
+}
+
+/// Test that concurrent requests creating the same account, storing shares
+/// against the same quota, or claiming the same recovery method, do not race,
+/// and that deleting an account removes everything at once.
+#[tokio::test]
+async fn test_concurrent_account_operations() {
+    setup_log();
+    test_concurrent_account_operations_scenario(&Depo::new_in_memory()).await;
+
+    let path = std::env::temp_dir().join("test_concurrent_account_operations.sqlite");
+    _ = std::fs::remove_file(&path);
+    test_concurrent_account_operations_scenario(&Depo::new_sqlite(&path).await.unwrap()).await;
+
+    let config = DbConfig::from_env().unwrap();
+    let schema_name = "test_concurrent_account_operations".to_string();
+    let backends = [
+        ("MySQL", Backend::MySql { config: config.clone(), schema_name: schema_name.clone() }),
+        ("PostgreSQL", Backend::Postgres { config, schema_name }),
+    ];
+    for (name, backend) in backends {
+        if let Err(e) = backend.create_db_if_needed().await {
+            warn!("{}", Yellow.paint(format!("Skipping {} in `{}` because can't connect to the database.", name, "test_concurrent_account_operations")).to_string());
+            warn!("{}", Yellow.paint(format!("{}", e)).to_string());
+            continue;
+        }
+        test_concurrent_account_operations_scenario(&backend.new_depo().await.unwrap()).await;
+    }
+}
+
+async fn test_concurrent_account_operations_scenario(depo: &Depo) {
+    let alice_public_key = PrivateKeyBase::new().public_keys();
+    let stores = (0..8u8).map(|i| {
+        let depo = depo.clone();
+        let key = alice_public_key.clone();
+        tokio::spawn(async move { depo.store_share(&key, &Bytes::from(vec![i]), None, None).await })
+    }).collect::<Vec<_>>();
+    for store in stores {
+        store.await.unwrap().unwrap();
+    }
+    assert_eq!(depo.get_shares(&alice_public_key, &Default::default()).await.unwrap().len(), 8);
+
+    // Concurrent stores cannot together exceed the account's quota.
+    let max_shares = depo.descriptor().max_shares_per_account() as usize;
+    let dave_public_key = PrivateKeyBase::new().public_keys();
+    let stores = (0..max_shares + 4).map(|i| {
+        let depo = depo.clone();
+        let key = dave_public_key.clone();
+        tokio::spawn(async move { depo.store_share(&key, &Bytes::from((i as u32).to_be_bytes().to_vec()), None, None).await })
+    }).collect::<Vec<_>>();
+    let mut stored = 0;
+    for store in stores {
+        match store.await.unwrap() {
+            Ok(_) => stored += 1,
+            Err(e) => assert!(matches!(e, DepoError::QuotaExceeded(_))),
+        }
+    }
+    assert_eq!(stored, max_shares);
+    depo.delete_account(&dave_public_key).await.unwrap();
+
+    let bob_public_key = PrivateKeyBase::new().public_keys();
+    depo.store_share(&bob_public_key, &Bytes::from_static(b"bob"), None, None).await.unwrap();
+    let recovery = format!("{}@example.com", hex::encode(bc_rand::random_data(8)));
+    let updates = [&alice_public_key, &bob_public_key].map(|key| {
+        let depo = depo.clone();
+        let key = key.clone();
+        let recovery = recovery.clone();
+        tokio::spawn(async move { depo.update_recovery(&key, Some(&recovery)).await })
+    });
+    let mut succeeded = 0;
+    for update in updates {
+        if update.await.unwrap().is_ok() {
+            succeeded += 1;
+        }
+    }
+    assert_eq!(succeeded, 1);
+
+    depo.delete_account(&alice_public_key).await.unwrap();
+    depo.delete_account(&bob_public_key).await.unwrap();
+    let error = depo.get_shares(&alice_public_key, &Default::default()).await.unwrap_err();
+    assert!(matches!(error, DepoError::UnknownPublicKey(_)));
+
+    // The recovery method is free again once its account is deleted.
+    let carol_public_key = PrivateKeyBase::new().public_keys();
+    depo.store_share(&carol_public_key, &Bytes::from_static(b"carol"), None, None).await.unwrap();
+    depo.update_recovery(&carol_public_key, Some(&recovery)).await.unwrap();
+    depo.delete_account(&carol_public_key).await.unwrap();
+}
+
+/// Test that metrics count requests and errors without identifying accounts.
+#[tokio::test]
+async fn test_metrics() {
+    setup_log();
+    let depo = Depo::new_in_memory();
+    let alice_private_key = PrivateKeyBase::new();
+    let alice_public_key = alice_private_key.public_keys();
+
+    let request = add_request_date(
+        StoreShareRequest::new(&alice_public_key, Bytes::from_static(b"share")).envelope(),
+        dcbor::Date::now(),
+    ).unwrap();
+    StoreShareResponse::try_from(signed_call(&depo, request.clone(), &alice_private_key).await).unwrap();
+    signed_call(&depo, request, &alice_private_key).await;
+    depo.handle_request_string("nonsense".to_string()).await;
+
+    // The counts of accounts and shares are refreshed when garbage is collected.
+    depo.collect_garbage(None).await.unwrap();
+    let metrics = depo.metrics_text().unwrap();
+    assert!(metrics.contains(r#"depo_requests_total{function="storeShare"} 1"#));
+    assert!(metrics.contains(r#"depo_request_duration_seconds_count{function="storeShare"} 1"#));
+    assert!(metrics.contains(r#"depo_errors_total{code="duplicate_request"} 1"#));
+    assert!(metrics.contains(r#"depo_errors_total{code="invalid_request"} 1"#));
+    assert!(metrics.contains("depo_users 1"));
+    assert!(metrics.contains("depo_records 1"));
+    assert!(!metrics.contains(&alice_public_key.ur_string()));
+    assert!(!metrics.contains("depo_pool"));
+
+    // Backends with a connection pool also report how busy it is.
+    let config = DbConfig::from_env().unwrap();
+    let backend = Backend::Postgres { config, schema_name: "test_metrics".to_string() };
+    if let Err(e) = backend.create_db_if_needed().await {
+        warn!("{}", Yellow.paint(format!("Skipping PostgreSQL in `{}` because can't connect to the database.", "test_metrics")).to_string());
+        warn!("{}", Yellow.paint(format!("{}", e)).to_string());
+        return;
+    }
+    let depo = backend.new_depo().await.unwrap();
+    depo.check_storage().await.unwrap();
+    let metrics = depo.metrics_text().unwrap();
+    assert!(metrics.contains("depo_pool_max_size"));
+    assert!(metrics.contains("depo_pool_connections_in_use 0"));
+    assert!(metrics.contains("depo_pool_connections_idle 1"));
+}
+
+/// Test that requests are limited per key and recovery attempts per recovery
+/// method.
+#[tokio::test]
+async fn test_rate_limits() {
+    setup_log();
+    let depo = Depo::new_in_memory()
+        .with_key_rate_limit(Some(RateLimit::per_minute(3)))
+        .with_recovery_rate_limit(Some(RateLimit::per_hour(2)));
+
+    let alice_private_key = PrivateKeyBase::new();
+    let alice_public_key = alice_private_key.public_keys();
+    for _ in 0..3 {
+        let request = GetSharesRequest::new(&alice_public_key, vec![]).envelope();
+        dated_call(&depo, request, &alice_private_key).await;
+    }
+    let request = GetSharesRequest::new(&alice_public_key, vec![]).envelope();
+    let response = dated_call(&depo, request, &alice_private_key).await;
+    assert!(response.error::<String>().unwrap().contains("rate limit exceeded for this key"));
+
+    for attempt in 0..3 {
+        let bob_private_key = PrivateKeyBase::new();
+        let bob_public_key = bob_private_key.public_keys();
+        let request = StartRecoveryRequest::new(&bob_public_key, "alice@example.com").envelope();
+        let response = dated_call(&depo, request, &bob_private_key).await;
+        let error = response.error::<String>().unwrap();
+        if attempt < 2 {
+            assert!(error.contains("unknown recovery"));
+        } else {
+            assert!(error.contains("rate limit exceeded for this recovery method"));
+        }
+    }
+}
+
+/// Test that the server answers 429 to addresses over the rate limit.
+#[tokio::test]
+async fn test_server_ip_rate_limit() {
+    setup_log();
+    let port: u16 = 5338;
+    let config = ServerConfig {
+        port,
+        ip_rate_limit: Some(RateLimit::per_minute(2)),
+        ..ServerConfig::new(Backend::Memory)
+    };
+    tokio::spawn(async move {
+        start_server(&config).await.unwrap();
+    });
+    sleep(Duration::from_secs(1)).await;
+
+    let client = Client::new();
+    for expected in [StatusCode::OK, StatusCode::OK, StatusCode::TOO_MANY_REQUESTS] {
+        let response = client.post(url(port)).body("nonsense").send().await.unwrap();
+        assert_eq!(response.status(), expected);
+    }
+    let response = client.get(url(port)).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+async fn dated_call(depo: &Depo, request: Envelope, client_private_key: &PrivateKeyBase) -> Envelope {
+    signed_call(depo, add_request_date(request, dcbor::Date::now()).unwrap(), client_private_key).await
+}
+
+async fn signed_call(depo: &Depo, request: Envelope, client_private_key: &PrivateKeyBase) -> Envelope {
+    let encrypted_request = request.sign_and_encrypt(client_private_key, depo.public_key()).unwrap();
+    depo.handle_request(encrypted_request).await
+        .verify_and_decrypt(depo.public_key(), client_private_key).unwrap()
+}
+
+/// Test that `/reset-db` only exists when an admin key is configured, and only
+/// accepts requests signed by that key.
+#[tokio::test]
+async fn test_server_reset_db() {
+    setup_log();
+    let disabled_port: u16 = 5334;
+    let enabled_port: u16 = 5335;
+    let admin_private_key = PrivateKeyBase::new();
+    let admin_public_key = admin_private_key.public_keys();
+
+    let disabled_config = ServerConfig { port: disabled_port, ..ServerConfig::new(Backend::Memory) };
+    tokio::spawn(async move {
+        start_server(&disabled_config).await.unwrap();
+    });
+    let enabled_config = ServerConfig {
+        port: enabled_port,
+        admin_key: Some(admin_public_key.clone()),
+        ..ServerConfig::new(Backend::Memory)
+    };
+    tokio::spawn(async move {
+        start_server(&enabled_config).await.unwrap();
+    });
+    sleep(Duration::from_secs(1)).await;
+
+    let disabled = ClientRequestHandler::new(disabled_port);
+    let depo_public_key = get_public_key(&disabled).await.unwrap();
+    let body = add_request_date(ResetDbRequest::new(&admin_public_key).envelope(), dcbor::Date::now()).unwrap()
+        .sign_and_encrypt(&admin_private_key, &depo_public_key).unwrap()
+        .ur_string();
+    assert_eq!(disabled.reset_db(body).await, StatusCode::NOT_FOUND);
+
+    let enabled = ClientRequestHandler::new(enabled_port);
+    let depo_public_key = get_public_key(&enabled).await.unwrap();
+
+    assert_eq!(enabled.reset_db("not an envelope".to_string()).await, StatusCode::BAD_REQUEST);
+
+    let other_private_key = PrivateKeyBase::new();
+    let other_public_key = other_private_key.public_keys();
+    let body = add_request_date(ResetDbRequest::new(&other_public_key).envelope(), dcbor::Date::now()).unwrap()
+        .sign_and_encrypt(&other_private_key, &depo_public_key).unwrap()
+        .ur_string();
+    assert_eq!(enabled.reset_db(body).await, StatusCode::FORBIDDEN);
+
+    let body = add_request_date(ResetDbRequest::new(&admin_public_key).envelope(), dcbor::Date::now()).unwrap()
+        .sign_and_encrypt(&admin_private_key, &depo_public_key).unwrap()
+        .ur_string();
+    assert_eq!(enabled.reset_db(body).await, StatusCode::OK);
+}
+
+/// Test the server over HTTPS, including picking up a new certificate on SIGHUP.
+#[cfg(unix)]
+#[tokio::test]
+async fn test_server_tls() {
+    setup_log();
+    let port: u16 = 5336;
+    let dir = std::env::temp_dir();
+    let cert_path = dir.join("test_server_tls.crt");
+    let key_path = dir.join("test_server_tls.key");
+
+    let write_cert = || {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_pem = cert.serialize_pem().unwrap();
+        std::fs::write(&cert_path, &cert_pem).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        cert_pem
+    };
+    let https_client = |cert_pem: &str| {
+        Client::builder()
+            .tls_built_in_root_certs(false)
+            .add_root_certificate(reqwest::Certificate::from_pem(cert_pem.as_bytes()).unwrap())
+            .build()
+            .unwrap()
+    };
+    let mut url = Url::parse("https://localhost").unwrap();
+    url.set_port(Some(port)).unwrap();
+
+    let old_cert = write_cert();
+    let config = ServerConfig {
+        port,
+        tls: Some(TlsConfig::new(&cert_path, &key_path)),
+        ..ServerConfig::new(Backend::Memory)
+    };
+    tokio::spawn(async move {
+        start_server(&config).await.unwrap();
+    });
+    sleep(Duration::from_secs(1)).await;
+
+    let resp = https_client(&old_cert).get(url.clone()).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    PublicKeyBase::from_ur_string(resp.text().await.unwrap()).unwrap();
+
+    // Plain HTTP is not served.
+    let mut http_url = url.clone();
+    http_url.set_scheme("http").unwrap();
+    assert!(Client::new().get(http_url).send().await.is_err());
+
+    // A renewed certificate is served after SIGHUP.
+    let new_cert = write_cert();
+    let status = std::process::Command::new("kill")
+        .args(["-HUP", &std::process::id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    sleep(Duration::from_millis(500)).await;
+
+    assert!(https_client(&old_cert).get(url.clone()).send().await.is_err());
+    let resp = https_client(&new_cert).get(url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+/// Test the health, readiness, and metrics routes.
+#[tokio::test]
+async fn test_server_health() {
+    setup_log();
+    let port: u16 = 5337;
+    let path = std::env::temp_dir().join("test_server_health.sqlite");
+    _ = std::fs::remove_file(&path);
+
+    let config = ServerConfig { port, ..ServerConfig::new(Backend::Sqlite(path)) };
+    tokio::spawn(async move {
+        start_server(&config).await.unwrap();
+    });
+    sleep(Duration::from_secs(1)).await;
+
+    let client = Client::new();
+    let get = |route: &str| client.get(url(port).join(route).unwrap()).send();
+
+    let response = get("healthz").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await.unwrap(), r#"{"status":"ok"}"#);
+
+    let response = get("readyz").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await.unwrap(), r#"{"status":"ready","storage":"ok"}"#);
+
+    let response = get("metrics").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.text().await.unwrap().contains("depo_users 0"));
+}
+
+/// Test that the descriptor route serves the server's key and limits, signed by
+/// that key.
+#[tokio::test]
+async fn test_server_descriptor() {
+    setup_log();
+    let port: u16 = 5339;
+    let config = ServerConfig { port, ..ServerConfig::new(Backend::Memory) };
+    tokio::spawn(async move {
+        start_server(&config).await.unwrap();
+    });
+    sleep(Duration::from_secs(1)).await;
+
+    let client = Client::new();
+    let public_key_string = client.get(url(port)).send().await.unwrap().text().await.unwrap();
+    let public_key = PublicKeyBase::from_ur_string(public_key_string).unwrap();
+    let response = client.get(url(port).join("descriptor").unwrap()).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let signed = Envelope::from_ur_string(response.text().await.unwrap()).unwrap();
+
+    let descriptor = ServerDescriptor::verify(&signed, &public_key).unwrap();
+    assert_eq!(descriptor.public_key(), &public_key);
+    assert_eq!(descriptor.version(), env!("CARGO_PKG_VERSION"));
+    assert_eq!(descriptor.max_data_size(), 1000);
+    assert_eq!(descriptor.max_label_size(), 500);
+    assert_eq!(descriptor.continuation_expiry_seconds(), 60 * 60 * 24);
+    assert_eq!(descriptor.max_shares_per_account(), 100);
+    assert_eq!(descriptor.request_window_seconds(), 60 * 5);
+    assert_eq!(descriptor.functions().len(), 11);
+    assert!(descriptor.supports_function("storeShare"));
+    assert!(descriptor.supports_function("finishRecovery"));
+    assert!(!descriptor.supports_function("resetDb"));
+    assert_eq!(descriptor.recovery_verifications(), ["local"]);
+
+    // A descriptor is only trusted for the key it is signed by.
+    let other_key = PrivateKeyBase::new();
+    assert!(ServerDescriptor::verify(&signed, &other_key.public_keys()).is_err());
+    let forged = descriptor.sign(&other_key);
+    assert!(ServerDescriptor::verify(&forged, &public_key).is_err());
+}
+
+/// Test the client library against the HTTP server, including following a
+/// rotation of the server key.
+#[cfg(feature = "client")]
+#[tokio::test]
+async fn test_depo_client() {
+    setup_log();
+    let port: u16 = 5340;
+    let codes = recovery_codes_path("test_depo_client");
+    let path = std::env::temp_dir().join("test_depo_client.sqlite");
+    _ = std::fs::remove_file(&path);
+    let backend = Backend::Sqlite(path);
+    let recovery_verifier = Arc::new(LocalRecoveryVerifier::with_file(&codes));
+    let config = ServerConfig { port, recovery_verifier, ..ServerConfig::new(backend.clone()) };
+    tokio::spawn(async move {
+        start_server(&config).await.unwrap();
+    });
+    sleep(Duration::from_secs(1)).await;
+
+    let client = DepoClient::connect(url(port)).await.unwrap();
+    assert_eq!(client.descriptor().await.unwrap().public_key(), client.server_key());
+
+    let alice_private_key = PrivateKeyBase::new();
+    let receipt_1 = client.store_share(&alice_private_key, b"cafebabe", None, None).await.unwrap();
+    let receipt_2 = client.store_share(&alice_private_key, b"deadbeef", Some(60), None).await.unwrap();
+    assert_eq!(client.get_share(&alice_private_key, &receipt_1).await.unwrap(), Bytes::from_static(b"cafebabe"));
+    assert_eq!(client.get_shares(&alice_private_key, &HashSet::new()).await.unwrap().len(), 2);
+    let shares = client.list_shares(&alice_private_key).await.unwrap();
+    assert_eq!(shares.iter().map(|share| share.size()).collect::<Vec<_>>(), vec![8, 8]);
+    let label = Envelope::new("wallet");
+    client.update_share_label(&alice_private_key, &receipt_1, Some(&label)).await.unwrap();
+    let shares = client.list_shares(&alice_private_key).await.unwrap();
+    assert!(shares.iter().find(|share| share.receipt() == &receipt_1).unwrap().label().unwrap().is_identical_to(label));
+    client.delete_share(&alice_private_key, &receipt_2).await.unwrap();
+    assert!(client.get_share(&alice_private_key, &receipt_2).await.is_err());
+    let error = client.store_share(&alice_private_key, vec![0; 1001], None, None).await.unwrap_err();
+    assert_eq!(error.downcast_ref::<ErrorResponse>().unwrap().code(), Some(DepoError::DataTooLarge.code()));
+
+    client.update_recovery(&alice_private_key, Some("alice@example.com")).await.unwrap();
+    assert_eq!(client.get_recovery(&alice_private_key).await.unwrap().as_deref(), Some("alice@example.com"));
+    let alice_new_private_key = PrivateKeyBase::new();
+    let continuation = client.start_recovery(&alice_new_private_key, "alice@example.com").await.unwrap();
+    let code = latest_verification_code(&codes, "alice@example.com");
+    client.finish_recovery(&alice_new_private_key, continuation, code).await.unwrap();
+    assert!(client.get_recovery(&alice_private_key).await.is_err());
+    let alice_private_key = alice_new_private_key;
+    let bob_key = PrivateKeyBase::new().public_keys();
+    client.update_key(&alice_private_key, &bob_key).await.unwrap();
+    assert!(client.get_share(&alice_private_key, &receipt_1).await.is_err());
+
+    // A client pinned to a key that was rotated follows the rotation, and one
+    // pinned to some other key refuses to.
+    backend.rotate_key(&KeyStorage::default(), Duration::from_secs(60)).await.unwrap();
+    let rotated_port: u16 = 5341;
+    let config = ServerConfig { port: rotated_port, ..ServerConfig::new(backend) };
+    tokio::spawn(async move {
+        start_server(&config).await.unwrap();
+    });
+    sleep(Duration::from_secs(1)).await;
+
+    let mut client = DepoClient::new(url(rotated_port), client.server_key().clone());
+    let previous_key = client.server_key().clone();
+    assert!(client.follow_key_rotations().await.unwrap());
+    assert_ne!(client.server_key(), &previous_key);
+    assert!(!client.follow_key_rotations().await.unwrap());
+    client.store_share(&PrivateKeyBase::new(), b"rotated", None, None).await.unwrap();
+
+    let mut stranger = DepoClient::new(url(rotated_port), PrivateKeyBase::new().public_keys());
+    assert!(stranger.follow_key_rotations().await.is_err());
+    assert!(stranger.descriptor().await.is_err());
+}
+
+/// Test the `depo-cli` binary against the HTTP server.
+#[cfg(feature = "client")]
+#[tokio::test]
+async fn test_depo_cli() {
+    setup_log();
+    let port: u16 = 5342;
+    let config = ServerConfig { port, ..ServerConfig::new(Backend::Memory) };
+    tokio::spawn(async move {
+        start_server(&config).await.unwrap();
+    });
+    sleep(Duration::from_secs(1)).await;
+
+    let dir = std::env::temp_dir();
+    let key_file = dir.join("test_depo_cli.prvkeys");
+    let share_file = dir.join("test_depo_cli.share");
+    let output_file = dir.join("test_depo_cli.output");
+    for path in [&key_file, &output_file] {
+        _ = std::fs::remove_file(path);
+    }
+    std::fs::write(&share_file, b"cafebabe").unwrap();
+    let cli = |args: &[&str]| {
+        let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_depo-cli"))
+            .args(["--url", url(port).as_str(), "--key-file", key_file.to_str().unwrap()])
+            .args(args)
+            .output();
+        async move {
+            let output = output.await.unwrap();
+            (output.status.success(), String::from_utf8(output.stdout).unwrap().trim().to_string())
+        }
+    };
+
+    let (success, public_key) = cli(&["keygen"]).await;
+    assert!(success);
+    assert_eq!(cli(&["public-key"]).await, (true, public_key));
+    assert!(!cli(&["keygen"]).await.0, "keygen must not replace an existing key");
+
+    let (success, receipt) = cli(&["store", share_file.to_str().unwrap()]).await;
+    assert!(success);
+    assert!(receipt.starts_with("ur:envelope/"));
+    assert_eq!(cli(&["list"]).await, (true, receipt.clone()));
+    let (success, listing) = cli(&["list", "--long"]).await;
+    assert!(success);
+    assert!(listing.starts_with(&format!("{} 8 ", receipt)));
+    assert!(listing.ends_with(" - -"));
+    assert!(cli(&["set-label", &receipt, "savings wallet"]).await.0);
+    assert!(cli(&["list", "--long"]).await.1.ends_with(" - savings wallet"));
+    assert!(cli(&["get", &receipt, "--output", output_file.to_str().unwrap()]).await.0);
+    assert_eq!(std::fs::read(&output_file).unwrap(), b"cafebabe");
+
+    assert!(cli(&["set-recovery", "carol@example.com"]).await.0);
+    assert_eq!(cli(&["get-recovery"]).await, (true, "carol@example.com".to_string()));
+
+    assert!(cli(&["delete", &receipt]).await.0);
+    assert_eq!(cli(&["list"]).await, (true, String::new()));
+    assert!(!cli(&["get", &receipt]).await.0);
+}
+
+/// Test splitting a secret across several depositories with SSKR and
+/// recovering it when one of them has lost its share.
+#[cfg(feature = "client")]
+#[tokio::test]
+async fn test_sskr_distribution() {
+    setup_log();
+    let ports: [u16; 3] = [5343, 5344, 5345];
+    for port in ports {
+        let config = ServerConfig { port, ..ServerConfig::new(Backend::Memory) };
+        tokio::spawn(async move {
+            start_server(&config).await.unwrap();
+        });
+    }
+    sleep(Duration::from_secs(1)).await;
+    let mut depos = Vec::new();
+    for port in ports {
+        depos.push(DepoClient::connect(url(port)).await.unwrap());
+    }
+
+    let key = PrivateKeyBase::new();
+    let secret = hex!("7daa851251002874e1a1995f0897e6b1");
+    let spec = SSKRSpec::new(1, vec![SSKRGroupSpec::new(2, 3).unwrap()]).unwrap();
+    assert!(ShareDistribution::distribute(secret, &spec, &depos[..2], &key).await.is_err());
+    let distribution = ShareDistribution::distribute(secret, &spec, &depos, &key).await.unwrap();
+    assert_eq!(distribution.shares().len(), 3);
+
+    // The distribution survives being passed around as a UR.
+    let ur = distribution.clone().envelope().ur_string();
+    let distribution = ShareDistribution::try_from(Envelope::from_ur_string(ur).unwrap()).unwrap();
+    assert_eq!(distribution.recover(&key).await.unwrap(), secret);
+    assert!(distribution.recover(&PrivateKeyBase::new()).await.is_err());
+
+    let lost = &distribution.shares()[0];
+    depos[ports.iter().position(|port| lost.url().port() == Some(*port)).unwrap()]
+        .delete_share(&key, lost.receipt()).await.unwrap();
+    assert_eq!(distribution.recover(&key).await.unwrap(), secret);
+
+    distribution.delete(&key).await.unwrap();
+    assert!(distribution.recover(&key).await.is_err());
 }
 
 /// Test against the full Depo HTTP server running in separate process.
+/// The server must be started with `--recovery-codes-file` pointing at
+/// `test_server_separate.codes` in the temporary directory.
 #[tokio::test]
 async fn test_server_separate() {
     setup_log();
+    let codes = std::env::temp_dir().join("test_server_separate.codes");
 
     let port: u16 = 5332;
     let depo = ClientRequestHandler::new(port);
@@ -86,7 +1240,7 @@ async fn test_server_separate() {
         }
     };
 
-    test_depo_scenario(&depo_public_key, &depo).await;
+    test_depo_scenario(&depo_public_key, &depo, &codes).await;
 }
 
 #[async_trait]
@@ -115,6 +1269,13 @@ impl ClientRequestHandler {
     }
 }
 
+impl ClientRequestHandler {
+    async fn reset_db(&self, body: String) -> StatusCode {
+        let url = url(self.port).join("reset-db").unwrap();
+        self.client.post(url).body(body).send().await.unwrap().status()
+    }
+}
+
 #[async_trait]
 impl RequestHandler for ClientRequestHandler {
     async fn handle_encrypted_request(&self, encrypted_request: Envelope) -> Envelope {
@@ -146,7 +1307,7 @@ async fn server_call(
     depo_public_key: &PublicKeyBase,
     depo: &impl RequestHandler,
 ) -> Envelope {
-    let request = request.envelope();
+    let request = add_request_date(request.envelope(), dcbor::Date::now()).unwrap();
     let encrypted_request = request.sign_and_encrypt(client_private_key, depo_public_key).unwrap();
 
     let raw_response = depo.handle_encrypted_request(encrypted_request).await;
@@ -162,7 +1323,25 @@ async fn server_call(
     response
 }
 
-pub async fn test_depo_scenario(depo_public_key: &PublicKeyBase, depo: &impl RequestHandler) {
+/// Returns a fresh file for a `LocalRecoveryVerifier` to write codes to.
+fn recovery_codes_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("{}.codes", name));
+    _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Returns the most recent verification code sent to the recovery contact.
+fn latest_verification_code(codes: &Path, recovery: &str) -> String {
+    std::fs::read_to_string(codes).unwrap()
+        .lines()
+        .rev()
+        .filter_map(|line| line.split_once('\t'))
+        .find(|(r, _)| *r == recovery)
+        .map(|(_, code)| code.to_string())
+        .unwrap()
+}
+
+pub async fn test_depo_scenario(depo_public_key: &PublicKeyBase, depo: &impl RequestHandler, recovery_codes: &Path) {
     info!("{}", Cyan.paint("=== Alice stores a share"));
     let alice_private_key = PrivateKeyBase::new();
     let alice_public_key = alice_private_key.public_keys();
@@ -330,30 +1509,88 @@ pub async fn test_depo_scenario(depo_public_key: &PublicKeyBase, depo: &impl Req
     let response_envelope = server_call(request, &bob_private_key_2, depo_public_key, depo).await;
     assert!(response_envelope.error::<String>().unwrap().contains("unknown recovery"));
 
+    info!("{}", Red.paint("=== A recovery is started, but Bob changes his recovery method before it is finished"));
+    let request = StartRecoveryRequest::new(&bob_public_key_2, bob_recovery);
+    let response_envelope = server_call(request, &bob_private_key_2, depo_public_key, depo).await;
+    let stale_continuation = StartRecoveryResponse::try_from(response_envelope).unwrap().continuation().clone();
+    let stale_code = latest_verification_code(recovery_codes, bob_recovery);
+    let request = UpdateRecoveryRequest::new(&bob_public_key, Some("bob@example.net"));
+    let response_envelope = server_call(request, &bob_private_key, depo_public_key, depo).await;
+    assert!(response_envelope.is_result_ok().unwrap());
+    let request = UpdateRecoveryRequest::new(&bob_public_key, Some(bob_recovery));
+    let response_envelope = server_call(request, &bob_private_key, depo_public_key, depo).await;
+    assert!(response_envelope.is_result_ok().unwrap());
+    let request = add_verification_code(FinishRecoveryRequest::new(&bob_public_key_2, stale_continuation).envelope(), stale_code).unwrap();
+    let response_envelope = server_call(request, &bob_private_key_2, depo_public_key, depo).await;
+    assert!(response_envelope.error::<String>().unwrap().contains("continuation already used or no longer valid"));
+
     info!("{}", Cyan.paint("=== Bob requests a transfer using the correct recovery method"));
     let request = StartRecoveryRequest::new(&bob_public_key_2, bob_recovery);
     let response_envelope = server_call(request, &bob_private_key_2, depo_public_key, depo).await;
     let response = StartRecoveryResponse::try_from(response_envelope).unwrap();
 
     // The recovery continuation is both signed by the server and encrypted to
-    // the server, and is also time-limited. It is sent to Bob's recovery
-    // contact method, which acts as a second factor. Once in possession of the
-    // recovery continuation, Bob can use it to finish the recovery process.
+    // the server, and is also time-limited. A one-time verification code is
+    // sent to Bob's recovery contact method, which acts as a second factor.
+    // Bob needs both the continuation and the code to finish the recovery.
     //
-    // For testing purposes only, we're allowed to skip the second factor and
-    // get the recovery continuation directly.
+    // For testing purposes only, the code is read from the file the local
+    // verifier writes it to.
     let continuation = response.continuation();
+    let verification_code = latest_verification_code(recovery_codes, bob_recovery);
 
     info!("{}", Red.paint("=== Bob attempts to use the recovery continuation to finish setting his new public key, but the request is signed by his old key"));
-    let request = FinishRecoveryRequest::new(&bob_public_key, continuation.clone());
+    let request = add_verification_code(FinishRecoveryRequest::new(&bob_public_key, continuation.clone()).envelope(), &verification_code).unwrap();
     let response_envelope = server_call(request, &bob_private_key, depo_public_key, depo).await;
     assert!(response_envelope.error::<String>().unwrap().contains("invalid user signing key"));
 
-    info!("{}", Cyan.paint("=== Bob uses the recovery continuation to finish setting his new public key, properly signed by his new key"));
-    let request = FinishRecoveryRequest::new(&bob_public_key_2, continuation);
+    info!("{}", Red.paint("=== Someone who knows Bob's recovery method but not the code attempts to finish the recovery"));
+    let request = FinishRecoveryRequest::new(&bob_public_key_2, continuation.clone());
+    let response_envelope = server_call(request, &bob_private_key_2, depo_public_key, depo).await;
+    assert!(response_envelope.error::<String>().unwrap().contains("missing verification code"));
+
+    let wrong_code = if verification_code == "00000000" { "00000001" } else { "00000000" };
+    let request = add_verification_code(FinishRecoveryRequest::new(&bob_public_key_2, continuation.clone()).envelope(), wrong_code).unwrap();
+    let response_envelope = server_call(request, &bob_private_key_2, depo_public_key, depo).await;
+    assert!(response_envelope.error::<String>().unwrap().contains("invalid verification code"));
+
+    info!("{}", Red.paint("=== A wrong code uses up the continuation, so the right code no longer finishes the recovery"));
+    let request = add_verification_code(FinishRecoveryRequest::new(&bob_public_key_2, continuation.clone()).envelope(), &verification_code).unwrap();
+    let response_envelope = server_call(request, &bob_private_key_2, depo_public_key, depo).await;
+    assert!(response_envelope.error::<String>().unwrap().contains("continuation already used or no longer valid"));
+
+    info!("{}", Red.paint("=== Bob's new key is used by another account before he finishes a recovery"));
+    let request = StartRecoveryRequest::new(&bob_public_key_2, bob_recovery);
+    let response_envelope = server_call(request, &bob_private_key_2, depo_public_key, depo).await;
+    let continuation = StartRecoveryResponse::try_from(response_envelope).unwrap().continuation().clone();
+    let verification_code = latest_verification_code(recovery_codes, bob_recovery);
+    let request = StoreShareRequest::new(&bob_public_key_2, Bytes::from_static(b"squatter"));
+    let response_envelope = server_call(request, &bob_private_key_2, depo_public_key, depo).await;
+    assert!(StoreShareResponse::try_from(response_envelope).is_ok());
+    let request = add_verification_code(FinishRecoveryRequest::new(&bob_public_key_2, continuation).envelope(), &verification_code).unwrap();
+    let response_envelope = server_call(request, &bob_private_key_2, depo_public_key, depo).await;
+    assert!(response_envelope.error::<String>().unwrap().contains("public key already in use"));
+    let request = DeleteAccountRequest::new(&bob_public_key_2);
+    let response_envelope = server_call(request, &bob_private_key_2, depo_public_key, depo).await;
+    assert!(response_envelope.is_result_ok().unwrap());
+
+    info!("{}", Cyan.paint("=== Bob starts the recovery again"));
+    let request = StartRecoveryRequest::new(&bob_public_key_2, bob_recovery);
+    let response_envelope = server_call(request, &bob_private_key_2, depo_public_key, depo).await;
+    let response = StartRecoveryResponse::try_from(response_envelope).unwrap();
+    let continuation = response.continuation();
+    let verification_code = latest_verification_code(recovery_codes, bob_recovery);
+
+    info!("{}", Cyan.paint("=== Bob uses the recovery continuation and code to finish setting his new public key, properly signed by his new key"));
+    let request = add_verification_code(FinishRecoveryRequest::new(&bob_public_key_2, continuation.clone()).envelope(), &verification_code).unwrap();
     let response_envelope = server_call(request, &bob_private_key_2, depo_public_key, depo).await;
     assert!(response_envelope.is_result_ok().unwrap());
 
+    info!("{}", Red.paint("=== The recovery continuation cannot be used a second time"));
+    let request = add_verification_code(FinishRecoveryRequest::new(&bob_public_key_2, continuation).envelope(), &verification_code).unwrap();
+    let response_envelope = server_call(request, &bob_private_key_2, depo_public_key, depo).await;
+    assert!(response_envelope.error::<String>().unwrap().contains("continuation already used or no longer valid"));
+
     info!("{}", Red.paint("=== Bob can no longer retrieve his shares using the old public key"));
     let request = GetSharesRequest::new(&bob_public_key, vec![]);
     let response_envelope = server_call(request, &bob_private_key, depo_public_key, depo).await;